@@ -15,11 +15,16 @@ pub fn derive_keywords(input: TokenStream) -> TokenStream {
     };
     let keywords: Vec<_> = fields
         .iter()
-        .filter(|field| field.attrs.len() == 1)
-        .map(|field| {
-            match field.attrs[0].parse_meta() {
+        .filter_map(|field| {
+            let attr = field
+                .attrs
+                .iter()
+                .find(|attr| attr.path.is_ident("keyword"))?;
+            Some((field, attr))
+        })
+        .map(|(field, attr)| {
+            match attr.parse_meta() {
                 Ok(Meta::Path(_)) => {
-                    // TODO: check path? (but don't need to for just me)
                     // TODO: this without manual `Lit` stuff?
                     let left = Lit::ByteStr(LitByteStr::new(
                         field.ident.to_string().to_uppercase().as_bytes(),
@@ -42,7 +47,7 @@ pub fn derive_keywords(input: TokenStream) -> TokenStream {
                             }) = meta
                             {
                                 let left = Lit::ByteStr(LitByteStr::new(
-                                    format!("{}", segments[0].ident).as_bytes(),
+                                    segments[0].ident.to_string().to_uppercase().as_bytes(),
                                     segments[0].span(),
                                 ));
                                 let a = &input.ident;
@@ -62,9 +67,9 @@ pub fn derive_keywords(input: TokenStream) -> TokenStream {
         /// Helper struct with method `get` to search keywords
         pub struct Keyword;
         impl Keyword {
-            /// Searches keyword for bytestring match, returns `Option<Token>`
+            /// Searches keyword for case-insensitive bytestring match, returns `Option<Token>`
             pub fn get(key: &[u8]) -> Option<Token> {
-                match key {
+                match &key.to_ascii_uppercase()[..] {
                     #(#keywords)*
                     _ => None,
                 }
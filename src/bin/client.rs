@@ -1,75 +1,123 @@
-use std::io::Cursor;
-
-use anyhow::Result;
-use futures::{select, FutureExt};
+use anyhow::{bail, Context, Result};
 use rustyline::{history::DefaultHistory, Config, EditMode, Editor};
-use sequel::connection::Frame;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::watch,
-};
+use sequel::connection::{Connection, Frame};
+use tokio::net::TcpStream;
 
-// TODO: use `Connection`?
 #[tokio::main]
 async fn main() -> Result<()> {
-    let mut stream = TcpStream::connect("127.0.0.1:3000").await?;
-    let (mut rd, mut wr) = stream.split();
+    let socket = TcpStream::connect("127.0.0.1:3000").await?;
+    let mut connection = Connection::new(socket);
+    let mut rl = Editor::<(), DefaultHistory>::with_config(
+        Config::builder()
+            .auto_add_history(true)
+            .edit_mode(EditMode::Emacs)
+            .build(),
+    )?;
 
-    let (tx, mut rx) = watch::channel(String::new());
-    // TODO: this is pretty bad
-    let (tx2, mut rx2) = watch::channel(false);
-    tokio::spawn(async move {
-        let mut rl = Editor::<(), DefaultHistory>::with_config(
-            Config::builder()
-                .auto_add_history(true)
-                .edit_mode(EditMode::Emacs)
-                .build(),
-        )
-        .unwrap();
+    loop {
+        // Reading a line blocks, so it happens off the runtime's threads
+        let (editor, readline) = tokio::task::spawn_blocking(move || {
+            let readline = rl.readline("SQL> ");
+            (rl, readline)
+        })
+        .await?;
+        rl = editor;
+        let Ok(line) = readline else {
+            println!("bye");
+            break;
+        };
+        // TODO: don't send if incomplete; allow user to write multiline cmds
+        let res = match line.strip_prefix("\\copy") {
+            Some(copy) => local_copy(&mut connection, copy).await,
+            None => request(&mut connection, Frame::Cmd(line.clone().into())).await,
+        };
+        match res {
+            Ok(frame) => println!("{}", frame.render(&line)),
+            Err(e) => println!("\x1b[31mError: {}\x1b[0m", e),
+        }
+    }
 
-        loop {
-            if let Ok(readline) = rl.readline("SQL> ") {
-                tx.send(readline).unwrap();
-            } else {
-                break;
-            }
-            loop {
-                rx2.changed().await.unwrap();
-                if *rx2.borrow() {
-                    break;
-                }
+    Ok(())
+}
+
+async fn request(connection: &mut Connection, frame: Frame) -> Result<Frame> {
+    connection.write_frame(&frame).await?;
+    match connection.read_frame().await? {
+        Some(frame) => Ok(frame),
+        None => bail!("Server closed the connection"),
+    }
+}
+
+/// `\copy` takes the same arguments as `COPY`, but reads or writes a file on this machine,
+/// with the rows going over the connection
+async fn local_copy(connection: &mut Connection, copy: &str) -> Result<Frame> {
+    let Some((statement, from, path)) = split_copy(copy) else {
+        bail!("Usage: \\copy table [(cols)] FROM 'file' [options] or \\copy (query) TO 'file' [options]");
+    };
+    if from {
+        let data =
+            std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path))?;
+        match request(connection, Frame::Cmd(statement.into())).await? {
+            Frame::Null => request(connection, Frame::Text(data)).await,
+            other => Ok(other),
+        }
+    } else {
+        match request(connection, Frame::Cmd(statement.into())).await? {
+            Frame::Text(data) => {
+                std::fs::write(&path, &data)
+                    .with_context(|| format!("Failed to write {}", path))?;
+                Ok(Frame::Null)
             }
+            other => Ok(other),
         }
-    });
-
-    let mut buf = [0; 4096];
+    }
+}
 
-    loop {
-        select! {
-            n = rd.read(&mut buf).fuse() => {
-                let mut cursor = Cursor::new(&buf[..n?]);
-                if let Ok(frame) = Frame::parse(&mut cursor) {
-                    println!("{}", frame);
+/// The `COPY` statement sent for a `\copy`, with `STDIN` or `STDOUT` in place of the file,
+/// whether rows are copied from the file, and the file's path
+fn split_copy(copy: &str) -> Option<(String, bool, String)> {
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in copy.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, c) if depth == 0 && c.is_whitespace() => {
+                let rest = &copy[i..];
+                let word_end = rest.trim_start().find(char::is_whitespace)?;
+                let start = rest.len() - rest.trim_start().len();
+                let word = &rest[start..start + word_end];
+                let from = word.eq_ignore_ascii_case("FROM");
+                if !from && !word.eq_ignore_ascii_case("TO") {
+                    continue;
                 }
-                tx2.send(true)?;
-            }
-            res = rx.changed().fuse() => {
-                if res.is_ok() {
-                    tx2.send(false)?;
-                    // TODO: don't send if incomplete; allow user to write multiline cmds
-                    let line = rx.borrow();
-                    wr.write_u8(b':').await?;
-                    wr.write_all(line.as_bytes()).await?;
-                    wr.write_all(b"\r\n").await?;
-                    wr.flush().await?;
-                } else {
-                    println!("bye");
-                    break;
+                let after = rest[start + word_end..].trim_start();
+                let q = after.chars().next().filter(|c| matches!(c, '\'' | '"'))?;
+                let mut path = String::new();
+                let mut chars = after[1..].char_indices().peekable();
+                while let Some((j, c)) = chars.next() {
+                    if c != q {
+                        path.push(c);
+                    } else if chars.next_if(|&(_, c)| c == q).is_some() {
+                        path.push(q);
+                    } else {
+                        let statement = format!(
+                            "COPY {}{} {}{}",
+                            &copy[..i],
+                            &rest[..start + word_end],
+                            if from { "STDIN" } else { "STDOUT" },
+                            &after[j + 2..]
+                        );
+                        return Some((statement, from, path));
+                    }
                 }
+                return None;
             }
+            (None, _) => {}
         }
     }
-
-    Ok(())
+    None
 }
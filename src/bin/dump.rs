@@ -0,0 +1,67 @@
+use std::io::Read;
+
+use anyhow::{anyhow, bail, Context, Result};
+use sequel::{
+    connection::{Connection, Frame},
+    split_script,
+};
+use tokio::net::TcpStream;
+
+const USAGE: &str = "Usage: sequel-dump [--addr <host:port>] [--load <file or ->]";
+
+/// Prints a script that recreates the server's tables, or with `--load` runs such a script,
+/// stopping at the first statement that fails. The script runs as one transaction, so then
+/// none of it is kept.
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut addr = "127.0.0.1:3000".to_string();
+    let mut load = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--addr", Some(arg)) => addr = arg,
+            ("--load", Some(path)) => load = Some(path),
+            _ => bail!(USAGE),
+        }
+    }
+
+    let socket = TcpStream::connect(&addr)
+        .await
+        .with_context(|| format!("Failed to connect to {}", addr))?;
+    let mut connection = Connection::new(socket);
+    match load {
+        None => match run(&mut connection, "DUMP".into()).await? {
+            Frame::Text(script) => print!("{}", script),
+            other => bail!("Expected a script, got {}", other),
+        },
+        Some(path) => {
+            let mut script = String::new();
+            if path == "-" {
+                std::io::stdin().read_to_string(&mut script)?;
+            } else {
+                script = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path))?;
+            }
+            let statements = split_script(&script);
+            for (i, statement) in statements.iter().enumerate() {
+                run(&mut connection, statement.clone())
+                    .await
+                    .with_context(|| format!("Statement {} failed: {}", i + 1, statement))?;
+            }
+            eprintln!("Ran {} statements", statements.len());
+        }
+    }
+    Ok(())
+}
+
+/// Sends `statement` and waits for the answer, failing on an error
+async fn run(connection: &mut Connection, statement: String) -> Result<Frame> {
+    connection
+        .write_frame(&Frame::Cmd(statement.into()))
+        .await?;
+    match connection.read_frame().await? {
+        Some(Frame::Error(e)) | Some(Frame::SpannedError(e, _)) => Err(anyhow!(e)),
+        Some(frame) => Ok(frame),
+        None => bail!("Server closed the connection"),
+    }
+}
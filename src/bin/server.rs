@@ -1,33 +1,76 @@
-use anyhow::Result;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
 use sequel::{
     connection::{Connection, Frame},
-    run_cmd, Db,
+    Db, Session, Storage,
 };
 use tokio::net::{TcpListener, TcpStream};
 
+/// Where data is kept unless `--data-dir` or `SEQUEL_DATA_DIR` says otherwise
+const DEFAULT_DATA_DIR: &str = "data";
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let (data_dir, restore) = args()?;
+    if let Some(backup) = restore {
+        Storage::restore(&data_dir, &backup)?;
+        println!("Restored {}", backup);
+    }
+    let (storage, tables) = Storage::open(data_dir)?;
+    let storage = Arc::new(storage);
+    let db: Db = Arc::new(tables.into());
+
     let listener = TcpListener::bind("127.0.0.1:3000").await?;
 
     println!("Listening");
 
-    let db = Db::default();
-
     loop {
         let (socket, _) = listener.accept().await?;
         let db = db.clone();
+        let storage = storage.clone();
 
         tokio::spawn(async move {
-            process(socket, db).await;
+            process(socket, db, storage).await;
         });
     }
 }
 
-async fn process(socket: TcpStream, db: Db) {
+/// The data directory, and the backup to restore into it before starting if there is one
+fn args() -> Result<(String, Option<String>)> {
+    let mut data_dir = None;
+    let mut restore = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--data-dir", Some(dir)) => data_dir = Some(dir),
+            ("--restore", Some(backup)) => restore = Some(backup),
+            _ => bail!("Usage: sequel-server [--data-dir <path>] [--restore <backup>]"),
+        }
+    }
+    let data_dir = data_dir.unwrap_or_else(|| {
+        std::env::var("SEQUEL_DATA_DIR").unwrap_or_else(|_| DEFAULT_DATA_DIR.into())
+    });
+    Ok((data_dir, restore))
+}
+
+async fn process(socket: TcpStream, db: Db, storage: Arc<Storage>) {
     println!("Accepted");
     let mut connection = Connection::new(socket);
-    while let Some(Frame::Cmd(cmd)) = connection.read_frame().await.unwrap() {
-        let response = run_cmd(&db, cmd);
+    let mut session = Session::with_storage(db.clone(), storage.clone());
+    while let Some(frame) = connection.read_frame().await.unwrap() {
+        // A statement can block waiting for a row another connection holds
+        let response = tokio::task::block_in_place(|| {
+            panic::catch_unwind(AssertUnwindSafe(|| session.run(frame)))
+        });
+        let response = response.unwrap_or_else(|_| {
+            // A fresh session, which rolls back the transaction of the one it replaces
+            session = Session::with_storage(db.clone(), storage.clone());
+            Frame::Error("Internal error, the transaction has been rolled back".into())
+        });
         connection.write_frame(&response).await.unwrap();
     }
     println!("Client disconnected");
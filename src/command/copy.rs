@@ -0,0 +1,376 @@
+use std::fs;
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use ordered_float::OrderedFloat;
+
+use crate::{
+    connection::Frame,
+    db::{Changes, Conflict, Db, Table, Txn},
+    parse::{
+        error_at, CopyFormat, CopyOptions, CopyTarget, LiteralValue, OnError, Select, Spanned,
+        Token, Tokens,
+    },
+    plan, Ty,
+};
+
+use super::{insert::build_rows, json::Json, table_mut};
+
+/// Adds the rows of a CSV or JSON file to `table`. Each goes through the same checks as an
+/// `INSERT`, and with `OnError::Report` the rows that fail them are answered with, one per
+/// line of the file, while the rest are added.
+pub fn copy_from(
+    db: &Db,
+    txn: &mut Txn,
+    table: Spanned<Token>,
+    cols: Tokens,
+    source: CopyTarget,
+    options: CopyOptions,
+) -> Result<Frame> {
+    let text = match source {
+        CopyTarget::File(path) => {
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path))?
+        }
+        CopyTarget::Data(text) => text,
+        CopyTarget::Client => bail!("COPY FROM STDIN needs a session"),
+    };
+    let name = table.ident().ok_or_else(|| anyhow!("Internal error"))?;
+    db.write(name, |tables| {
+        let data = table_mut(tables, &table)?;
+        let records = match options.format {
+            CopyFormat::Csv => csv_records(data, &cols, &text, &options)?,
+            CopyFormat::Json => Json::parse_array(&text)?
+                .into_iter()
+                .map(|(line, value)| (line, json_record(value, &cols)))
+                .collect(),
+            CopyFormat::Ndjson => text
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(i, line)| {
+                    let record = Json::parse(line).and_then(|value| json_record(value, &cols));
+                    (i + 1, record)
+                })
+                .collect(),
+        };
+
+        // Values that can't be read, or rows that don't fit the columns, fail before any
+        // row is added
+        let mut rows = Vec::new();
+        let mut rejected = Vec::new();
+        for (line, record) in records {
+            let row = record.and_then(|(cols, row)| build_rows(data, cols, vec![row]));
+            match row {
+                Ok(mut row) => rows.push((line, row.remove(0))),
+                Err(e) if options.on_error == OnError::Abort => bail!("Line {}: {}", line, e),
+                Err(e) => rejected.push((line, e.to_string())),
+            }
+        }
+
+        Changes::apply(tables, txn, |changes| {
+            for (line, cols) in rows {
+                let res = changes.attempt(|changes| changes.insert(name, cols).map(|_| ()));
+                match res {
+                    Ok(()) => {}
+                    // Waiting for the row starts the statement over
+                    Err(e) if e.is::<Conflict>() => return Err(e),
+                    Err(e) if options.on_error == OnError::Abort => {
+                        bail!("Line {}: {}", line, e)
+                    }
+                    Err(e) => rejected.push((line, e.to_string())),
+                }
+            }
+            Ok(())
+        })?;
+        Ok(match options.on_error {
+            OnError::Abort => Frame::Null,
+            OnError::Report => {
+                rejected.sort_by_key(|(line, _)| *line);
+                let mut rows = vec![vec!["line".into(), "error".into()]];
+                rows.extend(
+                    rejected
+                        .into_iter()
+                        .map(|(line, e)| vec![line.to_string().into(), e.into()]),
+                );
+                Frame::Table(rows)
+            }
+        })
+    })
+}
+
+/// Writes the rows of `query` to a file, or with `CopyTarget::Client` answers with them
+pub fn copy_to(
+    db: &Db,
+    txn: &mut Txn,
+    query: Select,
+    target: CopyTarget,
+    options: CopyOptions,
+) -> Result<Frame> {
+    let out = db.read(&query.tables(), |tables| {
+        let mut op = plan::select(&query, tables, txn, false)?;
+        let columns = op.columns().to_vec();
+        let mut out = String::new();
+        if options.header {
+            let names: Vec<_> = columns
+                .iter()
+                .map(|name| LiteralValue::String(name.clone()))
+                .collect();
+            write_record(&mut out, &names, &options);
+        }
+        let mut objects = Vec::new();
+        while let Some(tuple) = op.next()? {
+            match options.format {
+                CopyFormat::Csv => write_record(&mut out, &tuple, &options),
+                CopyFormat::Json | CopyFormat::Ndjson => {
+                    let members = columns
+                        .iter()
+                        .cloned()
+                        .zip(tuple.into_iter().map(json_value))
+                        .collect();
+                    objects.push(Json::Object(members).to_string());
+                }
+            }
+        }
+        match options.format {
+            CopyFormat::Csv => {}
+            CopyFormat::Json if objects.is_empty() => out += "[]\n",
+            CopyFormat::Json => out += &format!("[\n{}\n]\n", objects.join(",\n")),
+            CopyFormat::Ndjson => objects
+                .iter()
+                .for_each(|object| out += &format!("{}\n", object)),
+        }
+        Ok(out)
+    })?;
+    match target {
+        CopyTarget::File(path) => {
+            fs::write(&path, out).with_context(|| format!("Failed to write {}", path))?;
+            Ok(Frame::Null)
+        }
+        CopyTarget::Client => Ok(Frame::Text(out)),
+        CopyTarget::Data(_) => Err(anyhow!("Internal error")),
+    }
+}
+
+/// The CSV records of `text` as values for `cols`, with the line each starts on
+#[allow(clippy::type_complexity)]
+fn csv_records(
+    table: &Table,
+    cols: &Tokens,
+    text: &str,
+    options: &CopyOptions,
+) -> Result<Vec<(usize, Result<(Tokens, Vec<LiteralValue>)>)>> {
+    let types = match cols {
+        Tokens::List(cols) => cols
+            .iter()
+            .map(|col| {
+                let name = col.ident().ok_or_else(|| anyhow!("Internal error"))?;
+                table
+                    .visible_keys()
+                    .find(|header| header.name() == name)
+                    .map(|header| header.ty().clone())
+                    .ok_or_else(|| error_at(col.span, format!("Column {} not found", name)))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Tokens::Omitted => table
+            .visible_keys()
+            .map(|header| header.ty().clone())
+            .collect(),
+    };
+    let mut records = read_csv(text, options)?;
+    if options.header && !records.is_empty() {
+        records.remove(0);
+    }
+    Ok(records
+        .into_iter()
+        .map(|(line, fields)| {
+            let row = fields
+                .into_iter()
+                .zip(types.iter().chain(std::iter::repeat(&Ty::String)))
+                .map(|(field, ty)| value(field, ty))
+                .collect::<Result<Vec<_>>>()
+                .map(|row| (cols.clone(), row));
+            (line, row)
+        })
+        .collect())
+}
+
+/// The columns and values of a JSON object. With a list of columns, only those keys are
+/// read. Arrays and objects are kept as JSON text.
+fn json_record(value: Json, cols: &Tokens) -> Result<(Tokens, Vec<LiteralValue>)> {
+    let Json::Object(members) = value else {
+        bail!("Expected an object");
+    };
+    let mut keys = Vec::new();
+    let mut row = Vec::new();
+    for (key, value) in members {
+        if let Tokens::List(cols) = cols {
+            if !cols.iter().any(|col| col.ident() == Some(&key)) {
+                continue;
+            }
+        }
+        if keys.contains(&key) {
+            bail!("Key {} appears twice", key);
+        }
+        row.push(match value {
+            Json::Null => LiteralValue::Null,
+            Json::Bool(b) => LiteralValue::Bool(b),
+            Json::Number(n) => LiteralValue::Number(OrderedFloat(n)),
+            Json::Int(n) => LiteralValue::Int(n),
+            Json::Decimal(d) => LiteralValue::Decimal(d),
+            Json::String(s) => LiteralValue::String(s),
+            value @ (Json::Array(_) | Json::Object(_)) => LiteralValue::String(value.to_string()),
+        });
+        keys.push(key);
+    }
+    let keys = keys
+        .into_iter()
+        .map(|key| Token::Identifier(key).into())
+        .collect();
+    Ok((Tokens::List(keys), row))
+}
+
+fn json_value(val: LiteralValue) -> Json {
+    match val {
+        LiteralValue::Null => Json::Null,
+        LiteralValue::Bool(b) => Json::Bool(b),
+        LiteralValue::Number(n) => Json::Number(n.0),
+        LiteralValue::Int(n) => Json::Int(n),
+        LiteralValue::Decimal(d) => Json::Decimal(d),
+        LiteralValue::String(s) => Json::String(s),
+        // Written as the text they are read back from
+        val => Json::String(String::from_utf8_lossy(&Bytes::from(&val)).into_owned()),
+    }
+}
+
+/// The value of a field in a column of type `ty`. `None` is the `NULL` marker.
+fn value(field: Option<String>, ty: &Ty) -> Result<LiteralValue> {
+    let Some(field) = field else {
+        return Ok(LiteralValue::Null);
+    };
+    Ok(match ty {
+        // Dates and times are read from the text once the row is added
+        Ty::String
+        | Ty::Varchar(_)
+        | Ty::Char(_)
+        | Ty::Date
+        | Ty::Time
+        | Ty::Timestamp
+        | Ty::TimestampTz
+        | Ty::Interval => LiteralValue::String(field),
+        Ty::Decimal(_) => LiteralValue::Decimal(
+            field
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid number {:?}", field))?,
+        ),
+        // Stored as the column's type, once the row is added
+        Ty::Number | Ty::SmallInt | Ty::Integer | Ty::BigInt | Ty::Real | Ty::Double => {
+            match field.trim().parse() {
+                Ok(n) => LiteralValue::Int(n),
+                Err(_) => LiteralValue::Number(
+                    field
+                        .trim()
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid number {:?}", field))?,
+                ),
+            }
+        }
+        Ty::Bool => match &field.trim().to_ascii_lowercase()[..] {
+            "true" | "t" | "1" => LiteralValue::Bool(true),
+            "false" | "f" | "0" => LiteralValue::Bool(false),
+            _ => bail!("Invalid bool {:?}", field),
+        },
+    })
+}
+
+/// The records of a CSV file, with the line each starts on. Fields between quotes can hold
+/// the delimiter, line breaks and the quote itself, written twice. Blank lines are skipped.
+#[allow(clippy::type_complexity)]
+fn read_csv(text: &str, options: &CopyOptions) -> Result<Vec<(usize, Vec<Option<String>>)>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut in_quotes = false;
+    let (mut line, mut start) = (1, 1);
+
+    let end_field = |record: &mut Vec<_>, field: &mut String, quoted: &mut bool| {
+        let field = std::mem::take(field);
+        record.push(if !*quoted && field == options.null {
+            None
+        } else {
+            Some(field)
+        });
+        *quoted = false;
+    };
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c == options.quote {
+                if chars.peek() == Some(&options.quote) {
+                    chars.next();
+                    field.push(c);
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        } else if c == options.quote && field.is_empty() && !quoted {
+            in_quotes = true;
+            quoted = true;
+        } else if c == options.delimiter {
+            end_field(&mut record, &mut field, &mut quoted);
+        } else if c == '\n' || (c == '\r' && chars.peek() == Some(&'\n')) {
+            if c == '\r' {
+                chars.next();
+            }
+            if !record.is_empty() || !field.is_empty() || quoted {
+                end_field(&mut record, &mut field, &mut quoted);
+                records.push((start, std::mem::take(&mut record)));
+            }
+            line += 1;
+            start = line;
+        } else {
+            field.push(c);
+        }
+    }
+    if in_quotes {
+        bail!("Line {}: Quoted field is not closed", start);
+    }
+    if !record.is_empty() || !field.is_empty() || quoted {
+        end_field(&mut record, &mut field, &mut quoted);
+        records.push((start, record));
+    }
+    Ok(records)
+}
+
+/// Adds a line of `values` to `out`. Strings are quoted when they would otherwise read back
+/// differently.
+fn write_record(out: &mut String, values: &[LiteralValue], options: &CopyOptions) {
+    let quote = options.quote.to_string();
+    let fields: Vec<_> = values
+        .iter()
+        .map(|val| match val {
+            LiteralValue::Null => options.null.clone(),
+            LiteralValue::Number(n) => n.to_string(),
+            LiteralValue::Int(n) => n.to_string(),
+            LiteralValue::Decimal(d) => d.to_string(),
+            LiteralValue::Bool(b) => b.to_string(),
+            LiteralValue::String(s)
+                if *s == options.null
+                    || s.contains([options.delimiter, options.quote, '\n', '\r']) =>
+            {
+                format!("{0}{1}{0}", quote, s.replace(&quote, &quote.repeat(2)))
+            }
+            LiteralValue::String(s) => s.clone(),
+            val => String::from_utf8_lossy(&Bytes::from(val)).into_owned(),
+        })
+        .collect();
+    out.push_str(&fields.join(&options.delimiter.to_string()));
+    out.push('\n');
+}
@@ -2,122 +2,362 @@ use anyhow::{anyhow, bail, Ok, Result};
 
 use crate::{
     connection::Frame,
-    db::{ColumnHeader, Db, DefaultOpt, Table},
-    parse::{ColDecl, Command, Constraint, Expr, Key, TableDef, Token},
+    db::{Changes, Column, ColumnHeader, Db, DefaultOpt, ForeignKey, Table, Tables, Txn},
+    parse::{
+        error_at, ColDecl, Command, Constraint, Expr, References, Spanned, TableConstraint,
+        TableConstraintKind, TableDef, Token, Ty,
+    },
+    plan,
 };
 
-pub fn create_table(db: &Db, name: Token, def: TableDef) -> Result<Frame> {
+pub fn create_table(db: &Db, txn: &mut Txn, name: Spanned<Token>, def: TableDef) -> Result<Frame> {
     match def {
-        TableDef::Cols(col_decls) => from_col_decls(db, name, col_decls),
-        TableDef::As(cmd) => from_other(db, name, *cmd),
+        TableDef::Cols { cols, constraints } => from_col_decls(db, txn, name, cols, constraints),
+        TableDef::As(cmd) => from_other(db, txn, name, *cmd),
     }
 }
 
-fn from_col_decls(db: &Db, name: Token, col_decls: Vec<ColDecl>) -> Result<Frame> {
+fn from_col_decls(
+    db: &Db,
+    txn: &mut Txn,
+    name: Spanned<Token>,
+    col_decls: Vec<ColDecl>,
+    table_constraints: Vec<TableConstraint>,
+) -> Result<Frame> {
+    let primary_key = primary_key(&col_decls, &table_constraints)?;
     let mut col_headers = Vec::new();
-    for col_decl in col_decls {
+    for col_decl in &col_decls {
         for constraint in col_decl.constraints() {
             match constraint {
                 Constraint::NotNull => {}
                 Constraint::Unique => {}
                 Constraint::PrimaryKey => {}
-                Constraint::ForeignKey => unimplemented!(),
-                Constraint::Check(_) => {}
+                Constraint::References(_) => {}
+                Constraint::Check(expr) => check_idents(expr, &col_decls)?,
                 Constraint::Default(_) => {}
-                Constraint::CreateIndex => unimplemented!(),
+                Constraint::CreateIndex => {}
             }
         }
         col_headers.push(
             ColumnHeader::new(col_decl.ident()?.to_string())
                 .ty(col_decl.ty().clone())
-                .primary_key(col_decl.constraints().contains(&Constraint::PrimaryKey))
+                .primary_key(primary_key.contains(col_decl.ident()?))
                 .unique(col_decl.constraints().contains(&Constraint::Unique))
                 .not_null(col_decl.constraints().contains(&Constraint::NotNull))
-                .def(extract_default(col_decl.constraints()))
+                .def(extract_default(col_decl.constraints())?)
                 .check(extract_check(col_decl.constraints()))
                 .build()?,
         );
     }
-    let table = Table::try_from(col_headers)?;
+    let mut table = Table::try_from(col_headers)?;
+    let name = name
+        .ident()
+        .ok_or_else(|| anyhow!("Internal error"))?
+        .to_string();
+    for col_decl in &col_decls {
+        if col_decl.constraints().contains(&Constraint::CreateIndex) {
+            let col = col_decl.ident()?.to_string();
+            table.create_index(format!("{}_{}_idx", name, col), vec![col], false)?;
+        }
+    }
 
-    let mut db = db.lock().unwrap();
-    db.insert(
-        name.ident()
-            .ok_or_else(|| anyhow!("Internal error"))?
-            .to_string(),
-        table,
-    );
-    Ok(Frame::Null)
+    db.alter(|tables| {
+        let mut foreign_keys = Vec::new();
+        for col_decl in &col_decls {
+            for constraint in col_decl.constraints() {
+                if let Constraint::References(references) = constraint {
+                    let col = vec![col_decl.ident_token().clone()];
+                    foreign_keys.push(foreign_key(tables, &name, &table, &col, references, None)?);
+                }
+            }
+        }
+        for constraint in table_constraints {
+            let constraint_name = constraint.name.and_then(|name| name.ident().cloned());
+            match constraint.kind {
+                TableConstraintKind::PrimaryKey(_) => table.set_pk_name(constraint_name),
+                TableConstraintKind::Unique(cols) => {
+                    table.add_unique(constraint_name, column_names(&cols, &col_decls)?)?;
+                }
+                TableConstraintKind::Check(expr) => {
+                    check_idents(&expr, &col_decls)?;
+                    table.add_check(constraint_name, expr);
+                }
+                TableConstraintKind::ForeignKey { cols, references } => {
+                    foreign_keys.push(foreign_key(
+                        tables,
+                        &name,
+                        &table,
+                        &cols,
+                        &references,
+                        constraint_name,
+                    )?);
+                }
+            }
+        }
+        for fk in foreign_keys {
+            table.add_foreign_key(fk);
+        }
+        Changes::apply(tables, txn, |changes| {
+            changes.create_table(&name, table)?;
+            Ok(Frame::Null)
+        })
+    })
 }
 
-fn from_other(db: &Db, name: Token, command: Command) -> Result<Frame> {
-    if let Command::Select { key, table } = command {
-        let mut db = db.lock().unwrap();
-        let table_name = table.ident().ok_or_else(|| anyhow!("Internal error"))?;
-        let table = db
-            .get(table_name)
-            .ok_or_else(|| anyhow!("Table \"{}\" not found", table_name))?;
-        let names = match &key {
-            Key::Glob => None,
-            Key::List(names) => Some(
-                names
-                    .iter()
-                    .map(|col| {
-                        Ok(col
-                            .ident()
-                            .ok_or_else(|| anyhow!("Internal error"))?
-                            .to_string())
-                    })
-                    .collect::<Result<Vec<_>>>()?,
+fn from_other(db: &Db, txn: &mut Txn, name: Spanned<Token>, command: Command) -> Result<Frame> {
+    let Command::Select(select) = command else {
+        bail!("expected `SELECT`");
+    };
+    db.alter(|tables| {
+        let plan = plan::plan(&select, tables)?;
+        let fields = plan.schema();
+
+        // Columns copied straight from a table keep their definition; computed ones only
+        // get a type
+        let headers = fields
+            .iter()
+            .map(|field| {
+                let source = field.source.as_ref().and_then(|(table, col)| {
+                    tables
+                        .get(table)?
+                        .col_headers()
+                        .iter()
+                        .find(|header| header.name() == col)
+                });
+                match source {
+                    Some(header) if header.name() == field.name => Ok(header.clone()),
+                    _ => ColumnHeader::new(field.name.clone())
+                        .ty(field.ty.clone())
+                        .build(),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut new_table = Table::try_from(headers)?;
+
+        let mut rows = Vec::new();
+        {
+            let mut op = plan::compile(plan, tables, txn, false)?;
+            while let Some(tuple) = op.next()? {
+                rows.push(tuple);
+            }
+        }
+        new_table.append_all(
+            rows.into_iter()
+                .map(|tuple| {
+                    fields
+                        .iter()
+                        .zip(tuple)
+                        .map(|(field, val)| Column::new(val, field.name.clone()))
+                        .collect()
+                })
+                .collect(),
+        )?;
+
+        let name = name.ident().ok_or_else(|| anyhow!("Internal error"))?;
+        Changes::apply(tables, txn, |changes| {
+            changes.create_table(name, new_table)?;
+            Ok(Frame::Null)
+        })
+    })
+}
+
+/// Resolves a foreign key from columns `cols` of `table`, which is being created as `name`.
+/// The referenced columns must exist, have the same types, and be the primary key or have
+/// a unique index.
+fn foreign_key(
+    tables: &Tables,
+    name: &str,
+    table: &Table,
+    cols: &[Spanned<Token>],
+    references: &References,
+    constraint: Option<String>,
+) -> Result<ForeignKey> {
+    let ref_name = references
+        .table
+        .ident()
+        .ok_or_else(|| anyhow!("Internal error"))?;
+    let ref_table = if ref_name == name {
+        table
+    } else {
+        tables.get(ref_name).ok_or_else(|| {
+            error_at(
+                references.table.span,
+                format!("Table \"{}\" not found", ref_name),
+            )
+        })?
+    };
+    let ref_cols = if references.cols.is_empty() {
+        ref_table
+            .primary_key()
+            .into_iter()
+            .map(|name| Spanned::from(Token::Identifier(name)))
+            .collect()
+    } else {
+        references.cols.clone()
+    };
+    if ref_cols.len() != cols.len() {
+        return Err(error_at(
+            references.table.span,
+            format!(
+                "Foreign key has {} columns but references {}",
+                cols.len(),
+                ref_cols.len()
             ),
-        };
-        let headers = match &names {
-            None => table.col_headers().to_vec(),
-            Some(names) => table
+        ));
+    }
+
+    let (mut columns, mut ref_columns) = (Vec::new(), Vec::new());
+    for (col, ref_col) in cols.iter().zip(&ref_cols) {
+        let header = |table: &Table, col: &Spanned<Token>, table_name: &str| {
+            let col_name = col.ident().ok_or_else(|| anyhow!("Internal error"))?;
+            table
                 .col_headers()
                 .iter()
-                .filter(|header| names.contains(&header.name().to_owned()))
+                .find(|header| header.name() == col_name)
                 .cloned()
-                .collect(),
+                .ok_or_else(|| {
+                    error_at(
+                        col.span,
+                        format!("Column {} not found in table \"{}\"", col_name, table_name),
+                    )
+                })
         };
-        let mut new_table = Table::try_from(headers)?;
-        for row in table.rows() {
-            let cols = match &names {
-                None => row.all_cols(),
-                Some(names) => row
-                    .all_cols()
-                    .into_iter()
-                    .filter(|col| names.contains(&col.name().to_owned()))
-                    .collect(),
-            };
-            new_table.append(cols)?;
+        let (header, ref_header) = (
+            header(table, col, name)?,
+            header(ref_table, ref_col, ref_name)?,
+        );
+        // Unpadded strings can reference each other whatever their length
+        let text = |ty: &Ty| matches!(ty, Ty::String | Ty::Varchar(_));
+        if header.ty() != ref_header.ty() && !(text(header.ty()) && text(ref_header.ty())) {
+            return Err(error_at(
+                col.span,
+                format!(
+                    "Foreign key column {} has type {:?} but {} has type {:?}",
+                    header.name(),
+                    header.ty(),
+                    ref_header.name(),
+                    ref_header.ty()
+                ),
+            ));
         }
+        columns.push(header.name().to_string());
+        ref_columns.push(ref_header.name().to_string());
+    }
+    if !ref_table.is_unique(&ref_columns) {
+        return Err(error_at(
+            references.table.span,
+            format!(
+                "No unique constraint on {}({}) for the foreign key to reference",
+                ref_name,
+                ref_columns.join(", ")
+            ),
+        ));
+    }
+    Ok(ForeignKey {
+        name: constraint,
+        columns,
+        table: ref_name.clone(),
+        ref_columns,
+        on_delete: references.on_delete,
+        on_update: references.on_update,
+    })
+}
 
-        db.insert(
-            name.ident()
-                .ok_or_else(|| anyhow!("Internal error"))?
-                .to_string(),
-            new_table,
-        );
-        Ok(Frame::Null)
-    } else {
-        bail!("expected `SELECT`");
+/// Makes sure a `CHECK` only refers to columns of the table being created
+/// The primary key columns, declared either on one column or as a table constraint
+fn primary_key(
+    col_decls: &[ColDecl],
+    table_constraints: &[TableConstraint],
+) -> Result<Vec<String>> {
+    let mut declared = Vec::new();
+    for col_decl in col_decls {
+        if col_decl.constraints().contains(&Constraint::PrimaryKey) {
+            declared.push((
+                col_decl.ident_token().span,
+                vec![col_decl.ident()?.to_string()],
+            ));
+        }
+    }
+    for constraint in table_constraints {
+        if let TableConstraintKind::PrimaryKey(cols) = &constraint.kind {
+            declared.push((cols[0].span, column_names(cols, col_decls)?));
+        }
+    }
+    match &declared[..] {
+        [] => Ok(Vec::new()),
+        [(_, cols)] => Ok(cols.clone()),
+        [_, (span, _), ..] => Err(error_at(*span, "Cannot have more than one primary key")),
     }
 }
 
-fn extract_default(constraints: &[Constraint]) -> DefaultOpt {
-    constraints
-        .iter()
-        .find_map(|constraint| {
-            if let Constraint::Default(lit) = constraint {
-                // TODO: will it ever be `DefaultOpt::Increment(_)`?
-                // also stuff like `GETDATE()`
-                Some(DefaultOpt::Some(lit.clone()))
-            } else {
-                None
+/// The names of `cols`, which must all be declared and appear only once
+fn column_names(cols: &[Spanned<Token>], col_decls: &[ColDecl]) -> Result<Vec<String>> {
+    let mut names: Vec<String> = Vec::new();
+    for col in cols {
+        let name = col.ident().ok_or_else(|| anyhow!("Internal error"))?;
+        if !col_decls
+            .iter()
+            .any(|col_decl| col_decl.ident().ok() == Some(name))
+        {
+            return Err(error_at(col.span, format!("Column {} not found", name)));
+        }
+        if names.contains(name) {
+            return Err(error_at(col.span, format!("Column {} listed twice", name)));
+        }
+        names.push(name.clone());
+    }
+    Ok(names)
+}
+
+fn check_idents(expr: &Expr, col_decls: &[ColDecl]) -> Result<()> {
+    match expr {
+        Expr::Ident(ident) => {
+            if !col_decls
+                .iter()
+                .any(|col_decl| col_decl.ident().ok() == ident.ident())
+            {
+                return Err(error_at(
+                    ident.span,
+                    format!("Invalid identifier in check condition: {}", ident.node),
+                ));
             }
-        })
-        .unwrap_or(DefaultOpt::None)
+            Ok(())
+        }
+        Expr::Qualified { table, .. } => Err(error_at(
+            table.span,
+            format!("Invalid identifier in check condition: {}", expr),
+        )),
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => check_idents(expr, col_decls),
+        Expr::Binary { left, right, .. } => {
+            check_idents(left, col_decls)?;
+            check_idents(right, col_decls)
+        }
+        Expr::Function { args, .. } => args.iter().try_for_each(|arg| check_idents(arg, col_decls)),
+        Expr::Literal(_) | Expr::Param(_) => Ok(()),
+    }
+}
+
+/// The value of a `DEFAULT` constraint, which is a literal or `now()`
+fn extract_default(constraints: &[Constraint]) -> Result<DefaultOpt> {
+    let Some(expr) = constraints.iter().find_map(|constraint| match constraint {
+        Constraint::Default(expr) => Some(expr),
+        _ => None,
+    }) else {
+        return Ok(DefaultOpt::None);
+    };
+    match expr {
+        Expr::Literal(lit) => Ok(DefaultOpt::Some(lit.clone())),
+        Expr::Function { name, args, star }
+            if args.is_empty()
+                && !star
+                && name
+                    .ident()
+                    .is_some_and(|name| name.eq_ignore_ascii_case("now")) =>
+        {
+            Ok(DefaultOpt::Now)
+        }
+        _ => bail!("DEFAULT must be a value or now(), got {}", expr),
+    }
 }
 
 fn extract_check(constraints: &[Constraint]) -> Option<Expr> {
@@ -0,0 +1,56 @@
+use anyhow::{anyhow, Result};
+
+use crate::{
+    connection::Frame,
+    db::{Changes, Db, Txn},
+    parse::{error_at, Expr, FromClause, Select, SelectItem, Spanned, TableRef, Token},
+    plan,
+};
+
+/// Removes every row matching `filter`, or none if a foreign key forbids it
+pub fn delete(
+    db: &Db,
+    txn: &mut Txn,
+    table: Spanned<Token>,
+    filter: Option<Expr>,
+) -> Result<Frame> {
+    let table_name = table.ident().ok_or_else(|| anyhow!("Internal error"))?;
+    db.write(table_name, |tables| {
+        let pk = tables
+            .get(table_name)
+            .ok_or_else(|| error_at(table.span, format!("Table \"{}\" not found", table_name)))?
+            .primary_key();
+        let select = Select {
+            items: pk
+                .into_iter()
+                .map(|name| SelectItem::Expr {
+                    expr: Expr::Ident(Token::Identifier(name).into()),
+                    alias: None,
+                })
+                .collect(),
+            from: Some(FromClause {
+                table: TableRef {
+                    name: table.clone(),
+                    alias: None,
+                },
+                joins: Vec::new(),
+            }),
+            filter,
+            ..Default::default()
+        };
+        let mut keys = Vec::new();
+        {
+            let mut op = plan::select(&select, tables, txn, false)?;
+            while let Some(tuple) = op.next()? {
+                keys.push(tuple);
+            }
+        }
+
+        Changes::apply(tables, txn, |changes| {
+            for key in keys {
+                changes.delete(table_name, &key)?;
+            }
+            Ok(Frame::Null)
+        })
+    })
+}
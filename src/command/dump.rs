@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    connection::Frame,
+    db::{Db, DefaultOpt, Row, Table, Txn},
+    parse::{LiteralValue, RefAction},
+};
+
+/// Rows per `INSERT`
+const BATCH: usize = 100;
+
+/// A script that recreates the tables and rows `txn` sees, loaded as a single transaction.
+/// Tables come after the ones they reference, and each is followed by its rows and indexes.
+/// Hidden keys are left out, so they are numbered again when the script is loaded.
+pub fn dump(db: &Db, txn: &mut Txn) -> Result<Frame> {
+    let snapshot = txn.snapshot();
+    let script = db.read_all(|tables| {
+        let mut names = tables.names(&snapshot);
+        let mut ordered: Vec<String> = Vec::new();
+        while !names.is_empty() {
+            // A table can only reference tables created before it, or itself
+            let Some(i) = names.iter().position(|name| {
+                tables.get(name).is_some_and(|table| {
+                    table
+                        .foreign_keys()
+                        .iter()
+                        .all(|fk| &fk.table == name || ordered.contains(&fk.table))
+                })
+            }) else {
+                bail!(
+                    "Cannot dump tables {} as their foreign keys reference each other",
+                    names.join(", ")
+                );
+            };
+            ordered.push(names.remove(i));
+        }
+
+        let mut script = String::from("BEGIN;\n");
+        for name in &ordered {
+            let table = tables.get(name).ok_or_else(|| anyhow!("Internal error"))?;
+            txn.read(name);
+            script += &create_table(name, table);
+            let rows = parents_first(
+                name,
+                table,
+                tables.rows(name, &snapshot)?.collect::<Result<_>>()?,
+            );
+            for batch in rows.chunks(BATCH) {
+                script += &insert(name, table, batch);
+            }
+            for index in table.indexes().iter().filter(|index| !index.constraint()) {
+                script += &format!(
+                    "CREATE {}INDEX {} ON {} ({});\n",
+                    if index.unique() { "UNIQUE " } else { "" },
+                    index.name(),
+                    name,
+                    index.columns().join(", ")
+                );
+            }
+        }
+        script += "COMMIT;\n";
+        Ok(script)
+    })?;
+    Ok(Frame::Text(script))
+}
+
+fn create_table(name: &str, table: &Table) -> String {
+    let pk = table.primary_key();
+    let mut elements = Vec::new();
+    for header in table.visible_keys() {
+        let mut col = format!("{} {}", header.name(), header.ty());
+        if pk.len() == 1 && table.pk_name().is_none() && header.is_primary() {
+            col += " PRIMARY KEY";
+        }
+        if header.not_null() {
+            col += " NOT NULL";
+        }
+        if header.unique() {
+            col += " UNIQUE";
+        }
+        match header.default() {
+            DefaultOpt::Some(val) => col += &format!(" DEFAULT {}", literal(val)),
+            DefaultOpt::Now => col += " DEFAULT now()",
+            _ => {}
+        }
+        if let Some(check) = header.check() {
+            col += &format!(" CHECK ({})", check);
+        }
+        elements.push(col);
+    }
+    match table.pk_name() {
+        Some(constraint) => elements.push(format!(
+            "CONSTRAINT {} PRIMARY KEY ({})",
+            constraint,
+            pk.join(", ")
+        )),
+        None if pk.len() > 1 => elements.push(format!("PRIMARY KEY ({})", pk.join(", "))),
+        None => {}
+    }
+    for index in table.indexes().iter().filter(|index| index.constraint()) {
+        // Columns declared `UNIQUE` get their index back from the declaration
+        let declared = match index.columns() {
+            [col] => table
+                .col_headers()
+                .iter()
+                .any(|header| header.name() == col && header.unique()),
+            _ => false,
+        };
+        if !declared {
+            elements.push(format!(
+                "CONSTRAINT {} UNIQUE ({})",
+                index.name(),
+                index.columns().join(", ")
+            ));
+        }
+    }
+    for (constraint, check) in table.checks() {
+        elements.push(match constraint {
+            Some(constraint) => format!("CONSTRAINT {} CHECK ({})", constraint, check),
+            None => format!("CHECK ({})", check),
+        });
+    }
+    for fk in table.foreign_keys() {
+        let mut element = match &fk.name {
+            Some(constraint) => format!("CONSTRAINT {} ", constraint),
+            None => String::new(),
+        };
+        element += &format!(
+            "FOREIGN KEY ({}) REFERENCES {} ({})",
+            fk.columns.join(", "),
+            fk.table,
+            fk.ref_columns.join(", ")
+        );
+        for (event, action) in [("DELETE", fk.on_delete), ("UPDATE", fk.on_update)] {
+            if action != RefAction::NoAction {
+                element += &format!(" ON {} {}", event, action);
+            }
+        }
+        elements.push(element);
+    }
+    format!("CREATE TABLE {} ({});\n", name, elements.join(", "))
+}
+
+fn insert(name: &str, table: &Table, rows: &[Row]) -> String {
+    let cols: Vec<_> = table
+        .visible_keys()
+        .map(|header| header.name().to_string())
+        .collect();
+    let values: Vec<_> = rows
+        .iter()
+        .map(|row| {
+            let vals: Vec<_> = row
+                .cols(&cols)
+                .unwrap_or_default()
+                .iter()
+                .map(literal)
+                .collect();
+            format!("({})", vals.join(", "))
+        })
+        .collect();
+    format!(
+        "INSERT INTO {} ({}) VALUES {};\n",
+        name,
+        cols.join(", "),
+        values.join(", ")
+    )
+}
+
+/// `val` as it is written in a statement. Strings are single quoted, with quotes in them
+/// doubled.
+fn literal(val: &LiteralValue) -> String {
+    match val {
+        LiteralValue::String(s) => format!("'{}'", s.replace('\'', "''")),
+        other => other.to_string(),
+    }
+}
+
+/// Orders the rows of a table that references itself so that each row comes after the one
+/// it references. Rows caught in a cycle keep their place at the end.
+fn parents_first(name: &str, table: &Table, rows: Vec<Row>) -> Vec<Row> {
+    let fks: Vec<_> = table
+        .foreign_keys()
+        .iter()
+        .filter(|fk| fk.table == name)
+        .collect();
+    if fks.is_empty() {
+        return rows;
+    }
+    let mut added: Vec<HashSet<Vec<LiteralValue>>> = vec![HashSet::new(); fks.len()];
+    let mut ordered = Vec::with_capacity(rows.len());
+    let mut rest = rows;
+    loop {
+        let (ready, waiting): (Vec<_>, Vec<_>) = rest.into_iter().partition(|row| {
+            fks.iter().zip(&added).all(|(fk, added)| {
+                let key = row.cols(&fk.columns).unwrap_or_default();
+                key.contains(&LiteralValue::Null)
+                    || row.cols(&fk.ref_columns).as_ref() == Some(&key)
+                    || added.contains(&key)
+            })
+        });
+        rest = waiting;
+        if ready.is_empty() {
+            ordered.extend(rest);
+            return ordered;
+        }
+        for row in &ready {
+            for (fk, added) in fks.iter().zip(&mut added) {
+                added.insert(row.cols(&fk.ref_columns).unwrap_or_default());
+            }
+        }
+        ordered.extend(ready);
+    }
+}
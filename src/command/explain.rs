@@ -0,0 +1,23 @@
+use anyhow::{bail, Result};
+
+use crate::{
+    connection::Frame,
+    db::{Db, Txn},
+    parse::Command,
+    plan,
+};
+
+/// Shows the plan `cmd` would run with. `EXPLAIN ANALYZE` also runs it, discarding the
+/// output, so each operator can report what it actually did.
+pub fn explain(db: &Db, txn: &mut Txn, analyze: bool, cmd: Command) -> Result<Frame> {
+    match cmd {
+        Command::Select(select) => db.read(&select.tables(), |tables| {
+            let mut op = plan::select(&select, tables, txn, analyze)?;
+            if analyze {
+                while op.next()?.is_some() {}
+            }
+            Ok(plan::explain(op.as_ref()))
+        }),
+        _ => bail!("Only queries can be explained"),
+    }
+}
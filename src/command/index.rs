@@ -0,0 +1,63 @@
+use anyhow::{anyhow, Result};
+
+use crate::{
+    connection::Frame,
+    db::{Changes, Db, Txn},
+    parse::{error_at, Spanned, Token},
+};
+
+use super::table_mut;
+
+/// Builds an index over existing rows. Index names are unique across all tables.
+pub fn create_index(
+    db: &Db,
+    txn: &mut Txn,
+    name: Spanned<Token>,
+    table: Spanned<Token>,
+    cols: Vec<Spanned<Token>>,
+    unique: bool,
+) -> Result<Frame> {
+    let index_name = name.ident().ok_or_else(|| anyhow!("Internal error"))?;
+    db.alter(|tables| {
+        if tables
+            .iter()
+            .any(|(_, table)| table.index(index_name).is_some())
+        {
+            return Err(error_at(
+                name.span,
+                format!("Index \"{}\" already exists", index_name),
+            ));
+        }
+        let mut columns = Vec::new();
+        {
+            let table = table_mut(tables, &table)?;
+            for col in &cols {
+                let col_name = col.ident().ok_or_else(|| anyhow!("Internal error"))?;
+                if !table
+                    .col_headers()
+                    .iter()
+                    .any(|header| header.name() == col_name)
+                {
+                    return Err(error_at(col.span, format!("Unknown column {}", col_name)));
+                }
+                columns.push(col_name.to_string());
+            }
+        }
+        let table_name = table.ident().ok_or_else(|| anyhow!("Internal error"))?;
+        Changes::apply(tables, txn, |changes| {
+            changes.create_index(table_name, index_name, columns, unique)?;
+            Ok(Frame::Null)
+        })
+    })
+}
+
+pub fn drop_index(db: &Db, txn: &mut Txn, name: Spanned<Token>) -> Result<Frame> {
+    let index_name = name.ident().ok_or_else(|| anyhow!("Internal error"))?;
+    if db.alter(|tables| Changes::apply(tables, txn, |changes| changes.drop_index(index_name)))? {
+        return Ok(Frame::Null);
+    }
+    Err(error_at(
+        name.span,
+        format!("Index \"{}\" not found", index_name),
+    ))
+}
@@ -1,31 +1,65 @@
-use ordered_float::OrderedFloat;
 use std::cmp::Ordering;
 
 use anyhow::{anyhow, Result};
 
 use crate::{
     connection::Frame,
-    db::{Column, ColumnHeader, Db, DefaultOpt},
-    parse::{LiteralValue, Token, Tokens},
+    db::{Changes, Column, ColumnHeader, Db, DefaultOpt, Table, Txn},
+    parse::{error_at, Expr, LiteralValue, Spanned, Token, Tokens},
 };
 
-use super::on_table_mut;
+use super::table_mut;
 
-pub fn insert(db: &Db, table: Token, cols: Tokens, rows: Vec<Vec<LiteralValue>>) -> Result<Frame> {
-    on_table_mut(db, table, |table| match cols {
+/// Adds every row, or none of them if one is rejected
+pub fn insert(
+    db: &Db,
+    txn: &mut Txn,
+    table: Spanned<Token>,
+    cols: Tokens,
+    rows: Vec<Vec<Expr>>,
+) -> Result<Frame> {
+    let rows = rows
+        .iter()
+        .map(|values| values.iter().map(|val| val.eval(&[])).collect())
+        .collect::<Result<Vec<Vec<_>>>>()?;
+    let name = table.ident().ok_or_else(|| anyhow!("Internal error"))?;
+    db.write(name, |tables| {
+        let batch = build_rows(table_mut(tables, &table)?, cols, rows)?;
+        Changes::apply(tables, txn, |changes| {
+            for cols in batch {
+                changes.insert(name, cols)?;
+            }
+            Ok(Frame::Null)
+        })
+    })
+}
+
+/// Matches each row's values up with the table's columns, filling in defaults
+pub(super) fn build_rows(
+    table: &mut Table,
+    cols: Tokens,
+    rows: Vec<Vec<LiteralValue>>,
+) -> Result<Vec<Vec<Column>>> {
+    match cols {
         Tokens::List(specified_cols) => {
             let specified_col_names = specified_cols
                 .iter()
                 .map(|col| col.ident())
                 .collect::<Option<Vec<_>>>()
                 .ok_or_else(|| anyhow!("Internal error"))?;
-            let unknown_cols: Vec<_> = specified_col_names
+            let unknown_cols: Vec<_> = specified_cols
                 .iter()
-                .filter(|col| !table.visible_keys().any(|header| header.name() == **col))
+                .zip(&specified_col_names)
+                .filter(|(_, col)| !table.visible_keys().any(|header| header.name() == **col))
                 .collect();
-            if !unknown_cols.is_empty() {
-                return Err(anyhow!("Unknown columns: {:?}", unknown_cols,));
+            if let Some((first, _)) = unknown_cols.first() {
+                let names: Vec<_> = unknown_cols.iter().map(|(_, name)| name).collect();
+                return Err(error_at(
+                    first.span,
+                    format!("Unknown columns: {:?}", names),
+                ));
             }
+            let mut batch = Vec::new();
             for values in rows {
                 let mut columns = Vec::new();
                 for (name, val) in specified_col_names.iter().zip(values.iter()) {
@@ -53,17 +87,20 @@ pub fn insert(db: &Db, table: Token, cols: Tokens, rows: Vec<Vec<LiteralValue>>)
                     }
                     Ordering::Equal => {}
                 };
-                for default_col in table.col_headers_mut().iter_mut().filter(|col| {
-                    col.default() != &DefaultOpt::None
-                        && !specified_col_names.contains(&&col.name().to_string())
-                }) {
+                // Columns left out get their default, or `NULL`
+                for default_col in table
+                    .col_headers_mut()
+                    .iter_mut()
+                    .filter(|col| !specified_col_names.contains(&&col.name().to_string()))
+                {
                     columns.push(get_default(default_col)?);
                 }
-                table.append(columns)?;
+                batch.push(columns);
             }
-            Ok(Frame::Null)
+            Ok(batch)
         }
         Tokens::Omitted => {
+            let mut batch = Vec::new();
             for values in rows {
                 let mut columns = Vec::new();
                 for (c, val) in table.col_headers().iter().zip(values.iter()) {
@@ -80,20 +117,19 @@ pub fn insert(db: &Db, table: Token, cols: Tokens, rows: Vec<Vec<LiteralValue>>)
                     }
                     Ordering::Equal => {}
                 };
-                table.append(columns)?;
+                batch.push(columns);
             }
-            Ok(Frame::Null)
+            Ok(batch)
         }
-    })
+    }
 }
 
 fn get_default(header: &mut ColumnHeader) -> Result<Column> {
     let val = match header.default() {
         DefaultOpt::None => LiteralValue::Null,
         DefaultOpt::Some(val) => val.clone(),
-        DefaultOpt::Incrementing(_) => LiteralValue::Number(OrderedFloat(
-            header.inc().ok_or_else(|| anyhow!("Internal error"))? as f64,
-        )),
+        DefaultOpt::Now => LiteralValue::now(),
+        DefaultOpt::Incrementing(_) => LiteralValue::Int(header.inc()?),
     };
     Ok(Column::new(val, header.name().to_string()))
 }
@@ -0,0 +1,282 @@
+use std::{fmt::Display, iter::Peekable, str::Chars};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::parse::Decimal;
+
+/// How deeply arrays and objects can nest, which keeps reading them from overflowing the
+/// stack
+const MAX_DEPTH: usize = 128;
+
+/// A JSON value. Objects keep their keys in the order they were written.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+
+    /// A number without a fraction or exponent that fits in 64 bits
+    Int(i64),
+
+    /// A number with more digits than a float keeps
+    Decimal(Decimal),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// The value `text` holds, with nothing but whitespace around it
+    pub fn parse(text: &str) -> Result<Json> {
+        let mut reader = Reader::new(text);
+        let value = reader.value()?;
+        reader.end()?;
+        Ok(value)
+    }
+
+    /// The elements of the array `text` holds, each with the line it starts on
+    pub fn parse_array(text: &str) -> Result<Vec<(usize, Json)>> {
+        let mut reader = Reader::new(text);
+        let mut elements = Vec::new();
+        let mut read = || {
+            reader.expect('[')?;
+            if !reader.next_if(']') {
+                loop {
+                    reader.skip_whitespace();
+                    elements.push((reader.line, reader.value()?));
+                    if reader.next_if(']') {
+                        break;
+                    }
+                    reader.expect(',')?;
+                }
+            }
+            reader.end()
+        };
+        match read() {
+            Ok(()) => Ok(elements),
+            Err(e) => Err(anyhow!("Line {}: {}", reader.line, e)),
+        }
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::Int(n) => write!(f, "{}", n),
+            Json::Decimal(d) => write!(f, "{}", d),
+            Json::String(s) => write_string(f, s),
+            Json::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Reader<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+
+    /// Values currently being read inside one another
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(text: &'a str) -> Self {
+        Reader {
+            chars: text.chars().peekable(),
+            line: 1,
+            depth: 0,
+        }
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        if self.depth == MAX_DEPTH {
+            bail!("JSON nested too deeply");
+        }
+        self.depth += 1;
+        self.skip_whitespace();
+        let value = match self.chars.peek().copied() {
+            Some('n') => self.word("null", Json::Null)?,
+            Some('t') => self.word("true", Json::Bool(true))?,
+            Some('f') => self.word("false", Json::Bool(false))?,
+            Some('"') => Json::String(self.string()?),
+            Some('[') => {
+                self.chars.next();
+                let mut elements = Vec::new();
+                if !self.next_if(']') {
+                    loop {
+                        elements.push(self.value()?);
+                        if self.next_if(']') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                Json::Array(elements)
+            }
+            Some('{') => {
+                self.chars.next();
+                let mut members = Vec::new();
+                if !self.next_if('}') {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.expect(':')?;
+                        members.push((key, self.value()?));
+                        if self.next_if('}') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                Json::Object(members)
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(c) = self
+                    .chars
+                    .next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+                {
+                    number.push(c);
+                }
+                let invalid = || anyhow!("Invalid number {}", number);
+                let decimal: Decimal = number.parse().map_err(|_| invalid())?;
+                let float: f64 = number.parse().map_err(|_| invalid())?;
+                match number.parse() {
+                    Ok(n) if !number.contains(['.', 'e', 'E']) => Json::Int(n),
+                    _ if Decimal::from_f64(float).as_ref() == Some(&decimal) => Json::Number(float),
+                    _ => Json::Decimal(decimal),
+                }
+            }
+            Some(c) => bail!("Unexpected {:?}", c),
+            None => bail!("Unexpected end of JSON"),
+        };
+        self.depth -= 1;
+        Ok(value)
+    }
+
+    fn word(&mut self, word: &str, value: Json) -> Result<Json> {
+        for expected in word.chars() {
+            if self.chars.next() != Some(expected) {
+                bail!("Expected {}", word);
+            }
+        }
+        Ok(value)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.chars.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => {
+                        let mut code = self.hex()?;
+                        // A character outside the BMP is written as a surrogate pair
+                        if (0xd800..0xdc00).contains(&code) {
+                            if self.chars.next() != Some('\\') || self.chars.next() != Some('u') {
+                                bail!("Unpaired surrogate in string");
+                            }
+                            let low = self.hex()?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                bail!("Unpaired surrogate in string");
+                            }
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        }
+                        s.push(
+                            char::from_u32(code)
+                                .ok_or_else(|| anyhow!("Invalid escape in string"))?,
+                        );
+                    }
+                    _ => bail!("Invalid escape in string"),
+                },
+                Some('\n') | None => bail!("String is not closed"),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn hex(&mut self) -> Result<u32> {
+        let digits: String = (0..4).filter_map(|_| self.chars.next()).collect();
+        u32::from_str_radix(&digits, 16).map_err(|_| anyhow!("Invalid escape in string"))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.chars.next_if(|c| c.is_whitespace()) {
+            if c == '\n' {
+                self.line += 1;
+            }
+        }
+    }
+
+    /// Skips `c`, and any whitespace before it, if it comes next
+    fn next_if(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        self.chars.next_if_eq(&c).is_some()
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if !self.next_if(c) {
+            match self.chars.peek() {
+                Some(found) => bail!("Expected {:?}, found {:?}", c, found),
+                None => bail!("Unexpected end of JSON"),
+            }
+        }
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<()> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some(c) => bail!("Unexpected {:?} after the value", c),
+            None => Ok(()),
+        }
+    }
+}
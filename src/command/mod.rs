@@ -1,85 +1,360 @@
-use anyhow::{anyhow, Result};
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, bail, Result};
 use bytes::Bytes;
 
 use crate::{
     connection::Frame,
-    db::{Db, Table},
-    parse::{self, Command, Token},
+    db::{Db, Storage, Table, Tables, Txn},
+    parse::{self, error_at, Command, CopyTarget, LiteralValue, Spanned, SpannedError, Token},
 };
 
-use self::{create_table::create_table, insert::insert, select::select};
+use self::{
+    copy::{copy_from, copy_to},
+    create_table::create_table,
+    delete::delete,
+    dump::dump,
+    explain::explain,
+    index::{create_index, drop_index},
+    insert::insert,
+    select::select,
+    transaction::Transaction,
+    update::update,
+};
 
+mod copy;
 mod create_table;
+mod delete;
+mod dump;
+mod explain;
+mod index;
 mod insert;
+mod json;
 mod select;
+mod transaction;
+mod update;
 
-// Basicaly visitor pattern--rename?
+/// Runs a single statement outside of any session
 pub fn run_cmd(db: &Db, stream: Bytes) -> Frame {
-    let res = match parse::parse(stream) {
-        Ok(Command::Select { key, table }) => select(db, key, table),
-        Ok(Command::Insert { table, cols, rows }) => insert(db, table, cols, rows),
-        Ok(Command::CreateTable { name, def }) => create_table(db, name, def),
-        Err(e) => return Frame::Error(format!("{:?}", e)),
-    };
-    res.unwrap_or_else(|e| Frame::Error(format!("{:?}", e)))
+    Session::new(db.clone()).run(Frame::Cmd(stream))
 }
 
-fn on_table<F>(db: &Db, table: Token, f: F) -> Result<Frame>
-where
-    F: FnOnce(&Table) -> Result<Frame>,
-{
-    let db = db.lock().unwrap();
-    let table_name = table.ident().ok_or_else(|| anyhow!("Internal error"))?;
-    let table = db
-        .get(table_name)
-        .ok_or_else(|| anyhow!("Table \"{}\" not found", table_name))?;
-    f(table)
+/// Per-connection state. Prepared statements live until they are deallocated or the client
+/// disconnects, and an open transaction until it is committed or rolled back. Disconnecting
+/// rolls it back.
+pub struct Session {
+    db: Db,
+    storage: Option<Arc<Storage>>,
+    prepared: HashMap<String, Command>,
+    transaction: Option<Transaction>,
+
+    /// A `COPY ... FROM STDIN` waiting for the client to send its rows in a text frame
+    copy_in: Option<Command>,
+}
+
+impl Session {
+    pub fn new(db: Db) -> Session {
+        Session {
+            db,
+            storage: None,
+            prepared: HashMap::new(),
+            transaction: None,
+            copy_in: None,
+        }
+    }
+
+    /// A session whose changes are logged to `storage` before they are acknowledged
+    pub fn with_storage(db: Db, storage: Arc<Storage>) -> Session {
+        Session {
+            storage: Some(storage),
+            ..Session::new(db)
+        }
+    }
+
+    /// Answers a frame sent by the client. An error aborts the open transaction.
+    pub fn run(&mut self, frame: Frame) -> Frame {
+        let copy_in = self.copy_in.take();
+        let res = match frame {
+            Frame::Cmd(stream) => parse::parse(stream).and_then(|cmd| self.run_cmd(cmd)),
+            Frame::Execute(name, params) => self.execute(&name, &params),
+            Frame::Text(data) => match copy_in {
+                Some(Command::CopyFrom {
+                    table,
+                    cols,
+                    options,
+                    ..
+                }) => self.run_logged(Command::CopyFrom {
+                    table,
+                    cols,
+                    source: CopyTarget::Data(data),
+                    options,
+                }),
+                _ => Err(anyhow!("Expected COPY ... FROM STDIN before sending rows")),
+            },
+            other => Err(anyhow!("Expected a command, got {:?}", other)),
+        };
+        if let (Err(_), Some(transaction)) = (&res, &mut self.transaction) {
+            transaction.abort();
+        }
+        res.unwrap_or_else(error_frame)
+    }
+
+    fn run_cmd(&mut self, cmd: Command) -> Result<Frame> {
+        if let Some(transaction) = &self.transaction {
+            if !matches!(
+                cmd,
+                Command::Commit | Command::Rollback | Command::RollbackTo { .. }
+            ) {
+                transaction.check()?;
+            }
+        }
+        match cmd {
+            Command::Begin { isolation } => {
+                if self.transaction.is_some() {
+                    bail!("A transaction is already in progress");
+                }
+                self.transaction = Some(Transaction::begin(&self.db, isolation));
+                Ok(Frame::Null)
+            }
+            Command::Commit => {
+                self.transaction
+                    .take()
+                    .ok_or_else(|| anyhow!("No transaction in progress"))?
+                    .commit(self.storage.as_deref())?;
+                Ok(Frame::Null)
+            }
+            Command::Rollback => {
+                self.transaction
+                    .take()
+                    .ok_or_else(|| anyhow!("No transaction in progress"))?
+                    .rollback()?;
+                Ok(Frame::Null)
+            }
+            Command::Savepoint { name } => {
+                self.transaction_mut()?.savepoint(&name)?;
+                Ok(Frame::Null)
+            }
+            Command::RollbackTo { name } => {
+                self.transaction_mut()?.rollback_to(&name)?;
+                Ok(Frame::Null)
+            }
+            Command::Release { name } => {
+                self.transaction_mut()?.release(&name)?;
+                Ok(Frame::Null)
+            }
+            Command::Prepare { name, cmd } => {
+                let name = name.ident().ok_or_else(|| anyhow!("Internal error"))?;
+                self.prepared.insert(name.to_string(), *cmd);
+                Ok(Frame::Null)
+            }
+            Command::Execute { name, params } => {
+                let params = params
+                    .iter()
+                    .map(|param| param.eval(&[]))
+                    .collect::<Result<Vec<_>>>()?;
+                let ident = name.ident().ok_or_else(|| anyhow!("Internal error"))?;
+                if !self.prepared.contains_key(ident) {
+                    return Err(error_at(
+                        name.span,
+                        format!("Prepared statement \"{}\" not found", ident),
+                    ));
+                }
+                self.execute(ident, &params)
+            }
+            Command::Deallocate { name } => {
+                let ident = name.ident().ok_or_else(|| anyhow!("Internal error"))?;
+                self.prepared.remove(ident).ok_or_else(|| {
+                    error_at(
+                        name.span,
+                        format!("Prepared statement \"{}\" not found", ident),
+                    )
+                })?;
+                Ok(Frame::Null)
+            }
+            Command::Checkpoint => {
+                if let Some(storage) = &self.storage {
+                    storage.checkpoint()?;
+                }
+                Ok(Frame::Null)
+            }
+            Command::CopyFrom {
+                source: CopyTarget::Client,
+                ..
+            } => {
+                self.copy_in = Some(cmd);
+                Ok(Frame::Null)
+            }
+            Command::Backup { path } => {
+                let storage = self
+                    .storage
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("BACKUP needs a data directory"))?;
+                storage.backup(storage.file(&path)?)?;
+                Ok(Frame::Null)
+            }
+            cmd => self.run_logged(cmd),
+        }
+    }
+
+    fn transaction_mut(&mut self) -> Result<&mut Transaction> {
+        self.transaction
+            .as_mut()
+            .ok_or_else(|| anyhow!("No transaction in progress"))
+    }
+
+    /// Runs `cmd` in the open transaction. Outside of one it gets a transaction of its own,
+    /// committed right away and made durable first if there is storage.
+    fn run_logged(&mut self, cmd: Command) -> Result<Frame> {
+        let cmd = self.server_files(cmd)?;
+        match &mut self.transaction {
+            Some(transaction) => transaction.run(cmd),
+            None => Transaction::autocommit(&self.db, self.storage.as_deref(), cmd),
+        }
+    }
+
+    /// Points the file of a `COPY` at where it is on the server, inside the data directory
+    fn server_files(&self, cmd: Command) -> Result<Command> {
+        let file = |name: String| -> Result<CopyTarget> {
+            let path = self
+                .storage
+                .as_ref()
+                .ok_or_else(|| anyhow!("COPY with a file needs a data directory"))?
+                .file(&name)?;
+            match path.to_str() {
+                Some(path) => Ok(CopyTarget::File(path.to_string())),
+                None => bail!("File '{}' is not a valid path on the server", name),
+            }
+        };
+        Ok(match cmd {
+            Command::CopyFrom {
+                table,
+                cols,
+                source: CopyTarget::File(name),
+                options,
+            } => Command::CopyFrom {
+                table,
+                cols,
+                source: file(name)?,
+                options,
+            },
+            Command::CopyTo {
+                query,
+                target: CopyTarget::File(name),
+                options,
+            } => Command::CopyTo {
+                query,
+                target: file(name)?,
+                options,
+            },
+            cmd => cmd,
+        })
+    }
+
+    /// Runs a prepared statement without scanning or parsing it again
+    fn execute(&mut self, name: &str, params: &[LiteralValue]) -> Result<Frame> {
+        let cmd = self
+            .prepared
+            .get(name)
+            .ok_or_else(|| anyhow!("Prepared statement \"{}\" not found", name))?
+            .bind(params)?;
+        self.run_logged(cmd)
+    }
+}
+
+// Basicaly visitor pattern--rename?
+/// Runs `cmd` against `db` as part of `txn`
+fn run(db: &Db, txn: &mut Txn, cmd: Command) -> Result<Frame> {
+    match cmd {
+        Command::Select(query) => select(db, txn, *query),
+        Command::Insert { table, cols, rows } => insert(db, txn, table, cols, rows),
+        Command::Update { table, set, filter } => update(db, txn, table, set, filter),
+        Command::Delete { table, filter } => delete(db, txn, table, filter),
+        Command::CreateTable { name, def } => create_table(db, txn, name, def),
+        Command::CreateIndex {
+            name,
+            table,
+            cols,
+            unique,
+        } => create_index(db, txn, name, table, cols, unique),
+        Command::DropIndex { name } => drop_index(db, txn, name),
+        Command::Explain { analyze, cmd } => explain(db, txn, analyze, *cmd),
+        Command::Dump => dump(db, txn),
+        Command::CopyFrom {
+            table,
+            cols,
+            source,
+            options,
+        } => copy_from(db, txn, table, cols, source, options),
+        Command::CopyTo {
+            query,
+            target,
+            options,
+        } => copy_to(db, txn, *query, target, options),
+        Command::Prepare { .. } | Command::Execute { .. } | Command::Deallocate { .. } => {
+            bail!("Prepared statements need a session")
+        }
+        Command::Begin { .. }
+        | Command::Commit
+        | Command::Rollback
+        | Command::Savepoint { .. }
+        | Command::RollbackTo { .. }
+        | Command::Release { .. } => bail!("Transactions need a session"),
+        Command::Checkpoint | Command::Backup { .. } => {
+            bail!("Checkpoints and backups need a session")
+        }
+    }
+}
+
+/// Errors that point into the query keep their location so the client can show it. The
+/// message and its causes go on one line, as the frame ends at the first line break.
+fn error_frame(e: anyhow::Error) -> Frame {
+    let one_line = |msg: String| msg.replace(['\r', '\n'], " ");
+    match e.downcast_ref::<SpannedError>() {
+        Some(e) => Frame::SpannedError(one_line(e.msg().to_string()), e.span()),
+        None => Frame::Error(one_line(format!("{:#}", e))),
+    }
 }
 
-fn on_table_mut<F>(db: &Db, table: Token, f: F) -> Result<Frame>
-where
-    F: FnOnce(&mut Table) -> Result<Frame>,
-{
-    let mut db = db.lock().unwrap();
+fn table_mut<'a>(tables: &'a mut Tables, table: &Spanned<Token>) -> Result<&'a mut Table> {
     let table_name = table.ident().ok_or_else(|| anyhow!("Internal error"))?;
-    let table = db
+    tables
         .get_mut(table_name)
-        .ok_or_else(|| anyhow!("Table \"{}\" not found", table_name))?;
-    f(table)
+        .ok_or_else(|| error_at(table.span, format!("Table \"{}\" not found", table_name)))
 }
 
 #[cfg(test)]
 mod tests {
     use ordered_float::OrderedFloat;
-    use std::{
-        collections::HashMap,
-        fmt::Debug,
-        sync::{Arc, Mutex},
-    };
+    use std::{collections::HashMap, fmt::Debug, sync::Arc, time::Duration};
 
     use crate::{
-        db::{Column, ColumnHeader, DefaultOpt, Table},
-        parse::{ColDecl, Key, LiteralValue, TableDef, Token, Tokens, Ty},
+        db::{Column, ColumnHeader, Crash, DefaultOpt, Fault, Table},
+        parse::{ColDecl, Expr, Isolation, LiteralValue, Span, TableDef, Token, Tokens, Ty},
     };
 
     use super::*;
 
+    #[test]
+    fn one_line_errors() {
+        let e = anyhow!("Disk full\nat sector 7").context("Transaction could not be committed");
+        assert_eq!(
+            error_frame(e),
+            Frame::Error("Transaction could not be committed: Disk full at sector 7".into())
+        );
+    }
+
     #[test]
     fn test_select() {
         let db = init_db();
         assert_ok(
-            select(&db, Key::Glob, Token::Identifier("people".into())),
+            query(&db, "SELECT * FROM people"),
             Frame::Table(vec![
                 vec!["name".into(), "age".into(), "ID".into()],
                 vec!["Elliot".into(), "16".into(), "0".into()],
             ]),
         );
         assert_ok(
-            select(
-                &db,
-                Key::List(vec![Token::Identifier("name".into())]),
-                Token::Identifier("people".into()),
-            ),
+            query(&db, "SELECT name FROM people"),
             Frame::Table(vec![vec!["name".into()], vec!["Elliot".into()]]),
         );
     }
@@ -87,31 +362,33 @@ mod tests {
     #[test]
     fn test_insert() {
         let db = init_db();
-        assert!(insert(
+        assert!(autocommit(&db, |txn| insert(
             &db,
-            Token::Identifier("people".into()),
+            txn,
+            Token::Identifier("people".into()).into(),
             Tokens::List(vec![
-                Token::Identifier("name".into()),
-                Token::Identifier("age".into()),
+                Token::Identifier("name".into()).into(),
+                Token::Identifier("age".into()).into(),
             ]),
             vec![vec![
-                LiteralValue::String("Joe".into()),
-                LiteralValue::Number(OrderedFloat(60.0)),
+                Expr::Literal(LiteralValue::String("Joe".into())),
+                Expr::Literal(LiteralValue::Number(OrderedFloat(60.0))),
             ]],
-        )
+        ))
         .is_ok());
-        assert!(insert(
+        assert!(autocommit(&db, |txn| insert(
             &db,
-            Token::Identifier("people".into()),
+            txn,
+            Token::Identifier("people".into()).into(),
             Tokens::Omitted,
             vec![vec![
-                LiteralValue::String("Fredward".into()),
-                LiteralValue::Number(OrderedFloat(999.0)),
+                Expr::Literal(LiteralValue::String("Fredward".into())),
+                Expr::Literal(LiteralValue::Number(OrderedFloat(999.0))),
             ]],
-        )
+        ))
         .is_ok());
         assert_ok(
-            select(&db, Key::Glob, Token::Identifier("people".into())),
+            query(&db, "SELECT * FROM people"),
             Frame::Table(vec![
                 vec!["name".into(), "age".into(), "ID".into()],
                 vec!["Elliot".into(), "16".into(), "0".into()],
@@ -124,15 +401,16 @@ mod tests {
     #[test]
     fn insert_wrong_num_cols() {
         let db = init_db();
-        assert!(insert(
+        assert!(autocommit(&db, |txn| insert(
             &db,
-            Token::Identifier("people".into()),
+            txn,
+            Token::Identifier("people".into()).into(),
             Tokens::Omitted,
-            vec![vec![LiteralValue::String("Elliot".into())]],
-        )
+            vec![vec![Expr::Literal(LiteralValue::String("Elliot".into()))]],
+        ))
         .is_ok());
         assert_ok(
-            select(&db, Key::Glob, Token::Identifier("people".into())),
+            query(&db, "SELECT * FROM people"),
             Frame::Table(vec![
                 vec!["name".into(), "age".into(), "ID".into()],
                 vec!["Elliot".into(), "16".into(), "0".into()],
@@ -141,63 +419,64 @@ mod tests {
         );
 
         assert_err(
-            insert(
-                &db,
-                Token::Identifier("people".into()),
-                Tokens::Omitted,
-                vec![vec![
-                    LiteralValue::Number(OrderedFloat(1.0)),
-                    LiteralValue::Number(OrderedFloat(2.0)),
-                    LiteralValue::Number(OrderedFloat(3.0)),
-                    LiteralValue::Number(OrderedFloat(4.0)),
-                ]],
-            ),
+            autocommit(&db, |txn| {
+                insert(
+                    &db,
+                    txn,
+                    Token::Identifier("people".into()).into(),
+                    Tokens::Omitted,
+                    vec![vec![
+                        Expr::Literal(LiteralValue::Number(OrderedFloat(1.0))),
+                        Expr::Literal(LiteralValue::Number(OrderedFloat(2.0))),
+                        Expr::Literal(LiteralValue::Number(OrderedFloat(3.0))),
+                        Expr::Literal(LiteralValue::Number(OrderedFloat(4.0))),
+                    ]],
+                )
+            }),
             "too many values supplied",
         );
     }
 
     #[test]
     fn default_opts() {
-        let db: Db = Arc::new(Mutex::new(HashMap::from([(
-            "table".into(),
-            Table::try_from(vec![
-                ColumnHeader::new("three".into())
-                    .def(DefaultOpt::Some(LiteralValue::Number(OrderedFloat(3.0))))
-                    .ty(Ty::Number)
-                    .build()
-                    .unwrap(),
-                ColumnHeader::new("inc".into())
-                    .def(DefaultOpt::Incrementing(11))
-                    .ty(Ty::Number)
-                    .build()
-                    .unwrap(),
-            ])
-            .unwrap(),
-        )])));
-        assert!(insert(
+        let db: Db = Arc::new(
+            HashMap::from([(
+                "defaults".into(),
+                Table::try_from(vec![
+                    ColumnHeader::new("three".into())
+                        .def(DefaultOpt::Some(LiteralValue::Number(OrderedFloat(3.0))))
+                        .ty(Ty::Number)
+                        .build()
+                        .unwrap(),
+                    ColumnHeader::new("inc".into())
+                        .def(DefaultOpt::Incrementing(11))
+                        .ty(Ty::Number)
+                        .build()
+                        .unwrap(),
+                ])
+                .unwrap(),
+            )])
+            .into(),
+        );
+        assert!(autocommit(&db, |txn| insert(
             &db,
-            Token::Identifier("table".into()),
+            txn,
+            Token::Identifier("defaults".into()).into(),
             Tokens::Omitted,
             vec![vec![]]
-        )
+        ))
         .is_ok());
-        assert!(insert(
+        assert!(autocommit(&db, |txn| insert(
             &db,
-            Token::Identifier("table".into()),
-            Tokens::List(vec![Token::Identifier("three".into())]),
-            vec![vec![LiteralValue::Number(OrderedFloat(4.0))]]
-        )
+            txn,
+            Token::Identifier("defaults".into()).into(),
+            Tokens::List(vec![Token::Identifier("three".into()).into()]),
+            vec![vec![Expr::Literal(LiteralValue::Number(OrderedFloat(4.0)))]]
+        ))
         .is_ok());
 
         assert_ok(
-            select(
-                &db,
-                Key::List(vec![
-                    Token::Identifier("three".into()),
-                    Token::Identifier("inc".into()),
-                ]),
-                Token::Identifier("table".into()),
-            ),
+            query(&db, "SELECT three, inc FROM defaults"),
             Frame::Table(vec![
                 vec!["three".into(), "inc".into()],
                 vec!["3".into(), "11".into()],
@@ -209,72 +488,2044 @@ mod tests {
     #[test]
     fn test_create_table() {
         let db = Db::default();
-        assert!(create_table(
+        assert!(autocommit(&db, |txn| create_table(
             &db,
-            Token::Identifier("people".to_string()),
-            TableDef::Cols(vec![
-                ColDecl::new(
-                    Token::Identifier("name".to_string()),
-                    Ty::String,
-                    Vec::new()
-                ),
-                ColDecl::new(Token::Identifier("age".to_string()), Ty::Number, Vec::new())
-            ])
-        )
+            txn,
+            Token::Identifier("people".to_string()).into(),
+            TableDef::Cols {
+                cols: vec![
+                    ColDecl::new(
+                        Token::Identifier("name".to_string()).into(),
+                        Ty::String,
+                        Vec::new()
+                    ),
+                    ColDecl::new(
+                        Token::Identifier("age".to_string()).into(),
+                        Ty::Number,
+                        Vec::new()
+                    )
+                ],
+                constraints: Vec::new(),
+            }
+        ))
         .is_ok());
 
-        assert!(create_table(
+        assert!(autocommit(&db, |txn| create_table(
             &db,
-            Token::Identifier("names".to_string()),
-            TableDef::As(Box::new(Command::Select {
-                key: Key::List(vec![Token::Identifier("name".to_string())]),
-                table: Token::Identifier("people".to_string())
-            }))
-        )
+            txn,
+            Token::Identifier("names".to_string()).into(),
+            TableDef::As(Box::new(
+                parse::parse("SELECT name FROM people".into()).unwrap()
+            ))
+        ))
         .is_ok());
 
-        let db = db.lock().unwrap();
-        let people = db.get("people").unwrap();
-        assert_table_def_equals(people, &[("name", Ty::String), ("age", Ty::Number)]);
+        db.read(&["people".into(), "names".into()], |tables| {
+            let people = tables.get("people").unwrap();
+            assert_table_def_equals(people, &[("name", Ty::String), ("age", Ty::Number)]);
 
-        let names = db.get("names").unwrap();
-        assert_table_def_equals(names, &[("name", Ty::String)]);
+            let names = tables.get("names").unwrap();
+            assert_table_def_equals(names, &[("name", Ty::String)]);
+            Ok(())
+        })
+        .unwrap();
     }
 
-    fn init_db() -> Db {
-        let mut table = Table::try_from(vec![
-            ColumnHeader::new("name".into())
-                .ty(Ty::String)
-                .build()
-                .unwrap(),
-            ColumnHeader::new("age".into())
-                .ty(Ty::Number)
-                .build()
-                .unwrap(),
-        ])
-        .unwrap();
-        table
-            .append(vec![
-                Column::new(LiteralValue::String("Elliot".into()), "name".into()),
-                Column::new(LiteralValue::Number(OrderedFloat(16.0)), "age".into()),
+    #[test]
+    fn test_queries() {
+        let db = init_db();
+        for sql in [
+            "INSERT INTO people VALUES (\"Joe\", 60), (\"Ann\", 16), (\"Bo\", 35)",
+            "CREATE TABLE pets (name string, owner string)",
+            "INSERT INTO pets VALUES (\"Rex\", \"Joe\"), (\"Tom\", \"Ann\"), (\"Kit\", \"Joe\")",
+        ] {
+            assert_eq!(run_cmd(&db, sql.into()), Frame::Null);
+        }
+        let table = |rows: &[&[&str]]| {
+            Frame::Table(
+                rows.iter()
+                    .map(|row| row.iter().map(|val| Bytes::from(val.to_string())).collect())
+                    .collect(),
+            )
+        };
+
+        assert_ok(
+            query(
+                &db,
+                "SELECT name, age * 2 AS double FROM people WHERE age > 10 AND name <> \"Bo\" \
+                 ORDER BY age DESC, name LIMIT 2 OFFSET 1",
+            ),
+            table(&[&["name", "double"], &["Ann", "32"], &["Elliot", "32"]]),
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT p.name, pets.name FROM people p JOIN pets ON pets.owner = p.name \
+                 WHERE p.age > 20 ORDER BY 2",
+            ),
+            table(&[&["name", "name"], &["Joe", "Kit"], &["Joe", "Rex"]]),
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT age, count(*), min(name) FROM people GROUP BY age \
+                 HAVING count(*) > 1 OR age > 50 ORDER BY age",
+            ),
+            table(&[
+                &["age", "count(*)", "min(name)"],
+                &["16", "2", "Ann"],
+                &["60", "1", "Joe"],
+            ]),
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT count(*), sum(age), avg(age) FROM people WHERE age > 100",
+            ),
+            table(&[&["count(*)", "sum(age)", "avg(age)"], &["0", "", ""]]),
+        );
+        assert_ok(
+            query(&db, "SELECT DISTINCT age FROM people WHERE age < 50"),
+            table(&[&["age"], &["16"], &["35"]]),
+        );
+        assert_ok(
+            query(&db, "SELECT 1 + 2 * 3 AS n, upper(\"a\"), NULL IS NULL"),
+            table(&[&["n", "upper(\"a\")", "NULL IS NULL"], &["7", "A", "true"]]),
+        );
+
+        assert_err(
+            query(&db, "SELECT name, count(*) FROM people"),
+            "Column \"name\" must appear in GROUP BY or be used in an aggregate function",
+        );
+        assert_err(
+            query(&db, "SELECT name FROM people, pets"),
+            "Column reference \"name\" is ambiguous",
+        );
+        assert_err(
+            query(&db, "SELECT name FROM people WHERE count(*) > 1"),
+            "Aggregate function count is not allowed here",
+        );
+    }
+
+    #[test]
+    fn spanned_errors() {
+        let db = init_db();
+        assert_eq!(
+            run_cmd(&db, "SELECT name FROM peeple".into()),
+            Frame::SpannedError(
+                "Table \"peeple\" not found".into(),
+                Span {
+                    start: 17,
+                    end: 23,
+                    line: 1,
+                    col: 18
+                }
+            )
+        );
+        assert_eq!(
+            run_cmd(&db, "SELECT name, agee FROM people".into()),
+            Frame::SpannedError(
+                "Unknown column agee".into(),
+                Span {
+                    start: 13,
+                    end: 17,
+                    line: 1,
+                    col: 14
+                }
+            )
+        );
+        assert_eq!(
+            run_cmd(&db, "CREATE TABLE t (a number CHECK (b > 1))".into()),
+            Frame::SpannedError(
+                "Invalid identifier in check condition: b".into(),
+                Span {
+                    start: 32,
+                    end: 33,
+                    line: 1,
+                    col: 33
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn prepared_statements() {
+        let db = init_db();
+        let mut session = Session::new(db.clone());
+        assert_eq!(
+            session.run(Frame::Cmd(
+                "PREPARE add AS INSERT INTO people (name, age) VALUES (?, ?)".into()
+            )),
+            Frame::Null
+        );
+        assert_eq!(
+            session.run(Frame::Execute(
+                "add".into(),
+                vec![
+                    LiteralValue::String("Robert'); DROP TABLE people;--".into()),
+                    LiteralValue::Number(OrderedFloat(12.0)),
+                ]
+            )),
+            Frame::Null
+        );
+        assert_eq!(
+            session.run(Frame::Cmd("EXECUTE add (\"Joe\", 60)".into())),
+            Frame::Null
+        );
+        assert_error_frame(
+            session.run(Frame::Execute("add".into(), Vec::new())),
+            "Expected 2 parameters, got 0",
+        );
+        assert_ok(
+            query(&db, "SELECT * FROM people"),
+            Frame::Table(vec![
+                vec!["name".into(), "age".into(), "ID".into()],
+                vec!["Elliot".into(), "16".into(), "0".into()],
+                vec![
+                    "Robert'); DROP TABLE people;--".into(),
+                    "12".into(),
+                    "1".into(),
+                ],
+                vec!["Joe".into(), "60".into(), "2".into()],
+            ]),
+        );
+
+        assert_eq!(
+            session.run(Frame::Cmd("DEALLOCATE add".into())),
+            Frame::Null
+        );
+        assert_error_frame(
+            session.run(Frame::Execute("add".into(), Vec::new())),
+            "Prepared statement \"add\" not found",
+        );
+
+        // Prepared statements belong to the session that made them
+        assert_eq!(
+            run_cmd(&db, "PREPARE everyone AS SELECT * FROM people".into()),
+            Frame::Null
+        );
+        assert!(matches!(
+            run_cmd(&db, "EXECUTE everyone".into()),
+            Frame::SpannedError(..)
+        ));
+    }
+
+    #[test]
+    fn test_explain() {
+        let db = init_db();
+        assert_eq!(
+            run_cmd(&db, "EXPLAIN SELECT name FROM people".into()),
+            Frame::Table(vec![
+                vec!["QUERY PLAN".into()],
+                vec!["Project (name)".into()],
+                vec!["-> SeqScan on people".into()],
             ])
-            .unwrap();
-        Arc::new(Mutex::new(HashMap::from([("people".into(), table)])))
+        );
+
+        let Frame::Table(rows) = run_cmd(&db, "EXPLAIN ANALYZE SELECT name FROM people".into())
+        else {
+            panic!("expected table");
+        };
+        assert_eq!(
+            rows[0],
+            vec![Bytes::from("QUERY PLAN"), "rows".into(), "time".into()]
+        );
+        assert_eq!(rows[1][..2], [Bytes::from("Project (name)"), "1".into()]);
+        assert_eq!(
+            rows[2][..2],
+            [Bytes::from("-> SeqScan on people"), "1".into()]
+        );
+        assert!(rows[2][2].ends_with(b"ms"));
+
+        assert!(matches!(
+            run_cmd(&db, "EXPLAIN INSERT INTO people VALUES (1, 2)".into()),
+            Frame::SpannedError(e, _) if e == "Only queries can be explained"
+        ));
     }
 
-    fn assert_ok<T: Debug + PartialEq>(res: Result<T>, expected: T) {
-        assert!(res.is_ok());
-        match res {
-            Ok(res) => assert_eq!(res, expected),
-            Err(_) => unreachable!(),
+    #[test]
+    fn update_and_delete() {
+        let db = init_db();
+        for sql in [
+            "INSERT INTO people VALUES (\"Joe\", 60), (\"Ann\", 16), (\"Bo\", 35)",
+            "UPDATE people SET age = age + 1, name = upper(name) WHERE age < 20",
+            "DELETE FROM people WHERE name = \"Bo\"",
+        ] {
+            assert_eq!(run_cmd(&db, sql.into()), Frame::Null);
         }
+        assert_ok(
+            query(&db, "SELECT name, age FROM people"),
+            Frame::Table(vec![
+                vec!["name".into(), "age".into()],
+                vec!["ELLIOT".into(), "17".into()],
+                vec!["Joe".into(), "60".into()],
+                vec!["ANN".into(), "17".into()],
+            ]),
+        );
+        assert!(matches!(
+            run_cmd(&db, "UPDATE people SET height = 2".into()),
+            Frame::SpannedError(e, _) if e == "Unknown column height"
+        ));
+
+        // A rejected row leaves every row as it was
+        assert_eq!(
+            run_cmd(
+                &db,
+                "CREATE UNIQUE INDEX people_name ON people (name)".into()
+            ),
+            Frame::Null
+        );
+        assert_error_frame(
+            run_cmd(
+                &db,
+                "UPDATE people SET name = \"Joe\", age = 0 WHERE age < 20".into(),
+            ),
+            "Duplicate key in unique index people_name",
+        );
+        assert_ok(
+            query(&db, "SELECT min(name), sum(age) FROM people"),
+            Frame::Table(vec![
+                vec!["min(name)".into(), "sum(age)".into()],
+                vec!["ANN".into(), "94".into()],
+            ]),
+        );
+        assert_eq!(run_cmd(&db, "DELETE FROM people".into()), Frame::Null);
+        assert_ok(
+            query(&db, "SELECT name FROM people"),
+            Frame::Table(vec![vec!["name".into()]]),
+        );
     }
 
-    fn assert_err<T>(res: Result<T>, expected: &str) {
-        assert!(res.is_err());
-        match res {
-            Ok(_) => unreachable!(),
-            Err(e) => assert_eq!(e.to_string(), expected),
+    #[test]
+    fn indexes() {
+        let db = init_db();
+        for sql in [
+            "CREATE TABLE pets (name string, legs number CREATE INDEX)",
+            "INSERT INTO pets VALUES (\"Rex\", 4), (\"Polly\", 2), (\"Tom\", 4)",
+            "CREATE INDEX pets_name ON pets (name)",
+            "UPDATE pets SET legs = 3 WHERE name = \"Tom\"",
+            "DELETE FROM pets WHERE name = \"Polly\"",
+        ] {
+            assert_eq!(run_cmd(&db, sql.into()), Frame::Null);
+        }
+        assert_ok(
+            query(&db, "SELECT name FROM pets WHERE legs >= 3 AND legs < 4"),
+            Frame::Table(vec![vec!["name".into()], vec!["Tom".into()]]),
+        );
+        assert_ok(
+            query(&db, "SELECT legs FROM pets WHERE \"Rex\" = name"),
+            Frame::Table(vec![vec!["legs".into()], vec!["4".into()]]),
+        );
+        assert_ok(
+            query(&db, "SELECT legs FROM pets WHERE name = \"Polly\""),
+            Frame::Table(vec![vec!["legs".into()]]),
+        );
+
+        assert!(matches!(
+            run_cmd(&db, "CREATE INDEX pets_name ON people (name)".into()),
+            Frame::SpannedError(e, _) if e == "Index \"pets_name\" already exists"
+        ));
+        assert_eq!(
+            run_cmd(&db, "CREATE UNIQUE INDEX pets_legs ON pets (legs)".into()),
+            Frame::Null
+        );
+        assert_error_frame(
+            run_cmd(&db, "INSERT INTO pets VALUES (\"Max\", 4)".into()),
+            "Duplicate key in unique index pets_legs",
+        );
+        assert_eq!(run_cmd(&db, "DROP INDEX pets_name".into()), Frame::Null);
+        assert!(matches!(
+            run_cmd(&db, "DROP INDEX pets_name".into()),
+            Frame::SpannedError(e, _) if e == "Index \"pets_name\" not found"
+        ));
+    }
+
+    #[test]
+    fn unique_constraints() {
+        let db = init_db();
+        assert_eq!(
+            run_cmd(
+                &db,
+                "CREATE TABLE users (id number PRIMARY KEY, email string UNIQUE)".into()
+            ),
+            Frame::Null
+        );
+        assert_eq!(
+            run_cmd(
+                &db,
+                "INSERT INTO users VALUES (1, \"a@x\"), (2, \"b@x\")".into()
+            ),
+            Frame::Null
+        );
+
+        // Nothing from a rejected INSERT is kept
+        assert_error_frame(
+            run_cmd(
+                &db,
+                "INSERT INTO users VALUES (3, \"c@x\"), (4, \"a@x\")".into(),
+            ),
+            "Col email must be unique",
+        );
+        assert_error_frame(
+            run_cmd(
+                &db,
+                "INSERT INTO users VALUES (5, \"d@x\"), (1, \"e@x\")".into(),
+            ),
+            "Duplicate primary key (id)=(1)",
+        );
+        assert_ok(
+            query(&db, "SELECT id FROM users WHERE email = \"c@x\" OR id > 2"),
+            Frame::Table(vec![vec!["id".into()]]),
+        );
+        assert_error_frame(
+            run_cmd(&db, "DROP INDEX email_key".into()),
+            "Cannot drop index email_key because the UNIQUE constraint on email uses it",
+        );
+    }
+
+    #[test]
+    fn foreign_keys() {
+        let db = init_db();
+        for sql in [
+            "CREATE TABLE owners (name string PRIMARY KEY)",
+            "CREATE TABLE pets (name string, \
+             owner string REFERENCES owners (name) ON DELETE CASCADE ON UPDATE CASCADE)",
+            "CREATE TABLE vets (name string, owner string REFERENCES owners ON DELETE RESTRICT)",
+            "CREATE TABLE visits (pet string, owner string DEFAULT \"Jo\", \
+             FOREIGN KEY (owner) REFERENCES owners (name) ON DELETE SET DEFAULT \
+             ON UPDATE SET NULL)",
+            "INSERT INTO owners VALUES (\"Joe\"), (\"Ann\"), (\"Bo\")",
+            "INSERT INTO pets VALUES (\"Rex\", \"Joe\"), (\"Tom\", \"Ann\")",
+            "INSERT INTO pets (name) VALUES (\"Stray\")",
+            "INSERT INTO vets VALUES (\"Dr. Who\", \"Bo\")",
+            "INSERT INTO visits VALUES (\"Tom\", \"Ann\"), (\"Rex\", \"Joe\")",
+        ] {
+            assert_eq!(run_cmd(&db, sql.into()), Frame::Null, "{}", sql);
+        }
+        assert_error_frame(
+            run_cmd(&db, "INSERT INTO pets VALUES (\"Kit\", \"Al\")".into()),
+            "Key (owner)=(\"Al\") is not present in table \"owners\"",
+        );
+        assert!(matches!(
+            run_cmd(&db, "CREATE TABLE t (a string REFERENCES pets (name))".into()),
+            Frame::SpannedError(e, _)
+                if e == "No unique constraint on pets(name) for the foreign key to reference"
+        ));
+
+        // Joe becomes Jo: his pet follows, his visit loses its owner. Then Ann leaves,
+        // taking her pet with her, and her visit falls back to the default owner.
+        for sql in [
+            "INSERT INTO owners VALUES (\"Jo\") ",
+            "DELETE FROM owners WHERE name = \"Jo\"",
+            "UPDATE owners SET name = \"Jo\" WHERE name = \"Joe\"",
+            "DELETE FROM owners WHERE name = \"Ann\"",
+        ] {
+            assert_eq!(run_cmd(&db, sql.into()), Frame::Null, "{}", sql);
+        }
+        assert_ok(
+            query(&db, "SELECT name, owner FROM pets"),
+            Frame::Table(vec![
+                vec!["name".into(), "owner".into()],
+                vec!["Rex".into(), "Jo".into()],
+                vec!["Stray".into(), "".into()],
+            ]),
+        );
+        assert_ok(
+            query(&db, "SELECT pet, owner FROM visits ORDER BY pet"),
+            Frame::Table(vec![
+                vec!["pet".into(), "owner".into()],
+                vec!["Rex".into(), "".into()],
+                vec!["Tom".into(), "Jo".into()],
+            ]),
+        );
+
+        assert_error_frame(
+            run_cmd(&db, "DELETE FROM owners".into()),
+            "Key (name)=(\"Bo\") is still referenced from table \"vets\"",
+        );
+        assert_ok(
+            query(&db, "SELECT count(*) FROM owners"),
+            Frame::Table(vec![vec!["count(*)".into()], vec!["2".into()]]),
+        );
+
+        // `NO ACTION` only cares about the end of the statement
+        for sql in [
+            "CREATE TABLE staff (id number PRIMARY KEY, boss number REFERENCES staff)",
+            "INSERT INTO staff (id) VALUES (1)",
+            "INSERT INTO staff VALUES (2, 1), (3, 2)",
+        ] {
+            assert_eq!(run_cmd(&db, sql.into()), Frame::Null, "{}", sql);
+        }
+        assert_error_frame(
+            run_cmd(&db, "DELETE FROM staff WHERE id < 3".into()),
+            "Key (id)=(2) is still referenced from table \"staff\"",
+        );
+        assert_eq!(run_cmd(&db, "DELETE FROM staff".into()), Frame::Null);
+    }
+
+    #[test]
+    fn table_constraints() {
+        let db = init_db();
+        for sql in [
+            "CREATE TABLE shifts (worker string, day number, start number, finish number, \
+             room string, PRIMARY KEY (day, worker), CONSTRAINT one_per_room UNIQUE (day, room), \
+             CHECK (start < finish), CONSTRAINT short CHECK (finish - start <= 8))",
+            "CREATE TABLE swaps (worker string, day number, \
+             FOREIGN KEY (worker, day) REFERENCES shifts ON DELETE CASCADE)",
+            "INSERT INTO shifts VALUES (\"Al\", 1, 9, 17, \"A\"), (\"Bo\", 1, 9, 12, \"B\"), \
+             (\"Al\", 2, 8, 12, \"A\")",
+            "INSERT INTO swaps VALUES (\"Al\", 1)",
+        ] {
+            assert_eq!(run_cmd(&db, sql.into()), Frame::Null, "{}", sql);
+        }
+        for (sql, err) in [
+            (
+                "INSERT INTO shifts VALUES (\"Al\", 1, 18, 20, \"C\")",
+                "Duplicate primary key (worker, day)=(\"Al\", 1)",
+            ),
+            (
+                "INSERT INTO shifts VALUES (\"Cy\", 1, 13, 17, \"B\")",
+                "Cols (day, room) must be unique together",
+            ),
+            (
+                "INSERT INTO shifts VALUES (\"Cy\", 3, 17, 9, \"A\")",
+                "Check condition start < finish failed",
+            ),
+            (
+                "INSERT INTO shifts VALUES (\"Cy\", 3, 6, 18, \"A\")",
+                "Check constraint short failed",
+            ),
+            (
+                "INSERT INTO shifts (day, start, finish) VALUES (3, 9, 10)",
+                "Column worker non-nullable",
+            ),
+            (
+                "INSERT INTO swaps VALUES (\"Bo\", 2)",
+                "Key (worker, day)=(\"Bo\", 2) is not present in table \"shifts\"",
+            ),
+        ] {
+            assert_error_frame(run_cmd(&db, sql.into()), err);
+        }
+
+        // Rows are ordered by the whole key
+        assert_eq!(
+            run_cmd(
+                &db,
+                "DELETE FROM shifts WHERE start = 9 AND worker = \"Al\"".into()
+            ),
+            Frame::Null
+        );
+        assert_ok(
+            query(&db, "SELECT * FROM shifts"),
+            Frame::Table(vec![
+                vec![
+                    "worker".into(),
+                    "day".into(),
+                    "start".into(),
+                    "finish".into(),
+                    "room".into(),
+                ],
+                vec!["Al".into(), "2".into(), "8".into(), "12".into(), "A".into()],
+                vec!["Bo".into(), "1".into(), "9".into(), "12".into(), "B".into()],
+            ]),
+        );
+        assert_ok(
+            query(&db, "SELECT count(*) FROM swaps"),
+            Frame::Table(vec![vec!["count(*)".into()], vec!["0".into()]]),
+        );
+
+        assert!(matches!(
+            run_cmd(
+                &db,
+                "CREATE TABLE t (a number PRIMARY KEY, b number, PRIMARY KEY (a, b))".into()
+            ),
+            Frame::SpannedError(e, _) if e == "Cannot have more than one primary key"
+        ));
+        assert!(matches!(
+            run_cmd(&db, "CREATE TABLE t (a number, UNIQUE (a, c))".into()),
+            Frame::SpannedError(e, _) if e == "Column c not found"
+        ));
+    }
+
+    #[test]
+    fn persistence() {
+        let dir = temp_dir("persistence");
+        let (storage, tables) = Storage::open(&dir).unwrap();
+        assert!(tables.is_empty());
+        let mut session = Session::with_storage(Db::default(), Arc::new(storage));
+        for sql in [
+            "CREATE TABLE owners (name string PRIMARY KEY, age number CHECK (age >= 0))",
+            "CREATE TABLE pets (name string NOT NULL, owner string REFERENCES owners \
+             ON DELETE CASCADE, kind string DEFAULT \"cat\", UNIQUE (name, owner), \
+             CONSTRAINT named CHECK (name <> owner))",
+            "CREATE INDEX pets_kind ON pets (kind)",
+            "INSERT INTO owners VALUES (\"Ann\", 30), (\"Bo\", 4)",
+            "INSERT INTO pets (name, owner) VALUES (\"Rex\", \"Ann\"), (\"Tom\", \"Bo\")",
+            "DELETE FROM owners WHERE name = \"Bo\"",
+        ] {
+            assert_eq!(session.run(Frame::Cmd(sql.into())), Frame::Null, "{}", sql);
+        }
+        drop(session);
+
+        // Everything comes back, including constraints and where the hidden key left off
+        let (_, tables) = Storage::open(&dir).unwrap();
+        let db: Db = Arc::new(tables.into());
+        assert_ok(
+            query(&db, "SELECT * FROM pets"),
+            Frame::Table(vec![
+                vec!["name".into(), "owner".into(), "kind".into(), "ID".into()],
+                vec!["Rex".into(), "Ann".into(), "cat".into(), "0".into()],
+            ]),
+        );
+        for (sql, err) in [
+            (
+                "INSERT INTO owners VALUES (\"Cy\", -1)",
+                "Check condition on age failed",
+            ),
+            (
+                "INSERT INTO pets (name, owner) VALUES (\"Ann\", \"Ann\")",
+                "Check constraint named failed",
+            ),
+            (
+                "INSERT INTO pets (name, owner) VALUES (\"Rex\", \"Ann\")",
+                "Cols (name, owner) must be unique together",
+            ),
+            (
+                "DROP INDEX name_owner_key",
+                "Cannot drop index name_owner_key",
+            ),
+        ] {
+            assert_error_frame(run_cmd(&db, sql.into()), err);
+        }
+        for sql in [
+            "INSERT INTO pets (name) VALUES (\"Stray\")",
+            "DROP INDEX pets_kind",
+            "DELETE FROM owners",
+        ] {
+            assert_eq!(run_cmd(&db, sql.into()), Frame::Null, "{}", sql);
+        }
+        // Like sequences, the rejected inserts used up 2 and 3
+        assert_ok(
+            query(&db, "SELECT name, ID FROM pets"),
+            Frame::Table(vec![
+                vec!["name".into(), "ID".into()],
+                vec!["Stray".into(), "4".into()],
+            ]),
+        );
+
+        // A damaged file is refused
+        let path = dir.join("sequel.db");
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        let res = Storage::open(&dir);
+        assert!(format!("{:?}", res.as_ref().err().unwrap()).contains("checksum mismatch"));
+        assert_err(res, &format!("Failed to load {}", path.display()));
+
+        // So is one from before tables were laid out as they are now
+        data[last] ^= 1;
+        for version in [1u32, 2] {
+            data[4..8].copy_from_slice(&version.to_le_bytes());
+            std::fs::write(&path, &data).unwrap();
+            let res = Storage::open(&dir);
+            assert!(format!("{:?}", res.err().unwrap())
+                .contains(&format!("Unsupported data file version {}", version)));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn crash_recovery() {
+        let setup = [
+            "CREATE TABLE owners (name string PRIMARY KEY)",
+            "CREATE TABLE pets (name string, owner string REFERENCES owners ON DELETE CASCADE)",
+            "INSERT INTO owners VALUES (\"Ann\"), (\"Bo\")",
+            "INSERT INTO pets VALUES (\"Rex\", \"Ann\"), (\"Tom\", \"Bo\")",
+        ];
+        for (crash, deleted) in [
+            (Crash::BeforeLogWrite, false),
+            (Crash::TornLogWrite, false),
+            (Crash::BeforeLogSync, true),
+            (Crash::AfterLogSync, true),
+            (Crash::TornDataWrite, true),
+            (Crash::BeforeDataRename, true),
+            (Crash::BeforeLogTruncate, true),
+        ] {
+            let dir = temp_dir(&format!("crash-{:?}", crash));
+            let (storage, _) = Storage::open(&dir).unwrap();
+            let storage = Arc::new(storage);
+            let db = Db::default();
+            let mut session = Session::with_storage(db.clone(), storage.clone());
+            for sql in setup {
+                assert_eq!(session.run(Frame::Cmd(sql.into())), Frame::Null, "{}", sql);
+            }
+
+            // The cascaded delete is logged with the statement that caused it
+            let delete = Frame::Cmd("DELETE FROM owners WHERE name = \"Bo\"".into());
+            match crash {
+                Crash::TornDataWrite | Crash::BeforeDataRename | Crash::BeforeLogTruncate => {
+                    assert_eq!(session.run(delete), Frame::Null);
+                    storage.crash_at(Some(crash));
+                    assert_err(storage.checkpoint(), &format!("Crashed at {:?}", crash));
+                }
+                _ => {
+                    storage.crash_at(Some(crash));
+                    assert_error_frame(session.run(delete), "Failed to log changes");
+                }
+            }
+            drop(session);
+            drop(storage);
+
+            // Recovery replays each record once, and the storage is usable afterwards
+            let (storage, tables) = Storage::open(&dir).unwrap();
+            let db: Db = Arc::new(tables.into());
+            let mut rows = vec![
+                vec!["name".into(), "ID".into()],
+                vec!["Rex".into(), "0".into()],
+            ];
+            if !deleted {
+                rows.push(vec!["Tom".into(), "1".into()]);
+            }
+            assert_ok(
+                query(&db, "SELECT name, ID FROM pets"),
+                Frame::Table(rows.clone()),
+            );
+            let insert = Frame::Cmd("INSERT INTO pets VALUES (\"Max\", \"Ann\")".into());
+            let mut session = Session::with_storage(db, Arc::new(storage));
+            assert_eq!(session.run(insert), Frame::Null, "{:?}", crash);
+            drop(session);
+
+            let (_, tables) = Storage::open(&dir).unwrap();
+            rows.push(vec!["Max".into(), "2".into()]);
+            let db: Db = Arc::new(tables.into());
+            assert_ok(query(&db, "SELECT name, ID FROM pets"), Frame::Table(rows));
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn log_failures() {
+        let dir = temp_dir("log-failures");
+        let (storage, _) = Storage::open(&dir).unwrap();
+        let storage = Arc::new(storage);
+        let db = Db::default();
+        let mut session = Session::with_storage(db.clone(), storage.clone());
+        run_ok(&mut session, "CREATE TABLE t (a number PRIMARY KEY)");
+        run_ok(&mut session, "INSERT INTO t VALUES (1)");
+        let insert = |session: &mut Session, a: u32| {
+            session.run(Frame::Cmd(format!("INSERT INTO t VALUES ({})", a).into()))
+        };
+
+        // A record that didn't reach the disk is cut off, and later ones go after the last
+        // good one
+        storage.fail_at(&[Fault::LogSync]);
+        assert_error_frame(insert(&mut session, 2), "Failed to log changes");
+        storage.fail_at(&[]);
+        assert_eq!(insert(&mut session, 3), Frame::Null);
+        drop(session);
+
+        // So is one left half written by a panic
+        let mut session = Session::with_storage(db.clone(), storage.clone());
+        storage.crash_at(Some(Crash::PanicInLogWrite));
+        let res =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| insert(&mut session, 4)));
+        assert!(res.is_err());
+        storage.crash_at(None);
+        drop(session);
+        let mut session = Session::with_storage(db, storage.clone());
+        assert_eq!(insert(&mut session, 5), Frame::Null);
+        drop(session);
+        drop(storage);
+
+        let (storage, tables) = Storage::open(&dir).unwrap();
+        let storage = Arc::new(storage);
+        let db: Db = Arc::new(tables.into());
+        assert_ok(
+            query(&db, "SELECT a FROM t"),
+            Frame::Table(vec![
+                vec!["a".into()],
+                vec!["1".into()],
+                vec!["3".into()],
+                vec!["5".into()],
+            ]),
+        );
+
+        // If it can't be cut off, nothing more is committed
+        let mut session = Session::with_storage(db, storage.clone());
+        storage.fail_at(&[Fault::LogSync, Fault::LogTruncate]);
+        assert_error_frame(insert(&mut session, 6), "Failed to log changes");
+        storage.fail_at(&[]);
+        assert_error_frame(
+            insert(&mut session, 7),
+            "The log could not be repaired after a failed write, restart to recover",
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("sequel-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn backup() {
+        let dir = temp_dir("backup");
+        let restored = temp_dir("backup-restored");
+        let path = dir.join("files/copy.db");
+        let (storage, _) = Storage::open(&dir).unwrap();
+        let mut alice = Session::with_storage(Db::default(), Arc::new(storage));
+        let backup = "BACKUP TO 'copy.db'";
+        for sql in [
+            "CREATE TABLE t (a number PRIMARY KEY)",
+            "INSERT INTO t VALUES (1)",
+            "CHECKPOINT",
+            "INSERT INTO t VALUES (2)",
+            "BEGIN",
+            "INSERT INTO t VALUES (3)",
+            // The copy has what was committed, not what is still pending
+            backup,
+            "COMMIT",
+        ] {
+            assert_eq!(
+                alice.run(Frame::Cmd(sql.to_string().into())),
+                Frame::Null,
+                "{}",
+                sql
+            );
+        }
+        assert_error_frame(
+            run_cmd(&Db::default(), backup.into()),
+            "BACKUP needs a data directory",
+        );
+        // Backups stay inside the data directory
+        for path in ["/tmp/copy.db", "../copy.db", "files/../../copy.db", ""] {
+            assert_error_frame(
+                alice.run(Frame::Cmd(format!("BACKUP TO '{}'", path).into())),
+                &format!("File '{}' must be a relative path", path),
+            );
+        }
+
+        Storage::restore(&restored, &path).unwrap();
+        let (_, tables) = Storage::open(&restored).unwrap();
+        let db: Db = Arc::new(tables.into());
+        assert_ok(
+            query(&db, "SELECT * FROM t"),
+            Frame::Table(vec![vec!["a".into()], vec!["1".into()], vec!["2".into()]]),
+        );
+
+        assert!(Storage::restore(&restored, dir.join("sequel.wal")).is_err());
+    }
+
+    #[test]
+    fn dump() {
+        let db = Db::default();
+        let mut session = Session::new(db.clone());
+        for sql in [
+            "CREATE TABLE people (name string PRIMARY KEY, age number NOT NULL DEFAULT 1 \
+             CHECK (age >= 0), email string UNIQUE)",
+            "CREATE TABLE pets (name string, owner string REFERENCES people ON DELETE CASCADE, \
+             parent string, CONSTRAINT pets_pk PRIMARY KEY (name, owner), \
+             CONSTRAINT pair UNIQUE (owner, parent), CHECK (name <> owner))",
+            "CREATE TABLE tags (id number, pet string, owner string, \
+             CONSTRAINT tags_pk PRIMARY KEY (id), CONSTRAINT tagged FOREIGN KEY (pet, owner) REFERENCES pets (name, owner))",
+            "CREATE TABLE nodes (id number PRIMARY KEY, up number REFERENCES nodes (id))",
+            "CREATE TABLE notes (text string CREATE INDEX)",
+            "CREATE TABLE sizes (n bigint, x double precision, y smallint, z numeric(30, 3), \
+             big boolean DEFAULT FALSE)",
+            "CREATE TABLE visits (day date DEFAULT DATE '2024-01-01', at timestamptz \
+             DEFAULT now(), start time, span interval)",
+            "CREATE UNIQUE INDEX people_age ON people (age)",
+            "INSERT INTO people VALUES ('Ann', 30, 'a@x'), ('O''Neil', 4, NULL)",
+            "INSERT INTO pets VALUES ('Rex', 'Ann', NULL)",
+            "INSERT INTO nodes VALUES (2, NULL), (3, 2), (1, 3)",
+            "INSERT INTO notes VALUES ('a; b'), ('\"quoted\"')",
+            "INSERT INTO sizes VALUES (9007199254740993, 0.5, 3, 123456789012345678901234567.891, \
+             TRUE)",
+            "INSERT INTO visits (start, span) VALUES ('12:30:00.5', '-1 day 02:00:00')",
+        ] {
+            assert_eq!(session.run(Frame::Cmd(sql.into())), Frame::Null, "{}", sql);
+        }
+        let Frame::Text(script) = session.run(Frame::Cmd("DUMP".into())) else {
+            panic!("expected a script");
+        };
+        assert!(script.starts_with(
+            "BEGIN;\nCREATE TABLE nodes (id number PRIMARY KEY, up number, \
+             FOREIGN KEY (up) REFERENCES nodes (id));\n\
+             INSERT INTO nodes (id, up) VALUES (2, NULL), (3, 2), (1, 3);\n"
+        ));
+        assert!(script.contains(
+            "CREATE TABLE tags (id number, pet string, owner string, \
+             CONSTRAINT tags_pk PRIMARY KEY (id), \
+             CONSTRAINT tagged FOREIGN KEY (pet, owner) REFERENCES pets (name, owner));\n"
+        ));
+
+        // Loading the script gives the same tables back
+        let copy = Db::default();
+        let mut session = Session::new(copy.clone());
+        for sql in parse::split_script(&script) {
+            assert_eq!(
+                session.run(Frame::Cmd(sql.clone().into())),
+                Frame::Null,
+                "{}",
+                sql
+            );
+        }
+        assert_eq!(session.run(Frame::Cmd("DUMP".into())), Frame::Text(script));
+        assert_ok(
+            query(&copy, "SELECT name FROM people WHERE email IS NULL"),
+            Frame::Table(vec![vec!["name".into()], vec!["O'Neil".into()]]),
+        );
+    }
+
+    #[test]
+    fn numeric_types() {
+        let db = Db::default();
+        let mut session = Session::new(db.clone());
+        run_ok(
+            &mut session,
+            "CREATE TABLE n (id bigint PRIMARY KEY, s smallint, i int, r real, \
+             d double precision)",
+        );
+        run_ok(
+            &mut session,
+            "INSERT INTO n VALUES (9007199254740993, 1, 7, 0.1, 0.1), (2, 2.4, -7, 1, 2)",
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT id, s + 1, i / 2, i % 2, i * 1.5, r, d FROM n ORDER BY id DESC",
+            ),
+            Frame::Table(vec![
+                vec![
+                    "id".into(),
+                    "s + 1".into(),
+                    "i / 2".into(),
+                    "i % 2".into(),
+                    "i * 1.5".into(),
+                    "r".into(),
+                    "d".into(),
+                ],
+                vec![
+                    "9007199254740993".into(),
+                    "2".into(),
+                    "3".into(),
+                    "1".into(),
+                    "10.5".into(),
+                    "0.10000000149011612".into(),
+                    "0.1".into(),
+                ],
+                vec![
+                    "2".into(),
+                    "3".into(),
+                    "-3".into(),
+                    "-1".into(),
+                    "-10.5".into(),
+                    "1".into(),
+                    "2".into(),
+                ],
+            ]),
+        );
+
+        // Integers and floats with the same value are equal
+        assert_ok(
+            query(
+                &db,
+                "SELECT id FROM n WHERE d = 2.0 AND i = -7.0 AND id = 2",
+            ),
+            Frame::Table(vec![vec!["id".into()], vec!["2".into()]]),
+        );
+        assert_error_frame(
+            session.run(Frame::Cmd("INSERT INTO n (id, s) VALUES (3, 40000)".into())),
+            "Value 40000 out of range for smallint",
+        );
+        assert_error_frame(
+            session.run(Frame::Cmd(
+                "UPDATE n SET i = i * 1000000000 WHERE i > 0".into(),
+            )),
+            "Value 7000000000 out of range for integer",
+        );
+        assert_error_frame(
+            session.run(Frame::Cmd("SELECT id * id FROM n".into())),
+            "Integer out of range",
+        );
+        assert_error_frame(
+            session.run(Frame::Cmd(
+                "SELECT sum(id) + 9223372036854775807 FROM n".into(),
+            )),
+            "Integer out of range",
+        );
+        assert_error_frame(
+            session.run(Frame::Cmd("SELECT i / 0 FROM n".into())),
+            "Division by zero",
+        );
+    }
+
+    #[test]
+    fn decimal_type() {
+        let db = Db::default();
+        let mut session = Session::new(db.clone());
+        run_ok(
+            &mut session,
+            "CREATE TABLE prices (item string PRIMARY KEY, price decimal(10, 2), rate numeric)",
+        );
+        run_ok(
+            &mut session,
+            "INSERT INTO prices VALUES ('a', 19.99, 0.1), ('b', 0.015, 0.2), \
+             ('c', 99999999.99, 123456789012345678901234567890.123456789)",
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT price, price * 3, price / 3, rate + 0.2 FROM prices ORDER BY item",
+            ),
+            Frame::Table(vec![
+                vec![
+                    "price".into(),
+                    "price * 3".into(),
+                    "price / 3".into(),
+                    "rate + 0.2".into(),
+                ],
+                vec![
+                    "19.99".into(),
+                    "59.97".into(),
+                    "6.6633333333333333".into(),
+                    "0.3".into(),
+                ],
+                vec![
+                    "0.02".into(),
+                    "0.06".into(),
+                    "0.0066666666666667".into(),
+                    "0.4".into(),
+                ],
+                vec![
+                    "99999999.99".into(),
+                    "299999999.97".into(),
+                    "33333333.3300000000000000".into(),
+                    "123456789012345678901234567890.323456789".into(),
+                ],
+            ]),
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT sum(price), avg(price) FROM prices WHERE price = 19.990 OR item = 'b'",
+            ),
+            Frame::Table(vec![
+                vec!["sum(price)".into(), "avg(price)".into()],
+                vec!["20.01".into(), "10.0050000000000000".into()],
+            ]),
+        );
+        assert_error_frame(
+            session.run(Frame::Cmd(
+                "INSERT INTO prices VALUES ('d', 100000000, 0)".into(),
+            )),
+            "Value 100000000 out of range for decimal(10, 2)",
+        );
+
+        // Literals with a fraction or exponent are exact until they meet a float column
+        assert_ok(
+            query(&db, "SELECT 0.1 + 0.2, 1.5e2 FROM prices WHERE item = 'a'"),
+            Frame::Table(vec![
+                vec!["0.1 + 0.2".into(), "150".into()],
+                vec!["0.3".into(), "150".into()],
+            ]),
+        );
+        run_ok(&mut session, "CREATE TABLE floats (x double PRIMARY KEY)");
+        run_ok(&mut session, "INSERT INTO floats VALUES (0.1), (2.5e-3)");
+        assert_ok(
+            query(&db, "SELECT x FROM floats WHERE x < 0.05"),
+            Frame::Table(vec![vec!["x".into()], vec!["0.0025".into()]]),
+        );
+        assert_ok(
+            query(&db, "SELECT x * 0.5 FROM floats WHERE x > 0.05"),
+            Frame::Table(vec![vec!["x * 0.5".into()], vec!["0.05".into()]]),
+        );
+    }
+
+    #[test]
+    fn string_types() {
+        let db = Db::default();
+        let mut session = Session::new(db.clone());
+        run_ok(
+            &mut session,
+            "CREATE TABLE codes (code CHAR(4) PRIMARY KEY, name Character Varying(5), \
+             note TEXT UNIQUE)",
+        );
+        run_ok(
+            &mut session,
+            "INSERT INTO codes VALUES ('ab', 'Ann  ', 'x'), ('abcd', 'Bobby   ', NULL)",
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT code, length(code), name FROM codes WHERE code = 'ab' OR name = 'Bobby'",
+            ),
+            Frame::Table(vec![
+                vec!["code".into(), "length(code)".into(), "name".into()],
+                vec!["ab  ".into(), "4".into(), "Ann  ".into()],
+                vec!["abcd".into(), "4".into(), "Bobby".into()],
+            ]),
+        );
+        assert_error_frame(
+            session.run(Frame::Cmd(
+                "INSERT INTO codes VALUES ('x', 'Carolyn', '')".into(),
+            )),
+            "Value \"Carolyn\" too long for varchar(5)",
+        );
+        assert_error_frame(
+            session.run(Frame::Cmd("UPDATE codes SET code = 'abcde'".into())),
+            "Value \"abcde\" too long for char(4)",
+        );
+
+        // Text of any length can reference text of another
+        run_ok(
+            &mut session,
+            "CREATE TABLE notes (name VARCHAR(10) REFERENCES codes (note), body string)",
+        );
+    }
+
+    #[test]
+    fn booleans() {
+        let db = Db::default();
+        let mut session = Session::new(db.clone());
+        run_ok(
+            &mut session,
+            "CREATE TABLE flags (name string PRIMARY KEY, active BOOLEAN NOT NULL DEFAULT TRUE, \
+             seen bool DEFAULT NULL)",
+        );
+        run_ok(&mut session, "INSERT INTO flags (name) VALUES ('a')");
+        run_ok(
+            &mut session,
+            "INSERT INTO flags VALUES ('b', FALSE, TRUE), ('c', true, NULL)",
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT name, seen FROM flags WHERE active ORDER BY name",
+            ),
+            Frame::Table(vec![
+                vec!["name".into(), "seen".into()],
+                vec!["a".into(), "".into()],
+                vec!["c".into(), "".into()],
+            ]),
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT name, active = seen FROM flags WHERE NOT active OR seen IS NULL \
+                 ORDER BY active, name DESC",
+            ),
+            Frame::Table(vec![
+                vec!["name".into(), "active = seen".into()],
+                vec!["b".into(), "false".into()],
+                vec!["c".into(), "".into()],
+                vec!["a".into(), "".into()],
+            ]),
+        );
+        run_ok(
+            &mut session,
+            "UPDATE flags SET active = seen WHERE name = 'b'",
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT min(active), count(*) FROM flags WHERE active = TRUE",
+            ),
+            Frame::Table(vec![
+                vec!["min(active)".into(), "count(*)".into()],
+                vec!["true".into(), "3".into()],
+            ]),
+        );
+        assert_error_frame(
+            session.run(Frame::Cmd("INSERT INTO flags VALUES ('d', 1, NULL)".into())),
+            "Expected bool",
+        );
+        assert_error_frame(
+            session.run(Frame::Cmd("SELECT name FROM flags WHERE name".into())),
+            "Expected boolean, got \"a\"",
+        );
+    }
+
+    #[test]
+    fn temporal_types() {
+        let db = Db::default();
+        let mut session = Session::new(db.clone());
+        run_ok(
+            &mut session,
+            "CREATE TABLE events (id INTEGER PRIMARY KEY, day DATE, at TIMESTAMP, \
+             at_tz TIMESTAMP WITH TIME ZONE, starts TIME, length INTERVAL, \
+             added TIMESTAMPTZ DEFAULT now())",
+        );
+        run_ok(
+            &mut session,
+            "INSERT INTO events (id, day, at, at_tz, starts, length) VALUES \
+             (1, DATE '2024-02-29', '2024-02-29 13:45:30.25', '2024-02-29T13:45:30+02:00', \
+             '09:30', INTERVAL '1 year 2 months 3 days 04:05:06'), \
+             (2, '2023-12-31', TIMESTAMP '2023-12-31 23:59:59', '2024-01-01 00:00:00Z', \
+             TIME '23:00:00', '90 minutes')",
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT id, day, at, at_tz, starts, length FROM events ORDER BY day",
+            ),
+            Frame::Table(vec![
+                vec![
+                    "id".into(),
+                    "day".into(),
+                    "at".into(),
+                    "at_tz".into(),
+                    "starts".into(),
+                    "length".into(),
+                ],
+                vec![
+                    "2".into(),
+                    "2023-12-31".into(),
+                    "2023-12-31 23:59:59".into(),
+                    "2024-01-01 00:00:00+00".into(),
+                    "23:00:00".into(),
+                    "01:30:00".into(),
+                ],
+                vec![
+                    "1".into(),
+                    "2024-02-29".into(),
+                    "2024-02-29 13:45:30.25".into(),
+                    "2024-02-29 11:45:30+00".into(),
+                    "09:30:00".into(),
+                    "1 year 2 mons 3 days 04:05:06".into(),
+                ],
+            ]),
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT day + 1 AS a, day + INTERVAL '1 year' AS b, at - INTERVAL '1 month' AS c, \
+                 starts + length AS d, DATE '2024-03-01' - day AS e, \
+                 at - TIMESTAMP '2024-02-28 12:00' AS f FROM events WHERE id = 1",
+            ),
+            Frame::Table(vec![
+                vec![
+                    "a".into(),
+                    "b".into(),
+                    "c".into(),
+                    "d".into(),
+                    "e".into(),
+                    "f".into(),
+                ],
+                vec![
+                    "2024-03-01".into(),
+                    "2025-02-28 00:00:00".into(),
+                    "2024-01-29 13:45:30.25".into(),
+                    "13:35:06".into(),
+                    "1".into(),
+                    "1 day 01:45:30.25".into(),
+                ],
+            ]),
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT id FROM events WHERE day < '2024-01-01' OR at > DATE '2024-02-29' \
+                 AND length > INTERVAL '1 hour' ORDER BY id",
+            ),
+            Frame::Table(vec![vec!["id".into()], vec!["1".into()], vec!["2".into()]]),
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT EXTRACT(year FROM day) AS y, date_part('dow', day) AS dow, \
+                 extract(SECOND FROM at) AS s, EXTRACT(epoch FROM length) AS e, \
+                 date_trunc('month', at) AS m FROM events ORDER BY id",
+            ),
+            Frame::Table(vec![
+                vec!["y".into(), "dow".into(), "s".into(), "e".into(), "m".into()],
+                vec![
+                    "2024".into(),
+                    "4".into(),
+                    "30.250000".into(),
+                    "37015506.000000".into(),
+                    "2024-02-01 00:00:00".into(),
+                ],
+                vec![
+                    "2023".into(),
+                    "0".into(),
+                    "59.000000".into(),
+                    "5400.000000".into(),
+                    "2023-12-01 00:00:00".into(),
+                ],
+            ]),
+        );
+        assert_ok(
+            query(
+                &db,
+                "SELECT count(*) FROM events WHERE added <= now() \
+                 AND added > CURRENT_TIMESTAMP - INTERVAL '1 hour'",
+            ),
+            Frame::Table(vec![vec!["count(*)".into()], vec!["2".into()]]),
+        );
+        assert_ok(
+            query(&db, "SELECT now() IS NOT NULL"),
+            Frame::Table(vec![vec!["now() IS NOT NULL".into()], vec!["true".into()]]),
+        );
+        for sql in ["SELECT now(*)", "SELECT count() FROM events"] {
+            assert!(matches!(
+                session.run(Frame::Cmd(sql.into())),
+                Frame::SpannedError(e, _) if e.starts_with("Wrong number of arguments")
+            ));
+        }
+        assert_error_frame(
+            session.run(Frame::Cmd(
+                "INSERT INTO events (id, day) VALUES (3, '2023-02-29')".into(),
+            )),
+            "Invalid date \"2023-02-29\"",
+        );
+        assert!(matches!(
+            session.run(Frame::Cmd("SELECT TIMESTAMP '2024-01-01 25:00'".into())),
+            Frame::SpannedError(e, _) if e == "Invalid timestamp \"2024-01-01 25:00\""
+        ));
+        assert_error_frame(
+            session.run(Frame::Cmd(
+                "CREATE TABLE bad (id INTEGER PRIMARY KEY, n INTEGER DEFAULT now())".into(),
+            )),
+            "Default type doesn't match declared type; expected a date or time",
+        );
+    }
+
+    #[test]
+    fn copy() {
+        let dir = temp_dir("copy");
+        std::fs::create_dir_all(dir.join("files")).unwrap();
+        let csv = dir.join("files/people.csv");
+        std::fs::write(
+            &csv,
+            "name;age;email\r\n'Ann; Lee';30;a@x\r\n\r\n'Bo ''B''';n/a;n/a\nCy;old;\n\
+             Dee;-1;'line\nbreak'\n",
+        )
+        .unwrap();
+        let db = Db::default();
+        let (storage, _) = Storage::open(&dir).unwrap();
+        let mut session = Session::with_storage(db.clone(), Arc::new(storage));
+        run_ok(
+            &mut session,
+            "CREATE TABLE people (name string PRIMARY KEY, age number CHECK (age >= 0), \
+             email string)",
+        );
+        let from = |on_error: &str| {
+            format!(
+                "COPY people FROM 'people.csv' WITH (DELIMITER ';', QUOTE '''', NULL 'n/a', \
+                 HEADER, ON_ERROR {})",
+                on_error
+            )
+        };
+
+        // Files stay inside the data directory
+        assert_error_frame(
+            run_cmd(&db, from("ABORT").into()),
+            "COPY with a file needs a data directory",
+        );
+        for path in [csv.display().to_string(), "../files/people.csv".to_string()] {
+            assert_error_frame(
+                session.run(Frame::Cmd(format!("COPY people FROM '{}'", path).into())),
+                &format!("File '{}' must be a relative path", path),
+            );
+        }
+        assert_error_frame(
+            session.run(Frame::Cmd("COPY people TO '../sequel.db'".into())),
+            "File '../sequel.db' must be a relative path",
+        );
+
+        // One bad row and nothing is added
+        assert_error_frame(
+            session.run(Frame::Cmd(from("ABORT").into())),
+            "Line 5: Invalid number \"old\"",
+        );
+        assert_ok(
+            query(&db, "SELECT name FROM people"),
+            Frame::Table(vec![vec!["name".into()]]),
+        );
+        assert_eq!(
+            session.run(Frame::Cmd(from("REPORT").into())),
+            Frame::Table(vec![
+                vec!["line".into(), "error".into()],
+                vec!["5".into(), "Invalid number \"old\"".into()],
+                vec!["6".into(), "Check condition on age failed".into()],
+            ])
+        );
+        assert_ok(
+            query(&db, "SELECT name, age, email FROM people"),
+            Frame::Table(vec![
+                vec!["name".into(), "age".into(), "email".into()],
+                vec!["Ann; Lee".into(), "30".into(), "a@x".into()],
+                vec!["Bo 'B'".into(), "".into(), "".into()],
+            ]),
+        );
+
+        let to = "COPY (SELECT name, email FROM people) TO 'out.csv' (HEADER, DELIMITER ';')";
+        assert_eq!(session.run(Frame::Cmd(to.into())), Frame::Null);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("files/out.csv")).unwrap(),
+            "name;email\n\"Ann; Lee\";a@x\nBo 'B';\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copy_json() {
+        let db = Db::default();
+        let mut session = Session::new(db.clone());
+        run_ok(
+            &mut session,
+            "CREATE TABLE events (id number PRIMARY KEY, kind string NOT NULL,\
+             data string)",
+        );
+        run_ok(
+            &mut session,
+            "COPY events FROM STDIN (FORMAT ndjson, ON_ERROR report)",
+        );
+        let ndjson = "{\"id\": 1, \"kind\": \"a\\\"b\\u00e9\", \"data\": {\"x\": [1, null]}}\n\
+                      \n\
+                      {\"kind\": \"c\", \"id\": 2.5}\n\
+                      {\"id\": 3}\n\
+                      {\"id\": 4, \"kind\": \"d\", \"other\": 1}\n\
+                      [1]\n\
+                      {\"id\": 5,\n";
+        assert_eq!(
+            session.run(Frame::Text(ndjson.into())),
+            Frame::Table(vec![
+                vec!["line".into(), "error".into()],
+                vec!["4".into(), "Column kind non-nullable".into()],
+                vec!["5".into(), "Unknown columns: [\"other\"]".into()],
+                vec!["6".into(), "Expected an object".into()],
+                vec!["7".into(), "Unexpected end of JSON".into()],
+            ])
+        );
+        assert_error_frame(
+            session.run(Frame::Text(ndjson.into())),
+            "Expected COPY ... FROM STDIN before sending rows",
+        );
+
+        let Frame::Text(json) =
+            session.run(Frame::Cmd("COPY events TO STDOUT (FORMAT json)".into()))
+        else {
+            panic!("expected JSON");
+        };
+        assert_eq!(
+            json,
+            "[\n{\"id\":1,\"kind\":\"a\\\"bé\",\"data\":\"{\\\"x\\\":[1,null]}\"},\n\
+             {\"id\":2.5,\"kind\":\"c\",\"data\":null}\n]\n"
+        );
+        assert_eq!(
+            session.run(Frame::Cmd(
+                "COPY (SELECT id FROM events WHERE id > 2) TO STDOUT (FORMAT ndjson)".into()
+            )),
+            Frame::Text("{\"id\":2.5}\n".into())
+        );
+
+        // Reading the array back gives the same rows
+        let copy = Db::default();
+        let mut session = Session::new(copy.clone());
+        run_ok(
+            &mut session,
+            "CREATE TABLE events (id number PRIMARY KEY, kind string, data string)",
+        );
+        run_ok(
+            &mut session,
+            "COPY events (id, kind) FROM STDIN (FORMAT json)",
+        );
+        assert_eq!(session.run(Frame::Text(json.clone())), Frame::Null);
+        assert_ok(
+            query(&copy, "SELECT id, kind, data FROM events"),
+            Frame::Table(vec![
+                vec!["id".into(), "kind".into(), "data".into()],
+                vec!["1".into(), "a\"bé".into(), "".into()],
+                vec!["2.5".into(), "c".into(), "".into()],
+            ]),
+        );
+        run_ok(&mut session, "COPY events FROM STDIN (FORMAT json)");
+        assert_error_frame(
+            session.run(Frame::Text("[{\"id\": 3},\n{\"id\": 1}]".into())),
+            "Line 2: ",
+        );
+        run_ok(&mut session, "COPY events FROM STDIN (FORMAT json)");
+        assert_error_frame(
+            session.run(Frame::Text("[{\"id\": 3},\n{\"id\" 1}]".into())),
+            "Line 2: Expected ':', found '1'",
+        );
+        run_ok(&mut session, "COPY events FROM STDIN (FORMAT json)");
+        assert_error_frame(
+            session.run(Frame::Text("[".repeat(100_000))),
+            "Line 1: JSON nested too deeply",
+        );
+    }
+
+    #[test]
+    fn transactions() {
+        let db = Db::default();
+        let mut alice = Session::new(db.clone());
+        let mut bob = Session::new(db.clone());
+        run_ok(
+            &mut alice,
+            "CREATE TABLE accounts (name string PRIMARY KEY, balance number)",
+        );
+        run_ok(
+            &mut alice,
+            "INSERT INTO accounts VALUES (\"a\", 10), (\"b\", 0)",
+        );
+        let balances =
+            |session: &mut Session| session.run(Frame::Cmd("SELECT balance FROM accounts".into()));
+        let before = Frame::Table(vec![
+            vec!["balance".into()],
+            vec!["10".into()],
+            vec!["0".into()],
+        ]);
+        let after = Frame::Table(vec![
+            vec!["balance".into()],
+            vec!["5".into()],
+            vec!["5".into()],
+        ]);
+
+        // Changes are only seen by the session making them until it commits
+        run_ok(&mut alice, "BEGIN");
+        run_ok(
+            &mut alice,
+            "UPDATE accounts SET balance = 5 WHERE name = \"a\"",
+        );
+        run_ok(
+            &mut alice,
+            "UPDATE accounts SET balance = 5 WHERE name = \"b\"",
+        );
+        assert_eq!(balances(&mut alice), after);
+        assert_eq!(balances(&mut bob), before);
+        run_ok(&mut alice, "ROLLBACK");
+        assert_eq!(balances(&mut alice), before);
+
+        run_ok(&mut alice, "BEGIN TRANSACTION");
+        run_ok(&mut alice, "UPDATE accounts SET balance = 5");
+        assert_eq!(balances(&mut bob), before);
+        run_ok(&mut alice, "COMMIT");
+        assert_eq!(balances(&mut bob), after);
+
+        // A failed statement aborts the transaction
+        run_ok(&mut alice, "BEGIN");
+        run_ok(&mut alice, "DELETE FROM accounts WHERE name = \"a\"");
+        assert_error_frame(
+            alice.run(Frame::Cmd("INSERT INTO accounts VALUES (\"b\", 1)".into())),
+            "Duplicate primary key (name)=(\"b\")",
+        );
+        assert_error_frame(
+            balances(&mut alice),
+            "Transaction is aborted, commands are ignored until ROLLBACK",
+        );
+        assert_error_frame(
+            alice.run(Frame::Cmd("COMMIT".into())),
+            "Transaction was aborted and has been rolled back",
+        );
+        assert_eq!(balances(&mut alice), after);
+
+        // Disconnecting rolls back
+        run_ok(&mut alice, "BEGIN");
+        run_ok(&mut alice, "DELETE FROM accounts");
+        drop(alice);
+        assert_eq!(balances(&mut bob), after);
+
+        // Changing rows another transaction is changing waits until it ends
+        run_ok(&mut bob, "BEGIN");
+        run_ok(&mut bob, "DELETE FROM accounts WHERE name = \"a\"");
+        run_ok(&mut bob, "INSERT INTO accounts VALUES (\"c\", 0)");
+        let mut carol = Session::new(db.clone());
+        let carol = std::thread::spawn(move || {
+            (
+                carol.run(Frame::Cmd(
+                    "UPDATE accounts SET balance = 1 WHERE name = \"a\"".into(),
+                )),
+                carol.run(Frame::Cmd("INSERT INTO accounts VALUES (\"c\", 1)".into())),
+            )
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        run_ok(&mut bob, "COMMIT");
+        let (update, insert) = carol.join().unwrap();
+        assert_eq!(update, Frame::Null);
+        assert_error_frame(insert, "Duplicate primary key (name)=(\"c\")");
+        assert_ok(
+            query(&db, "SELECT name FROM accounts"),
+            Frame::Table(vec![
+                vec!["name".into()],
+                vec!["b".into()],
+                vec!["c".into()],
+            ]),
+        );
+
+        for (sql, err) in [
+            ("COMMIT", "No transaction in progress"),
+            ("ROLLBACK", "No transaction in progress"),
+        ] {
+            assert_error_frame(bob.run(Frame::Cmd(sql.into())), err);
+        }
+        run_ok(&mut bob, "BEGIN");
+        assert_error_frame(
+            bob.run(Frame::Cmd("BEGIN".into())),
+            "A transaction is already in progress",
+        );
+    }
+
+    #[test]
+    fn pending_unique_values() {
+        let db = Db::default();
+        let mut alice = Session::new(db.clone());
+        run_ok(
+            &mut alice,
+            "CREATE TABLE users (id number PRIMARY KEY, email string UNIQUE)",
+        );
+
+        // A value another transaction is adding is only a duplicate once it commits
+        for (end, expected) in [
+            ("ROLLBACK", None),
+            ("COMMIT", Some("Col email must be unique")),
+        ] {
+            run_ok(&mut alice, "BEGIN");
+            run_ok(&mut alice, "INSERT INTO users VALUES (1, \"a\")");
+            let mut bob = Session::new(db.clone());
+            let bob = std::thread::spawn(move || {
+                bob.run(Frame::Cmd("INSERT INTO users VALUES (2, \"a\")".into()))
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            run_ok(&mut alice, end);
+            match expected {
+                None => assert_eq!(bob.join().unwrap(), Frame::Null),
+                Some(err) => assert_error_frame(bob.join().unwrap(), err),
+            }
+            run_ok(&mut alice, "DELETE FROM users");
+        }
+    }
+
+    #[test]
+    fn failed_changes_keep_rows_free() {
+        let db = Db::default();
+        let mut alice = Session::new(db.clone());
+        run_ok(
+            &mut alice,
+            "CREATE TABLE t (k number PRIMARY KEY, v string UNIQUE)",
+        );
+        run_ok(&mut alice, "INSERT INTO t VALUES (1, \"a\"), (2, \"b\")");
+
+        // An update that fails leaves the row to others while the transaction goes on
+        run_ok(&mut alice, "BEGIN");
+        run_ok(&mut alice, "SAVEPOINT s");
+        assert_error_frame(
+            alice.run(Frame::Cmd("UPDATE t SET v = \"b\" WHERE k = 1".into())),
+            "Col v must be unique",
+        );
+        run_ok(&mut alice, "ROLLBACK TO s");
+        let mut bob = Session::new(db.clone());
+        let (tx, rx) = std::sync::mpsc::channel();
+        let bob = std::thread::spawn(move || {
+            tx.send(bob.run(Frame::Cmd("UPDATE t SET v = \"c\" WHERE k = 1".into())))
+        });
+        let res = rx.recv_timeout(Duration::from_secs(5));
+        run_ok(&mut alice, "ROLLBACK");
+        bob.join().unwrap().ok();
+        assert_eq!(res, Ok(Frame::Null));
+    }
+
+    #[test]
+    fn concurrent_increments() {
+        let db = Db::default();
+        let mut session = Session::new(db.clone());
+        run_ok(&mut session, "CREATE TABLE t (k number PRIMARY KEY, n int)");
+        run_ok(&mut session, "INSERT INTO t VALUES (1, 0)");
+
+        // Each statement sees the row as the others left it, so no increment is lost
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let mut session = Session::new(db.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..200 {
+                        run_ok(&mut session, "UPDATE t SET n = n + 1 WHERE k = 1");
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_ok(
+            query(&db, "SELECT n FROM t"),
+            Frame::Table(vec![vec!["n".into()], vec!["800".into()]]),
+        );
+    }
+
+    #[test]
+    fn isolation_levels() {
+        let db = Db::default();
+        let mut alice = Session::new(db.clone());
+        let mut bob = Session::new(db.clone());
+        let mut other = Session::new(db.clone());
+        let column = |session: &mut Session, sql: &'static str| -> Vec<Bytes> {
+            match session.run(Frame::Cmd(sql.into())) {
+                Frame::Table(rows) => rows[1..].iter().map(|row| row[0].clone()).collect(),
+                other => panic!("expected a table, got {:?}", other),
+            }
+        };
+        let values = |session: &mut Session| column(session, "SELECT v FROM t");
+        run_ok(
+            &mut other,
+            "CREATE TABLE t (k number PRIMARY KEY, v number)",
+        );
+        run_ok(&mut other, "CREATE INDEX t_v ON t (v)");
+        run_ok(&mut other, "INSERT INTO t VALUES (1, 10), (2, 20)");
+
+        // Each statement of a READ COMMITTED transaction sees what was committed before it,
+        // while a REPEATABLE READ one keeps the snapshot it started with
+        run_ok(&mut alice, "BEGIN");
+        run_ok(&mut bob, "BEGIN ISOLATION LEVEL REPEATABLE READ");
+        assert_eq!(values(&mut alice), ["10", "20"]);
+        assert_eq!(values(&mut bob), ["10", "20"]);
+        run_ok(&mut other, "UPDATE t SET v = 11 WHERE k = 1");
+        assert_eq!(values(&mut alice), ["11", "20"]);
+        assert_eq!(values(&mut bob), ["10", "20"]);
+        assert_error_frame(
+            bob.run(Frame::Cmd("UPDATE t SET v = 12 WHERE k = 1".into())),
+            "Could not serialize access due to concurrent update",
+        );
+        run_ok(&mut bob, "ROLLBACK");
+        run_ok(&mut alice, "COMMIT");
+
+        // Changes that aren't committed stay hidden from others, through indexes too, and
+        // are gone once rolled back
+        run_ok(&mut alice, "BEGIN");
+        run_ok(&mut alice, "DELETE FROM t WHERE k = 2");
+        run_ok(&mut alice, "INSERT INTO t VALUES (3, 30)");
+        assert_eq!(values(&mut alice), ["11", "30"]);
+        assert_eq!(values(&mut other), ["11", "20"]);
+        let by_index = "SELECT k FROM t WHERE v > 15";
+        assert_eq!(column(&mut alice, by_index), ["3"]);
+        assert_eq!(column(&mut other, by_index), ["2"]);
+        run_ok(&mut alice, "ROLLBACK");
+        assert_eq!(values(&mut other), ["11", "20"]);
+
+        // Of two SERIALIZABLE transactions that read what the other changed, only the first
+        // to commit can
+        run_ok(&mut alice, "BEGIN ISOLATION LEVEL SERIALIZABLE");
+        run_ok(&mut bob, "BEGIN TRANSACTION ISOLATION LEVEL SERIALIZABLE");
+        assert_eq!(values(&mut alice), ["11", "20"]);
+        assert_eq!(values(&mut bob), ["11", "20"]);
+        run_ok(&mut alice, "UPDATE t SET v = 0 WHERE k = 1");
+        run_ok(&mut bob, "UPDATE t SET v = 0 WHERE k = 2");
+        run_ok(&mut alice, "COMMIT");
+        assert_error_frame(
+            bob.run(Frame::Cmd("COMMIT".into())),
+            "Transaction could not be committed and has been rolled back",
+        );
+        assert_eq!(values(&mut other), ["0", "20"]);
+    }
+
+    #[test]
+    fn scans_keep_order() {
+        let db = Db::default();
+        let mut alice = Session::new(db.clone());
+        let mut other = Session::new(db.clone());
+        let column = |session: &mut Session, sql: &'static str| -> Vec<Bytes> {
+            match session.run(Frame::Cmd(sql.into())) {
+                Frame::Table(rows) => rows[1..].iter().map(|row| row[0].clone()).collect(),
+                other => panic!("expected a table, got {:?}", other),
+            }
+        };
+        run_ok(
+            &mut other,
+            "CREATE TABLE t (k number PRIMARY KEY, v number)",
+        );
+        run_ok(&mut other, "CREATE INDEX t_v ON t (v)");
+        run_ok(
+            &mut other,
+            "INSERT INTO t VALUES (1, 10), (2, 20), (3, 30), (4, 40), (5, 50)",
+        );
+
+        // Rows changed since the snapshot come back as they were, in between the others
+        run_ok(&mut alice, "BEGIN ISOLATION LEVEL REPEATABLE READ");
+        assert_eq!(column(&mut alice, "SELECT k FROM t").len(), 5);
+        run_ok(&mut other, "DELETE FROM t WHERE k = 2");
+        run_ok(&mut other, "UPDATE t SET v = 5 WHERE k = 4");
+        run_ok(&mut other, "INSERT INTO t VALUES (0, 0), (6, 60)");
+        assert_eq!(
+            column(&mut alice, "SELECT k FROM t"),
+            ["1", "2", "3", "4", "5"]
+        );
+        assert_eq!(
+            column(&mut alice, "SELECT v FROM t WHERE v >= 10"),
+            ["10", "20", "30", "40", "50"]
+        );
+        run_ok(&mut alice, "COMMIT");
+        assert_eq!(
+            column(&mut alice, "SELECT k FROM t"),
+            ["0", "1", "3", "4", "5", "6"]
+        );
+        assert_eq!(
+            column(&mut alice, "SELECT k FROM t WHERE v >= 0"),
+            ["0", "4", "1", "3", "5", "6"]
+        );
+    }
+
+    #[test]
+    fn row_locks() {
+        let db = Db::default();
+        let mut alice = Session::new(db.clone());
+        let mut bob = Session::new(db.clone());
+        run_ok(
+            &mut alice,
+            "CREATE TABLE t (k number PRIMARY KEY, v number)",
+        );
+        run_ok(&mut alice, "INSERT INTO t VALUES (1, 10), (2, 20)");
+
+        // Rows selected FOR UPDATE can't be changed by others until the transaction ends,
+        // after which they see its changes
+        run_ok(&mut alice, "BEGIN");
+        assert_eq!(
+            alice.run(Frame::Cmd("SELECT v FROM t WHERE k = 1 FOR UPDATE".into())),
+            Frame::Table(vec![vec!["v".into()], vec!["10".into()]]),
+        );
+        let waiting = std::thread::spawn(move || {
+            let update = bob.run(Frame::Cmd("UPDATE t SET v = v + 1 WHERE k = 1".into()));
+            (bob, update)
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        run_ok(&mut alice, "UPDATE t SET v = 100 WHERE k = 1");
+        run_ok(&mut alice, "COMMIT");
+        let (mut bob, update) = waiting.join().unwrap();
+        assert_eq!(update, Frame::Null);
+        assert_ok(
+            query(&db, "SELECT v FROM t"),
+            Frame::Table(vec![
+                vec!["v".into()],
+                vec!["101".into()],
+                vec!["20".into()],
+            ]),
+        );
+        assert_error_frame(
+            alice.run(Frame::Cmd(
+                "SELECT * FROM t a JOIN t b ON a.k = b.k FOR UPDATE".into(),
+            )),
+            "FOR UPDATE is only allowed on queries of one table",
+        );
+
+        // Of two transactions waiting for each other, one is rolled back so the other can
+        // go on
+        run_ok(&mut alice, "BEGIN");
+        run_ok(&mut bob, "BEGIN");
+        run_ok(&mut alice, "UPDATE t SET v = 1 WHERE k = 1");
+        run_ok(&mut bob, "UPDATE t SET v = 2 WHERE k = 2");
+        let cross = |mut session: Session, sql: &'static str| {
+            std::thread::spawn(move || {
+                let update = session.run(Frame::Cmd(sql.into()));
+                (update, session.run(Frame::Cmd("COMMIT".into())))
+            })
+        };
+        let alice = cross(alice, "UPDATE t SET v = 1 WHERE k = 2");
+        let bob = cross(bob, "UPDATE t SET v = 2 WHERE k = 1");
+        let mut results = [alice.join().unwrap(), bob.join().unwrap()];
+        results.sort_by_key(|(update, _)| *update != Frame::Null);
+        let [(update, commit), (deadlocked, aborted)] = results;
+        assert_eq!((update, commit), (Frame::Null, Frame::Null));
+        assert_error_frame(
+            deadlocked,
+            "Deadlock detected, the transaction has been rolled back",
+        );
+        assert_error_frame(aborted, "Transaction was aborted and has been rolled back");
+        match query(&db, "SELECT v FROM t").unwrap() {
+            Frame::Table(rows) => assert!(rows[1] == rows[2], "{:?}", rows),
+            other => panic!("expected a table, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn savepoints() {
+        let db = Db::default();
+        let mut session = Session::new(db.clone());
+        let names = |session: &mut Session| -> Vec<Bytes> {
+            match session.run(Frame::Cmd("SELECT name FROM t".into())) {
+                Frame::Table(rows) => rows[1..].iter().map(|row| row[0].clone()).collect(),
+                other => panic!("expected a table, got {:?}", other),
+            }
+        };
+        run_ok(&mut session, "CREATE TABLE t (name string)");
+        run_ok(&mut session, "BEGIN");
+        run_ok(&mut session, "INSERT INTO t VALUES (\"a\")");
+        run_ok(&mut session, "SAVEPOINT one");
+        run_ok(&mut session, "INSERT INTO t VALUES (\"b\")");
+        run_ok(&mut session, "SAVEPOINT two");
+        run_ok(&mut session, "INSERT INTO t VALUES (\"c\")");
+        run_ok(&mut session, "ROLLBACK TO two");
+        assert_eq!(names(&mut session), vec!["a", "b"]);
+
+        // Rolling back to an older savepoint drops the newer ones
+        run_ok(&mut session, "INSERT INTO t VALUES (\"d\")");
+        run_ok(&mut session, "ROLLBACK TO SAVEPOINT one");
+        assert_eq!(names(&mut session), vec!["a"]);
+        assert!(matches!(
+            session.run(Frame::Cmd("RELEASE two".into())),
+            Frame::SpannedError(msg, _) if msg == "Savepoint \"two\" not found"
+        ));
+
+        // That error aborted the transaction, which rolling back to a savepoint ends
+        assert_error_frame(
+            session.run(Frame::Cmd("INSERT INTO t VALUES (\"e\")".into())),
+            "Transaction is aborted",
+        );
+        run_ok(&mut session, "ROLLBACK TO one");
+        run_ok(&mut session, "INSERT INTO t VALUES (\"e\")");
+        run_ok(&mut session, "SAVEPOINT one");
+        run_ok(&mut session, "INSERT INTO t VALUES (\"f\")");
+
+        // Releasing keeps the changes, and uncovers the older savepoint of the same name
+        run_ok(&mut session, "RELEASE SAVEPOINT one");
+        assert_eq!(names(&mut session), vec!["a", "e", "f"]);
+        run_ok(&mut session, "ROLLBACK TO one");
+        run_ok(&mut session, "INSERT INTO t VALUES (\"g\")");
+        run_ok(&mut session, "COMMIT");
+        assert_ok(
+            query(&db, "SELECT name FROM t"),
+            Frame::Table(vec![
+                vec!["name".into()],
+                vec!["a".into()],
+                vec!["g".into()],
+            ]),
+        );
+
+        assert_error_frame(
+            session.run(Frame::Cmd("SAVEPOINT one".into())),
+            "No transaction in progress",
+        );
+    }
+
+    #[test]
+    fn durable_transactions() {
+        let dir = temp_dir("transactions");
+        let (storage, tables) = Storage::open(&dir).unwrap();
+        let storage = Arc::new(storage);
+        let db: Db = Arc::new(tables.into());
+        let mut session = Session::with_storage(db.clone(), storage.clone());
+        run_ok(&mut session, "BEGIN");
+        run_ok(&mut session, "CREATE TABLE t (a number PRIMARY KEY)");
+        run_ok(&mut session, "INSERT INTO t VALUES (1), (2)");
+        run_ok(&mut session, "COMMIT");
+        run_ok(&mut session, "BEGIN");
+        run_ok(&mut session, "INSERT INTO t VALUES (3)");
+        drop(session);
+        drop(storage);
+
+        let (_, tables) = Storage::open(&dir).unwrap();
+        let db: Db = Arc::new(tables.into());
+        assert_ok(
+            query(&db, "SELECT a FROM t"),
+            Frame::Table(vec![vec!["a".into()], vec!["1".into()], vec!["2".into()]]),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn poisoned_locks() {
+        let db = Db::default();
+        let mut session = Session::new(db.clone());
+        run_ok(&mut session, "CREATE TABLE t (a integer PRIMARY KEY)");
+        run_ok(&mut session, "BEGIN");
+        run_ok(&mut session, "INSERT INTO t VALUES (1)");
+
+        // A statement that panics with the table locked leaves the lock poisoned, which
+        // doesn't keep the open transaction from being rolled back
+        let panicked = std::panic::catch_unwind(|| {
+            db.write("t", |_| -> Result<()> { panic!("statement failed") })
+        });
+        assert!(panicked.is_err());
+        drop(session);
+
+        let mut session = Session::new(db.clone());
+        run_ok(&mut session, "INSERT INTO t VALUES (1), (2)");
+        assert_ok(
+            query(&db, "SELECT count(*) FROM t"),
+            Frame::Table(vec![vec!["count(*)".into()], vec!["2".into()]]),
+        );
+    }
+
+    #[test]
+    fn hidden_row_ids() {
+        let db = Db::default();
+        let mut session = Session::new(db.clone());
+        run_ok(&mut session, "CREATE TABLE t (a text)");
+        let values = vec!["('x')"; 300].join(", ");
+        let sql = format!("INSERT INTO t VALUES {}", values);
+        assert_eq!(session.run(Frame::Cmd(sql.into())), Frame::Null);
+        run_ok(&mut session, "INSERT INTO t VALUES ('y')");
+        assert_ok(
+            query(&db, "SELECT count(*) FROM t"),
+            Frame::Table(vec![vec!["count(*)".into()], vec!["301".into()]]),
+        );
+    }
+
+    fn run_ok(session: &mut Session, sql: &'static str) {
+        assert_eq!(session.run(Frame::Cmd(sql.into())), Frame::Null, "{}", sql);
+    }
+
+    fn query(db: &Db, sql: &str) -> Result<Frame> {
+        match parse::parse(Bytes::copy_from_slice(sql.as_bytes()))? {
+            Command::Select(query) => autocommit(db, |txn| select(db, txn, *query)),
+            other => panic!("expected a query, got {:?}", other),
+        }
+    }
+
+    /// Runs `f` in a transaction of its own, committed if it succeeds
+    fn autocommit(db: &Db, f: impl FnOnce(&mut Txn) -> Result<Frame>) -> Result<Frame> {
+        let mut txn = db.begin(Isolation::default());
+        let res = f(&mut txn);
+        match res {
+            Ok(_) => db.commit(&txn),
+            Err(_) => db.rollback(txn).unwrap(),
+        }
+        res
+    }
+
+    fn init_db() -> Db {
+        let mut table = Table::try_from(vec![
+            ColumnHeader::new("name".into())
+                .ty(Ty::String)
+                .build()
+                .unwrap(),
+            ColumnHeader::new("age".into())
+                .ty(Ty::Number)
+                .build()
+                .unwrap(),
+        ])
+        .unwrap();
+        table
+            .append(vec![
+                Column::new(LiteralValue::String("Elliot".into()), "name".into()),
+                Column::new(LiteralValue::Number(OrderedFloat(16.0)), "age".into()),
+            ])
+            .unwrap();
+        Arc::new(HashMap::from([("people".into(), table)]).into())
+    }
+
+    fn assert_ok<T: Debug + PartialEq>(res: Result<T>, expected: T) {
+        assert!(res.is_ok(), "{:?}", res.as_ref().err());
+        match res {
+            Ok(res) => assert_eq!(res, expected),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    fn assert_err<T>(res: Result<T>, expected: &str) {
+        assert!(res.is_err());
+        match res {
+            Ok(_) => unreachable!(),
+            Err(e) => assert_eq!(e.to_string(), expected),
+        }
+    }
+
+    /// Error frames carry the message followed by its causes
+    fn assert_error_frame(frame: Frame, expected: &str) {
+        match frame {
+            Frame::Error(e) => assert!(e.starts_with(expected), "{} != {}", e, expected),
+            other => panic!("expected error, got {:?}", other),
         }
     }
 
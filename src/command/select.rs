@@ -1,64 +1,69 @@
-use anyhow::{anyhow, Result};
-use bytes::Bytes;
+use anyhow::{anyhow, bail, Result};
 
 use crate::{
     connection::Frame,
-    db::Db,
-    parse::{Key, LiteralValue, Token},
+    db::{Db, Tables, Txn},
+    parse::{error_at, Expr, Select, SelectItem, Token},
+    plan,
 };
 
-use super::on_table;
-
-pub fn select(db: &Db, key: Key, table: Token) -> Result<Frame> {
-    on_table(db, table, |table| match key {
-        Key::Glob => {
-            let headers: Vec<_> = table
-                .col_headers()
-                .iter()
-                .map(|header| LiteralValue::String(header.name().into()))
-                .collect();
-            let header_names: Vec<_> = table
-                .col_headers()
-                .iter()
-                .map(|header| header.name().to_string())
-                .collect();
-            let mut contents: Vec<Vec<_>> = table
-                .rows()
-                .iter()
-                .map(|row| row.cols(&header_names[..]))
-                .collect::<Option<Vec<_>>>()
-                .ok_or_else(|| anyhow!("Unknown column names"))?;
-            contents.insert(0, headers);
-            Ok(Frame::Table(
-                contents
-                    .into_iter()
-                    .map(|row| row.iter().map(Bytes::from).collect())
-                    .collect(),
-            ))
-        }
-        Key::List(cols) => {
-            let names = cols
-                .iter()
-                .map(|col| {
-                    Ok(col
-                        .ident()
-                        .ok_or_else(|| anyhow!("Internal error"))?
-                        .to_string())
-                })
-                .collect::<Result<Vec<_>>>()?;
-            let mut contents: Vec<Vec<_>> = table
-                .rows()
-                .iter()
-                .map(|row| row.cols(&names[..]))
-                .collect::<Option<Vec<_>>>()
-                .ok_or_else(|| anyhow!("Unknown column names"))?;
-            contents.insert(0, names.into_iter().map(LiteralValue::String).collect());
-            Ok(Frame::Table(
-                contents
-                    .into_iter()
-                    .map(|row| row.iter().map(Bytes::from).collect())
-                    .collect(),
-            ))
+/// The query holds the locks on its tables while it runs, as its scans read the rows from
+/// the pages as they are pulled. Writers to those tables wait for it to finish.
+pub fn select(db: &Db, txn: &mut Txn, select: Select) -> Result<Frame> {
+    db.read(&select.tables(), |tables| {
+        if select.for_update {
+            lock_rows(tables, txn, &select)?;
         }
+        let mut op = plan::select(&select, tables, txn, false)?;
+        plan::run(op.as_mut())
     })
 }
+
+/// Locks the rows a `FOR UPDATE` query returns, which must come from a single table
+fn lock_rows(tables: &Tables, txn: &mut Txn, select: &Select) -> Result<()> {
+    let Some(from) = select
+        .from
+        .as_ref()
+        .filter(|from| from.joins.is_empty())
+        .filter(|_| !select.distinct && select.group_by.is_empty() && select.having.is_none())
+    else {
+        bail!("FOR UPDATE is only allowed on queries of one table without DISTINCT or GROUP BY");
+    };
+    let name = from
+        .table
+        .name
+        .ident()
+        .ok_or_else(|| anyhow!("Internal error"))?;
+    let pk = tables
+        .get(name)
+        .ok_or_else(|| {
+            error_at(
+                from.table.name.span,
+                format!("Table \"{}\" not found", name),
+            )
+        })?
+        .primary_key();
+    let keys = Select {
+        items: pk
+            .into_iter()
+            .map(|name| SelectItem::Expr {
+                expr: Expr::Ident(Token::Identifier(name).into()),
+                alias: None,
+            })
+            .collect(),
+        from: Some(from.clone()),
+        filter: select.filter.clone(),
+        order_by: select.order_by.clone(),
+        limit: select.limit.clone(),
+        offset: select.offset.clone(),
+        ..Default::default()
+    };
+    let mut pks = Vec::new();
+    {
+        let mut op = plan::select(&keys, tables, txn, false)?;
+        while let Some(tuple) = op.next()? {
+            pks.push(tuple);
+        }
+    }
+    tables.lock_rows(txn, name, &pks)
+}
@@ -0,0 +1,177 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{
+    connection::Frame,
+    db::{Conflict, Db, Storage, Txn},
+    parse::{error_at, Command, Isolation, Spanned, Token},
+};
+
+/// Work a session has done since BEGIN. Statements change the shared tables right away,
+/// but other transactions keep seeing the rows as they were until it commits, and can't
+/// change those rows in the meantime. Dropping the transaction rolls it back.
+pub struct Transaction {
+    db: Db,
+
+    /// Taken when the transaction ends
+    txn: Option<Txn>,
+
+    /// Set when a statement fails, after which only COMMIT or ROLLBACK are accepted
+    aborted: bool,
+
+    /// Oldest first
+    savepoints: Vec<Savepoint>,
+}
+
+/// How far the transaction had got when the savepoint was made
+struct Savepoint {
+    name: String,
+    at: (usize, usize),
+}
+
+impl Transaction {
+    pub fn begin(db: &Db, isolation: Isolation) -> Transaction {
+        Transaction {
+            txn: Some(db.begin(isolation)),
+            db: db.clone(),
+            aborted: false,
+            savepoints: Vec::new(),
+        }
+    }
+
+    /// Runs `cmd` in a transaction of its own, committed as soon as it succeeds
+    pub fn autocommit(db: &Db, storage: Option<&Storage>, cmd: Command) -> Result<Frame> {
+        let mut transaction = Transaction::begin(db, Isolation::ReadCommitted);
+        let frame = transaction.run(cmd)?;
+        transaction.end(storage)?;
+        Ok(frame)
+    }
+
+    /// A statement that needs a row another transaction holds waits for that transaction
+    /// to end, then starts over, as does one that finds a row changed since it began. If
+    /// waiting would close a cycle of transactions waiting for
+    /// each other, this transaction is rolled back instead, letting the others go on.
+    pub fn run(&mut self, cmd: Command) -> Result<Frame> {
+        self.check()?;
+        loop {
+            let txn = self.txn.as_mut().ok_or_else(|| anyhow!("Internal error"))?;
+            self.db.snapshot(txn);
+            let err = match super::run(&self.db, txn, cmd.clone()) {
+                Ok(frame) => return Ok(frame),
+                Err(err) => err,
+            };
+            let Some(conflict) = err.downcast_ref::<Conflict>() else {
+                return Err(err);
+            };
+            let Some(holder) = conflict.holder else {
+                continue;
+            };
+            if let Err(deadlock) = self.db.wait(txn, holder) {
+                if let Some(txn) = self.txn.take() {
+                    self.db.rollback(txn)?;
+                }
+                self.savepoints.clear();
+                return Err(deadlock);
+            }
+        }
+    }
+
+    /// Fails if an earlier statement did
+    pub fn check(&self) -> Result<()> {
+        if self.aborted {
+            bail!("Transaction is aborted, commands are ignored until ROLLBACK");
+        }
+        Ok(())
+    }
+
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+
+    /// Savepoints can share a name, in which case the newest one is used until it is
+    /// released
+    pub fn savepoint(&mut self, name: &Spanned<Token>) -> Result<()> {
+        self.check()?;
+        let name = name.ident().ok_or_else(|| anyhow!("Internal error"))?;
+        let at = self
+            .txn
+            .as_ref()
+            .ok_or_else(|| anyhow!("Internal error"))?
+            .savepoint();
+        self.savepoints.push(Savepoint {
+            name: name.to_string(),
+            at,
+        });
+        Ok(())
+    }
+
+    /// Undoes the changes made since the savepoint, which also ends an abort. Savepoints
+    /// made after it are gone, while it stays to be rolled back to again.
+    pub fn rollback_to(&mut self, name: &Spanned<Token>) -> Result<()> {
+        let i = self.find(name)?;
+        self.savepoints.truncate(i + 1);
+        let at = self.savepoints[i].at;
+        let txn = self.txn.as_mut().ok_or_else(|| anyhow!("Internal error"))?;
+        self.db.rollback_to(txn, at)?;
+        self.aborted = false;
+        Ok(())
+    }
+
+    /// Forgets the savepoint and those made after it, keeping the changes made since
+    pub fn release(&mut self, name: &Spanned<Token>) -> Result<()> {
+        self.check()?;
+        let i = self.find(name)?;
+        self.savepoints.truncate(i);
+        Ok(())
+    }
+
+    fn find(&self, name: &Spanned<Token>) -> Result<usize> {
+        let ident = name.ident().ok_or_else(|| anyhow!("Internal error"))?;
+        self.savepoints
+            .iter()
+            .rposition(|savepoint| &savepoint.name == ident)
+            .ok_or_else(|| error_at(name.span, format!("Savepoint \"{}\" not found", ident)))
+    }
+
+    /// Undoes every change and ends the transaction
+    pub fn rollback(mut self) -> Result<()> {
+        let txn = self.txn.take().ok_or_else(|| anyhow!("Internal error"))?;
+        self.db.rollback(txn)
+    }
+
+    /// Makes the changes visible to everyone, logging them to `storage` as a single record
+    /// first. A `Serializable` transaction fails here if it read tables that others have
+    /// changed since it started, in which case it is rolled back.
+    pub fn commit(self, storage: Option<&Storage>) -> Result<()> {
+        if self.aborted {
+            bail!("Transaction was aborted and has been rolled back");
+        }
+        self.end(storage)
+            .context("Transaction could not be committed and has been rolled back")
+    }
+
+    fn end(mut self, storage: Option<&Storage>) -> Result<()> {
+        let txn = self.txn.take().ok_or_else(|| anyhow!("Internal error"))?;
+        let db = &self.db;
+        let res = match storage {
+            Some(storage) if !txn.log().is_empty() => {
+                storage.commit(txn.log(), || db.validate(&txn), || db.commit(&txn))
+            }
+            _ => db.try_commit(&txn),
+        };
+        if res.is_err() {
+            db.rollback(txn)?;
+        }
+        res
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if let Some(txn) = self.txn.take() {
+            // Panicking again while unwinding from a panic would abort the process
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| self.db.rollback(txn)));
+        }
+    }
+}
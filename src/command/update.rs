@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Result};
+
+use crate::{
+    connection::Frame,
+    db::{Changes, Column, Db, Txn},
+    parse::{error_at, Expr, FromClause, Select, SelectItem, Spanned, TableRef, Token},
+    plan,
+};
+
+/// Sets columns of every row matching `filter` to the values of the `set` expressions,
+/// which are evaluated against the row before any change. Either every row is updated or,
+/// if one of them is rejected, none is.
+pub fn update(
+    db: &Db,
+    txn: &mut Txn,
+    table: Spanned<Token>,
+    set: Vec<(Spanned<Token>, Expr)>,
+    filter: Option<Expr>,
+) -> Result<Frame> {
+    let table_name = table.ident().ok_or_else(|| anyhow!("Internal error"))?;
+    db.write(table_name, |tables| {
+        let headers: Vec<_> = tables
+            .get(table_name)
+            .ok_or_else(|| error_at(table.span, format!("Table \"{}\" not found", table_name)))?
+            .col_headers()
+            .iter()
+            .map(|header| header.name().to_string())
+            .collect();
+        let mut targets = Vec::new();
+        for (col, _) in &set {
+            let name = col.ident().ok_or_else(|| anyhow!("Internal error"))?;
+            let i = headers
+                .iter()
+                .position(|header| header == name)
+                .ok_or_else(|| error_at(col.span, format!("Unknown column {}", name)))?;
+            targets.push(i);
+        }
+
+        // Every current column, followed by the new values
+        let mut items = vec![SelectItem::Wildcard(None)];
+        items.extend(
+            set.into_iter()
+                .map(|(_, expr)| SelectItem::Expr { expr, alias: None }),
+        );
+        let select = Select {
+            items,
+            from: Some(FromClause {
+                table: TableRef {
+                    name: table.clone(),
+                    alias: None,
+                },
+                joins: Vec::new(),
+            }),
+            filter,
+            ..Default::default()
+        };
+        let mut tuples = Vec::new();
+        {
+            let mut op = plan::select(&select, tables, txn, false)?;
+            while let Some(tuple) = op.next()? {
+                tuples.push(tuple);
+            }
+        }
+
+        let pk = tables
+            .get(table_name)
+            .ok_or_else(|| anyhow!("Internal error"))?
+            .primary_key()
+            .iter()
+            .map(|name| headers.iter().position(|header| header == name))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow!("Internal error"))?;
+        Changes::apply(tables, txn, |changes| {
+            for tuple in tuples {
+                let (old, new_vals) = tuple.split_at(headers.len());
+                let mut new = old.to_vec();
+                for (i, val) in targets.iter().zip(new_vals) {
+                    new[*i] = val.clone();
+                }
+                let cols = headers
+                    .iter()
+                    .zip(new)
+                    .map(|(name, val)| Column::new(val, name.clone()))
+                    .collect();
+                let key: Vec<_> = pk.iter().map(|i| old[*i].clone()).collect();
+                changes.update(table_name, &key, cols)?;
+            }
+            Ok(Frame::Null)
+        })
+    })
+}
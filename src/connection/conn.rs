@@ -1,13 +1,16 @@
 use std::io::{self, Cursor};
 
 use anyhow::{bail, Result};
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Buf, BytesMut};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufWriter},
     net::TcpStream,
 };
 
-use super::Frame;
+use super::{
+    frame::{encode_param, encode_table},
+    Frame,
+};
 
 pub struct Connection {
     stream: BufWriter<TcpStream>,
@@ -61,20 +64,46 @@ impl Connection {
             }
             Frame::Table(rows) => {
                 self.stream.write_u8(b'*').await?;
-                if let Some(first_row) = rows.first() {
-                    self.write_table_row(first_row).await?;
-                    for row in &rows[1..] {
-                        self.stream.write_u8(b'^').await?;
-                        self.write_table_row(row).await?;
-                    }
-                }
-                self.stream.write_u8(b'*').await?;
+                self.stream.write_all(&encode_table(rows)).await?;
             }
             Frame::Error(e) => {
                 eprintln!("{}", e);
                 self.stream.write_u8(b'-').await?;
                 self.stream.write_all(e.as_bytes()).await?;
             }
+            Frame::SpannedError(e, span) => {
+                eprintln!("{}", e);
+                self.stream.write_u8(b'!').await?;
+                self.stream
+                    .write_all(
+                        format!("{},{},{},{}|", span.start, span.end, span.line, span.col)
+                            .as_bytes(),
+                    )
+                    .await?;
+                self.stream.write_all(e.as_bytes()).await?;
+            }
+            Frame::Execute(name, params) => {
+                self.stream.write_u8(b'$').await?;
+                self.stream.write_all(name.as_bytes()).await?;
+                self.stream
+                    .write_all(format!("\r\n{}", params.len()).as_bytes())
+                    .await?;
+                for param in params {
+                    let (tag, bytes) = encode_param(param);
+                    self.stream.write_all(b"\r\n").await?;
+                    self.stream.write_u8(tag).await?;
+                    self.stream
+                        .write_all(format!("{}\r\n", bytes.len()).as_bytes())
+                        .await?;
+                    self.stream.write_all(&bytes).await?;
+                }
+            }
+            Frame::Text(text) => {
+                self.stream
+                    .write_all(format!("#{}\r\n", text.len()).as_bytes())
+                    .await?;
+                self.stream.write_all(text.as_bytes()).await?;
+            }
             Frame::Null => self.stream.write_all(b"-1").await?,
         }
         self.stream.write_all(b"\r\n").await?;
@@ -82,16 +111,4 @@ impl Connection {
 
         Ok(())
     }
-
-    async fn write_table_row(&mut self, row: &[Bytes]) -> io::Result<()> {
-        if let Some(first_item) = row.first() {
-            self.stream.write_all(first_item).await?;
-            for item in &row[1..] {
-                self.stream.write_u8(b'|').await?;
-                self.stream.write_all(item).await?;
-            }
-        }
-
-        Ok(())
-    }
 }
@@ -3,17 +3,32 @@ use std::{fmt::Display, io::Cursor};
 use anyhow::{anyhow, bail, Context, Result};
 use bytes::{Buf, Bytes};
 
+use crate::{LiteralValue, Span, Ty};
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Frame {
     // Starts with `:`
     Cmd(Bytes),
 
-    // `*1|2^3|4*` -> [[1,2],[3,4]]
+    // `*` and the number of rows, then for each row its number of cells on one line, then
+    // each cell as its byte length on one line followed by the bytes:
+    // `*2\r\n1\r\n4\r\nName\r\n1\r\n6\r\nElliot\r\n` -> [[Name],[Elliot]]
     Table(Vec<Vec<Bytes>>),
 
     // Starts with `-`
     Error(String),
 
+    // `!start,end,line,col|message`, an error pointing into the query
+    SpannedError(String, Span),
+
+    // `$name`, then the number of parameters, then each parameter as a type tag (`s`, `n`,
+    // `b` or `_` for null) and byte length on one line followed by the bytes:
+    // `$insert_person\r\n2\r\ns6\r\nElliot\r\nn2\r\n16\r\n`
+    Execute(String, Vec<LiteralValue>),
+
+    // `#` and the byte length on one line, followed by the text: `#5\r\nhello\r\n`
+    Text(String),
+
     // -1
     Null,
 }
@@ -23,8 +38,11 @@ impl Frame {
         match get_u8(src) {
             None => Ok(None),
             Some(b':') => Ok(get_line(src).map(|_| ())),
-            Some(b'*') => Ok(get_until(src, b'*').map(|_| ())),
+            Some(b'*') => Ok(get_table(src)?.map(|_| ())),
             Some(b'-') => Ok(get_line(src).map(|_| ())),
+            Some(b'!') => Ok(get_line(src).map(|_| ())),
+            Some(b'$') => Ok(get_execute(src)?.map(|_| ())),
+            Some(b'#') => Ok(get_text(src)?.map(|_| ())),
             Some(c) => bail!("Unexpected char: {}", c),
         }
     }
@@ -35,19 +53,7 @@ impl Frame {
                 let line = throw_incomplete(get_line(src))?;
                 Ok(Frame::Cmd(Bytes::copy_from_slice(line)))
             }
-            b'*' => {
-                let table = std::str::from_utf8(throw_incomplete(get_until(src, b'*'))?)
-                    .context("Failed to parse table")?;
-                let mut rows = Vec::new();
-                for row in table.split('^') {
-                    let mut items = Vec::new();
-                    for item in row.split('|') {
-                        items.push(Bytes::copy_from_slice(item.as_bytes()));
-                    }
-                    rows.push(items);
-                }
-                Ok(Frame::Table(rows))
-            }
+            b'*' => Ok(Frame::Table(throw_incomplete(get_table(src)?)?)),
             b'-' => {
                 let line = throw_incomplete(get_line(src))?;
                 if line == [b'1'] {
@@ -56,9 +62,61 @@ impl Frame {
                     Ok(Frame::Error(String::from_utf8(line.to_vec())?))
                 }
             }
+            b'!' => {
+                let line = std::str::from_utf8(throw_incomplete(get_line(src))?)?;
+                let (pos, msg) = line
+                    .split_once('|')
+                    .ok_or_else(|| anyhow!("Malformed error frame"))?;
+                let pos = pos
+                    .split(',')
+                    .map(|n| n.parse())
+                    .collect::<Result<Vec<usize>, _>>()
+                    .context("Malformed error frame")?;
+                match pos[..] {
+                    [start, end, line, col] => Ok(Frame::SpannedError(
+                        msg.to_string(),
+                        Span {
+                            start,
+                            end,
+                            line,
+                            col,
+                        },
+                    )),
+                    _ => bail!("Malformed error frame"),
+                }
+            }
+            b'$' => {
+                let (name, params) = throw_incomplete(get_execute(src)?)?;
+                Ok(Frame::Execute(name, params))
+            }
+            b'#' => Ok(Frame::Text(throw_incomplete(get_text(src)?)?)),
             c => bail!("Unexpected char: {}", c),
         }
     }
+
+    /// Like `Display`, but shows errors with the offending line of `source` and a caret under
+    /// the span they point at
+    pub fn render(&self, source: &str) -> String {
+        match self {
+            Frame::SpannedError(e, span) => {
+                let line = source
+                    .lines()
+                    .nth(span.line.saturating_sub(1))
+                    .unwrap_or_default();
+                let gutter = span.line.to_string();
+                let pad = " ".repeat(gutter.len());
+                let width = span.end.saturating_sub(span.start).max(1);
+                format!(
+                    "\x1b[31mError: {}\x1b[0m\n{pad} |\n{gutter} | {}\n{pad} | {}\x1b[31m{}\x1b[0m",
+                    e,
+                    line,
+                    " ".repeat(span.col.saturating_sub(1)),
+                    "^".repeat(width),
+                )
+            }
+            other => other.to_string(),
+        }
+    }
 }
 
 impl Display for Frame {
@@ -102,11 +160,168 @@ impl Display for Frame {
                 Ok(())
             }
             Frame::Error(e) => write!(f, "\x1b[31mError: {}\x1b[0m", e),
+            Frame::SpannedError(e, span) => write!(
+                f,
+                "\x1b[31mError: {} (line {}, column {})\x1b[0m",
+                e, span.line, span.col
+            ),
+            Frame::Execute(name, params) => write!(f, "EXECUTE {} {:?}", name, params),
+            Frame::Text(text) => write!(f, "{}", text),
             Frame::Null => write!(f, "NULL"),
         }
     }
 }
 
+/// The body of a `Frame::Table`, up to the line break that ends every frame
+pub(super) fn encode_table(rows: &[Vec<Bytes>]) -> Vec<u8> {
+    let mut buf = format!("{}", rows.len()).into_bytes();
+    for row in rows {
+        buf.extend_from_slice(format!("\r\n{}", row.len()).as_bytes());
+        for cell in row {
+            buf.extend_from_slice(format!("\r\n{}\r\n", cell.len()).as_bytes());
+            buf.extend_from_slice(cell);
+        }
+    }
+    buf
+}
+
+/// Tag and bytes a parameter is sent as in a `Frame::Execute`
+pub(super) fn encode_param(param: &LiteralValue) -> (u8, Bytes) {
+    let tag = match param {
+        LiteralValue::String(_) => b's',
+        LiteralValue::Number(_) => b'n',
+        LiteralValue::Int(_) => b'i',
+        LiteralValue::Decimal(_) => b'd',
+        LiteralValue::Bool(_) => b'b',
+        LiteralValue::Null => b'_',
+        LiteralValue::Date(_) => b'D',
+        LiteralValue::Time(_) => b'T',
+        LiteralValue::Timestamp(_) => b'S',
+        LiteralValue::TimestampTz(_) => b'Z',
+        LiteralValue::Interval(_) => b'I',
+    };
+    (tag, Bytes::from(param))
+}
+
+fn decode_param(tag: u8, bytes: &[u8]) -> Result<LiteralValue> {
+    let text = std::str::from_utf8(bytes)?;
+    Ok(match tag {
+        b's' => LiteralValue::String(text.to_string()),
+        b'n' => LiteralValue::Number(text.parse().context("Malformed number parameter")?),
+        b'i' => LiteralValue::Int(text.parse().context("Malformed integer parameter")?),
+        b'd' => LiteralValue::Decimal(text.parse().context("Malformed decimal parameter")?),
+        b'b' => LiteralValue::Bool(text.parse().context("Malformed bool parameter")?),
+        b'_' => LiteralValue::Null,
+        b'D' | b'T' | b'S' | b'Z' | b'I' => {
+            let ty = match tag {
+                b'D' => Ty::Date,
+                b'T' => Ty::Time,
+                b'S' => Ty::Timestamp,
+                b'Z' => Ty::TimestampTz,
+                _ => Ty::Interval,
+            };
+            LiteralValue::String(text.to_string())
+                .coerce(&ty)
+                .context("Malformed date or time parameter")?
+        }
+        c => bail!("Unknown parameter type: {}", c as char),
+    })
+}
+
+/// Reads the body of a `Frame::Execute`, or `None` if it hasn't fully arrived
+fn get_execute(src: &mut Cursor<&[u8]>) -> Result<Option<(String, Vec<LiteralValue>)>> {
+    let Some(name) = get_line(src) else {
+        return Ok(None);
+    };
+    let name = String::from_utf8(name.to_vec())?;
+    let Some(count) = get_line(src) else {
+        return Ok(None);
+    };
+    let count: usize = std::str::from_utf8(count)?
+        .parse()
+        .context("Malformed parameter count")?;
+    // The count comes from the client, so space is only made as the parameters arrive
+    let mut params = Vec::new();
+    for _ in 0..count {
+        let Some(header) = get_line(src) else {
+            return Ok(None);
+        };
+        let (&tag, len) = header
+            .split_first()
+            .ok_or_else(|| anyhow!("Malformed parameter"))?;
+        let len: usize = std::str::from_utf8(len)?
+            .parse()
+            .context("Malformed parameter length")?;
+        let start = src.position() as usize;
+        if src.get_ref().len().saturating_sub(start) < len.saturating_add(2) {
+            return Ok(None);
+        }
+        if &src.get_ref()[start + len..start + len + 2] != b"\r\n" {
+            bail!("Malformed parameter");
+        }
+        params.push(decode_param(tag, &src.get_ref()[start..start + len])?);
+        src.set_position((start + len + 2) as u64);
+    }
+    Ok(Some((name, params)))
+}
+
+/// Reads the body of a `Frame::Table`, or `None` if it hasn't fully arrived
+fn get_table(src: &mut Cursor<&[u8]>) -> Result<Option<Vec<Vec<Bytes>>>> {
+    let Some(count) = get_line(src) else {
+        return Ok(None);
+    };
+    let count: usize = std::str::from_utf8(count)?
+        .parse()
+        .context("Malformed row count")?;
+    // Like parameters, rows only take space once they arrive
+    let mut rows = Vec::new();
+    for _ in 0..count {
+        let Some(width) = get_line(src) else {
+            return Ok(None);
+        };
+        let width: usize = std::str::from_utf8(width)?
+            .parse()
+            .context("Malformed cell count")?;
+        let mut row = Vec::new();
+        for _ in 0..width {
+            let Some(cell) = get_sized(src, "cell")? else {
+                return Ok(None);
+            };
+            row.push(Bytes::copy_from_slice(cell));
+        }
+        rows.push(row);
+    }
+    Ok(Some(rows))
+}
+
+/// Reads the body of a `Frame::Text`, or `None` if it hasn't fully arrived
+fn get_text(src: &mut Cursor<&[u8]>) -> Result<Option<String>> {
+    match get_sized(src, "text")? {
+        Some(text) => Ok(Some(String::from_utf8(text.to_vec())?)),
+        None => Ok(None),
+    }
+}
+
+/// Reads a byte length on one line and that many bytes followed by a line break, or `None`
+/// if they haven't fully arrived
+fn get_sized<'a>(src: &mut Cursor<&'a [u8]>, what: &str) -> Result<Option<&'a [u8]>> {
+    let Some(len) = get_line(src) else {
+        return Ok(None);
+    };
+    let len: usize = std::str::from_utf8(len)?
+        .parse()
+        .with_context(|| format!("Malformed {} length", what))?;
+    let start = src.position() as usize;
+    if src.get_ref().len().saturating_sub(start) < len.saturating_add(2) {
+        return Ok(None);
+    }
+    if &src.get_ref()[start + len..start + len + 2] != b"\r\n" {
+        bail!("Malformed {}", what);
+    }
+    src.set_position((start + len + 2) as u64);
+    Ok(Some(&src.get_ref()[start..start + len]))
+}
+
 fn throw_incomplete<T>(res: Option<T>) -> Result<T> {
     res.ok_or_else(|| anyhow!("stream ended early"))
 }
@@ -132,17 +347,3 @@ fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Option<&'a [u8]> {
 
     None
 }
-
-fn get_until<'a>(src: &mut Cursor<&'a [u8]>, c: u8) -> Option<&'a [u8]> {
-    let start = src.position() as usize;
-    let end = src.get_ref().len() - 1;
-
-    for i in start..end {
-        if src.get_ref()[i] == c {
-            src.set_position(i as u64);
-            return Some(&src.get_ref()[start..i]);
-        }
-    }
-
-    None
-}
@@ -6,6 +6,12 @@ pub use frame::Frame;
 
 #[cfg(test)]
 mod tests {
+    use std::io::Cursor;
+
+    use ordered_float::OrderedFloat;
+
+    use crate::{LiteralValue, Span};
+
     use super::*;
 
     #[test]
@@ -22,4 +28,109 @@ mod tests {
 "#;
         assert_eq!(format!("{table}"), expected);
     }
+
+    #[test]
+    fn render_spanned_error() {
+        let err = Frame::SpannedError(
+            "Table \"peeple\" not found".into(),
+            Span {
+                start: 14,
+                end: 20,
+                line: 1,
+                col: 15,
+            },
+        );
+        let expected = "\x1b[31mError: Table \"peeple\" not found\x1b[0m
+  |
+1 | SELECT * FROM peeple
+  |               \x1b[31m^^^^^^\x1b[0m";
+        assert_eq!(err.render("SELECT * FROM peeple"), expected);
+    }
+
+    #[test]
+    fn parse_spanned_error() {
+        let src = b"!14,20,1,15|Table \"peeple\" not found\r\n";
+        let frame = Frame::parse(&mut Cursor::new(&src[..])).unwrap();
+        assert_eq!(
+            frame,
+            Frame::SpannedError(
+                "Table \"peeple\" not found".into(),
+                Span {
+                    start: 14,
+                    end: 20,
+                    line: 1,
+                    col: 15,
+                },
+            )
+        );
+    }
+
+    #[test]
+    fn parse_text() {
+        let src = b"#9\r\nDUMP;\r\nx*\r\n";
+        let mut cursor = Cursor::new(&src[..]);
+        assert!(Frame::check(&mut cursor).unwrap().is_some());
+        assert_eq!(cursor.position() as usize, src.len());
+        assert_eq!(
+            Frame::parse(&mut Cursor::new(&src[..])).unwrap(),
+            Frame::Text("DUMP;\r\nx*".into())
+        );
+        assert!(Frame::check(&mut Cursor::new(&src[..8])).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_table() {
+        let rows = vec![
+            vec!["count(*)".into(), "a|b".into()],
+            vec!["2^3".into(), "".into()],
+            vec!["*\r\n*".into(), "-1".into()],
+        ];
+        let mut src = vec![b'*'];
+        src.extend(frame::encode_table(&rows));
+        src.extend(b"\r\n");
+        let mut cursor = Cursor::new(&src[..]);
+        assert!(Frame::check(&mut cursor).unwrap().is_some());
+        assert_eq!(cursor.position() as usize, src.len());
+        assert_eq!(
+            Frame::parse(&mut Cursor::new(&src[..])).unwrap(),
+            Frame::Table(rows)
+        );
+
+        let partial = &src[..src.len() - 3];
+        assert!(Frame::check(&mut Cursor::new(partial)).unwrap().is_none());
+        let huge = format!("*{}\r\n{}\r\n", usize::MAX, usize::MAX);
+        assert!(Frame::check(&mut Cursor::new(huge.as_bytes()))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn parse_execute() {
+        let src = b"$add\r\n3\r\ns5\r\na\r\nb|\r\nn2\r\n16\r\n_0\r\n\r\n";
+        let mut cursor = Cursor::new(&src[..]);
+        assert!(Frame::check(&mut cursor).unwrap().is_some());
+        assert_eq!(cursor.position() as usize, src.len());
+
+        let frame = Frame::parse(&mut Cursor::new(&src[..])).unwrap();
+        assert_eq!(
+            frame,
+            Frame::Execute(
+                "add".into(),
+                vec![
+                    LiteralValue::String("a\r\nb|".into()),
+                    LiteralValue::Number(OrderedFloat(16.0)),
+                    LiteralValue::Null,
+                ]
+            )
+        );
+
+        let partial = &src[..src.len() - 5];
+        assert!(Frame::check(&mut Cursor::new(partial)).unwrap().is_none());
+
+        // Counts and lengths are believed only once that much has arrived
+        let huge = format!("$add\r\n{}\r\ns{}\r\n", usize::MAX, usize::MAX);
+        assert!(Frame::check(&mut Cursor::new(huge.as_bytes()))
+            .unwrap()
+            .is_none());
+    }
 }
@@ -0,0 +1,541 @@
+use anyhow::{anyhow, bail, Result};
+
+use crate::parse::{LiteralValue, RefAction};
+
+use super::{database::Tables, mvcc::Txn, Column, DefaultOpt, ForeignKey, Index, Redo, Row, Table};
+
+/// The writes made by one statement of a transaction. Foreign keys are enforced as rows
+/// change, and if the statement fails every change it made is undone. Each change that is
+/// kept is added to the transaction's log.
+pub struct Changes<'a, 't> {
+    tables: &'a mut Tables<'t>,
+    txn: &'a mut Txn,
+
+    /// Where the statement's changes start in the transaction's undo list and log
+    start: (usize, usize),
+
+    /// `NO ACTION` references to keys that went away, which must be gone too by the end of
+    /// the statement
+    deferred: Vec<(String, ForeignKey, Vec<LiteralValue>)>,
+}
+
+pub(super) enum Undo {
+    Insert {
+        table: String,
+        pk: Vec<LiteralValue>,
+    },
+    Delete {
+        table: String,
+        cols: Vec<Column>,
+    },
+
+    /// `pk` is the primary key after the update
+    Update {
+        table: String,
+        pk: Vec<LiteralValue>,
+        cols: Vec<Column>,
+    },
+
+    /// `replaced` is the table that had the same name, if any
+    CreateTable {
+        name: String,
+        replaced: Option<Table>,
+    },
+    CreateIndex {
+        table: String,
+        name: String,
+    },
+    DropIndex {
+        table: String,
+        index: Index,
+    },
+}
+
+impl<'a, 't> Changes<'a, 't> {
+    /// Runs `f` against `tables` as part of `txn`, keeping its changes only if it succeeds
+    pub fn apply<T>(
+        tables: &'a mut Tables<'t>,
+        txn: &'a mut Txn,
+        f: impl FnOnce(&mut Changes<'a, 't>) -> Result<T>,
+    ) -> Result<T> {
+        let mut changes = Changes {
+            start: txn.savepoint(),
+            tables,
+            txn,
+            deferred: Vec::new(),
+        };
+        let res = f(&mut changes).and_then(|res| {
+            changes.check_deferred()?;
+            Ok(res)
+        });
+        if res.is_err() {
+            undo(changes.tables, changes.txn, changes.start)?;
+        }
+        res
+    }
+
+    /// Runs `f`, undoing just the changes it made if it fails so the statement can go on
+    pub fn attempt<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let start = self.txn.savepoint();
+        let deferred = self.deferred.len();
+        let res = f(self);
+        if res.is_err() {
+            undo(self.tables, self.txn, start)?;
+            self.deferred.truncate(deferred);
+        }
+        res
+    }
+
+    /// Adds `table` as `name`, replacing any table of that name
+    pub fn create_table(&mut self, name: &str, table: Table) -> Result<()> {
+        self.tables.versions().create(self.txn, name)?;
+        let replaced = self.tables.insert(name, table.try_clone()?)?;
+        self.txn.log.push(Redo::CreateTable {
+            name: name.to_string(),
+            table,
+        });
+        self.txn.undo.push(Undo::CreateTable {
+            name: name.to_string(),
+            replaced,
+        });
+        Ok(())
+    }
+
+    pub fn create_index(
+        &mut self,
+        table: &str,
+        name: &str,
+        columns: Vec<String>,
+        unique: bool,
+    ) -> Result<()> {
+        self.table(table)?
+            .create_index(name.to_string(), columns.clone(), unique)?;
+        self.txn.undo.push(Undo::CreateIndex {
+            table: table.to_string(),
+            name: name.to_string(),
+        });
+        self.txn.log.push(Redo::CreateIndex {
+            table: table.to_string(),
+            name: name.to_string(),
+            columns,
+            unique,
+        });
+        Ok(())
+    }
+
+    /// Whether some table had an index called `name` to drop
+    pub fn drop_index(&mut self, name: &str) -> Result<bool> {
+        let mut names: Vec<_> = self.tables.iter().map(|(name, _)| name.clone()).collect();
+        names.sort();
+        for table in names {
+            if let Some(index) = self.table(&table)?.drop_index(name)? {
+                self.txn.undo.push(Undo::DropIndex {
+                    table: table.clone(),
+                    index,
+                });
+                self.txn.log.push(Redo::DropIndex {
+                    table,
+                    name: name.to_string(),
+                });
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn table(&mut self, name: &str) -> Result<&mut Table> {
+        self.tables.versions().check_table(self.txn.id, name)?;
+        self.tables
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("Table \"{}\" not found", name))
+    }
+
+    /// Adds a row, returning its primary key
+    pub fn insert(&mut self, table: &str, cols: Vec<Column>) -> Result<Vec<LiteralValue>> {
+        // A key another transaction is adding is reported as such rather than as a duplicate
+        let key = values(&cols, &self.table(table)?.primary_key());
+        self.tables.versions().check_row(self.txn, table, &key)?;
+        self.check_unique(table, &key, &cols)?;
+        let pk = self.table(table)?.append(cols)?;
+        self.txn.undo.push(Undo::Insert {
+            table: table.to_string(),
+            pk: pk.clone(),
+        });
+        self.tables.versions().touch(self.txn, table, &pk, None)?;
+        self.check_released(table, &pk)?;
+        let cols = self
+            .table(table)?
+            .get(&pk)?
+            .ok_or_else(|| anyhow!("Internal error"))?
+            .all_cols();
+        self.txn.log.push(Redo::Insert {
+            table: table.to_string(),
+            cols,
+        });
+        self.check_references(table, &pk, None)?;
+        Ok(pk)
+    }
+
+    /// Removes a row, along with whatever its foreign keys' `ON DELETE` actions say. Rows
+    /// that are already gone are skipped.
+    pub fn delete(&mut self, table: &str, pk: &[LiteralValue]) -> Result<()> {
+        self.tables.versions().check_update(self.txn, table, pk)?;
+        let Some(row) = self.table(table)?.delete(pk)? else {
+            return Ok(());
+        };
+        let cols = row.all_cols();
+        self.txn.undo.push(Undo::Delete {
+            table: table.to_string(),
+            cols: cols.clone(),
+        });
+        self.txn.log.push(Redo::Delete {
+            table: table.to_string(),
+            pk: pk.to_vec(),
+        });
+        self.tables
+            .versions()
+            .touch(self.txn, table, pk, Some(&row))?;
+        for (child, fk) in self.referencing(table) {
+            self.key_removed(&child, &fk, values(&cols, &fk.ref_columns), None)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces a row, returning its new primary key. Rows referencing a key that changed
+    /// follow the `ON UPDATE` action of their foreign key.
+    pub fn update(
+        &mut self,
+        table: &str,
+        pk: &[LiteralValue],
+        cols: Vec<Column>,
+    ) -> Result<Vec<LiteralValue>> {
+        // The row is only marked as changed once the change was made and can be undone
+        self.tables.versions().check_update(self.txn, table, pk)?;
+        let current = self
+            .table(table)?
+            .get(pk)?
+            .ok_or_else(|| anyhow!("No row with primary key ({})", join(pk)))?;
+        let old = current.all_cols();
+        self.check_unique(table, pk, &cols)?;
+        let new_pk = self.table(table)?.update(pk, cols.clone())?;
+        self.txn.log.push(Redo::Update {
+            table: table.to_string(),
+            pk: pk.to_vec(),
+            cols,
+        });
+        self.txn.undo.push(Undo::Update {
+            table: table.to_string(),
+            pk: new_pk.clone(),
+            cols: old.clone(),
+        });
+        self.tables
+            .versions()
+            .touch(self.txn, table, pk, Some(&current))?;
+        if new_pk != pk {
+            self.tables
+                .versions()
+                .touch(self.txn, table, &new_pk, None)?;
+        }
+        self.check_released(table, &new_pk)?;
+        self.check_references(table, &new_pk, Some(&old))?;
+        let new = self
+            .table(table)?
+            .get(&new_pk)?
+            .ok_or_else(|| anyhow!("Internal error"))?
+            .all_cols();
+        for (child, fk) in self.referencing(table) {
+            let (old_key, new_key) = (values(&old, &fk.ref_columns), values(&new, &fk.ref_columns));
+            if old_key != new_key {
+                self.key_removed(&child, &fk, old_key, Some(new_key))?;
+            }
+        }
+        Ok(new_pk)
+    }
+
+    /// Makes sure the row's foreign keys point at existing rows. With the row's `old`
+    /// columns, only foreign keys whose values changed are checked.
+    fn check_references(
+        &mut self,
+        table: &str,
+        pk: &[LiteralValue],
+        old: Option<&[Column]>,
+    ) -> Result<()> {
+        let child = self.table(table)?;
+        let cols = child
+            .get(pk)?
+            .ok_or_else(|| anyhow!("Internal error"))?
+            .all_cols();
+        for fk in child.foreign_keys().to_vec() {
+            let key = values(&cols, &fk.columns);
+            if key.contains(&LiteralValue::Null)
+                || old.is_some_and(|old| values(old, &fk.columns) == key)
+            {
+                continue;
+            }
+            let parents = self.table(&fk.table)?.lookup(&fk.ref_columns, &key)?;
+            if parents.is_empty() {
+                bail!(
+                    "Key ({})=({}) is not present in table \"{}\"",
+                    fk.columns.join(", "),
+                    join(&key),
+                    fk.table
+                );
+            }
+            // A parent that is still being added could be rolled back
+            for parent in parents {
+                self.tables
+                    .versions()
+                    .check_row(self.txn, &fk.table, &parent)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if another transaction has a pending change to a row other than `pk` that
+    /// holds the unique values in `cols`, which are only a duplicate if it commits
+    fn check_unique(&mut self, table: &str, pk: &[LiteralValue], cols: &[Column]) -> Result<()> {
+        let holders: Vec<_> = self
+            .table(table)?
+            .indexes()
+            .iter()
+            .filter(|index| index.unique())
+            .flat_map(|index| {
+                let key = values(cols, index.columns());
+                if key.contains(&LiteralValue::Null) {
+                    Vec::new()
+                } else {
+                    index.get(&key)
+                }
+            })
+            .filter(|holder| holder != pk)
+            .collect();
+        for holder in holders {
+            self.tables.versions().check_row(self.txn, table, &holder)?;
+        }
+        Ok(())
+    }
+
+    /// Fails if the row takes unique values that another transaction's pending change
+    /// freed, as they would clash if that transaction rolled back
+    fn check_released(&self, table: &str, pk: &[LiteralValue]) -> Result<()> {
+        let data = self
+            .tables
+            .get(table)
+            .ok_or_else(|| anyhow!("Internal error"))?;
+        let row = data.get(pk)?.ok_or_else(|| anyhow!("Internal error"))?;
+        for index in data.indexes().iter().filter(|index| index.unique()) {
+            let key = row.cols(index.columns());
+            if key
+                .as_ref()
+                .is_some_and(|key| !key.contains(&LiteralValue::Null))
+            {
+                self.tables
+                    .versions()
+                    .check_released(self.txn, table, |old: &Row| {
+                        old.cols(index.columns()) == key
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Foreign keys referencing `table`, with the table they belong to
+    fn referencing(&self, table: &str) -> Vec<(String, ForeignKey)> {
+        self.tables
+            .iter()
+            .flat_map(|(name, child)| {
+                child
+                    .foreign_keys()
+                    .iter()
+                    .filter(|fk| fk.table == table)
+                    .map(|fk| (name.clone(), fk.clone()))
+            })
+            .collect()
+    }
+
+    /// Applies `fk`'s action to the rows of `child` referencing `key`, which was deleted or
+    /// replaced by `new_key`
+    fn key_removed(
+        &mut self,
+        child: &str,
+        fk: &ForeignKey,
+        key: Vec<LiteralValue>,
+        new_key: Option<Vec<LiteralValue>>,
+    ) -> Result<()> {
+        if key.contains(&LiteralValue::Null) {
+            return Ok(());
+        }
+        // Rows another transaction is removing would be back if it rolled back
+        self.tables
+            .versions()
+            .check_released(self.txn, child, |old: &Row| {
+                old.cols(&fk.columns).as_ref() == Some(&key)
+            })?;
+        let rows = self.table(child)?.lookup(&fk.columns, &key)?;
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let action = match new_key {
+            Some(_) => fk.on_update,
+            None => fk.on_delete,
+        };
+        let vals = match (action, new_key) {
+            (RefAction::NoAction, _) => {
+                self.deferred.push((child.to_string(), fk.clone(), key));
+                return Ok(());
+            }
+            (RefAction::Restrict, _) => bail!(still_referenced(child, fk, &key)),
+            (RefAction::Cascade, None) => {
+                for pk in rows {
+                    self.delete(child, &pk)?;
+                }
+                return Ok(());
+            }
+            (RefAction::Cascade, Some(new_key)) => new_key,
+            (RefAction::SetNull, _) => vec![LiteralValue::Null; fk.columns.len()],
+            (RefAction::SetDefault, _) => {
+                let headers = self.table(child)?.col_headers();
+                fk.columns
+                    .iter()
+                    .map(
+                        |col| match headers.iter().find(|header| header.name() == col) {
+                            Some(header) => match header.default() {
+                                DefaultOpt::Some(val) => val.clone(),
+                                DefaultOpt::Now => LiteralValue::now(),
+                                _ => LiteralValue::Null,
+                            },
+                            None => LiteralValue::Null,
+                        },
+                    )
+                    .collect()
+            }
+        };
+        for pk in rows {
+            let Some(row) = self.table(child)?.get(&pk)? else {
+                continue;
+            };
+            let mut cols = row.all_cols();
+            for col in &mut cols {
+                if let Some(i) = fk.columns.iter().position(|name| name == col.name()) {
+                    *col = Column::new(vals[i].clone(), col.name().to_string());
+                }
+            }
+            self.update(child, &pk, cols)?;
+        }
+        Ok(())
+    }
+
+    fn check_deferred(&mut self) -> Result<()> {
+        for (child, fk, key) in std::mem::take(&mut self.deferred) {
+            if self
+                .table(&fk.table)?
+                .lookup(&fk.ref_columns, &key)?
+                .is_empty()
+                && !self.table(&child)?.lookup(&fk.columns, &key)?.is_empty()
+            {
+                bail!(still_referenced(&child, &fk, &key));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Takes back the changes `txn` made since it was at `savepoint`, newest first. A change
+/// that can't be taken back doesn't stop the others, and the first such error is returned.
+pub(super) fn undo(tables: &mut Tables, txn: &mut Txn, savepoint: (usize, usize)) -> Result<()> {
+    let (undo, log) = savepoint;
+    let mut res = Ok(());
+    for change in txn.undo.drain(undo..).rev() {
+        if let Err(e) = change.revert(tables) {
+            res = res.and(Err(e.context("Failed to undo a change")));
+        }
+    }
+    txn.log.truncate(log);
+    res
+}
+
+impl Undo {
+    /// The table the change was made to
+    pub(super) fn table(&self) -> &str {
+        match self {
+            Undo::Insert { table, .. }
+            | Undo::Delete { table, .. }
+            | Undo::Update { table, .. }
+            | Undo::CreateIndex { table, .. }
+            | Undo::DropIndex { table, .. } => table,
+            Undo::CreateTable { name, .. } => name,
+        }
+    }
+
+    fn revert(self, tables: &mut Tables) -> Result<()> {
+        fn table<'a>(tables: &'a mut Tables, name: &str) -> Result<&'a mut Table> {
+            tables
+                .get_mut(name)
+                .ok_or_else(|| anyhow!("Table \"{}\" not found", name))
+        }
+        match self {
+            Undo::Insert { table: name, pk } => {
+                table(tables, &name)?.delete(&pk)?;
+            }
+            Undo::Delete { table: name, cols } => {
+                table(tables, &name)?.append(cols)?;
+            }
+            Undo::Update {
+                table: name,
+                pk,
+                cols,
+            } => {
+                table(tables, &name)?.update(&pk, cols)?;
+            }
+            Undo::CreateTable { name, replaced } => {
+                tables.remove(&name)?;
+                if let Some(table) = replaced {
+                    tables.insert(&name, table)?;
+                }
+            }
+            Undo::CreateIndex {
+                table: name,
+                name: index,
+            } => {
+                table(tables, &name)?.drop_index(&index)?;
+            }
+            Undo::DropIndex { table: name, index } => {
+                table(tables, &name)?.create_index(
+                    index.name().to_string(),
+                    index.columns().to_vec(),
+                    index.unique(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The values of `names` among `cols`, in order
+fn values(cols: &[Column], names: &[String]) -> Vec<LiteralValue> {
+    names
+        .iter()
+        .map(|name| {
+            cols.iter()
+                .find(|col| col.name() == name)
+                .map(|col| col.data().clone())
+                .unwrap_or(LiteralValue::Null)
+        })
+        .collect()
+}
+
+fn join(vals: &[LiteralValue]) -> String {
+    vals.iter()
+        .map(|val| val.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn still_referenced(child: &str, fk: &ForeignKey, key: &[LiteralValue]) -> String {
+    format!(
+        "Key ({})=({}) is still referenced from table \"{}\"",
+        fk.ref_columns.join(", "),
+        join(key),
+        child
+    )
+}
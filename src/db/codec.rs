@@ -0,0 +1,548 @@
+//! The binary encoding of tables and log records. Integers are little-endian, strings and
+//! lists are prefixed with their length, and values with a tag for their type.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+use bytes::{Buf, BufMut, Bytes};
+use ordered_float::OrderedFloat;
+
+use crate::{
+    parse::{self, Expr, Interval, LiteralValue, RefAction},
+    Ty,
+};
+
+use super::{Column, ColumnHeader, DefaultOpt, ForeignKey, Redo, Row, Table};
+
+pub fn put_tables(buf: &mut Vec<u8>, tables: &HashMap<String, Table>) -> Result<()> {
+    buf.put_u32_le(tables.len() as u32);
+    let mut names: Vec<_> = tables.keys().collect();
+    names.sort();
+    for name in names {
+        put_str(buf, name);
+        put_table(buf, &tables[name])?;
+    }
+    Ok(())
+}
+
+pub fn get_tables(buf: &mut Bytes) -> Result<HashMap<String, Table>> {
+    let mut tables = HashMap::new();
+    for _ in 0..get_u32(buf)? {
+        let name = get_str(buf)?;
+        tables.insert(name, get_table(buf)?);
+    }
+    Ok(tables)
+}
+
+pub fn put_redo(buf: &mut Vec<u8>, redo: &Redo) -> Result<()> {
+    match redo {
+        Redo::Insert { table, cols } => {
+            buf.put_u8(0);
+            put_str(buf, table);
+            put_cols(buf, cols);
+        }
+        Redo::Delete { table, pk } => {
+            buf.put_u8(1);
+            put_str(buf, table);
+            put_values(buf, pk);
+        }
+        Redo::Update { table, pk, cols } => {
+            buf.put_u8(2);
+            put_str(buf, table);
+            put_values(buf, pk);
+            put_cols(buf, cols);
+        }
+        Redo::CreateTable { name, table } => {
+            buf.put_u8(3);
+            put_str(buf, name);
+            put_table(buf, table)?;
+        }
+        Redo::CreateIndex {
+            table,
+            name,
+            columns,
+            unique,
+        } => {
+            buf.put_u8(4);
+            put_str(buf, table);
+            put_str(buf, name);
+            put_strs(buf, columns);
+            buf.put_u8(*unique as u8);
+        }
+        Redo::DropIndex { table, name } => {
+            buf.put_u8(5);
+            put_str(buf, table);
+            put_str(buf, name);
+        }
+    }
+    Ok(())
+}
+
+pub fn get_redo(buf: &mut Bytes) -> Result<Redo> {
+    Ok(match get_u8(buf)? {
+        0 => Redo::Insert {
+            table: get_str(buf)?,
+            cols: get_cols(buf)?,
+        },
+        1 => Redo::Delete {
+            table: get_str(buf)?,
+            pk: get_values(buf)?,
+        },
+        2 => Redo::Update {
+            table: get_str(buf)?,
+            pk: get_values(buf)?,
+            cols: get_cols(buf)?,
+        },
+        3 => Redo::CreateTable {
+            name: get_str(buf)?,
+            table: get_table(buf)?,
+        },
+        4 => Redo::CreateIndex {
+            table: get_str(buf)?,
+            name: get_str(buf)?,
+            columns: get_strs(buf)?,
+            unique: get_u8(buf)? != 0,
+        },
+        5 => Redo::DropIndex {
+            table: get_str(buf)?,
+            name: get_str(buf)?,
+        },
+        tag => bail!("Unknown log record tag {}", tag),
+    })
+}
+
+fn put_table(buf: &mut Vec<u8>, table: &Table) -> Result<()> {
+    let headers = table.col_headers();
+    buf.put_u32_le(headers.len() as u32);
+    for header in headers {
+        put_header(buf, header);
+    }
+    put_opt_str(buf, table.pk_name());
+
+    buf.put_u32_le(table.indexes().len() as u32);
+    for index in table.indexes() {
+        put_str(buf, index.name());
+        put_strs(buf, index.columns());
+        buf.put_u8(index.unique() as u8);
+        buf.put_u8(index.constraint() as u8);
+    }
+
+    buf.put_u32_le(table.foreign_keys().len() as u32);
+    for fk in table.foreign_keys() {
+        put_opt_str(buf, fk.name.as_deref());
+        put_strs(buf, &fk.columns);
+        put_str(buf, &fk.table);
+        put_strs(buf, &fk.ref_columns);
+        buf.put_u8(ref_action_tag(fk.on_delete));
+        buf.put_u8(ref_action_tag(fk.on_update));
+    }
+
+    buf.put_u32_le(table.checks().len() as u32);
+    for (name, expr) in table.checks() {
+        put_opt_str(buf, name.as_deref());
+        put_str(buf, &expr.to_string());
+    }
+
+    buf.put_u64_le(table.len() as u64);
+    for row in table.rows() {
+        let cols = row?.all_cols();
+        for header in headers {
+            let val = cols
+                .iter()
+                .find(|col| col.name() == header.name())
+                .map_or(&LiteralValue::Null, Column::data);
+            put_value(buf, val);
+        }
+    }
+    Ok(())
+}
+
+fn get_table(buf: &mut Bytes) -> Result<Table> {
+    let headers = (0..get_u32(buf)?)
+        .map(|_| get_header(buf))
+        .collect::<Result<Vec<_>>>()?;
+    let mut table = Table::try_from(headers.clone())?;
+    table.set_pk_name(get_opt_str(buf)?);
+
+    for _ in 0..get_u32(buf)? {
+        let name = get_str(buf)?;
+        let columns = get_strs(buf)?;
+        let unique = get_u8(buf)? != 0;
+        let constraint = get_u8(buf)? != 0;
+        // Column `UNIQUE` constraints already got their index from the headers
+        if table.index(&name).is_some() {
+            continue;
+        }
+        if constraint {
+            table.add_unique(Some(name), columns)?;
+        } else {
+            table.create_index(name, columns, unique)?;
+        }
+    }
+
+    for _ in 0..get_u32(buf)? {
+        table.add_foreign_key(ForeignKey {
+            name: get_opt_str(buf)?,
+            columns: get_strs(buf)?,
+            table: get_str(buf)?,
+            ref_columns: get_strs(buf)?,
+            on_delete: get_ref_action(buf)?,
+            on_update: get_ref_action(buf)?,
+        });
+    }
+
+    for _ in 0..get_u32(buf)? {
+        let name = get_opt_str(buf)?;
+        let expr = get_expr(buf)?;
+        table.add_check(name, expr);
+    }
+
+    for _ in 0..get_u64(buf)? {
+        let cols = headers
+            .iter()
+            .map(|header| Ok(Column::new(get_value(buf)?, header.name().to_string())))
+            .collect::<Result<Vec<_>>>()?;
+        table.append(cols)?;
+    }
+    Ok(table)
+}
+
+fn put_header(buf: &mut Vec<u8>, header: &ColumnHeader) {
+    put_str(buf, header.name());
+    let flags = [
+        header.is_primary(),
+        header.is_hidden(),
+        header.not_null(),
+        header.unique(),
+    ];
+    buf.put_u8(
+        flags
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (i, flag)| acc | (*flag as u8) << i),
+    );
+    buf.put_u8(match header.ty() {
+        Ty::String => 0,
+        Ty::Number => 1,
+        Ty::Bool => 2,
+        Ty::SmallInt => 3,
+        Ty::Integer => 4,
+        Ty::BigInt => 5,
+        Ty::Real => 6,
+        Ty::Double => 7,
+        Ty::Decimal(_) => 8,
+        Ty::Varchar(_) => 9,
+        Ty::Char(_) => 10,
+        Ty::Date => 11,
+        Ty::Time => 12,
+        Ty::Timestamp => 13,
+        Ty::TimestampTz => 14,
+        Ty::Interval => 15,
+    });
+    match header.ty() {
+        Ty::Decimal(precision) => {
+            let (precision, scale) = precision.unwrap_or_default();
+            buf.put_u32_le(precision);
+            buf.put_u32_le(scale);
+        }
+        Ty::Varchar(len) | Ty::Char(len) => buf.put_u32_le(*len),
+        _ => {}
+    }
+    match header.default() {
+        DefaultOpt::None => buf.put_u8(0),
+        DefaultOpt::Some(val) => {
+            buf.put_u8(1);
+            put_value(buf, val);
+        }
+        DefaultOpt::Incrementing(next) => {
+            buf.put_u8(2);
+            buf.put_u64_le(*next as u64);
+        }
+        DefaultOpt::Now => buf.put_u8(3),
+    }
+    put_opt_str(buf, header.check().map(Expr::to_string).as_deref());
+}
+
+fn get_header(buf: &mut Bytes) -> Result<ColumnHeader> {
+    let name = get_str(buf)?;
+    let flags = get_u8(buf)?;
+    let flag = |i: u8| flags & (1 << i) != 0;
+    let ty = match get_u8(buf)? {
+        0 => Ty::String,
+        1 => Ty::Number,
+        2 => Ty::Bool,
+        3 => Ty::SmallInt,
+        4 => Ty::Integer,
+        5 => Ty::BigInt,
+        6 => Ty::Real,
+        7 => Ty::Double,
+        8 => {
+            let (precision, scale) = (get_u32(buf)?, get_u32(buf)?);
+            Ty::Decimal((precision > 0).then_some((precision, scale)))
+        }
+        9 => Ty::Varchar(get_u32(buf)?),
+        10 => Ty::Char(get_u32(buf)?),
+        11 => Ty::Date,
+        12 => Ty::Time,
+        13 => Ty::Timestamp,
+        14 => Ty::TimestampTz,
+        15 => Ty::Interval,
+        tag => bail!("Unknown type tag {}", tag),
+    };
+    let default = match get_u8(buf)? {
+        0 => DefaultOpt::None,
+        1 => DefaultOpt::Some(get_value(buf)?),
+        2 => DefaultOpt::Incrementing(
+            get_u64(buf)?
+                .try_into()
+                .map_err(|_| anyhow!("Counter out of range"))?,
+        ),
+        3 => DefaultOpt::Now,
+        tag => bail!("Unknown default tag {}", tag),
+    };
+    let check = match get_u8(buf)? {
+        0 => None,
+        _ => Some(get_expr(buf)?),
+    };
+    ColumnHeader::new(name)
+        .ty(ty)
+        .primary_key(flag(0))
+        .hidden(flag(1))
+        .not_null(flag(2))
+        .unique(flag(3))
+        .def(default)
+        .check(check)
+        .build()
+}
+
+/// The primary key columns of `row`, then the others
+pub fn put_row(buf: &mut Vec<u8>, row: &Row) {
+    put_cols(buf, row.key());
+    put_cols(buf, row.non_key());
+}
+
+pub fn get_row(buf: &mut Bytes) -> Result<Row> {
+    Ok(Row::new(get_cols(buf)?, get_cols(buf)?))
+}
+
+fn put_cols(buf: &mut Vec<u8>, cols: &[Column]) {
+    buf.put_u32_le(cols.len() as u32);
+    for col in cols {
+        put_str(buf, col.name());
+        put_value(buf, col.data());
+    }
+}
+
+fn get_cols(buf: &mut Bytes) -> Result<Vec<Column>> {
+    (0..get_u32(buf)?)
+        .map(|_| {
+            let name = get_str(buf)?;
+            Ok(Column::new(get_value(buf)?, name))
+        })
+        .collect()
+}
+
+fn put_values(buf: &mut Vec<u8>, vals: &[LiteralValue]) {
+    buf.put_u32_le(vals.len() as u32);
+    for val in vals {
+        put_value(buf, val);
+    }
+}
+
+fn get_values(buf: &mut Bytes) -> Result<Vec<LiteralValue>> {
+    (0..get_u32(buf)?).map(|_| get_value(buf)).collect()
+}
+
+fn put_value(buf: &mut Vec<u8>, val: &LiteralValue) {
+    match val {
+        LiteralValue::Null => buf.put_u8(0),
+        LiteralValue::String(s) => {
+            buf.put_u8(1);
+            put_str(buf, s);
+        }
+        LiteralValue::Number(n) => {
+            buf.put_u8(2);
+            buf.put_f64_le(n.0);
+        }
+        LiteralValue::Bool(b) => {
+            buf.put_u8(3);
+            buf.put_u8(*b as u8);
+        }
+        LiteralValue::Int(n) => {
+            buf.put_u8(4);
+            buf.put_i64_le(*n);
+        }
+        LiteralValue::Decimal(d) => {
+            buf.put_u8(5);
+            put_str(buf, &d.to_string());
+        }
+        LiteralValue::Date(d) => {
+            buf.put_u8(6);
+            buf.put_i32_le(*d);
+        }
+        LiteralValue::Time(t) => {
+            buf.put_u8(7);
+            buf.put_i64_le(*t);
+        }
+        LiteralValue::Timestamp(t) => {
+            buf.put_u8(8);
+            buf.put_i64_le(*t);
+        }
+        LiteralValue::TimestampTz(t) => {
+            buf.put_u8(9);
+            buf.put_i64_le(*t);
+        }
+        LiteralValue::Interval(i) => {
+            buf.put_u8(10);
+            buf.put_i32_le(i.months);
+            buf.put_i32_le(i.days);
+            buf.put_i64_le(i.micros);
+        }
+    }
+}
+
+fn get_value(buf: &mut Bytes) -> Result<LiteralValue> {
+    Ok(match get_u8(buf)? {
+        0 => LiteralValue::Null,
+        1 => LiteralValue::String(get_str(buf)?),
+        2 => {
+            need(buf, 8)?;
+            LiteralValue::Number(OrderedFloat(buf.get_f64_le()))
+        }
+        3 => LiteralValue::Bool(get_u8(buf)? != 0),
+        4 => {
+            need(buf, 8)?;
+            LiteralValue::Int(buf.get_i64_le())
+        }
+        5 => LiteralValue::Decimal(get_str(buf)?.parse()?),
+        6 => {
+            need(buf, 4)?;
+            LiteralValue::Date(buf.get_i32_le())
+        }
+        7 => {
+            need(buf, 8)?;
+            LiteralValue::Time(buf.get_i64_le())
+        }
+        8 => {
+            need(buf, 8)?;
+            LiteralValue::Timestamp(buf.get_i64_le())
+        }
+        9 => {
+            need(buf, 8)?;
+            LiteralValue::TimestampTz(buf.get_i64_le())
+        }
+        10 => {
+            need(buf, 16)?;
+            LiteralValue::Interval(Interval {
+                months: buf.get_i32_le(),
+                days: buf.get_i32_le(),
+                micros: buf.get_i64_le(),
+            })
+        }
+        tag => bail!("Unknown value tag {}", tag),
+    })
+}
+
+fn ref_action_tag(action: RefAction) -> u8 {
+    match action {
+        RefAction::NoAction => 0,
+        RefAction::Restrict => 1,
+        RefAction::Cascade => 2,
+        RefAction::SetNull => 3,
+        RefAction::SetDefault => 4,
+    }
+}
+
+fn get_ref_action(buf: &mut Bytes) -> Result<RefAction> {
+    Ok(match get_u8(buf)? {
+        0 => RefAction::NoAction,
+        1 => RefAction::Restrict,
+        2 => RefAction::Cascade,
+        3 => RefAction::SetNull,
+        4 => RefAction::SetDefault,
+        tag => bail!("Unknown referential action tag {}", tag),
+    })
+}
+
+/// Conditions are kept as SQL and parsed again when loaded
+fn get_expr(buf: &mut Bytes) -> Result<Expr> {
+    parse::parse_expr(Bytes::from(get_str(buf)?))
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.put_u32_le(s.len() as u32);
+    buf.put_slice(s.as_bytes());
+}
+
+fn put_opt_str(buf: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            buf.put_u8(1);
+            put_str(buf, s);
+        }
+        None => buf.put_u8(0),
+    }
+}
+
+fn put_strs(buf: &mut Vec<u8>, strs: &[String]) {
+    buf.put_u32_le(strs.len() as u32);
+    for s in strs {
+        put_str(buf, s);
+    }
+}
+
+/// Fails instead of panicking when the data ends early
+fn need(buf: &Bytes, n: usize) -> Result<()> {
+    if buf.remaining() < n {
+        bail!("Unexpected end of data");
+    }
+    Ok(())
+}
+
+fn get_u8(buf: &mut Bytes) -> Result<u8> {
+    need(buf, 1)?;
+    Ok(buf.get_u8())
+}
+
+pub fn get_u32(buf: &mut Bytes) -> Result<u32> {
+    need(buf, 4)?;
+    Ok(buf.get_u32_le())
+}
+
+pub fn get_u64(buf: &mut Bytes) -> Result<u64> {
+    need(buf, 8)?;
+    Ok(buf.get_u64_le())
+}
+
+fn get_str(buf: &mut Bytes) -> Result<String> {
+    let len = get_u32(buf)? as usize;
+    need(buf, len)?;
+    String::from_utf8(buf.split_to(len).to_vec()).map_err(|_| anyhow!("Invalid UTF-8 in data"))
+}
+
+fn get_opt_str(buf: &mut Bytes) -> Result<Option<String>> {
+    match get_u8(buf)? {
+        0 => Ok(None),
+        _ => Ok(Some(get_str(buf)?)),
+    }
+}
+
+fn get_strs(buf: &mut Bytes) -> Result<Vec<String>> {
+    (0..get_u32(buf)?).map(|_| get_str(buf)).collect()
+}
+
+/// CRC-32 (IEEE), computed bit by bit
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
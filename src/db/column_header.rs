@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 
 use crate::{
     parse::{Expr, LiteralValue},
@@ -9,7 +9,10 @@ use crate::{
 pub enum DefaultOpt {
     None,
     Some(LiteralValue),
-    Incrementing(u8),
+    Incrementing(i64),
+
+    /// The time the row is added, from `DEFAULT now()`
+    Now,
 }
 
 // TODO: macro for this
@@ -75,44 +78,30 @@ impl ColumnHeaderBuilder {
 
     pub fn build(self) -> Result<ColumnHeader> {
         if let Some(ty) = self.ty {
-            let print_err = |def_ty| {
-                anyhow!(
-                    "Default type doesn't match declared type; expected {}, got {:?}",
-                    def_ty,
-                    ty
-                )
-            };
-            match &self.default {
-                DefaultOpt::None => {}
-                DefaultOpt::Some(val) => match val {
-                    LiteralValue::String(_) => {
-                        if ty != Ty::String {
-                            return Err(print_err("String"));
-                        }
-                    }
-                    LiteralValue::Number(_) => {
-                        if ty != Ty::Number {
-                            return Err(print_err("Number"));
-                        }
-                    }
-                    LiteralValue::Bool(_) => {
-                        if ty != Ty::Bool {
-                            return Err(print_err("Number"));
-                        }
-                    }
-                    LiteralValue::Null => unreachable!(),
-                },
-                DefaultOpt::Incrementing(_) => {
-                    if ty != Ty::Number {
-                        return Err(print_err("Number"));
-                    }
+            // Defaults are kept as the column's type
+            let default = match self.default {
+                DefaultOpt::Some(val) => {
+                    DefaultOpt::Some(val.clone().coerce(&ty).map_err(|e| {
+                        anyhow!(
+                            "Default type doesn't match declared type; {} for {}",
+                            e,
+                            val
+                        )
+                    })?)
+                }
+                DefaultOpt::Incrementing(_) if !ty.is_numeric() => {
+                    bail!("Default type doesn't match declared type; expected a number")
                 }
+                DefaultOpt::Now if !ty.is_temporal() || ty == Ty::Interval => {
+                    bail!("Default type doesn't match declared type; expected a date or time")
+                }
+                default => default,
             };
             Ok(ColumnHeader {
                 name: self.name,
                 is_primary_key: self.is_primary_key,
                 is_hidden: self.is_hidden,
-                default: self.default,
+                default,
                 not_null: self.not_null,
                 unique: self.unique,
                 check: self.check,
@@ -137,6 +126,7 @@ pub struct ColumnHeader {
 }
 
 impl ColumnHeader {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(name: String) -> ColumnHeaderBuilder {
         ColumnHeaderBuilder::new(name)
     }
@@ -150,7 +140,7 @@ impl ColumnHeader {
             unique: true,
             default: DefaultOpt::Incrementing(0),
             check: None,
-            ty: Ty::Number,
+            ty: Ty::BigInt,
         }
     }
 
@@ -166,12 +156,31 @@ impl ColumnHeader {
         &self.default
     }
 
-    pub fn inc(&mut self) -> Option<u8> {
-        if let DefaultOpt::Incrementing(i) = self.default {
-            self.default = DefaultOpt::Incrementing(i + 1);
-            Some(i)
-        } else {
-            None
+    /// Hands out the next value of an auto-incrementing column
+    pub fn inc(&mut self) -> Result<i64> {
+        let DefaultOpt::Incrementing(i) = self.default else {
+            bail!("Internal error");
+        };
+        let next = i
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("Column \"{}\" has run out of values", self.name))?;
+        self.default = DefaultOpt::Incrementing(next);
+        Ok(i)
+    }
+
+    /// Makes sure the counter won't hand out `val` or anything below it again
+    pub fn advance_past(&mut self, val: &LiteralValue) {
+        let n = match val {
+            LiteralValue::Int(n) => *n,
+            val => match val.number() {
+                Ok(n) => n.0 as i64,
+                Err(_) => return,
+            },
+        };
+        if let DefaultOpt::Incrementing(next) = &mut self.default {
+            if n >= *next {
+                *next = n.saturating_add(1);
+            }
         }
     }
 
@@ -0,0 +1,466 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    iter,
+    ops::Bound,
+    panic::{self, AssertUnwindSafe},
+    sync::{Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::parse::{Isolation, LiteralValue};
+
+use super::{
+    changes::{self, Undo},
+    index,
+    mvcc::{Snapshot, Txn, TxnId, Versions},
+    Row, Table,
+};
+
+/// Every table, holding the newest version of each row, along with what it takes to see
+/// the rows as some transaction's snapshot does.
+///
+/// A statement locks the catalog to find its tables, then each table it uses for reading
+/// or writing, so statements on different tables run in parallel. Only those that create
+/// tables or indexes lock the catalog for writing, which keeps out every other statement.
+/// Tables are always locked in order of their names.
+///
+/// A statement that panics leaves the locks it held poisoned. They are taken regardless,
+/// since rolling back its transaction puts its rows back as they were.
+#[derive(Default)]
+pub struct Database {
+    catalog: RwLock<Catalog>,
+    versions: Mutex<Versions>,
+
+    /// For each transaction waiting for a row, the transaction holding it
+    waits: Mutex<HashMap<TxnId, TxnId>>,
+
+    /// Notified whenever a transaction ends
+    ended: Condvar,
+}
+
+#[derive(Default)]
+struct Catalog {
+    tables: HashMap<String, RwLock<Table>>,
+
+    /// For each table, the tables a foreign key links it to, in either direction
+    links: HashMap<String, Vec<String>>,
+}
+
+impl Catalog {
+    fn new(tables: HashMap<String, Table>) -> Catalog {
+        let mut links: HashMap<String, Vec<String>> = HashMap::new();
+        for (name, table) in &tables {
+            for fk in table.foreign_keys() {
+                links
+                    .entry(name.clone())
+                    .or_default()
+                    .push(fk.table.clone());
+                links
+                    .entry(fk.table.clone())
+                    .or_default()
+                    .push(name.clone());
+            }
+        }
+        Catalog {
+            tables: tables
+                .into_iter()
+                .map(|(name, table)| (name, RwLock::new(table)))
+                .collect(),
+            links,
+        }
+    }
+
+    /// `table` and the tables linked to it, directly or through others. Changing a row can
+    /// touch any of them through foreign keys.
+    fn linked(&self, table: &str) -> BTreeSet<String> {
+        let mut found = BTreeSet::from([table.to_string()]);
+        let mut todo = vec![table];
+        while let Some(name) = todo.pop() {
+            for link in self.links.get(name).into_iter().flatten() {
+                if found.insert(link.clone()) {
+                    todo.push(link);
+                }
+            }
+        }
+        found
+    }
+}
+
+impl From<HashMap<String, Table>> for Database {
+    fn from(tables: HashMap<String, Table>) -> Self {
+        Database {
+            catalog: RwLock::new(Catalog::new(tables)),
+            ..Default::default()
+        }
+    }
+}
+
+impl Database {
+    pub fn begin(&self, isolation: Isolation) -> Txn {
+        self.versions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .begin(isolation)
+    }
+
+    /// The snapshot the next statement of `txn` reads from
+    pub fn snapshot(&self, txn: &mut Txn) -> Snapshot {
+        self.versions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .snapshot(txn)
+    }
+
+    /// Fails if committing `txn` now could break serializability
+    pub fn validate(&self, txn: &Txn) -> Result<()> {
+        self.versions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .validate(txn)
+    }
+
+    /// Makes the changes of `txn` visible to other transactions
+    pub fn commit(&self, txn: &Txn) {
+        self.versions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .commit(txn);
+        self.notify_ended();
+    }
+
+    /// Validates and commits `txn` in one step, for when nothing else keeps others from
+    /// committing in between
+    pub fn try_commit(&self, txn: &Txn) -> Result<()> {
+        let mut versions = self.versions.lock().unwrap_or_else(PoisonError::into_inner);
+        versions.validate(txn)?;
+        versions.commit(txn);
+        drop(versions);
+        self.notify_ended();
+        Ok(())
+    }
+
+    /// Ends `txn` even if some of its changes couldn't be undone, which is reported
+    pub fn rollback(&self, mut txn: Txn) -> Result<()> {
+        let res = self.undo(&mut txn, (0, 0));
+        self.versions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .rollback(&txn);
+        self.notify_ended();
+        res
+    }
+
+    /// Undoes the changes `txn` made since it was at `savepoint`. The rows it changed stay
+    /// reserved for it until it ends.
+    pub fn rollback_to(&self, txn: &mut Txn, savepoint: (usize, usize)) -> Result<()> {
+        self.undo(txn, savepoint)
+    }
+
+    fn undo(&self, txn: &mut Txn, savepoint: (usize, usize)) -> Result<()> {
+        let undo = &txn.undo[savepoint.0.min(txn.undo.len())..];
+        if undo
+            .iter()
+            .any(|change| matches!(change, Undo::CreateTable { .. }))
+        {
+            self.alter(|tables| changes::undo(tables, txn, savepoint))
+        } else {
+            let names: BTreeSet<_> = undo
+                .iter()
+                .map(|change| change.table().to_string())
+                .collect();
+            let catalog = self.catalog.read().unwrap_or_else(PoisonError::into_inner);
+            let mut tables = Tables {
+                locked: Locked::Write(lock(&catalog, names, |table| {
+                    table.write().unwrap_or_else(PoisonError::into_inner)
+                })),
+                versions: &self.versions,
+            };
+            changes::undo(&mut tables, txn, savepoint)
+        }
+    }
+
+    /// Runs `f` with the tables called `names` locked for reading. Names that aren't
+    /// tables are left out.
+    pub fn read<T>(&self, names: &[String], f: impl FnOnce(&Tables) -> Result<T>) -> Result<T> {
+        let catalog = self.catalog.read().unwrap_or_else(PoisonError::into_inner);
+        let tables = Tables {
+            locked: Locked::Read(lock(&catalog, names.iter().cloned().collect(), |table| {
+                table.read().unwrap_or_else(PoisonError::into_inner)
+            })),
+            versions: &self.versions,
+        };
+        f(&tables)
+    }
+
+    /// Like `read`, with every table locked
+    pub fn read_all<T>(&self, f: impl FnOnce(&Tables) -> Result<T>) -> Result<T> {
+        let catalog = self.catalog.read().unwrap_or_else(PoisonError::into_inner);
+        let names = catalog.tables.keys().cloned().collect();
+        let tables = Tables {
+            locked: Locked::Read(lock(&catalog, names, |table| {
+                table.read().unwrap_or_else(PoisonError::into_inner)
+            })),
+            versions: &self.versions,
+        };
+        f(&tables)
+    }
+
+    /// Runs `f` with `table`, and every table its rows' foreign keys can reach, locked for
+    /// writing
+    pub fn write<T>(&self, table: &str, f: impl FnOnce(&mut Tables) -> Result<T>) -> Result<T> {
+        let catalog = self.catalog.read().unwrap_or_else(PoisonError::into_inner);
+        let mut tables = Tables {
+            locked: Locked::Write(lock(&catalog, catalog.linked(table), |table| {
+                table.write().unwrap_or_else(PoisonError::into_inner)
+            })),
+            versions: &self.versions,
+        };
+        f(&mut tables)
+    }
+
+    /// Runs `f` with the catalog locked for writing, so it can add and change tables
+    pub fn alter<T>(&self, f: impl FnOnce(&mut Tables) -> Result<T>) -> Result<T> {
+        let mut catalog = self.catalog.write().unwrap_or_else(PoisonError::into_inner);
+        let mut tables = Tables {
+            locked: Locked::All(
+                std::mem::take(&mut catalog.tables)
+                    .into_iter()
+                    .map(|(name, table)| {
+                        (
+                            name,
+                            table.into_inner().unwrap_or_else(PoisonError::into_inner),
+                        )
+                    })
+                    .collect(),
+            ),
+            versions: &self.versions,
+        };
+        // The tables go back into the catalog even if `f` panics
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(&mut tables)));
+        if let Locked::All(all) = tables.locked {
+            *catalog = Catalog::new(all);
+        }
+        res.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    /// Blocks until `holder`, which has a row `txn` needs, ends. Fails right away instead
+    /// if `holder` is waiting for `txn`, directly or through others, as they would wait
+    /// for each other forever.
+    pub fn wait(&self, txn: &Txn, holder: TxnId) -> Result<()> {
+        let mut waits = self.waits.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut next = Some(holder);
+        while let Some(id) = next {
+            if id == txn.id {
+                bail!("Deadlock detected, the transaction has been rolled back");
+            }
+            next = waits.get(&id).copied();
+        }
+        waits.insert(txn.id, holder);
+        let mut waits = self
+            .ended
+            .wait_while(waits, |_| {
+                self.versions
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .is_open(holder)
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        waits.remove(&txn.id);
+        Ok(())
+    }
+
+    fn notify_ended(&self) {
+        let _waits = self.waits.lock().unwrap_or_else(PoisonError::into_inner);
+        self.ended.notify_all();
+    }
+}
+
+/// Locks the tables of `catalog` called `names` with `lock`, in order
+fn lock<'a, G>(
+    catalog: &'a Catalog,
+    names: BTreeSet<String>,
+    lock: impl Fn(&'a RwLock<Table>) -> G,
+) -> BTreeMap<String, G> {
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let table = catalog.tables.get(&name)?;
+            Some((name, lock(table)))
+        })
+        .collect()
+}
+
+/// The tables a statement works on, locked for it
+pub struct Tables<'a> {
+    locked: Locked<'a>,
+    versions: &'a Mutex<Versions>,
+}
+
+enum Locked<'a> {
+    Read(BTreeMap<String, RwLockReadGuard<'a, Table>>),
+    Write(BTreeMap<String, RwLockWriteGuard<'a, Table>>),
+
+    /// Every table, taken out of the catalog while it is locked for writing
+    All(HashMap<String, Table>),
+}
+
+impl<'a> Tables<'a> {
+    pub fn get(&self, name: &str) -> Option<&Table> {
+        match &self.locked {
+            Locked::Read(tables) => tables.get(name).map(|table| &**table),
+            Locked::Write(tables) => tables.get(name).map(|table| &**table),
+            Locked::All(tables) => tables.get(name),
+        }
+    }
+
+    /// `None` unless the table is locked for writing
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Table> {
+        match &mut self.locked {
+            Locked::Read(_) => None,
+            Locked::Write(tables) => tables.get_mut(name).map(|table| &mut **table),
+            Locked::All(tables) => tables.get_mut(name),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&String, &Table)> + '_> {
+        match &self.locked {
+            Locked::Read(tables) => Box::new(tables.iter().map(|(name, table)| (name, &**table))),
+            Locked::Write(tables) => Box::new(tables.iter().map(|(name, table)| (name, &**table))),
+            Locked::All(tables) => Box::new(tables.iter()),
+        }
+    }
+
+    /// Adds `table` as `name`, returning the table it replaces. Only possible with the
+    /// catalog locked for writing.
+    pub fn insert(&mut self, name: &str, table: Table) -> Result<Option<Table>> {
+        match &mut self.locked {
+            Locked::All(tables) => Ok(tables.insert(name.to_string(), table)),
+            _ => bail!("Internal error"),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<Option<Table>> {
+        match &mut self.locked {
+            Locked::All(tables) => Ok(tables.remove(name)),
+            _ => bail!("Internal error"),
+        }
+    }
+
+    pub(super) fn versions(&self) -> MutexGuard<'a, Versions> {
+        self.versions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the rows of `table` with primary keys `pks` for `txn` until it ends. Under
+    /// `ReadCommitted` the snapshot is then taken again, so the rows are read as they are
+    /// once locked.
+    pub fn lock_rows(&self, txn: &mut Txn, table: &str, pks: &[Vec<LiteralValue>]) -> Result<()> {
+        let mut versions = self.versions();
+        for pk in pks {
+            versions.lock(txn, table, pk)?;
+        }
+        versions.snapshot(txn);
+        Ok(())
+    }
+
+    /// The rows of `table` that `snapshot` sees, in primary key order. They are read from
+    /// the pages one at a time, as the caller asks for them.
+    pub fn rows(
+        &self,
+        table: &str,
+        snapshot: &Snapshot,
+    ) -> Result<impl Iterator<Item = Result<Row>> + '_> {
+        let (data, snapshot) = (self.table(table, snapshot)?, *snapshot);
+        let history = self.versions().history(table);
+        let mut changed = history
+            .changed()
+            .cloned()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .peekable();
+        let mut rows = data.rows().peekable();
+        Ok(iter::from_fn(move || loop {
+            let current = match rows.peek() {
+                Some(Ok(row)) => Some(row.primary_key()),
+                Some(Err(_)) => return rows.next(),
+                None => None,
+            };
+            // Rows nobody sees differently come straight from the table
+            let Some(pk) =
+                changed.next_if(|pk| current.as_ref().is_none_or(|current| pk <= current))
+            else {
+                return rows.next();
+            };
+            let row = match current.as_ref() == Some(&pk) {
+                true => rows.next().and_then(Result::ok),
+                false => None,
+            };
+            if let Some(row) = history.visible(&pk, row, &snapshot) {
+                return Some(Ok(row));
+            }
+        }))
+    }
+
+    /// The rows of `table` that `snapshot` sees whose value in the first column of index
+    /// `name` lies between `lower` and `upper`, in index order. Like `rows`, those nobody
+    /// sees differently are read as the caller asks for them.
+    pub fn index_rows(
+        &self,
+        table: &str,
+        name: &str,
+        lower: Bound<&LiteralValue>,
+        upper: Bound<&LiteralValue>,
+        snapshot: &Snapshot,
+    ) -> Result<impl Iterator<Item = Result<Row>> + '_> {
+        let data = self.table(table, snapshot)?;
+        let index = data
+            .index(name)
+            .ok_or_else(|| anyhow!("Index \"{}\" not found", name))?;
+        let name = name.to_string();
+        let history = self.versions().history(table);
+        let key = |row: &Row| (row.cols(index.columns()), row.primary_key());
+        let mut changed = Vec::new();
+        for pk in history.changed() {
+            changed.extend(history.visible(pk, data.get(pk)?, snapshot).filter(|row| {
+                row.cols(&index.columns()[..1])
+                    .is_some_and(|key| index::in_range(&key[0], lower, upper))
+            }));
+        }
+        changed.sort_by_cached_key(key);
+        let mut changed = changed.into_iter().peekable();
+        let mut rows = index
+            .range(lower, upper)
+            .into_iter()
+            .filter(move |pk| history.is_current(pk))
+            .map(move |pk| {
+                data.get(&pk)?
+                    .ok_or_else(|| anyhow!("Index \"{}\" is out of date", name))
+            })
+            .peekable();
+        Ok(iter::from_fn(move || match rows.peek() {
+            Some(Ok(row)) if changed.peek().is_some_and(|next| key(next) < key(row)) => {
+                changed.next().map(Ok)
+            }
+            Some(_) => rows.next(),
+            None => changed.next().map(Ok),
+        }))
+    }
+
+    /// The names of the locked tables `snapshot` can use, in order
+    pub fn names(&self, snapshot: &Snapshot) -> Vec<String> {
+        let versions = self.versions();
+        let mut names: Vec<_> = self
+            .iter()
+            .map(|(name, _)| name.clone())
+            .filter(|name| versions.check_table(snapshot.txn, name).is_ok())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn table(&self, name: &str, snapshot: &Snapshot) -> Result<&Table> {
+        self.versions().check_table(snapshot.txn, name)?;
+        self.get(name)
+            .ok_or_else(|| anyhow!("Table \"{}\" not found", name))
+    }
+}
@@ -0,0 +1,27 @@
+use crate::parse::RefAction;
+
+/// Requires the values of `columns` in each row, unless one of them is `NULL`, to appear in
+/// `ref_columns` of some row of `table`
+#[derive(Clone, Debug)]
+pub struct ForeignKey {
+    /// Name of the constraint, if it was given one
+    pub name: Option<String>,
+    pub columns: Vec<String>,
+    pub table: String,
+    pub ref_columns: Vec<String>,
+    pub on_delete: RefAction,
+    pub on_update: RefAction,
+}
+
+impl ForeignKey {
+    /// Describes the constraint in errors, e.g. `pets(owner) REFERENCES people(name)`
+    pub fn describe(&self, table: &str) -> String {
+        format!(
+            "{}({}) REFERENCES {}({})",
+            table,
+            self.columns.join(", "),
+            self.table,
+            self.ref_columns.join(", ")
+        )
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::parse::LiteralValue;
+
+use super::{
+    codec,
+    page::{BufferPool, Page, PageId, PAGE_SIZE},
+    Row,
+};
+
+/// Slot count and where the records start
+const HEADER: usize = 4;
+
+/// Offset and length of a record, where a length of zero means the slot is unused
+const SLOT: usize = 4;
+
+/// Rows that encode to more than this are kept in a chain of overflow pages instead
+const INLINE_MAX: usize = PAGE_SIZE / 4;
+
+/// Overflow pages start with the next page of the chain
+const NEXT: usize = 8;
+const END: PageId = PageId::MAX;
+
+const INLINE: u8 = 0;
+const OVERFLOW: u8 = 1;
+
+/// The rows of a table, kept in slotted pages of a buffer pool. A page starts with its
+/// header and slots, while the records fill it from the end. A record is a tag followed
+/// by the encoded row or, for a row too big to share a page, where its overflow chain
+/// starts and how long it is. The pages are only scratch space: the table is made durable
+/// by the log and data file.
+pub struct Heap {
+    pool: &'static BufferPool,
+
+    /// Where the row with each primary key is, in key order. Only the rows go to the pages,
+    /// so every primary key of the table stays in memory.
+    keys: BTreeMap<Vec<LiteralValue>, RowId>,
+
+    /// Free bytes of each page holding records
+    space: HashMap<PageId, usize>,
+
+    /// The same, ordered to find a page with room for a record
+    by_space: BTreeSet<(usize, PageId)>,
+
+    /// Pages of the overflow chains
+    overflow: BTreeSet<PageId>,
+}
+
+#[derive(Clone, Copy)]
+struct RowId {
+    page: PageId,
+    slot: usize,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap::with_pool(BufferPool::shared())
+    }
+
+    pub fn with_pool(pool: &'static BufferPool) -> Heap {
+        Heap {
+            pool,
+            keys: BTreeMap::new(),
+            space: HashMap::new(),
+            by_space: BTreeSet::new(),
+            overflow: BTreeSet::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn contains(&self, pk: &[LiteralValue]) -> bool {
+        self.keys.contains_key(pk)
+    }
+
+    /// The row with primary key `pk`
+    pub fn get(&self, pk: &[LiteralValue]) -> Result<Option<Row>> {
+        match self.keys.get(pk) {
+            Some(&id) => Ok(Some(self.decode(&self.record(id)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Every row, ordered by primary key
+    pub fn iter(&self) -> impl Iterator<Item = Result<Row>> + '_ {
+        self.keys.values().map(|&id| self.decode(&self.record(id)?))
+    }
+
+    /// Adds `row`, replacing the one with the same primary key
+    pub fn insert(&mut self, row: Row) -> Result<()> {
+        let pk = row.primary_key();
+        self.remove(&pk)?;
+        let mut data = Vec::new();
+        codec::put_row(&mut data, &row);
+        let record = if data.len() > INLINE_MAX {
+            let first = self.write_chain(&data)?;
+            let mut record = vec![OVERFLOW];
+            record.extend(first.to_le_bytes());
+            record.extend((data.len() as u64).to_le_bytes());
+            record
+        } else {
+            let mut record = vec![INLINE];
+            record.extend(data);
+            record
+        };
+        let id = self.place(&record)?;
+        self.keys.insert(pk, id);
+        Ok(())
+    }
+
+    /// Removes the row with primary key `pk`, returning it
+    pub fn remove(&mut self, pk: &[LiteralValue]) -> Result<Option<Row>> {
+        let Some(&id) = self.keys.get(pk) else {
+            return Ok(None);
+        };
+        let record = self.record(id)?;
+        let row = self.decode(&record)?;
+        if record[0] == OVERFLOW {
+            self.free_chain(u64_at(&record, 1))?;
+        }
+        let free = self.pool.write(id.page, |page| clear_slot(page, id.slot))?;
+        self.keys.remove(pk);
+        self.set_space(id.page, free);
+        Ok(Some(row))
+    }
+
+    /// A copy of the heap on pages of its own
+    pub fn try_clone(&self) -> Result<Heap> {
+        let mut heap = Heap::with_pool(self.pool);
+        for row in self.iter() {
+            heap.insert(row?)?;
+        }
+        Ok(heap)
+    }
+
+    /// Puts `record` on a page with room for it, starting a new page if none has
+    fn place(&mut self, record: &[u8]) -> Result<RowId> {
+        let need = record.len() + SLOT;
+        let page = match self.by_space.range((need, 0)..).next() {
+            Some(&(_, page)) => page,
+            None => {
+                let page = self.pool.allocate()?;
+                self.pool.write(page, |page| put_u16(page, 2, PAGE_SIZE))?;
+                page
+            }
+        };
+        let (slot, free) = self.pool.write(page, |data| {
+            let slot = put_record(data, record);
+            (slot, free_space(data))
+        })?;
+        self.set_space(page, free);
+        Ok(RowId { page, slot })
+    }
+
+    /// Records how much room `page` has left, giving it back to the pool once it is empty
+    fn set_space(&mut self, page: PageId, free: usize) {
+        if let Some(old) = self.space.remove(&page) {
+            self.by_space.remove(&(old, page));
+        }
+        if free == PAGE_SIZE - HEADER {
+            self.pool.free(page);
+        } else {
+            self.space.insert(page, free);
+            self.by_space.insert((free, page));
+        }
+    }
+
+    fn record(&self, id: RowId) -> Result<Vec<u8>> {
+        self.pool.read(id.page, |page| {
+            let (at, len) = slot(page, id.slot);
+            page[at..at + len].to_vec()
+        })
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<Row> {
+        let data = match record[0] {
+            OVERFLOW => self.read_chain(u64_at(record, 1), u64_at(record, 9) as usize)?,
+            _ => record[1..].to_vec(),
+        };
+        codec::get_row(&mut Bytes::from(data))
+    }
+
+    /// Spreads `data` over new overflow pages, returning the first
+    fn write_chain(&mut self, data: &[u8]) -> Result<PageId> {
+        let chunks: Vec<_> = data.chunks(PAGE_SIZE - NEXT).collect();
+        let mut pages = Vec::with_capacity(chunks.len());
+        for _ in &chunks {
+            match self.pool.allocate() {
+                Ok(page) => pages.push(page),
+                Err(e) => {
+                    for page in pages {
+                        self.pool.free(page);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        // Tracked before they are written, so they are freed with the heap if that fails
+        self.overflow.extend(&pages);
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = pages.get(i + 1).copied().unwrap_or(END);
+            self.pool.write(pages[i], |page| {
+                page[..NEXT].copy_from_slice(&next.to_le_bytes());
+                page[NEXT..NEXT + chunk.len()].copy_from_slice(chunk);
+            })?;
+        }
+        Ok(pages[0])
+    }
+
+    fn read_chain(&self, mut page: PageId, len: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            page = self.pool.read(page, |page| {
+                let n = (len - data.len()).min(PAGE_SIZE - NEXT);
+                data.extend_from_slice(&page[NEXT..NEXT + n]);
+                u64_at(page, 0)
+            })?;
+        }
+        Ok(data)
+    }
+
+    fn free_chain(&mut self, mut page: PageId) -> Result<()> {
+        while page != END {
+            let next = self.pool.read(page, |page| u64_at(page, 0))?;
+            self.pool.free(page);
+            self.overflow.remove(&page);
+            page = next;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for &page in self.space.keys().chain(&self.overflow) {
+            self.pool.free(page);
+        }
+    }
+}
+
+/// Writes `record` to a free slot of `page`, compacting the records first if the gap
+/// between the slots and the records is too small. The page must have room for it.
+fn put_record(page: &mut Page, record: &[u8]) -> usize {
+    let n = get_u16(page, 0);
+    let i = (0..n).find(|&i| slot(page, i).1 == 0).unwrap_or(n);
+    let slots_end = HEADER + SLOT * n.max(i + 1);
+    if get_u16(page, 2) < slots_end + record.len() {
+        compact(page);
+    }
+    let at = get_u16(page, 2) - record.len();
+    page[at..at + record.len()].copy_from_slice(record);
+    put_u16(page, 2, at);
+    put_u16(page, HEADER + SLOT * i, at);
+    put_u16(page, HEADER + SLOT * i + 2, record.len());
+    if i == n {
+        put_u16(page, 0, n + 1);
+    }
+    i
+}
+
+/// Frees slot `i`, and any unused slots left at the end, returning the page's free space
+fn clear_slot(page: &mut Page, i: usize) -> usize {
+    put_u16(page, HEADER + SLOT * i, 0);
+    put_u16(page, HEADER + SLOT * i + 2, 0);
+    let mut n = get_u16(page, 0);
+    while n > 0 && slot(page, n - 1).1 == 0 {
+        n -= 1;
+    }
+    put_u16(page, 0, n);
+    if n == 0 {
+        put_u16(page, 2, PAGE_SIZE);
+    }
+    free_space(page)
+}
+
+/// Moves the records to the end of the page, closing the holes left by removed ones
+fn compact(page: &mut Page) {
+    let records: Vec<_> = (0..get_u16(page, 0))
+        .map(|i| (i, slot(page, i)))
+        .filter(|(_, (_, len))| *len > 0)
+        .map(|(i, (at, len))| (i, page[at..at + len].to_vec()))
+        .collect();
+    let mut start = PAGE_SIZE;
+    for (i, record) in records {
+        start -= record.len();
+        page[start..start + record.len()].copy_from_slice(&record);
+        put_u16(page, HEADER + SLOT * i, start);
+    }
+    put_u16(page, 2, start);
+}
+
+/// Bytes not taken by the header, slots or records, holes included
+fn free_space(page: &Page) -> usize {
+    let n = get_u16(page, 0);
+    let used: usize = (0..n).map(|i| slot(page, i).1).sum();
+    PAGE_SIZE - HEADER - SLOT * n - used
+}
+
+fn slot(page: &Page, i: usize) -> (usize, usize) {
+    (
+        get_u16(page, HEADER + SLOT * i),
+        get_u16(page, HEADER + SLOT * i + 2),
+    )
+}
+
+fn get_u16(page: &[u8], at: usize) -> usize {
+    u16::from_le_bytes([page[at], page[at + 1]]) as usize
+}
+
+fn put_u16(page: &mut [u8], at: usize, val: usize) {
+    page[at..at + 2].copy_from_slice(&(val as u16).to_le_bytes());
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+};
+
+use anyhow::{bail, Result};
+
+use crate::parse::LiteralValue;
+
+use super::Row;
+
+/// An ordered secondary index, mapping the values of some columns to the primary keys of
+/// the rows holding them
+#[derive(Clone)]
+pub struct Index {
+    name: String,
+    columns: Vec<String>,
+    unique: bool,
+
+    /// Backs a `UNIQUE` constraint rather than being created by the user
+    constraint: bool,
+    entries: BTreeMap<Vec<LiteralValue>, BTreeSet<Vec<LiteralValue>>>,
+}
+
+impl Index {
+    pub fn new(name: String, columns: Vec<String>, unique: bool) -> Self {
+        Index {
+            name,
+            columns,
+            unique,
+            constraint: false,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// The unique index enforcing a `UNIQUE` constraint on `columns`, named after them
+    /// unless the constraint has a name
+    pub fn for_constraint(name: Option<String>, columns: Vec<String>) -> Self {
+        Index {
+            constraint: true,
+            ..Index::new(
+                name.unwrap_or_else(|| format!("{}_key", columns.join("_"))),
+                columns,
+                true,
+            )
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_ref()
+    }
+
+    pub fn columns(&self) -> &[String] {
+        self.columns.as_ref()
+    }
+
+    pub fn unique(&self) -> bool {
+        self.unique
+    }
+
+    pub fn constraint(&self) -> bool {
+        self.constraint
+    }
+
+    fn key(&self, row: &Row) -> Vec<LiteralValue> {
+        row.cols(&self.columns)
+            .expect("indexed columns belong to the table")
+    }
+
+    /// Fails if adding `row` would give a unique index a duplicate. Keys containing `NULL`
+    /// never conflict.
+    pub fn check(&self, row: &Row) -> Result<()> {
+        let key = self.key(row);
+        if self.unique && !key.contains(&LiteralValue::Null) {
+            if let Some(pks) = self.entries.get(&key) {
+                if pks.iter().any(|pk| *pk != row.primary_key()) {
+                    match &self.columns[..] {
+                        [col] if self.constraint => bail!("Col {} must be unique", col),
+                        cols if self.constraint => {
+                            bail!("Cols ({}) must be unique together", cols.join(", "))
+                        }
+                        _ => {}
+                    }
+                    bail!("Duplicate key in unique index {}", self.name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, row: &Row) {
+        let key = self.key(row);
+        self.entries
+            .entry(key)
+            .or_default()
+            .insert(row.primary_key());
+    }
+
+    pub fn remove(&mut self, row: &Row) {
+        let key = self.key(row);
+        if let Some(pks) = self.entries.get_mut(&key) {
+            pks.remove(&row.primary_key());
+            if pks.is_empty() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    /// Primary keys of the rows whose indexed columns hold `key`
+    pub fn get(&self, key: &[LiteralValue]) -> Vec<Vec<LiteralValue>> {
+        self.entries
+            .get(key)
+            .map(|pks| pks.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Primary keys of the rows whose first indexed column lies between `lower` and
+    /// `upper`, in index order. Only values of the same type as the bounds are considered.
+    pub fn range(
+        &self,
+        lower: Bound<&LiteralValue>,
+        upper: Bound<&LiteralValue>,
+    ) -> Vec<Vec<LiteralValue>> {
+        let bound = match (lower, upper) {
+            (Bound::Included(val) | Bound::Excluded(val), _)
+            | (_, Bound::Included(val) | Bound::Excluded(val)) => val,
+            (Bound::Unbounded, Bound::Unbounded) => {
+                return self.entries.values().flatten().cloned().collect()
+            }
+        };
+        let same_type = |val: &LiteralValue| val.same_kind(bound);
+        let start = match lower {
+            Bound::Included(val) | Bound::Excluded(val) => Bound::Included(vec![val.clone()]),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.entries
+            .range((start, Bound::Unbounded))
+            .map(|(key, pks)| (&key[0], pks))
+            .skip_while(|(first, _)| !same_type(first))
+            .skip_while(|(first, _)| matches!(lower, Bound::Excluded(val) if *first == val))
+            .take_while(|(first, _)| {
+                same_type(first)
+                    && match upper {
+                        Bound::Included(val) => *first <= val,
+                        Bound::Excluded(val) => *first < val,
+                        Bound::Unbounded => true,
+                    }
+            })
+            .flat_map(|(_, pks)| pks.iter().cloned())
+            .collect()
+    }
+}
+
+/// Whether `val` lies between `lower` and `upper`. Like for `Index::range`, only values of
+/// the same type as the bounds can.
+pub fn in_range(
+    val: &LiteralValue,
+    lower: Bound<&LiteralValue>,
+    upper: Bound<&LiteralValue>,
+) -> bool {
+    let same_type = |bound: &LiteralValue| val.same_kind(bound);
+    let above = match lower {
+        Bound::Included(bound) => same_type(bound) && val >= bound,
+        Bound::Excluded(bound) => same_type(bound) && val > bound,
+        Bound::Unbounded => true,
+    };
+    let below = match upper {
+        Bound::Included(bound) => same_type(bound) && val <= bound,
+        Bound::Excluded(bound) => same_type(bound) && val < bound,
+        Bound::Unbounded => true,
+    };
+    above && below
+}
@@ -1,17 +1,147 @@
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
+use std::sync::Arc;
 
 pub use self::{
+    changes::Changes,
     column_header::{ColumnHeader, DefaultOpt},
+    database::{Database, Tables},
+    foreign_key::ForeignKey,
+    index::Index,
+    mvcc::{Conflict, Txn},
+    redo::Redo,
     row::{Column, Row},
+    storage::Storage,
     table::Table,
 };
 
+#[cfg(test)]
+pub use self::storage::{Crash, Fault};
+
+mod changes;
+mod codec;
 mod column_header;
+mod database;
+mod foreign_key;
+mod heap;
+mod index;
+mod mvcc;
+mod page;
+mod redo;
 mod row;
+mod storage;
 mod table;
 
-// TODO: file persistence
-pub type Db = Arc<Mutex<HashMap<String, Table>>>;
+pub type Db = Arc<Database>;
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::OrderedFloat;
+
+    use crate::parse::LiteralValue;
+
+    use super::{
+        heap::Heap,
+        page::{BufferPool, PAGE_SIZE},
+        *,
+    };
+
+    fn row(id: f64, text: String) -> Row {
+        Row::new(
+            vec![Column::new(
+                LiteralValue::Number(OrderedFloat(id)),
+                "id".to_string(),
+            )],
+            vec![Column::new(LiteralValue::String(text), "text".to_string())],
+        )
+    }
+
+    #[test]
+    fn buffer_pool() {
+        let pool = BufferPool::temporary(2, 1);
+        let pages: Vec<_> = (0..5).map(|_| pool.allocate().unwrap()).collect();
+        for (i, &page) in pages.iter().enumerate() {
+            pool.write(page, |data| data[..8].fill(i as u8)).unwrap();
+        }
+        assert_eq!(pool.cached(), 2);
+
+        // Evicted pages were written back and are read in again
+        for (i, &page) in pages.iter().enumerate() {
+            assert_eq!(pool.read(page, |data| data[7]).unwrap(), i as u8);
+        }
+        assert_eq!(pool.cached(), 2);
+
+        // A freed page comes back zeroed
+        pool.free(pages[1]);
+        let page = pool.allocate().unwrap();
+        assert_eq!(page, pages[1]);
+        assert_eq!(pool.read(page, |data| data[0]).unwrap(), 0);
+
+        // Pages are spread over the shards, each evicting its own
+        let pool = BufferPool::temporary(4, 2);
+        let pages: Vec<_> = (0..9).map(|_| pool.allocate().unwrap()).collect();
+        for (i, &page) in pages.iter().enumerate() {
+            pool.write(page, |data| data[..8].fill(i as u8)).unwrap();
+        }
+        assert_eq!(pool.cached(), 4);
+        for (i, &page) in pages.iter().enumerate() {
+            assert_eq!(pool.read(page, |data| data[7]).unwrap(), i as u8);
+        }
+    }
+
+    #[test]
+    fn heap() {
+        let pool = Box::leak(Box::new(BufferPool::temporary(6, 2)));
+        let mut heap = Heap::with_pool(pool);
+        let key = |id: f64| vec![LiteralValue::Number(OrderedFloat(id))];
+
+        // Enough rows to spill out of the pool, and one spanning several pages
+        for id in 0..200 {
+            heap.insert(row(id as f64, format!("row {}", id))).unwrap();
+        }
+        let big = "x".repeat(PAGE_SIZE * 3);
+        heap.insert(row(500.0, big.clone())).unwrap();
+        assert_eq!(heap.len(), 201);
+        assert_eq!(
+            heap.get(&key(500.0)).unwrap(),
+            Some(row(500.0, big.clone()))
+        );
+        assert_eq!(
+            heap.get(&key(42.0))
+                .unwrap()
+                .unwrap()
+                .cols(&["text".to_string()]),
+            Some(vec![LiteralValue::String("row 42".to_string())])
+        );
+
+        // Removed rows make room for new ones
+        for id in 0..100 {
+            assert!(heap.remove(&key(id as f64)).unwrap().is_some());
+        }
+        assert!(heap.remove(&key(0.0)).unwrap().is_none());
+        heap.insert(row(1.0, "again".to_string())).unwrap();
+        assert_eq!(
+            heap.remove(&key(500.0))
+                .unwrap()
+                .unwrap()
+                .cols(&["text".to_string()]),
+            Some(vec![LiteralValue::String(big)])
+        );
+
+        let ids: Vec<_> = heap.iter().map(|row| row.unwrap().primary_key()).collect();
+        let expected: Vec<_> = [1.0]
+            .into_iter()
+            .chain((100..200).map(|id| id as f64))
+            .map(key)
+            .collect();
+        assert_eq!(ids, expected);
+
+        let copy = heap.try_clone().unwrap();
+        heap.insert(row(1.0, "changed".to_string())).unwrap();
+        assert_eq!(
+            copy.get(&key(1.0))
+                .unwrap()
+                .unwrap()
+                .cols(&["text".to_string()]),
+            Some(vec![LiteralValue::String("again".to_string())])
+        );
+    }
+}
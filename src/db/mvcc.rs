@@ -0,0 +1,455 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    fmt::Display,
+};
+
+use anyhow::{bail, Result};
+
+use crate::parse::{Isolation, LiteralValue};
+
+use super::{changes::Undo, Redo, Row};
+
+pub type TxnId = u64;
+
+/// Which versions of the rows a statement sees: those committed by the commit numbered
+/// `seq` or earlier, and those its own transaction made
+#[derive(Clone, Copy, Debug)]
+pub struct Snapshot {
+    pub(super) txn: TxnId,
+    seq: u64,
+}
+
+impl Snapshot {
+    fn sees(&self, stamp: Stamp) -> bool {
+        match stamp {
+            Stamp::Pending(txn) => txn == self.txn,
+            Stamp::Committed(seq) => seq <= self.seq,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Stamp {
+    Pending(TxnId),
+    Committed(u64),
+}
+
+/// A change to a row, and the row as it was before it (`None` if it didn't exist)
+#[derive(Clone)]
+struct Version {
+    stamp: Stamp,
+    old: Option<Row>,
+}
+
+/// The state of an open transaction. Its changes are made to the tables right away, and
+/// the versions of the rows they replaced are kept for other transactions to read.
+pub struct Txn {
+    pub(super) id: TxnId,
+    isolation: Isolation,
+    snapshot: Snapshot,
+
+    /// How to take back each change made so far, in order
+    pub(super) undo: Vec<Undo>,
+    pub(super) log: Vec<Redo>,
+
+    /// Tables read by queries, for `Serializable` transactions
+    reads: HashSet<String>,
+}
+
+impl Txn {
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot
+    }
+
+    /// Changes to log when the transaction commits
+    pub fn log(&self) -> &[Redo] {
+        &self.log
+    }
+
+    pub fn read(&mut self, table: &str) {
+        if self.isolation == Isolation::Serializable {
+            self.reads.insert(table.to_string());
+        }
+    }
+
+    /// How far the transaction has got, to roll back to later
+    pub fn savepoint(&self) -> (usize, usize) {
+        (self.undo.len(), self.log.len())
+    }
+}
+
+/// The bookkeeping behind snapshots: the old versions of recently changed rows, and which
+/// transactions are open
+#[derive(Default)]
+pub struct Versions {
+    /// Number of the last commit
+    seq: u64,
+    next_txn: TxnId,
+
+    /// For each table and primary key, the changes made to the row that some open
+    /// transaction might not see, oldest first. Only the last one can be pending.
+    history: HashMap<String, BTreeMap<Vec<LiteralValue>, Vec<Version>>>,
+
+    /// The snapshot of each open transaction
+    open: HashMap<TxnId, Snapshot>,
+
+    /// Rows each open transaction has changed
+    pending: HashMap<TxnId, Vec<(String, Vec<LiteralValue>)>>,
+
+    /// Rows locked by `SELECT ... FOR UPDATE`, and the transaction holding each
+    locks: HashMap<(String, Vec<LiteralValue>), TxnId>,
+
+    /// Tables created by transactions that haven't committed yet
+    created: HashMap<String, TxnId>,
+
+    /// Tables written by each commit that an open `Serializable` transaction might have
+    /// missed
+    commits: Vec<(u64, HashSet<String>)>,
+}
+
+impl Versions {
+    pub fn begin(&mut self, isolation: Isolation) -> Txn {
+        self.next_txn += 1;
+        let snapshot = Snapshot {
+            txn: self.next_txn,
+            seq: self.seq,
+        };
+        self.open.insert(snapshot.txn, snapshot);
+        Txn {
+            id: snapshot.txn,
+            isolation,
+            snapshot,
+            undo: Vec::new(),
+            log: Vec::new(),
+            reads: HashSet::new(),
+        }
+    }
+
+    /// The snapshot `txn`'s next statement reads from. Under `ReadCommitted` it is taken
+    /// anew for each statement.
+    pub fn snapshot(&mut self, txn: &mut Txn) -> Snapshot {
+        if txn.isolation == Isolation::ReadCommitted {
+            txn.snapshot.seq = self.seq;
+            self.open.insert(txn.id, txn.snapshot);
+        }
+        txn.snapshot
+    }
+
+    /// Fails unless `txn` may change the row of `table` with primary key `pk`, which is
+    /// `old`. No other transaction may have a pending change to it, and nobody can have
+    /// committed one the transaction didn't see either. The row's current version is kept for the transactions that can't see the change.
+    pub fn touch(
+        &mut self,
+        txn: &Txn,
+        table: &str,
+        pk: &[LiteralValue],
+        old: Option<&Row>,
+    ) -> Result<()> {
+        self.check_table(txn.id, table)?;
+        if self.check_update(txn, table, pk)? {
+            return Ok(());
+        }
+        let versions = self
+            .history
+            .entry(table.to_string())
+            .or_default()
+            .entry(pk.to_vec())
+            .or_default();
+        versions.push(Version {
+            stamp: Stamp::Pending(txn.id),
+            old: old.cloned(),
+        });
+        self.pending
+            .entry(txn.id)
+            .or_default()
+            .push((table.to_string(), pk.to_vec()));
+        Ok(())
+    }
+
+    /// Locks the row of `table` with primary key `pk` for `txn` until it ends, under the
+    /// same conditions as changing it
+    pub fn lock(&mut self, txn: &Txn, table: &str, pk: &[LiteralValue]) -> Result<()> {
+        self.check_table(txn.id, table)?;
+        if !self.check_update(txn, table, pk)? {
+            self.locks.insert((table.to_string(), pk.to_vec()), txn.id);
+        }
+        Ok(())
+    }
+
+    /// Fails unless `txn` may change or lock the row, returning whether it already has a
+    /// pending change to it. Under `ReadCommitted`, a change committed since the statement
+    /// began only means the statement has to start over, to see the row as it is now.
+    pub fn check_update(&self, txn: &Txn, table: &str, pk: &[LiteralValue]) -> Result<bool> {
+        self.check_row(txn, table, pk)?;
+        let last = self
+            .history
+            .get(table)
+            .and_then(|rows| rows.get(pk))
+            .and_then(|versions| versions.last());
+        match last.map(|version| version.stamp) {
+            Some(Stamp::Pending(_)) => Ok(true),
+            Some(Stamp::Committed(seq)) if seq > txn.snapshot.seq => {
+                if txn.isolation == Isolation::ReadCommitted {
+                    bail!(Conflict::changed(table));
+                }
+                bail!("Could not serialize access due to concurrent update")
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Fails if another transaction has a pending change to the row or has it locked
+    pub fn check_row(&self, txn: &Txn, table: &str, pk: &[LiteralValue]) -> Result<()> {
+        let last = self
+            .history
+            .get(table)
+            .and_then(|rows| rows.get(pk))
+            .and_then(|versions| versions.last());
+        if let Some(Version {
+            stamp: Stamp::Pending(id),
+            ..
+        }) = last
+        {
+            if *id != txn.id {
+                bail!(Conflict::new(table, *id));
+            }
+        }
+        match self.locks.get(&(table.to_string(), pk.to_vec())) {
+            Some(id) if *id != txn.id => bail!(Conflict::new(table, *id)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails if a row that another transaction is changing away held values `taken`
+    /// matches, which it would need back if that transaction rolled back
+    pub fn check_released(
+        &self,
+        txn: &Txn,
+        table: &str,
+        taken: impl Fn(&Row) -> bool,
+    ) -> Result<()> {
+        let Some(rows) = self.history.get(table) else {
+            return Ok(());
+        };
+        for versions in rows.values() {
+            if let Some(Version {
+                stamp: Stamp::Pending(id),
+                old: Some(old),
+            }) = versions.last()
+            {
+                if *id != txn.id && taken(old) {
+                    bail!(Conflict::new(table, *id));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if `table` was created by a transaction other than `txn` that is still open
+    pub fn check_table(&self, txn: TxnId, table: &str) -> Result<()> {
+        match self.created.get(table) {
+            Some(id) if *id != txn => bail!(
+                "Table \"{}\" was created by a transaction that hasn't committed",
+                table
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records that `txn` is creating `table`, which other transactions can't use until it
+    /// commits. A table it replaces can't have changes pending from them, and its old
+    /// versions are dropped since table definitions aren't versioned.
+    pub fn create(&mut self, txn: &Txn, table: &str) -> Result<()> {
+        self.check_table(txn.id, table)?;
+        for versions in self
+            .history
+            .get(table)
+            .into_iter()
+            .flat_map(BTreeMap::values)
+        {
+            if let Some(Version {
+                stamp: Stamp::Pending(id),
+                ..
+            }) = versions.last()
+            {
+                if *id != txn.id {
+                    bail!(Conflict::new(table, *id));
+                }
+            }
+        }
+        if let Some(((_, _), id)) = self
+            .locks
+            .iter()
+            .find(|((name, _), id)| name == table && **id != txn.id)
+        {
+            bail!(Conflict::new(table, *id));
+        }
+        self.history.remove(table);
+        self.created.insert(table.to_string(), txn.id);
+        Ok(())
+    }
+
+    pub fn is_open(&self, txn: TxnId) -> bool {
+        self.open.contains_key(&txn)
+    }
+
+    /// A copy of the old versions of the rows of `table`, for a scan to use without keeping
+    /// everyone else waiting. They stay right while the scan holds the table, since only
+    /// changes to it add versions, and only versions every open snapshot sees are dropped.
+    pub fn history(&self, table: &str) -> History {
+        History(self.history.get(table).cloned().unwrap_or_default())
+    }
+
+    /// Fails if committing `txn` could make the outcome differ from running the
+    /// transactions one at a time. That can only happen to a `Serializable` transaction
+    /// that read a table someone changed after it started, so it is refused in that case.
+    pub fn validate(&self, txn: &Txn) -> Result<()> {
+        if txn.isolation != Isolation::Serializable || txn.log.is_empty() {
+            return Ok(());
+        }
+        if self
+            .commits
+            .iter()
+            .any(|(seq, tables)| *seq > txn.snapshot.seq && !tables.is_disjoint(&txn.reads))
+        {
+            bail!("Could not serialize access due to read/write dependencies among transactions");
+        }
+        Ok(())
+    }
+
+    /// Makes `txn`'s changes visible to snapshots taken from now on
+    pub fn commit(&mut self, txn: &Txn) {
+        if !txn.log.is_empty() {
+            self.seq += 1;
+            let tables = txn
+                .log
+                .iter()
+                .map(|redo| redo.table().to_string())
+                .collect();
+            self.commits.push((self.seq, tables));
+        }
+        for (table, pk) in self.pending.remove(&txn.id).unwrap_or_default() {
+            if let Some(version) = self
+                .history
+                .get_mut(&table)
+                .and_then(|rows| rows.get_mut(&pk))
+                .and_then(|versions| versions.last_mut())
+            {
+                version.stamp = Stamp::Committed(self.seq);
+            }
+        }
+        self.end(txn);
+    }
+
+    /// Forgets `txn`'s changes, once they have been undone
+    pub fn rollback(&mut self, txn: &Txn) {
+        for (table, pk) in self.pending.remove(&txn.id).unwrap_or_default() {
+            if let Some(versions) = self
+                .history
+                .get_mut(&table)
+                .and_then(|rows| rows.get_mut(&pk))
+            {
+                versions.pop();
+            }
+        }
+        self.end(txn);
+    }
+
+    /// Closes `txn`, releasing its locks, and drops the versions no open transaction needs
+    /// anymore
+    fn end(&mut self, txn: &Txn) {
+        self.open.remove(&txn.id);
+        self.locks.retain(|_, id| *id != txn.id);
+        self.created.retain(|_, id| *id != txn.id);
+        let oldest = self
+            .open
+            .values()
+            .map(|snapshot| snapshot.seq)
+            .min()
+            .unwrap_or(self.seq);
+        let seen =
+            |version: &Version| matches!(version.stamp, Stamp::Committed(seq) if seq <= oldest);
+        for rows in self.history.values_mut() {
+            rows.retain(|_, versions| {
+                let old = versions.iter().take_while(|version| seen(version)).count();
+                versions.drain(..old);
+                !versions.is_empty()
+            });
+        }
+        self.history.retain(|_, rows| !rows.is_empty());
+        self.commits.retain(|(seq, _)| *seq > oldest);
+    }
+}
+
+/// The old versions of the rows of one table, by primary key
+pub struct History(BTreeMap<Vec<LiteralValue>, Vec<Version>>);
+
+impl History {
+    /// The version of a row that `snapshot` sees, given its `current` one
+    pub fn visible(
+        &self,
+        pk: &[LiteralValue],
+        current: Option<Row>,
+        snapshot: &Snapshot,
+    ) -> Option<Row> {
+        let Some(versions) = self.0.get(pk) else {
+            return current;
+        };
+        let mut row = current;
+        for version in versions.iter().rev() {
+            if snapshot.sees(version.stamp) {
+                break;
+            }
+            row = version.old.clone();
+        }
+        row
+    }
+
+    /// Whether every snapshot sees the row as it is in the table
+    pub fn is_current(&self, pk: &[LiteralValue]) -> bool {
+        !self.0.contains_key(pk)
+    }
+
+    /// Primary keys of the rows that some snapshot sees differently, which includes rows
+    /// that are gone
+    pub fn changed(&self) -> impl Iterator<Item = &Vec<LiteralValue>> {
+        self.0.keys()
+    }
+}
+
+/// A row is held by another transaction, which `txn` would have to wait for to go on, or
+/// was changed by one since the statement began, with no `holder` left to wait for
+#[derive(Debug)]
+pub struct Conflict {
+    table: String,
+    pub holder: Option<TxnId>,
+}
+
+impl Conflict {
+    fn new(table: &str, holder: TxnId) -> Conflict {
+        Conflict {
+            table: table.to_string(),
+            holder: Some(holder),
+        }
+    }
+
+    fn changed(table: &str) -> Conflict {
+        Conflict {
+            table: table.to_string(),
+            holder: None,
+        }
+    }
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "A row of \"{}\" is being changed by another transaction",
+            self.table
+        )
+    }
+}
+
+impl Error for Conflict {}
@@ -0,0 +1,245 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex, MutexGuard, OnceLock, PoisonError,
+    },
+};
+
+use anyhow::{Context, Result};
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Pages the shared pool keeps in memory, 4 MiB worth
+const SHARED_PAGES: usize = 1024;
+
+/// Parts the shared pool is split into, each locked on its own
+const SHARED_SHARDS: usize = 16;
+
+pub type PageId = u64;
+pub type Page = [u8; PAGE_SIZE];
+
+/// Caches pages in a fixed number of frames. A page is read in when it is used, taking the
+/// frame of one the clock hand finds unused since it last passed, and a page that was
+/// changed is only written back once it is evicted.
+///
+/// The pages are split between shards, each with its own file, frames and lock, so that
+/// pages in different shards can be used at the same time. Page `id` belongs to shard
+/// `id % shards`, and new pages are taken from each shard in turn.
+pub struct BufferPool {
+    shards: Vec<Mutex<Pool>>,
+
+    /// Shard the next page is allocated from
+    next: AtomicUsize,
+}
+
+struct Pool {
+    /// Where the file is created once a page is first written back to it
+    path: PathBuf,
+    file: Option<File>,
+    capacity: usize,
+    frames: Vec<Frame>,
+
+    /// The frame holding each cached page
+    cached: HashMap<PageId, usize>,
+
+    /// The next frame the clock looks at when a page has to be evicted
+    hand: usize,
+
+    /// Pages in the shard, in use or not
+    len: PageId,
+
+    /// Pages that were freed, to be handed out again
+    free: Vec<PageId>,
+}
+
+struct Frame {
+    id: PageId,
+    page: Box<Page>,
+    dirty: bool,
+
+    /// Set when the page is used, and cleared as the clock passes over it
+    referenced: bool,
+}
+
+impl BufferPool {
+    /// A pool over scratch files in the system's temporary directory, rather than the data
+    /// directory, which go away with the process. The frames are divided between `shards`.
+    pub fn temporary(capacity: usize, shards: usize) -> BufferPool {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let shards = shards.clamp(1, capacity.max(1));
+        let pool = NEXT.fetch_add(1, Ordering::Relaxed);
+        BufferPool {
+            shards: (0..shards)
+                .map(|shard| {
+                    Mutex::new(Pool {
+                        path: std::env::temp_dir().join(format!(
+                            "sequel-{}-{}-{}.pages",
+                            std::process::id(),
+                            pool,
+                            shard
+                        )),
+                        file: None,
+                        capacity: (capacity / shards).max(1),
+                        frames: Vec::new(),
+                        cached: HashMap::new(),
+                        hand: 0,
+                        len: 0,
+                        free: Vec::new(),
+                    })
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The pool the rows of every table are kept in
+    pub fn shared() -> &'static BufferPool {
+        static SHARED: OnceLock<BufferPool> = OnceLock::new();
+        SHARED.get_or_init(|| BufferPool::temporary(SHARED_PAGES, SHARED_SHARDS))
+    }
+
+    /// A new page, filled with zeros
+    pub fn allocate(&self) -> Result<PageId> {
+        let shard = self.next.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        let mut pool = self.lock(shard);
+        let local = pool.free.pop().unwrap_or_else(|| {
+            pool.len += 1;
+            pool.len - 1
+        });
+        let frame = pool.frame(local, true)?;
+        frame.dirty = true;
+        Ok(local * self.shards.len() as PageId + shard as PageId)
+    }
+
+    /// Hands `id` back to be allocated again. Its contents are dropped without being
+    /// written.
+    pub fn free(&self, id: PageId) {
+        let (shard, local) = self.locate(id);
+        let mut pool = self.lock(shard);
+        if let Some(&i) = pool.cached.get(&local) {
+            pool.frames[i].dirty = false;
+            pool.frames[i].referenced = false;
+        }
+        pool.free.push(local);
+    }
+
+    pub fn read<T>(&self, id: PageId, f: impl FnOnce(&Page) -> T) -> Result<T> {
+        let (shard, local) = self.locate(id);
+        Ok(f(&self.lock(shard).frame(local, false)?.page))
+    }
+
+    pub fn write<T>(&self, id: PageId, f: impl FnOnce(&mut Page) -> T) -> Result<T> {
+        let (shard, local) = self.locate(id);
+        let mut pool = self.lock(shard);
+        let frame = pool.frame(local, false)?;
+        frame.dirty = true;
+        Ok(f(&mut frame.page))
+    }
+
+    /// Number of pages in memory
+    #[cfg(test)]
+    pub fn cached(&self) -> usize {
+        (0..self.shards.len())
+            .map(|shard| self.lock(shard).cached.len())
+            .sum()
+    }
+
+    /// The shard page `id` belongs to, and its number within the shard
+    fn locate(&self, id: PageId) -> (usize, PageId) {
+        let n = self.shards.len() as PageId;
+        ((id % n) as usize, id / n)
+    }
+
+    /// A page whose user panicked is still usable, as the pool's own bookkeeping is never
+    /// left half done
+    fn lock(&self, shard: usize) -> MutexGuard<'_, Pool> {
+        self.shards[shard]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Pool {
+    /// The frame holding page `id`, reading it in unless it is `fresh`
+    fn frame(&mut self, id: PageId, fresh: bool) -> Result<&mut Frame> {
+        let i = match self.cached.get(&id) {
+            Some(&i) => i,
+            None => {
+                let i = self.evict()?;
+                let frame = &mut self.frames[i];
+                frame.id = id;
+                frame.dirty = false;
+                if !fresh {
+                    let file = open(&mut self.file, &self.path)?;
+                    file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+                    file.read_exact(&mut frame.page[..])
+                        .with_context(|| format!("Failed to read page {}", id))?;
+                }
+                self.cached.insert(id, i);
+                i
+            }
+        };
+        let frame = &mut self.frames[i];
+        if fresh {
+            frame.page.fill(0);
+        }
+        frame.referenced = true;
+        Ok(frame)
+    }
+
+    /// A frame to read a page into: a new one while there is room, or else that of the
+    /// first page the clock finds unreferenced, written back if it changed
+    fn evict(&mut self) -> Result<usize> {
+        if self.frames.len() < self.capacity {
+            self.frames.push(Frame {
+                id: 0,
+                page: Box::new([0; PAGE_SIZE]),
+                dirty: false,
+                referenced: false,
+            });
+            return Ok(self.frames.len() - 1);
+        }
+        let i = loop {
+            let i = self.hand;
+            self.hand = (self.hand + 1) % self.frames.len();
+            let frame = &mut self.frames[i];
+            if !frame.referenced {
+                break i;
+            }
+            frame.referenced = false;
+        };
+        let frame = &mut self.frames[i];
+        if frame.dirty {
+            let file = open(&mut self.file, &self.path)?;
+            file.seek(SeekFrom::Start(frame.id * PAGE_SIZE as u64))?;
+            file.write_all(&frame.page[..])
+                .with_context(|| format!("Failed to write page {}", frame.id))?;
+        }
+        if self.cached.get(&frame.id) == Some(&i) {
+            self.cached.remove(&frame.id);
+        }
+        Ok(i)
+    }
+}
+
+/// The shard's file, created at `path` when it is first needed
+fn open<'a>(file: &'a mut Option<File>, path: &Path) -> Result<&'a mut File> {
+    match file {
+        Some(file) => Ok(file),
+        None => {
+            let created = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .with_context(|| format!("Failed to create page file {}", path.display()))?;
+            // The open handle keeps the file usable where it can be unlinked
+            let _ = fs::remove_file(path);
+            Ok(file.insert(created))
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use crate::parse::LiteralValue;
+
+use super::{Column, Table};
+
+/// One change to the stored tables, as written to the log. Each row a statement touches,
+/// including through foreign key actions, gets its own record, so applying the records in
+/// order repeats the statement without evaluating anything again.
+pub enum Redo {
+    Insert {
+        table: String,
+        cols: Vec<Column>,
+    },
+    Delete {
+        table: String,
+        pk: Vec<LiteralValue>,
+    },
+
+    /// `pk` is the primary key before the update
+    Update {
+        table: String,
+        pk: Vec<LiteralValue>,
+        cols: Vec<Column>,
+    },
+
+    /// The table as it was created, with any rows it was created with
+    CreateTable {
+        name: String,
+        table: Table,
+    },
+    CreateIndex {
+        table: String,
+        name: String,
+        columns: Vec<String>,
+        unique: bool,
+    },
+    DropIndex {
+        table: String,
+        name: String,
+    },
+}
+
+impl Redo {
+    /// Repeats the change on `tables`. Auto-incrementing columns are moved past the values
+    /// the change stores, as they were when it was first made.
+    pub fn apply(&self, tables: &mut HashMap<String, Table>) -> Result<()> {
+        match self {
+            Redo::Insert { table, cols } => {
+                let table = get(tables, table)?;
+                table.append(cols.clone())?;
+                table.advance_counters(cols);
+            }
+            Redo::Delete { table, pk } => {
+                get(tables, table)?
+                    .delete(pk)?
+                    .ok_or_else(|| anyhow!("No row with primary key {:?}", pk))?;
+            }
+            Redo::Update { table, pk, cols } => {
+                let table = get(tables, table)?;
+                table.update(pk, cols.clone())?;
+                table.advance_counters(cols);
+            }
+            Redo::CreateTable { name, table } => {
+                tables.insert(name.clone(), table.try_clone()?);
+            }
+            Redo::CreateIndex {
+                table,
+                name,
+                columns,
+                unique,
+            } => get(tables, table)?.create_index(name.clone(), columns.clone(), *unique)?,
+            Redo::DropIndex { table, name } => {
+                get(tables, table)?.drop_index(name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Name of the table the change is made to
+    pub fn table(&self) -> &str {
+        match self {
+            Redo::Insert { table, .. }
+            | Redo::Delete { table, .. }
+            | Redo::Update { table, .. }
+            | Redo::CreateIndex { table, .. }
+            | Redo::DropIndex { table, .. } => table,
+            Redo::CreateTable { name, .. } => name,
+        }
+    }
+}
+
+fn get<'a>(tables: &'a mut HashMap<String, Table>, name: &str) -> Result<&'a mut Table> {
+    tables
+        .get_mut(name)
+        .ok_or_else(|| anyhow!("Table \"{}\" not found", name))
+}
@@ -1,56 +1,64 @@
 use crate::parse::LiteralValue;
 
-#[derive(Eq, Debug)]
+/// A row of a table, ordered by its primary key
+#[derive(Eq, Clone, Debug)]
 pub struct Row {
-    primary_key_col: Column,
+    /// The primary key columns, in the order they are declared in the table
+    key: Vec<Column>,
     cols: Vec<Column>,
 }
 
 impl Row {
-    pub fn new(primary_key_col: Column, cols: Vec<Column>) -> Row {
-        Row {
-            primary_key_col,
-            cols,
-        }
+    pub fn new(key: Vec<Column>, cols: Vec<Column>) -> Row {
+        Row { key, cols }
+    }
+
+    pub fn primary_key(&self) -> Vec<LiteralValue> {
+        self.key.iter().map(|col| col.data.clone()).collect()
+    }
+
+    pub fn key(&self) -> &[Column] {
+        &self.key
+    }
+
+    /// The columns outside the primary key
+    pub fn non_key(&self) -> &[Column] {
+        &self.cols
     }
 
     pub fn all_cols(&self) -> Vec<Column> {
-        self.cols
-            .clone()
-            .into_iter()
-            .chain([self.primary_key_col.clone()])
-            .collect()
+        self.cols.iter().chain(&self.key).cloned().collect()
     }
 
     pub fn cols(&self, names: &[String]) -> Option<Vec<LiteralValue>> {
-        let mut cols = Vec::new();
-        let all_col_names = self
-            .cols
-            .clone()
-            .into_iter()
-            .chain([self.primary_key_col.clone()]);
-        for name in names {
-            cols.push(all_col_names.clone().find(|col| col.name() == name)?.data);
-        }
-        Some(cols)
+        names
+            .iter()
+            .map(|name| {
+                self.cols
+                    .iter()
+                    .chain(&self.key)
+                    .find(|col| col.name() == name)
+                    .map(|col| col.data.clone())
+            })
+            .collect()
     }
 }
 
 impl PartialEq for Row {
     fn eq(&self, other: &Self) -> bool {
-        self.primary_key_col == other.primary_key_col
+        self.key == other.key
     }
 }
 
 impl PartialOrd for Row {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.primary_key_col.partial_cmp(&other.primary_key_col)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for Row {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.primary_key_col.cmp(&other.primary_key_col)
+        self.key.cmp(&other.key)
     }
 }
 
@@ -82,7 +90,7 @@ impl PartialEq for Column {
 
 impl PartialOrd for Column {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.data.partial_cmp(&other.data)
+        Some(self.cmp(other))
     }
 }
 
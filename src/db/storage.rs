@@ -0,0 +1,478 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Component, Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut, Bytes};
+
+use super::{
+    codec::{self, crc32},
+    Redo, Table,
+};
+
+const DATA_MAGIC: &[u8; 4] = b"SQDB";
+const LOG_MAGIC: &[u8; 4] = b"SQWL";
+
+/// Files of earlier versions laid tables out differently, and are refused
+const DATA_VERSION: u32 = 3;
+const LOG_VERSION: u32 = 2;
+
+const DATA_FILE: &str = "sequel.db";
+const LOG_FILE: &str = "sequel.wal";
+
+/// The directory, inside the data directory, that `BACKUP` and `COPY` read and write in
+const FILES_DIR: &str = "files";
+
+/// The log is folded into the data file once it grows past this many bytes
+const CHECKPOINT_SIZE: u64 = 16 << 20;
+
+/// A data directory holding a snapshot of every table and a write-ahead log of the changes
+/// made since.
+///
+/// The data file starts with a magic number, the format version, the sequence number of the
+/// last log record it includes, the length of the body and its CRC-32. The log starts with a
+/// magic number and version, followed by one record per statement: its length and CRC-32,
+/// then its sequence number and changes. A damaged data file is refused, while a damaged
+/// record can only be the tail of a write that never finished, so it is cut off.
+pub struct Storage {
+    dir: PathBuf,
+    log: Mutex<Log>,
+
+    #[cfg(test)]
+    crash: Mutex<Option<Crash>>,
+
+    #[cfg(test)]
+    faults: Mutex<Vec<Fault>>,
+}
+
+struct Log {
+    file: File,
+    len: u64,
+
+    /// Sequence number of the next record
+    next_lsn: u64,
+
+    /// Set when a failed write couldn't be cut off again, after which nothing more is logged
+    failed: bool,
+}
+
+/// Points at which a test can make storage stop as if the process had died
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Crash {
+    BeforeLogWrite,
+
+    /// Only half the record reaches the file
+    TornLogWrite,
+
+    /// Only half the record reaches the file, then the thread panics with the log held
+    PanicInLogWrite,
+    BeforeLogSync,
+    AfterLogSync,
+    TornDataWrite,
+    BeforeDataRename,
+    BeforeLogTruncate,
+}
+
+/// Writes a test can make fail while the process carries on
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Fault {
+    LogSync,
+
+    /// Cutting a failed record off the log
+    LogTruncate,
+}
+
+impl Storage {
+    /// Uses `dir` for data, creating it if needed, and recovers the tables: the data file
+    /// is loaded and the log replayed on top of it. The recovered tables are then written
+    /// back to the data file, leaving the log empty.
+    pub fn open(dir: impl AsRef<Path>) -> Result<(Storage, HashMap<String, Table>)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create data directory {}", dir.display()))?;
+
+        let log_path = dir.join(LOG_FILE);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&log_path)
+            .with_context(|| format!("Failed to open {}", log_path.display()))?;
+        let (tables, last, valid_len) = recover(&dir)?;
+        file.set_len(valid_len)?;
+
+        let storage = Storage {
+            dir,
+            log: Mutex::new(Log {
+                file,
+                len: valid_len,
+                next_lsn: last + 1,
+                failed: false,
+            }),
+            #[cfg(test)]
+            crash: Mutex::new(None),
+            #[cfg(test)]
+            faults: Mutex::new(Vec::new()),
+        };
+        storage.checkpoint_tables(&mut storage.lock_log(), &tables)?;
+        Ok((storage, tables))
+    }
+
+    /// Commits a transaction that made `changes`: once `validate` agrees, they are logged,
+    /// then `publish` makes them visible. They are on disk when this returns, so the commit
+    /// can be acknowledged. Commits go through here one at a time, so the log has changes
+    /// in the order they were made. Once the log can't be trusted, every commit fails
+    /// until the storage is opened again.
+    pub fn commit(
+        &self,
+        changes: &[Redo],
+        validate: impl FnOnce() -> Result<()>,
+        publish: impl FnOnce(),
+    ) -> Result<()> {
+        let mut log = self.lock_log();
+        if log.failed {
+            bail!("The log could not be repaired after a failed write, restart to recover");
+        }
+        validate()?;
+        if !changes.is_empty() {
+            self.append(&mut log, changes)
+                .context("Failed to log changes")?;
+        }
+        publish();
+        if log.len > CHECKPOINT_SIZE {
+            // The commit is durable either way, and the next one tries again
+            let _ = self.checkpoint_log(&mut log);
+        }
+        Ok(())
+    }
+
+    /// Folds the log into the data file and empties it
+    pub fn checkpoint(&self) -> Result<()> {
+        let mut log = self.lock_log();
+        self.checkpoint_log(&mut log)
+    }
+
+    /// Where `name`, a file named by a client, is on the server: under `files` in the data
+    /// directory, which is created if needed. Absolute paths and `..` are refused, so
+    /// clients can't reach anything outside it.
+    pub fn file(&self, name: &str) -> Result<PathBuf> {
+        let path = Path::new(name);
+        if name.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            bail!(
+                "File '{}' must be a relative path inside the data directory's {} directory",
+                name,
+                FILES_DIR
+            );
+        }
+        let dir = self.dir.join(FILES_DIR);
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        Ok(dir.join(path))
+    }
+
+    /// Writes a copy of the committed tables to `path`, in the data file format. Commits
+    /// only wait while the files are read; the copy is built and written after.
+    pub fn backup(&self, path: impl AsRef<Path>) -> Result<()> {
+        let (data, log) = {
+            let _log = self.lock_log();
+            read_files(&self.dir)?
+        };
+        let (tables, last, _) = replay(&self.dir, data, log)?;
+        let path = path.as_ref();
+        write_atomic(path, &encode_data(last, &tables)?)
+            .with_context(|| format!("Failed to write backup {}", path.display()))
+    }
+
+    /// Replaces the data in `dir` by the backup at `backup`, which is checked first. The
+    /// log is emptied, as its changes came after a different snapshot.
+    pub fn restore(dir: impl AsRef<Path>, backup: impl AsRef<Path>) -> Result<()> {
+        let (dir, backup) = (dir.as_ref(), backup.as_ref());
+        let data = fs::read(backup)
+            .with_context(|| format!("Failed to read backup {}", backup.display()))?;
+        decode_data(Bytes::from(data.clone()))
+            .with_context(|| format!("Failed to load backup {}", backup.display()))?;
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create data directory {}", dir.display()))?;
+        write_atomic(&dir.join(DATA_FILE), &data)?;
+        match fs::remove_file(dir.join(LOG_FILE)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The tables are rebuilt from what is on disk rather than taken from memory, where
+    /// they can hold changes that haven't committed
+    fn checkpoint_log(&self, log: &mut Log) -> Result<()> {
+        let (tables, _, _) = recover(&self.dir)?;
+        self.checkpoint_tables(log, &tables)
+    }
+
+    fn append(&self, log: &mut Log, changes: &[Redo]) -> Result<()> {
+        let mut payload = Vec::new();
+        payload.put_u64_le(log.next_lsn);
+        payload.put_u32_le(changes.len() as u32);
+        for redo in changes {
+            codec::put_redo(&mut payload, redo)?;
+        }
+        let mut record = Vec::with_capacity(payload.len() + 8);
+        record.put_u32_le(payload.len() as u32);
+        record.put_u32_le(crc32(&payload));
+        record.put_slice(&payload);
+
+        self.crash_point(Crash::BeforeLogWrite)?;
+        if self.crashes_at(Crash::TornLogWrite) {
+            log.file.write_all(&record[..record.len() / 2])?;
+            bail!("Crashed at {:?}", Crash::TornLogWrite);
+        }
+        if self.crashes_at(Crash::PanicInLogWrite) {
+            log.file.write_all(&record[..record.len() / 2])?;
+            panic!("Crashed at {:?}", Crash::PanicInLogWrite);
+        }
+        // Later records must not end up behind a partial or unsynced one
+        if let Err(e) = log.file.write_all(&record) {
+            self.discard(log);
+            return Err(e.into());
+        }
+        self.crash_point(Crash::BeforeLogSync)?;
+        let synced = self
+            .fault(Fault::LogSync)
+            .and_then(|()| Ok(log.file.sync_data()?));
+        if let Err(e) = synced {
+            self.discard(log);
+            return Err(e);
+        }
+        log.len += record.len() as u64;
+        log.next_lsn += 1;
+        self.crash_point(Crash::AfterLogSync)
+    }
+
+    /// The log, cut back to the end of its last complete record if a thread panicked while
+    /// holding it, as it may have been writing one
+    fn lock_log(&self) -> MutexGuard<'_, Log> {
+        self.log.lock().unwrap_or_else(|poisoned| {
+            let mut log = poisoned.into_inner();
+            self.discard(&mut log);
+            self.log.clear_poison();
+            log
+        })
+    }
+
+    /// Cuts the log back to the end of its last complete record. If even that fails, what
+    /// the log holds is unknown, so it is marked failed.
+    fn discard(&self, log: &mut Log) {
+        let res = self.fault(Fault::LogTruncate).and_then(|()| {
+            log.file.set_len(log.len)?;
+            log.file.sync_data()?;
+            Ok(())
+        });
+        if res.is_err() {
+            log.failed = true;
+        }
+    }
+
+    /// The data file is replaced before the log is emptied. A crash in between leaves
+    /// records that are also in the data file, which the sequence number tells apart.
+    fn checkpoint_tables(&self, log: &mut Log, tables: &HashMap<String, Table>) -> Result<()> {
+        let path = self.dir.join(DATA_FILE);
+        let tmp = path.with_extension("tmp");
+        let data = encode_data(log.next_lsn - 1, tables)?;
+        let mut file = File::create(&tmp)?;
+        if self.crashes_at(Crash::TornDataWrite) {
+            file.write_all(&data[..data.len() / 2])?;
+            bail!("Crashed at {:?}", Crash::TornDataWrite);
+        }
+        file.write_all(&data)?;
+        file.sync_all()?;
+        self.crash_point(Crash::BeforeDataRename)?;
+        fs::rename(&tmp, &path)?;
+        File::open(&self.dir)?.sync_all()?;
+
+        self.crash_point(Crash::BeforeLogTruncate)?;
+        let res = (|| -> Result<()> {
+            log.file.set_len(0)?;
+            log.file.write_all(LOG_MAGIC)?;
+            log.file.write_all(&LOG_VERSION.to_le_bytes())?;
+            log.file.sync_all()?;
+            Ok(())
+        })();
+        match res {
+            Ok(()) => log.len = 8,
+            Err(_) => log.failed = true,
+        }
+        res
+    }
+
+    #[cfg(test)]
+    pub fn crash_at(&self, point: Option<Crash>) {
+        *self.crash.lock().unwrap() = point;
+    }
+
+    #[cfg(test)]
+    fn crashes_at(&self, point: Crash) -> bool {
+        *self.crash.lock().unwrap() == Some(point)
+    }
+
+    #[cfg(not(test))]
+    fn crashes_at(&self, _point: Crash) -> bool {
+        false
+    }
+
+    #[cfg(test)]
+    pub fn fail_at(&self, faults: &[Fault]) {
+        *self.faults.lock().unwrap() = faults.to_vec();
+    }
+
+    #[cfg(test)]
+    fn fault(&self, fault: Fault) -> Result<()> {
+        if self.faults.lock().unwrap().contains(&fault) {
+            bail!("Failed at {:?}", fault);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(test))]
+    fn fault(&self, _fault: Fault) -> Result<()> {
+        Ok(())
+    }
+
+    fn crash_point(&self, point: Crash) -> Result<()> {
+        if self.crashes_at(point) {
+            bail!("Crashed at {:?}", point);
+        }
+        Ok(())
+    }
+}
+
+/// The tables in the data file with the log replayed on top, the sequence number of the last
+/// record included, and the length of the log up to the last complete record
+fn recover(dir: &Path) -> Result<(HashMap<String, Table>, u64, u64)> {
+    let (data, log) = read_files(dir)?;
+    replay(dir, data, log)
+}
+
+/// The contents of the data file, if there is one, and of the log
+fn read_files(dir: &Path) -> Result<(Option<Bytes>, Bytes)> {
+    let data_path = dir.join(DATA_FILE);
+    let data = match fs::read(&data_path) {
+        Ok(data) => Some(Bytes::from(data)),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", data_path.display())),
+    };
+    let log_path = dir.join(LOG_FILE);
+    let log =
+        fs::read(&log_path).with_context(|| format!("Failed to read {}", log_path.display()))?;
+    Ok((data, Bytes::from(log)))
+}
+
+/// Like `recover`, from the contents of the files in `dir`
+fn replay(
+    dir: &Path,
+    data: Option<Bytes>,
+    log: Bytes,
+) -> Result<(HashMap<String, Table>, u64, u64)> {
+    let (lsn, mut tables) = match data {
+        Some(data) => decode_data(data)
+            .with_context(|| format!("Failed to load {}", dir.join(DATA_FILE).display()))?,
+        None => (0, HashMap::new()),
+    };
+    let (records, valid_len) = read_log(log)
+        .with_context(|| format!("Failed to read {}", dir.join(LOG_FILE).display()))?;
+    let mut last = lsn;
+    for (record_lsn, changes) in records {
+        // Records already in the data file are left over from an unfinished checkpoint
+        if record_lsn <= lsn {
+            continue;
+        }
+        for redo in &changes {
+            redo.apply(&mut tables)
+                .with_context(|| format!("Failed to replay log record {}", record_lsn))?;
+        }
+        last = record_lsn;
+    }
+    Ok((tables, last, valid_len))
+}
+
+/// Writes `data` next to `path` and then moves it there, so `path` never holds half of it
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn encode_data(lsn: u64, tables: &HashMap<String, Table>) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    codec::put_tables(&mut body, tables)?;
+
+    let mut buf = Vec::with_capacity(body.len() + 28);
+    buf.put_slice(DATA_MAGIC);
+    buf.put_u32_le(DATA_VERSION);
+    buf.put_u64_le(lsn);
+    buf.put_u64_le(body.len() as u64);
+    buf.put_u32_le(crc32(&body));
+    buf.put_slice(&body);
+    Ok(buf)
+}
+
+/// The tables, and the sequence number of the last log record included in them
+fn decode_data(mut buf: Bytes) -> Result<(u64, HashMap<String, Table>)> {
+    if buf.remaining() < 8 || &buf[..4] != DATA_MAGIC {
+        bail!("Not a data file");
+    }
+    buf.advance(4);
+    let version = buf.get_u32_le();
+    if version != DATA_VERSION {
+        bail!("Unsupported data file version {}", version);
+    }
+    let lsn = codec::get_u64(&mut buf)?;
+    let len = codec::get_u64(&mut buf)?;
+    let checksum = codec::get_u32(&mut buf)?;
+    if buf.remaining() as u64 != len {
+        bail!("Data file is truncated");
+    }
+    if crc32(&buf) != checksum {
+        bail!("Data file checksum mismatch");
+    }
+    Ok((lsn, codec::get_tables(&mut buf)?))
+}
+
+/// The complete records in the log, with the length of the log up to the last of them
+#[allow(clippy::type_complexity)]
+fn read_log(mut buf: Bytes) -> Result<(Vec<(u64, Vec<Redo>)>, u64)> {
+    let total = buf.len();
+    if total < 8 {
+        // Nothing, or a header that was never finished
+        return Ok((Vec::new(), 0));
+    }
+    if &buf[..4] != LOG_MAGIC {
+        bail!("Not a log file");
+    }
+    buf.advance(4);
+    let version = buf.get_u32_le();
+    if version != LOG_VERSION {
+        bail!("Unsupported log version {}", version);
+    }
+
+    let mut records = Vec::new();
+    while buf.remaining() >= 8 {
+        let len = u32::from_le_bytes(buf[..4].try_into()?) as usize;
+        let checksum = u32::from_le_bytes(buf[4..8].try_into()?);
+        if buf.remaining() - 8 < len || crc32(&buf[8..8 + len]) != checksum {
+            break;
+        }
+        buf.advance(8);
+        let mut payload = buf.split_to(len);
+        let lsn = codec::get_u64(&mut payload)?;
+        let changes = (0..codec::get_u32(&mut payload)?)
+            .map(|_| codec::get_redo(&mut payload))
+            .collect::<Result<Vec<_>>>()?;
+        records.push((lsn, changes));
+    }
+    Ok((records, (total - buf.remaining()) as u64))
+}
@@ -1,15 +1,22 @@
-use ordered_float::OrderedFloat;
-use std::collections::BTreeSet;
+use std::ops::Bound;
 
 use anyhow::{anyhow, bail};
 
-use crate::{parse::LiteralValue, Ty};
+use crate::parse::{Expr, LiteralValue};
 
-use super::{row::Row, Column, ColumnHeader};
+use super::{heap::Heap, row::Row, Column, ColumnHeader, DefaultOpt, ForeignKey, Index};
 
 pub struct Table {
     col_headers: Vec<ColumnHeader>,
-    rows: BTreeSet<Row>,
+    rows: Heap,
+    indexes: Vec<Index>,
+    foreign_keys: Vec<ForeignKey>,
+
+    /// Name of the `PRIMARY KEY` constraint, if it was given one
+    pk_name: Option<String>,
+
+    /// Conditions on whole rows, with the name of their constraint if it has one
+    checks: Vec<(Option<String>, Expr)>,
 }
 
 impl TryFrom<Vec<ColumnHeader>> for Table {
@@ -24,33 +31,268 @@ impl TryFrom<Vec<ColumnHeader>> for Table {
                 bail!("Cannot have duplicate columns");
             }
         }
-        match cols.iter().filter(|col| col.is_primary()).count() {
-            0 => {
-                // If no primary key, create hidden auto incrementing
-                let mut col_headers = cols;
-                col_headers.push(ColumnHeader::new_hidden());
-                Ok(Table {
-                    col_headers,
-                    rows: BTreeSet::new(),
-                })
-            }
-            1 => Ok(Table {
-                col_headers: cols,
-                rows: BTreeSet::new(),
-            }),
-            n => bail!("Expected 1 primary key, found {}", n),
+        // The rows are ordered by primary key, so only other unique columns need an index
+        let indexes = cols
+            .iter()
+            .filter(|col| col.unique() && !col.is_primary())
+            .map(|col| Index::for_constraint(None, vec![col.name().to_string()]))
+            .collect();
+        let mut col_headers = cols;
+        if !col_headers.iter().any(|col| col.is_primary()) {
+            // If no primary key, create hidden auto incrementing
+            col_headers.push(ColumnHeader::new_hidden());
         }
+        Ok(Table {
+            col_headers,
+            rows: Heap::new(),
+            indexes,
+            foreign_keys: Vec::new(),
+            pk_name: None,
+            checks: Vec::new(),
+        })
     }
 }
 
 impl Table {
-    pub fn rows(&self) -> &BTreeSet<Row> {
+    /// Every row, ordered by primary key
+    pub fn rows(&self) -> impl Iterator<Item = anyhow::Result<Row>> + '_ {
         // TODO: don't include hidden?
-        &self.rows
+        self.rows.iter()
     }
 
-    pub fn append(&mut self, cols: Vec<Column>) -> anyhow::Result<()> {
-        for col in &cols {
+    /// A copy of the table, with its rows on pages of their own
+    pub fn try_clone(&self) -> anyhow::Result<Table> {
+        Ok(Table {
+            col_headers: self.col_headers.clone(),
+            rows: self.rows.try_clone()?,
+            indexes: self.indexes.clone(),
+            foreign_keys: self.foreign_keys.clone(),
+            pk_name: self.pk_name.clone(),
+            checks: self.checks.clone(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.len() == 0
+    }
+
+    /// The row with primary key `pk`
+    pub fn get(&self, pk: &[LiteralValue]) -> anyhow::Result<Option<Row>> {
+        self.rows.get(pk)
+    }
+
+    /// Adds a row, returning its primary key
+    pub fn append(&mut self, cols: Vec<Column>) -> anyhow::Result<Vec<LiteralValue>> {
+        let row = self.build_row(cols)?;
+        if self.rows.contains(&row.primary_key()) {
+            bail!(
+                "Duplicate primary key ({})=({})",
+                self.primary_key().join(", "),
+                row.primary_key()
+                    .iter()
+                    .map(|val| val.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        for index in &self.indexes {
+            index.check(&row)?;
+        }
+        for index in &mut self.indexes {
+            index.insert(&row);
+        }
+        let pk = row.primary_key();
+        if let Err(e) = self.rows.insert(row.clone()) {
+            for index in &mut self.indexes {
+                index.remove(&row);
+            }
+            return Err(e);
+        }
+        Ok(pk)
+    }
+
+    /// Adds every row or, if one of them is rejected, none
+    pub fn append_all(&mut self, rows: Vec<Vec<Column>>) -> anyhow::Result<()> {
+        let mut added = Vec::new();
+        for cols in rows {
+            match self.append(cols) {
+                Ok(pk) => added.push(pk),
+                Err(e) => {
+                    for pk in added {
+                        self.delete(&pk)?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the row with primary key `pk`, returning it
+    pub fn delete(&mut self, pk: &[LiteralValue]) -> anyhow::Result<Option<Row>> {
+        let Some(row) = self.rows.remove(pk)? else {
+            return Ok(None);
+        };
+        for index in &mut self.indexes {
+            index.remove(&row);
+        }
+        Ok(Some(row))
+    }
+
+    /// Replaces the row with primary key `pk` by one made of `cols`, returning the new
+    /// primary key. If the new row is rejected the old one is kept.
+    pub fn update(
+        &mut self,
+        pk: &[LiteralValue],
+        cols: Vec<Column>,
+    ) -> anyhow::Result<Vec<LiteralValue>> {
+        let old = self
+            .delete(pk)?
+            .ok_or_else(|| anyhow!("No row with primary key {:?}", pk))?;
+        match self.append(cols) {
+            Ok(pk) => Ok(pk),
+            Err(e) => {
+                for index in &mut self.indexes {
+                    index.insert(&old);
+                }
+                self.rows.insert(old)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Primary keys of the rows whose `columns` hold `values`. The primary key or an index
+    /// over the same columns is used if there is one.
+    pub fn lookup(
+        &self,
+        columns: &[String],
+        values: &[LiteralValue],
+    ) -> anyhow::Result<Vec<Vec<LiteralValue>>> {
+        if let Some(pk) = reorder(columns, values, &self.primary_key()) {
+            return Ok(self.rows.contains(&pk).then_some(pk).into_iter().collect());
+        }
+        for index in &self.indexes {
+            if let Some(key) = reorder(columns, values, index.columns()) {
+                return Ok(index.get(&key));
+            }
+        }
+        let mut pks = Vec::new();
+        for row in self.rows() {
+            let row = row?;
+            if row.cols(columns).as_deref() == Some(values) {
+                pks.push(row.primary_key());
+            }
+        }
+        Ok(pks)
+    }
+
+    /// Whether each combination of values in `columns` can only appear in one row
+    pub fn is_unique(&self, columns: &[String]) -> bool {
+        let values = vec![LiteralValue::Null; columns.len()];
+        reorder(columns, &values, &self.primary_key()).is_some()
+            || self
+                .indexes
+                .iter()
+                .any(|index| index.unique() && reorder(columns, &values, index.columns()).is_some())
+    }
+
+    pub fn pk_name(&self) -> Option<&str> {
+        self.pk_name.as_deref()
+    }
+
+    pub fn set_pk_name(&mut self, name: Option<String>) {
+        self.pk_name = name;
+    }
+
+    pub fn checks(&self) -> &[(Option<String>, Expr)] {
+        self.checks.as_ref()
+    }
+
+    /// Adds a `CHECK` on whole rows. Like for columns, only a false result fails it.
+    pub fn add_check(&mut self, name: Option<String>, expr: Expr) {
+        self.checks.push((name, expr));
+    }
+
+    /// Adds a `UNIQUE` constraint over `columns`, checking the existing rows
+    pub fn add_unique(&mut self, name: Option<String>, columns: Vec<String>) -> anyhow::Result<()> {
+        self.add_index(Index::for_constraint(name, columns))
+    }
+
+    pub fn foreign_keys(&self) -> &[ForeignKey] {
+        self.foreign_keys.as_ref()
+    }
+
+    pub fn add_foreign_key(&mut self, foreign_key: ForeignKey) {
+        self.foreign_keys.push(foreign_key);
+    }
+
+    pub fn indexes(&self) -> &[Index] {
+        self.indexes.as_ref()
+    }
+
+    pub fn index(&self, name: &str) -> Option<&Index> {
+        self.indexes.iter().find(|index| index.name() == name)
+    }
+
+    /// Builds an index over the table's current rows
+    pub fn create_index(
+        &mut self,
+        name: String,
+        columns: Vec<String>,
+        unique: bool,
+    ) -> anyhow::Result<()> {
+        if let Some(col) = columns
+            .iter()
+            .find(|col| !self.col_headers.iter().any(|header| header.name() == *col))
+        {
+            bail!("Column {} not found", col);
+        }
+        self.add_index(Index::new(name, columns, unique))
+    }
+
+    fn add_index(&mut self, mut index: Index) -> anyhow::Result<()> {
+        for row in self.rows.iter() {
+            let row = row?;
+            index.check(&row)?;
+            index.insert(&row);
+        }
+        self.indexes.push(index);
+        Ok(())
+    }
+
+    /// The index called `name`, if there was one to drop. Indexes backing a `UNIQUE`
+    /// constraint can't be dropped.
+    pub fn drop_index(&mut self, name: &str) -> anyhow::Result<Option<Index>> {
+        match self.indexes.iter().position(|index| index.name() == name) {
+            Some(i) if self.indexes[i].constraint() => bail!(
+                "Cannot drop index {} because the UNIQUE constraint on {} uses it",
+                name,
+                self.indexes[i].columns().join(", ")
+            ),
+            Some(i) => Ok(Some(self.indexes.remove(i))),
+            None => Ok(None),
+        }
+    }
+
+    /// Primary keys of the rows whose value in the first column of index `name` lies
+    /// between `lower` and `upper`
+    pub fn index_range(
+        &self,
+        name: &str,
+        lower: Bound<&LiteralValue>,
+        upper: Bound<&LiteralValue>,
+    ) -> Option<Vec<Vec<LiteralValue>>> {
+        Some(self.index(name)?.range(lower, upper))
+    }
+
+    /// Checks `cols` against the column definitions, filling in the primary key if it has
+    /// a default
+    fn build_row(&mut self, mut cols: Vec<Column>) -> anyhow::Result<Row> {
+        for col in &mut cols {
             let header = self
                 .col_headers
                 .iter()
@@ -59,85 +301,78 @@ impl Table {
 
             // Check null
             if let LiteralValue::Null = col.data() {
-                if header.not_null() {
+                if header.not_null() || header.is_primary() {
                     bail!("Column {} non-nullable", header.name());
                 }
                 continue;
             }
 
-            // Check unique
-            if header.unique()
-                && self
-                    .rows()
-                    .iter()
-                    .any(|row| row.cols(&[header.name().to_string()]).unwrap()[0] == *col.data())
-            {
-                bail!("Col {} must be unique", header.name());
-            }
-
-            // Check type
-            match header.ty() {
-                Ty::String => {
-                    if !matches!(col.data(), LiteralValue::String(_)) {
-                        bail!("Expected string")
-                    }
-                }
-                Ty::Number => {
-                    if !matches!(col.data(), LiteralValue::Number(_)) {
-                        bail!("Expected number")
-                    }
-                }
-                Ty::Bool => {
-                    if !matches!(col.data(), LiteralValue::Bool(_)) {
-                        bail!("Expected bool")
-                    }
+            // Check type, storing the value as the column's type
+            let data = col.data().clone().coerce(header.ty())?;
+            *col = Column::new(data, col.name().to_string());
+        }
+        for col in &cols {
+            let Some(header) = self.col_headers.iter().find(|h| h.name() == col.name()) else {
+                continue;
+            };
+            // `CHECK` condition; like in SQL, only a false result fails it, not `NULL`
+            if let Some(expr) = header.check() {
+                if expr.eval(&cols)?.truth()? == Some(false) {
+                    bail!("Check condition on {} failed", header.name())
                 }
             }
-
-            // `CHECK` condition
-            if let Some(expr) = header.check() {
-                match expr.eval(&cols)? {
-                    LiteralValue::Bool(b) => {
-                        if !b {
-                            bail!("Check condition on {} failed", header.name())
-                        }
-                    }
-                    _ => unreachable!(),
+        }
+        for (name, expr) in &self.checks {
+            if expr.eval(&cols)?.truth()? == Some(false) {
+                match name {
+                    Some(name) => bail!("Check constraint {} failed", name),
+                    None => bail!("Check condition {} failed", expr),
                 }
             }
         }
-        let (primary_col, cols): (Vec<_>, Vec<_>) = cols
+
+        let pk = self.primary_key();
+        let (mut key, cols): (Vec<_>, Vec<_>) = cols
             .into_iter()
-            .partition(|col| col.name() == self.primary_key().name());
-        match &primary_col[..] {
-            [] => {
-                let val =
-                    LiteralValue::Number(OrderedFloat(self.primary_key_mut().inc().ok_or_else(
-                        || anyhow!("Must specify primary key if it doesn't have default"),
-                    )? as f64));
-                self.rows
-                    .insert(Row::new(Column::new(val, "ID".into()), cols));
-            }
-            [primary_col] => {
-                self.rows.insert(Row::new(primary_col.clone(), cols));
-            }
-            _ => panic!(),
-        };
-        Ok(())
+            .partition(|col| pk.iter().any(|name| name == col.name()));
+        if key.is_empty() {
+            // Only a lone primary key column can fill itself in
+            let header = match &pk[..] {
+                [name] => self.col_headers.iter_mut().find(|col| col.name() == name),
+                _ => None,
+            };
+            let header = header
+                .filter(|header| matches!(header.default(), DefaultOpt::Incrementing(_)))
+                .ok_or_else(|| anyhow!("Must specify primary key if it doesn't have default"))?;
+            key.push(Column::new(LiteralValue::Int(header.inc()?), pk[0].clone()));
+        }
+        if key.len() != pk.len() {
+            bail!("Must specify every primary key column");
+        }
+        key.sort_by_key(|col| pk.iter().position(|name| name == col.name()));
+        Ok(Row::new(key, cols))
     }
 
-    fn primary_key(&self) -> &ColumnHeader {
+    /// Names of the primary key columns, in column order
+    pub fn primary_key(&self) -> Vec<String> {
         self.col_headers
             .iter()
-            .find(|col| col.is_primary())
-            .unwrap()
+            .filter(|col| col.is_primary())
+            .map(|col| col.name().to_string())
+            .collect()
     }
 
-    fn primary_key_mut(&mut self) -> &mut ColumnHeader {
-        self.col_headers
-            .iter_mut()
-            .find(|col| col.is_primary())
-            .unwrap()
+    /// Moves auto-incrementing columns past the values in `cols`
+    pub fn advance_counters(&mut self, cols: &[Column]) {
+        for col in cols {
+            if let Some(header) = self
+                .col_headers
+                .iter_mut()
+                .find(|header| header.name() == col.name())
+            {
+                header.advance_past(col.data());
+            }
+        }
     }
 
     pub fn col_headers(&self) -> &[ColumnHeader] {
@@ -158,3 +393,21 @@ impl Table {
             .filter(|col| !col.is_hidden())
     }
 }
+
+/// `values` of `columns`, rearranged to follow `target` if both name the same columns
+fn reorder(
+    columns: &[String],
+    values: &[LiteralValue],
+    target: &[String],
+) -> Option<Vec<LiteralValue>> {
+    if columns.len() != target.len() {
+        return None;
+    }
+    target
+        .iter()
+        .map(|name| {
+            let i = columns.iter().position(|col| col == name)?;
+            Some(values[i].clone())
+        })
+        .collect()
+}
@@ -2,7 +2,8 @@ mod command;
 pub mod connection;
 mod db;
 mod parse;
+mod plan;
 
-pub use command::run_cmd;
-pub use db::Db;
-pub use parse::Ty;
+pub use command::{run_cmd, Session};
+pub use db::{Db, Storage};
+pub use parse::{split_script, LiteralValue, Span, Ty};
@@ -1,86 +1,798 @@
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    hash::{Hash, Hasher},
+};
+
 use anyhow::{anyhow, bail, Ok, Result};
 use bytes::Bytes;
 use ordered_float::OrderedFloat;
 
-use crate::db::Column;
+use crate::{db::Column, plan};
 
-use super::token::Token;
+use super::{
+    decimal::Decimal,
+    temporal::{self, Interval},
+    token::{Spanned, Token},
+};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Command {
-    Select {
-        key: Key,
-        table: Token,
-    },
+    Select(Box<Select>),
     Insert {
-        table: Token,
+        table: Spanned<Token>,
         cols: Tokens,
-        rows: Vec<Vec<LiteralValue>>,
+        rows: Vec<Vec<Expr>>,
     },
     CreateTable {
-        name: Token,
+        name: Spanned<Token>,
         def: TableDef,
     },
+
+    /// UPDATE table SET col = expr, ... [WHERE filter]
+    Update {
+        table: Spanned<Token>,
+        set: Vec<(Spanned<Token>, Expr)>,
+        filter: Option<Expr>,
+    },
+
+    /// DELETE FROM table [WHERE filter]
+    Delete {
+        table: Spanned<Token>,
+        filter: Option<Expr>,
+    },
+
+    /// CREATE [UNIQUE] INDEX name ON table (cols)
+    CreateIndex {
+        name: Spanned<Token>,
+        table: Spanned<Token>,
+        cols: Vec<Spanned<Token>>,
+        unique: bool,
+    },
+
+    /// DROP INDEX name
+    DropIndex {
+        name: Spanned<Token>,
+    },
+
+    /// EXPLAIN [ANALYZE] query
+    Explain {
+        analyze: bool,
+        cmd: Box<Command>,
+    },
+
+    /// PREPARE name AS command
+    Prepare {
+        name: Spanned<Token>,
+        cmd: Box<Command>,
+    },
+
+    /// EXECUTE name (param, ...)
+    Execute {
+        name: Spanned<Token>,
+        params: Vec<Expr>,
+    },
+
+    /// DEALLOCATE name
+    Deallocate {
+        name: Spanned<Token>,
+    },
+
+    /// BEGIN [TRANSACTION] [ISOLATION LEVEL level]
+    Begin {
+        isolation: Isolation,
+    },
+    Commit,
+    Rollback,
+
+    /// SAVEPOINT name
+    Savepoint {
+        name: Spanned<Token>,
+    },
+
+    /// ROLLBACK TO [SAVEPOINT] name
+    RollbackTo {
+        name: Spanned<Token>,
+    },
+
+    /// RELEASE [SAVEPOINT] name
+    Release {
+        name: Spanned<Token>,
+    },
+
+    /// Folds the write-ahead log into the data file
+    Checkpoint,
+
+    /// BACKUP TO 'path'
+    Backup {
+        path: String,
+    },
+
+    /// A script of statements that recreate every table
+    Dump,
+
+    /// COPY table [(cols)] FROM 'path' | STDIN [[WITH] (options)]
+    CopyFrom {
+        table: Spanned<Token>,
+        cols: Tokens,
+        source: CopyTarget,
+        options: CopyOptions,
+    },
+
+    /// COPY (query) TO 'path' | STDOUT [[WITH] (options)], where COPY table [(cols)] TO
+    /// stands for the query of those columns
+    CopyTo {
+        query: Box<Select>,
+        target: CopyTarget,
+        options: CopyOptions,
+    },
+}
+
+/// Where `COPY` reads or writes its rows
+#[derive(Debug, PartialEq, Clone)]
+pub enum CopyTarget {
+    /// A file on the server
+    File(String),
+
+    /// `STDIN` or `STDOUT`: the rows go over the connection
+    Client,
+
+    /// What the client sent after `COPY ... FROM STDIN`
+    Data(String),
+}
+
+/// How a file is read or written by `COPY`
+#[derive(Debug, PartialEq, Clone)]
+pub struct CopyOptions {
+    pub delimiter: char,
+
+    /// Whether the first line names the columns, and is skipped when reading
+    pub header: bool,
+
+    /// Stands for `NULL` when it makes up a whole field without quotes
+    pub null: String,
+    pub quote: char,
+    pub on_error: OnError,
+    pub format: CopyFormat,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions {
+            delimiter: ',',
+            header: false,
+            null: String::new(),
+            quote: '"',
+            on_error: OnError::default(),
+            format: CopyFormat::default(),
+        }
+    }
+}
+
+/// What `COPY ... FROM` does with rows that can't be added
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OnError {
+    /// Fail on the first one, adding none of the rows
+    #[default]
+    Abort,
+
+    /// Add the others, and answer with the line and error of each one
+    Report,
+}
+
+/// How rows are written out by `COPY`
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum CopyFormat {
+    #[default]
+    Csv,
+
+    /// An array of objects, each keyed by column name
+    Json,
+
+    /// One object per line
+    Ndjson,
+}
+
+impl Command {
+    /// Copy of the command with `params` substituted for its placeholders
+    pub fn bind(&self, params: &[LiteralValue]) -> Result<Command> {
+        let mut cmd = self.clone();
+        let expected = cmd
+            .exprs_mut()
+            .into_iter()
+            .map(|expr| expr.param_count())
+            .max()
+            .unwrap_or(0);
+        if params.len() != expected {
+            bail!("Expected {} parameters, got {}", expected, params.len());
+        }
+        for expr in cmd.exprs_mut() {
+            expr.bind(params);
+        }
+        Ok(cmd)
+    }
+
+    fn exprs_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Command::Insert { rows, .. } => rows.iter_mut().flatten().collect(),
+            Command::CreateTable {
+                def: TableDef::Cols { cols, constraints },
+                ..
+            } => {
+                cols.iter_mut()
+                    .flat_map(|col_decl| col_decl.constraints.iter_mut())
+                    .filter_map(|constraint| match constraint {
+                        Constraint::Check(expr) => Some(expr),
+                        _ => None,
+                    })
+                    .chain(constraints.iter_mut().filter_map(
+                        |constraint| match &mut constraint.kind {
+                            TableConstraintKind::Check(expr) => Some(expr),
+                            _ => None,
+                        },
+                    ))
+                    .collect()
+            }
+            Command::Execute { params, .. } => params.iter_mut().collect(),
+            Command::Explain { cmd, .. } => cmd.exprs_mut(),
+            Command::Select(select) => select.exprs_mut(),
+            Command::Update { set, filter, .. } => set
+                .iter_mut()
+                .map(|(_, expr)| expr)
+                .chain(filter.as_mut())
+                .collect(),
+            Command::Delete { filter, .. } => filter.iter_mut().collect(),
+            Command::CreateTable {
+                def: TableDef::As(cmd),
+                ..
+            } => cmd.exprs_mut(),
+            Command::Prepare { .. }
+            | Command::Deallocate { .. }
+            | Command::CreateIndex { .. }
+            | Command::DropIndex { .. }
+            | Command::Begin { .. }
+            | Command::Commit
+            | Command::Rollback
+            | Command::Savepoint { .. }
+            | Command::RollbackTo { .. }
+            | Command::Release { .. }
+            | Command::Checkpoint
+            | Command::Backup { .. }
+            | Command::Dump
+            | Command::CopyFrom { .. } => Vec::new(),
+            Command::CopyTo { query, .. } => query.exprs_mut(),
+        }
+    }
+}
+
+/// SELECT [DISTINCT] items [FROM from] [WHERE filter] [GROUP BY exprs] [HAVING having]
+/// [ORDER BY order_by] [LIMIT limit] [OFFSET offset] [FOR UPDATE]
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Select {
+    pub distinct: bool,
+    pub items: Vec<SelectItem>,
+    pub from: Option<FromClause>,
+    pub filter: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    pub order_by: Vec<OrderBy>,
+    pub limit: Option<Expr>,
+    pub offset: Option<Expr>,
+
+    /// Whether the rows selected are locked until the transaction ends
+    pub for_update: bool,
+}
+
+impl Select {
+    /// Names of the tables in FROM
+    pub fn tables(&self) -> Vec<String> {
+        self.from
+            .iter()
+            .flat_map(|from| {
+                std::iter::once(&from.table).chain(from.joins.iter().map(|join| &join.table))
+            })
+            .filter_map(|table| table.name.ident().cloned())
+            .collect()
+    }
+
+    fn exprs_mut(&mut self) -> Vec<&mut Expr> {
+        let mut exprs: Vec<&mut Expr> = self
+            .items
+            .iter_mut()
+            .filter_map(|item| match item {
+                SelectItem::Expr { expr, .. } => Some(expr),
+                SelectItem::Wildcard(_) => None,
+            })
+            .collect();
+        if let Some(from) = &mut self.from {
+            exprs.extend(from.joins.iter_mut().filter_map(|join| join.on.as_mut()));
+        }
+        exprs.extend(self.filter.as_mut());
+        exprs.extend(self.group_by.iter_mut());
+        exprs.extend(self.having.as_mut());
+        exprs.extend(self.order_by.iter_mut().map(|order| &mut order.expr));
+        exprs.extend(self.limit.as_mut());
+        exprs.extend(self.offset.as_mut());
+        exprs
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SelectItem {
+    /// `*`, or `t.*` for the columns of just one table
+    Wildcard(Option<Spanned<Token>>),
+    Expr {
+        expr: Expr,
+        alias: Option<Spanned<Token>>,
+    },
+}
+
+/// A table followed by any tables joined to it, in order
+#[derive(Debug, PartialEq, Clone)]
+pub struct FromClause {
+    pub table: TableRef,
+    pub joins: Vec<Join>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TableRef {
+    pub name: Spanned<Token>,
+    pub alias: Option<Spanned<Token>>,
+}
+
+/// `JOIN table ON cond`, or a cross join (`, table` or `CROSS JOIN table`) without `on`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Join {
+    pub table: TableRef,
+    pub on: Option<Expr>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct OrderBy {
+    pub expr: Expr,
+    pub desc: bool,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Expr {
+    Literal(LiteralValue),
+    Ident(Spanned<Token>),
+
+    /// table.column
+    Qualified {
+        table: Spanned<Token>,
+        col: Spanned<Token>,
+    },
+
+    /// `$n`, or the nth `?` (1-based)
+    Param(usize),
+    Unary {
+        op: Token,
+        expr: Box<Expr>,
+    },
     Binary {
-        left: Token,
+        left: Box<Expr>,
         op: Token,
-        right: Token,
+        right: Box<Expr>,
+    },
+
+    /// expr IS [NOT] NULL
+    IsNull {
+        expr: Box<Expr>,
+        negated: bool,
+    },
+
+    /// name(args), or name(*) when `star` is set, with no args
+    Function {
+        name: Spanned<Token>,
+        args: Vec<Expr>,
+        star: bool,
     },
 }
 
 impl Expr {
     pub fn eval(&self, env: &[Column]) -> Result<LiteralValue> {
         match self {
+            Expr::Literal(val) => Ok(val.clone()),
+            Expr::Ident(ident) => {
+                let ident = ident.ident().ok_or_else(|| anyhow!("Internal error"))?;
+                Ok(env
+                    .iter()
+                    .find(|item| item.name() == ident)
+                    .ok_or_else(|| anyhow!("Invalid identifier {}", ident))?
+                    .data()
+                    .clone())
+            }
+            Expr::Qualified { table, col } => {
+                bail!("Invalid identifier {}.{}", table.node, col.node)
+            }
+            Expr::Param(n) => bail!("No value supplied for parameter ${}", n),
+            Expr::Unary { op, expr } => expr.eval(env)?.unary(op),
+            Expr::Binary { left, op, right } => left.eval(env)?.binary(op, &right.eval(env)?),
+            Expr::IsNull { expr, negated } => Ok(LiteralValue::Bool(
+                (expr.eval(env)? == LiteralValue::Null) != *negated,
+            )),
+            Expr::Function { name, args, .. } => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(env))
+                    .collect::<Result<Vec<_>>>()?;
+                plan::call(&name.node.to_string().to_lowercase(), &args)
+            }
+        }
+    }
+
+    fn param_count(&self) -> usize {
+        match self {
+            Expr::Param(n) => *n,
+            Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => expr.param_count(),
+            Expr::Binary { left, right, .. } => left.param_count().max(right.param_count()),
+            Expr::Function { args, .. } => args.iter().map(Expr::param_count).max().unwrap_or(0),
+            Expr::Literal(_) | Expr::Ident(_) | Expr::Qualified { .. } => 0,
+        }
+    }
+
+    /// Replaces placeholders with their values; `params` must cover every index
+    fn bind(&mut self, params: &[LiteralValue]) {
+        match self {
+            Expr::Param(n) => *self = Expr::Literal(params[*n - 1].clone()),
+            Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => expr.bind(params),
+            Expr::Binary { left, right, .. } => {
+                left.bind(params);
+                right.bind(params);
+            }
+            Expr::Function { args, .. } => args.iter_mut().for_each(|arg| arg.bind(params)),
+            Expr::Literal(_) | Expr::Ident(_) | Expr::Qualified { .. } => {}
+        }
+    }
+}
+
+/// SQL-ish rendering, used to name unaliased result columns
+impl Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Literal(val) => write!(f, "{}", val),
+            Expr::Ident(ident) => write!(f, "{}", ident.node),
+            Expr::Qualified { table, col } => write!(f, "{}.{}", table.node, col.node),
+            Expr::Param(n) => write!(f, "${}", n),
+            Expr::Unary {
+                op: Token::Not,
+                expr,
+            } => write!(f, "NOT {}", Operand(expr)),
+            Expr::Unary { op, expr } => write!(f, "{}{}", op, Operand(expr)),
             Expr::Binary { left, op, right } => {
-                let extract_value = |tok: &Token| -> Result<LiteralValue> {
-                    match tok {
-                        Token::Identifier(ident) => {
-                            Ok(env
-                                .iter()
-                                .find(|item| item.name() == ident)
-                                // TODO: check this when first creating table?
-                                .ok_or_else(|| anyhow!("Invalid identifier in check condition"))?
-                                .data()
-                                .clone())
-                        }
-                        Token::String(s) => Ok(LiteralValue::String(s.into())),
-                        Token::Number(n) => Ok(LiteralValue::Number(OrderedFloat(*n))),
-                        _ => bail!("expected identifier or literal value"),
-                    }
-                };
-                let left = extract_value(left)?;
-                let right = extract_value(right)?;
-                Ok(LiteralValue::Bool(match op {
-                    Token::GreaterThan => left.number()? > right.number()?,
-                    Token::GreaterEqual => left.number()? >= right.number()?,
-                    Token::Equal => left == right,
-                    Token::LessThan => left.number()? < right.number()?,
-                    Token::LessEqual => left.number()? <= right.number()?,
-                    _ => unreachable!(),
-                }))
+                write!(f, "{} {} {}", Operand(left), op, Operand(right))
+            }
+            Expr::IsNull { expr, negated } => write!(
+                f,
+                "{} IS {}NULL",
+                Operand(expr),
+                if *negated { "NOT " } else { "" }
+            ),
+            Expr::Function {
+                name, star: true, ..
+            } => write!(f, "{}(*)", name.node),
+            Expr::Function { name, args, .. } => {
+                let args: Vec<_> = args.iter().map(Expr::to_string).collect();
+                write!(f, "{}({})", name.node, args.join(", "))
             }
         }
     }
 }
 
-// TODO: real `Ty`s (varchar, etc.)
+/// Parenthesizes compound operands so nesting survives printing
+struct Operand<'a>(&'a Expr);
+
+impl Display for Operand<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Expr::Binary { .. } | Expr::IsNull { .. } => write!(f, "({})", self.0),
+            expr => write!(f, "{}", expr),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum Ty {
+    /// Text of any length
     String,
+
+    /// Text of at most this many characters
+    Varchar(u32),
+
+    /// Text of exactly this many characters, padded with spaces
+    Char(u32),
+
+    /// A floating-point number, like `Double`
     Number,
     Bool,
+
+    /// 16-bit integer
+    SmallInt,
+
+    /// 32-bit integer
+    Integer,
+
+    /// 64-bit integer
+    BigInt,
+
+    /// Single-precision floating point
+    Real,
+
+    /// Double-precision floating point
+    Double,
+
+    /// An exact decimal, with at most `precision` digits of which `scale` come after the
+    /// point; any number of either if `None`
+    Decimal(Option<(u32, u32)>),
+    Date,
+
+    /// Time of day, to the microsecond
+    Time,
+
+    /// Date and time, without a time zone
+    Timestamp,
+
+    /// A moment in time, kept in UTC
+    TimestampTz,
+    Interval,
+}
+
+impl Ty {
+    /// The range of an integer type
+    fn int_range(&self) -> Option<(i64, i64)> {
+        match self {
+            Ty::SmallInt => Some((i16::MIN.into(), i16::MAX.into())),
+            Ty::Integer => Some((i32::MIN.into(), i32::MAX.into())),
+            Ty::BigInt => Some((i64::MIN, i64::MAX)),
+            _ => None,
+        }
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            Ty::Number
+                | Ty::SmallInt
+                | Ty::Integer
+                | Ty::BigInt
+                | Ty::Real
+                | Ty::Double
+                | Ty::Decimal(_)
+        )
+    }
+
+    pub fn is_temporal(&self) -> bool {
+        matches!(
+            self,
+            Ty::Date | Ty::Time | Ty::Timestamp | Ty::TimestampTz | Ty::Interval
+        )
+    }
+
+    /// The type of `self op other` for an arithmetic operator. Dates, times and intervals
+    /// give what their arithmetic does; numbers are promoted.
+    pub fn arithmetic(&self, op: &Token, other: &Ty) -> Ty {
+        let int = |ty: &Ty| ty.int_range().is_some();
+        match (self, op, other) {
+            (Ty::Date, Token::Minus, Ty::Date) => Ty::Integer,
+            (Ty::Date, Token::Plus | Token::Minus, ty) | (ty, Token::Plus, Ty::Date) if int(ty) => {
+                Ty::Date
+            }
+            (Ty::Date, Token::Plus, Ty::Time | Ty::Interval)
+            | (Ty::Time | Ty::Interval, Token::Plus, Ty::Date)
+            | (Ty::Date, Token::Minus, Ty::Interval) => Ty::Timestamp,
+            (
+                Ty::Timestamp | Ty::TimestampTz | Ty::Time,
+                Token::Plus | Token::Minus,
+                Ty::Interval,
+            ) => self.clone(),
+            (Ty::Interval, Token::Plus, Ty::Timestamp | Ty::TimestampTz | Ty::Time) => {
+                other.clone()
+            }
+            (a, _, b) if a.is_temporal() || b.is_temporal() => Ty::Interval,
+            _ => self.promote(other),
+        }
+    }
+
+    /// The type of an arithmetic result with operands of types `self` and `other`: a float
+    /// if either is one, then a decimal if either is one, the wider integer if both are
+    /// integers
+    pub fn promote(&self, other: &Ty) -> Ty {
+        match (self, other) {
+            (Ty::Decimal(_), Ty::Number) | (Ty::Number, Ty::Decimal(_)) => return Ty::Number,
+            (Ty::Decimal(_), Ty::Real | Ty::Double) | (Ty::Real | Ty::Double, Ty::Decimal(_)) => {
+                return Ty::Double
+            }
+            (Ty::Decimal(_), _) | (_, Ty::Decimal(_)) => return Ty::Decimal(None),
+            _ => {}
+        }
+        let rank = |ty: &Ty| match ty {
+            Ty::SmallInt => 0,
+            Ty::Integer => 1,
+            Ty::BigInt => 2,
+            Ty::Real => 3,
+            Ty::Double => 4,
+            _ => 5,
+        };
+        match rank(self).max(rank(other)) {
+            0 => Ty::SmallInt,
+            1 => Ty::Integer,
+            2 => Ty::BigInt,
+            3 => Ty::Real,
+            4 => Ty::Double,
+            _ => Ty::Number,
+        }
+    }
+}
+
+/// The name a column declaration uses for the type
+impl Display for Ty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Ty::String => write!(f, "string"),
+            Ty::Varchar(len) => write!(f, "varchar({})", len),
+            Ty::Char(len) => write!(f, "char({})", len),
+            Ty::Number => write!(f, "number"),
+            Ty::Bool => write!(f, "bool"),
+            Ty::SmallInt => write!(f, "smallint"),
+            Ty::Integer => write!(f, "integer"),
+            Ty::BigInt => write!(f, "bigint"),
+            Ty::Real => write!(f, "real"),
+            Ty::Double => write!(f, "double precision"),
+            Ty::Decimal(None) => write!(f, "decimal"),
+            Ty::Decimal(Some((precision, scale))) => {
+                write!(f, "decimal({}, {})", precision, scale)
+            }
+            Ty::Date => write!(f, "date"),
+            Ty::Time => write!(f, "time"),
+            Ty::Timestamp => write!(f, "timestamp"),
+            Ty::TimestampTz => write!(f, "timestamptz"),
+            Ty::Interval => write!(f, "interval"),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+/// A value. Integers, decimals and floating point numbers compare, and hash, by the number
+/// they stand for, so `1`, `1.00` and `1.0` are the same key. A float stands for the
+/// shortest decimal that reads back as it when compared with a decimal. Likewise dates and
+/// timestamps compare by the moment they stand for, a date being its midnight.
+#[derive(Debug, Clone)]
 pub enum LiteralValue {
     String(String),
     Number(OrderedFloat<f64>),
     Bool(bool),
     Null,
+    Int(i64),
+    Decimal(Decimal),
+
+    /// Days since 1970-01-01
+    Date(i32),
+
+    /// Microseconds since midnight
+    Time(i64),
+
+    /// Microseconds since 1970-01-01 00:00:00
+    Timestamp(i64),
+
+    /// Microseconds since 1970-01-01 00:00:00 UTC
+    TimestampTz(i64),
+    Interval(Interval),
+}
+
+impl LiteralValue {
+    /// Where values of different kinds sort: strings, then numbers, then dates and
+    /// timestamps, times, intervals, booleans and finally `NULL`
+    fn rank(&self) -> u8 {
+        match self {
+            LiteralValue::String(_) => 0,
+            LiteralValue::Number(_) | LiteralValue::Int(_) | LiteralValue::Decimal(_) => 1,
+            LiteralValue::Date(_) | LiteralValue::Timestamp(_) | LiteralValue::TimestampTz(_) => 2,
+            LiteralValue::Time(_) => 3,
+            LiteralValue::Interval(_) => 4,
+            LiteralValue::Bool(_) => 5,
+            LiteralValue::Null => 6,
+        }
+    }
+
+    /// Whether `self` and `other` are the same kind of value, with integers and other
+    /// numbers alike
+    pub fn same_kind(&self, other: &LiteralValue) -> bool {
+        self.rank() == other.rank()
+    }
+}
+
+impl Ord for LiteralValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (LiteralValue::String(a), LiteralValue::String(b)) => a.cmp(b),
+            (LiteralValue::Number(a), LiteralValue::Number(b)) => a.cmp(b),
+            (LiteralValue::Int(a), LiteralValue::Int(b)) => a.cmp(b),
+            (LiteralValue::Int(a), LiteralValue::Number(b)) => cmp_int_float(*a, b.0),
+            (LiteralValue::Number(a), LiteralValue::Int(b)) => cmp_int_float(*b, a.0).reverse(),
+            (LiteralValue::Decimal(a), LiteralValue::Decimal(b)) => a.cmp(b),
+            (LiteralValue::Decimal(a), LiteralValue::Int(b)) => a.cmp(&Decimal::from(*b)),
+            (LiteralValue::Int(a), LiteralValue::Decimal(b)) => Decimal::from(*a).cmp(b),
+            (LiteralValue::Decimal(a), LiteralValue::Number(b)) => cmp_decimal_float(a, b.0),
+            (LiteralValue::Number(a), LiteralValue::Decimal(b)) => {
+                cmp_decimal_float(b, a.0).reverse()
+            }
+            (LiteralValue::Bool(a), LiteralValue::Bool(b)) => a.cmp(b),
+            (LiteralValue::Time(a), LiteralValue::Time(b)) => a.cmp(b),
+            (LiteralValue::Interval(a), LiteralValue::Interval(b)) => a.cmp(b),
+            (a, b) if a.rank() == 2 && b.rank() == 2 => a.instant().cmp(&b.instant()),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for LiteralValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for LiteralValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for LiteralValue {}
+
+impl Hash for LiteralValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
+        match self {
+            LiteralValue::String(s) => s.hash(state),
+            LiteralValue::Int(i) => i.hash(state),
+            // Whole numbers hash like the integer they equal
+            LiteralValue::Number(n) if n.fract() == 0.0 && n.abs() < I64_END => {
+                (n.0 as i64).hash(state)
+            }
+            LiteralValue::Number(n) => n.hash(state),
+            // Like the integer or float they equal, if any
+            LiteralValue::Decimal(d) => match d.to_i64() {
+                Some(i) => i.hash(state),
+                None if Decimal::from_f64(d.to_f64()).as_ref() == Some(d) => {
+                    OrderedFloat(d.to_f64()).hash(state)
+                }
+                None => d.hash(state),
+            },
+            LiteralValue::Date(_) | LiteralValue::Timestamp(_) | LiteralValue::TimestampTz(_) => {
+                self.instant().hash(state)
+            }
+            LiteralValue::Time(t) => t.hash(state),
+            LiteralValue::Interval(i) => i.hash(state),
+            LiteralValue::Bool(b) => b.hash(state),
+            LiteralValue::Null => {}
+        }
+    }
+}
+
+/// 2^63, the first float past the end of the `i64`s
+const I64_END: f64 = 9_223_372_036_854_775_808.0;
+
+/// Compares an integer with a float exactly, without rounding either. `NaN` is the largest
+/// float, as in `OrderedFloat`.
+fn cmp_int_float(i: i64, f: f64) -> Ordering {
+    if f.is_nan() || f >= I64_END {
+        return Ordering::Less;
+    }
+    if f < -I64_END {
+        return Ordering::Greater;
+    }
+    i.cmp(&(f.trunc() as i64))
+        .then_with(|| 0.0.partial_cmp(&f.fract()).unwrap_or(Ordering::Equal))
+}
+
+/// Compares a decimal with the shortest decimal a float prints as. `NaN` is the largest
+/// float, as in `OrderedFloat`.
+fn cmp_decimal_float(d: &Decimal, f: f64) -> Ordering {
+    match Decimal::from_f64(f) {
+        Some(f) => d.cmp(&f),
+        None if f == f64::NEG_INFINITY => Ordering::Greater,
+        None => Ordering::Less,
+    }
 }
 
 impl From<&LiteralValue> for Bytes {
@@ -88,6 +800,13 @@ impl From<&LiteralValue> for Bytes {
         match val {
             LiteralValue::String(s) => Bytes::copy_from_slice(s[..].as_bytes()),
             LiteralValue::Number(n) => Bytes::from(n.to_string()),
+            LiteralValue::Int(n) => Bytes::from(n.to_string()),
+            LiteralValue::Decimal(d) => Bytes::from(d.to_string()),
+            LiteralValue::Date(d) => Bytes::from(temporal::format_date(*d)),
+            LiteralValue::Time(t) => Bytes::from(temporal::format_time(*t)),
+            LiteralValue::Timestamp(t) => Bytes::from(temporal::format_timestamp(*t, false)),
+            LiteralValue::TimestampTz(t) => Bytes::from(temporal::format_timestamp(*t, true)),
+            LiteralValue::Interval(i) => Bytes::from(i.to_string()),
             LiteralValue::Bool(b) => {
                 if *b {
                     Bytes::from("true")
@@ -100,45 +819,270 @@ impl From<&LiteralValue> for Bytes {
     }
 }
 
-impl LiteralValue {
-    fn number(&self) -> Result<OrderedFloat<f64>> {
-        if let LiteralValue::Number(n) = self {
-            Ok(*n)
-        } else {
-            bail!("Expected number")
+impl Display for LiteralValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LiteralValue::String(s) => write!(f, "{:?}", s),
+            LiteralValue::Number(n) => write!(f, "{}", n),
+            LiteralValue::Int(n) => write!(f, "{}", n),
+            LiteralValue::Decimal(d) => write!(f, "{}", d),
+            LiteralValue::Bool(b) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            LiteralValue::Null => write!(f, "NULL"),
+            LiteralValue::Date(d) => write!(f, "DATE '{}'", temporal::format_date(*d)),
+            LiteralValue::Time(t) => write!(f, "TIME '{}'", temporal::format_time(*t)),
+            LiteralValue::Timestamp(t) => {
+                write!(f, "TIMESTAMP '{}'", temporal::format_timestamp(*t, false))
+            }
+            LiteralValue::TimestampTz(t) => {
+                write!(f, "TIMESTAMPTZ '{}'", temporal::format_timestamp(*t, true))
+            }
+            LiteralValue::Interval(i) => write!(f, "INTERVAL '{}'", i),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub enum Key {
-    Glob,
-    List(Vec<Token>),
+impl LiteralValue {
+    pub fn number(&self) -> Result<OrderedFloat<f64>> {
+        match self {
+            LiteralValue::Number(n) => Ok(*n),
+            LiteralValue::Int(n) => Ok(OrderedFloat(*n as f64)),
+            LiteralValue::Decimal(d) => Ok(OrderedFloat(d.to_f64())),
+            _ => bail!("Expected number, got {}", self),
+        }
+    }
+
+    /// The number as an exact decimal; floats give the shortest decimal they print as
+    pub fn decimal(&self) -> Result<Decimal> {
+        match self {
+            LiteralValue::Decimal(d) => Ok(d.clone()),
+            LiteralValue::Int(n) => Ok(Decimal::from(*n)),
+            LiteralValue::Number(n) => {
+                Decimal::from_f64(n.0).ok_or_else(|| anyhow!("Cannot use {} as a decimal", n))
+            }
+            _ => bail!("Expected number, got {}", self),
+        }
+    }
+
+    /// The value as it is stored in a column of type `ty`. Numbers convert between integers,
+    /// decimals and floating point, with integers and decimals rounded to fit, as long as
+    /// the digits before the point do. Strings can't be longer than the type allows, apart
+    /// from trailing spaces, which are cut off. Strings are read as dates, times and
+    /// intervals, and dates and timestamps convert between each other.
+    pub fn coerce(self, ty: &Ty) -> Result<LiteralValue> {
+        let out_of_range = |val: &LiteralValue| anyhow!("Value {} out of range for {}", val, ty);
+        Ok(match (self, ty) {
+            (LiteralValue::Null, _) => LiteralValue::Null,
+            (val, ty) if ty.is_temporal() => val.into_temporal(ty)?,
+            (val @ LiteralValue::String(_), Ty::String)
+            | (val @ LiteralValue::Bool(_), Ty::Bool) => val,
+            (LiteralValue::String(s), Ty::Varchar(len) | Ty::Char(len)) => {
+                let len = *len as usize;
+                let mut s = match s.char_indices().nth(len) {
+                    Some((end, _)) if s[end..].trim_end_matches(' ').is_empty() => {
+                        s[..end].to_string()
+                    }
+                    Some(_) => bail!("Value {:?} too long for {}", s, ty),
+                    None => s,
+                };
+                if let Ty::Char(_) = ty {
+                    let pad = len - s.chars().count();
+                    s.extend(std::iter::repeat_n(' ', pad));
+                }
+                LiteralValue::String(s)
+            }
+            (val, _) if val.rank() == 1 => match ty {
+                Ty::Number | Ty::Double => LiteralValue::Number(val.number()?),
+                Ty::Real => {
+                    let n = val.number()?.0 as f32;
+                    if n.is_infinite() && val.number()?.is_finite() {
+                        return Err(out_of_range(&val));
+                    }
+                    LiteralValue::Number(OrderedFloat(n.into()))
+                }
+                Ty::Decimal(None) => LiteralValue::Decimal(val.decimal()?),
+                Ty::Decimal(Some((precision, scale))) => {
+                    let d = val.decimal()?.round(*scale);
+                    if d.int_digits() > precision - scale {
+                        return Err(out_of_range(&val));
+                    }
+                    LiteralValue::Decimal(d)
+                }
+                _ => {
+                    let (min, max) = ty.int_range().ok_or_else(|| anyhow!("Expected {}", ty))?;
+                    let n = match &val {
+                        LiteralValue::Int(n) => Some(*n),
+                        LiteralValue::Number(n) if n.is_finite() && n.round().abs() < I64_END => {
+                            Some(n.round() as i64)
+                        }
+                        LiteralValue::Decimal(d) => d.round(0).to_i64(),
+                        _ => None,
+                    };
+                    match n {
+                        Some(n) if n >= min && n <= max => LiteralValue::Int(n),
+                        _ => return Err(out_of_range(&val)),
+                    }
+                }
+            },
+            _ => bail!("Expected {}", ty),
+        })
+    }
+
+    /// `NULL` is neither true nor false; `None` stands for it
+    pub fn truth(&self) -> Result<Option<bool>> {
+        match self {
+            LiteralValue::Bool(b) => Ok(Some(*b)),
+            LiteralValue::Null => Ok(None),
+            other => bail!("Expected boolean, got {}", other),
+        }
+    }
+
+    pub fn unary(&self, op: &Token) -> Result<LiteralValue> {
+        if *self == LiteralValue::Null {
+            return Ok(LiteralValue::Null);
+        }
+        Ok(match (op, self) {
+            (Token::Minus, LiteralValue::Int(n)) => LiteralValue::Int(
+                n.checked_neg()
+                    .ok_or_else(|| anyhow!("Integer out of range"))?,
+            ),
+            (Token::Plus, LiteralValue::Int(n)) => LiteralValue::Int(*n),
+            (Token::Minus, LiteralValue::Decimal(d)) => LiteralValue::Decimal(-d.clone()),
+            (Token::Plus, LiteralValue::Decimal(d)) => LiteralValue::Decimal(d.clone()),
+            (Token::Minus, LiteralValue::Interval(i)) => LiteralValue::Interval(i.neg()?),
+            (Token::Plus, LiteralValue::Interval(i)) => LiteralValue::Interval(*i),
+            (Token::Minus, _) => LiteralValue::Number(-self.number()?),
+            (Token::Plus, _) => LiteralValue::Number(self.number()?),
+            (Token::Not, _) => LiteralValue::Bool(!self.truth()?.unwrap_or_default()),
+            _ => bail!("Internal error"),
+        })
+    }
+
+    /// `+`, `-`, `*`, `/` or `%`. Two integers give an integer, failing if it overflows, and
+    /// `/` between them drops the remainder. A float on either side gives a float, and
+    /// otherwise a decimal on either side gives an exact decimal. Dates, times and intervals
+    /// have arithmetic of their own.
+    fn arithmetic(&self, op: &Token, other: &LiteralValue) -> Result<LiteralValue> {
+        if let Some(res) = self.temporal_arithmetic(op, other) {
+            return res;
+        }
+        let float = |val: &LiteralValue| matches!(val, LiteralValue::Number(_));
+        if (matches!(self, LiteralValue::Decimal(_)) || matches!(other, LiteralValue::Decimal(_)))
+            && !float(self)
+            && !float(other)
+        {
+            let (a, b) = (self.decimal()?, other.decimal()?);
+            return Ok(LiteralValue::Decimal(match op {
+                Token::Plus => a.add(&b),
+                Token::Minus => a.sub(&b),
+                Token::Star => a.mul(&b),
+                Token::Slash => a.div(&b)?,
+                Token::Percent => a.rem(&b)?,
+                _ => bail!("Internal error"),
+            }));
+        }
+        if let (LiteralValue::Int(a), LiteralValue::Int(b)) = (self, other) {
+            let res = match op {
+                Token::Slash | Token::Percent if *b == 0 => bail!("Division by zero"),
+                Token::Plus => a.checked_add(*b),
+                Token::Minus => a.checked_sub(*b),
+                Token::Star => a.checked_mul(*b),
+                Token::Slash => a.checked_div(*b),
+                Token::Percent => a.checked_rem(*b),
+                _ => bail!("Internal error"),
+            };
+            return res
+                .map(LiteralValue::Int)
+                .ok_or_else(|| anyhow!("Integer out of range"));
+        }
+        Ok(match op {
+            Token::Plus => LiteralValue::Number(self.number()? + other.number()?),
+            Token::Minus => LiteralValue::Number(self.number()? - other.number()?),
+            Token::Star => LiteralValue::Number(self.number()? * other.number()?),
+            Token::Slash | Token::Percent => {
+                let divisor = other.number()?;
+                if divisor == 0.0 {
+                    bail!("Division by zero");
+                }
+                LiteralValue::Number(if *op == Token::Slash {
+                    self.number()? / divisor
+                } else {
+                    self.number()? % divisor
+                })
+            }
+            _ => bail!("Internal error"),
+        })
+    }
+
+    /// Applies a binary operator. `AND` and `OR` follow three-valued logic; everything else is
+    /// `NULL` if either side is.
+    pub fn binary(&self, op: &Token, other: &LiteralValue) -> Result<LiteralValue> {
+        match op {
+            Token::And => {
+                return Ok(match (self.truth()?, other.truth()?) {
+                    (Some(false), _) | (_, Some(false)) => LiteralValue::Bool(false),
+                    (Some(true), Some(true)) => LiteralValue::Bool(true),
+                    _ => LiteralValue::Null,
+                })
+            }
+            Token::Or => {
+                return Ok(match (self.truth()?, other.truth()?) {
+                    (Some(true), _) | (_, Some(true)) => LiteralValue::Bool(true),
+                    (Some(false), Some(false)) => LiteralValue::Bool(false),
+                    _ => LiteralValue::Null,
+                })
+            }
+            _ => {}
+        }
+        if *self == LiteralValue::Null || *other == LiteralValue::Null {
+            return Ok(LiteralValue::Null);
+        }
+        let cmp = || -> Result<std::cmp::Ordering> {
+            if self.rank() != other.rank() {
+                bail!("Cannot compare {} with {}", self, other);
+            }
+            Ok(self.cmp(other))
+        };
+        Ok(match op {
+            Token::Plus | Token::Minus | Token::Star | Token::Slash | Token::Percent => {
+                self.arithmetic(op, other)?
+            }
+            Token::Equal => LiteralValue::Bool(self == other),
+            Token::NotEqual => LiteralValue::Bool(self != other),
+            Token::GreaterThan => LiteralValue::Bool(cmp()?.is_gt()),
+            Token::GreaterEqual => LiteralValue::Bool(cmp()?.is_ge()),
+            Token::LessThan => LiteralValue::Bool(cmp()?.is_lt()),
+            Token::LessEqual => LiteralValue::Bool(cmp()?.is_le()),
+            _ => bail!("Internal error"),
+        })
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Tokens {
     Omitted,
-    List(Vec<Token>),
+    List(Vec<Spanned<Token>>),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum TableDef {
-    Cols(Vec<ColDecl>),
+    Cols {
+        cols: Vec<ColDecl>,
+        constraints: Vec<TableConstraint>,
+    },
 
     /// SELECT bar, baz FROM foo
     As(Box<Command>),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct ColDecl {
-    ident: Token,
+    ident: Spanned<Token>,
     ty: Ty,
     constraints: Vec<Constraint>,
 }
 
 impl ColDecl {
-    pub fn new(ident: Token, ty: Ty, constraints: Vec<Constraint>) -> Self {
+    pub fn new(ident: Spanned<Token>, ty: Ty, constraints: Vec<Constraint>) -> Self {
         ColDecl {
             ident,
             ty,
@@ -150,6 +1094,11 @@ impl ColDecl {
         self.ident.ident().ok_or_else(|| anyhow!("Internal error"))
     }
 
+    /// The column name with its place in the query
+    pub fn ident_token(&self) -> &Spanned<Token> {
+        &self.ident
+    }
+
     pub fn ty(&self) -> &Ty {
         &self.ty
     }
@@ -159,13 +1108,95 @@ impl ColDecl {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Constraint {
     NotNull,
     Unique,
     PrimaryKey,
-    ForeignKey,
+    References(References),
     Check(Expr),
-    Default(LiteralValue),
+
+    /// A value, or `now()`
+    Default(Expr),
     CreateIndex,
 }
+
+/// A constraint declared alongside the columns, which may span several of them
+#[derive(Debug, PartialEq, Clone)]
+pub struct TableConstraint {
+    /// CONSTRAINT name
+    pub name: Option<Spanned<Token>>,
+    pub kind: TableConstraintKind,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TableConstraintKind {
+    /// PRIMARY KEY (cols)
+    PrimaryKey(Vec<Spanned<Token>>),
+
+    /// UNIQUE (cols)
+    Unique(Vec<Spanned<Token>>),
+
+    /// CHECK (expr)
+    Check(Expr),
+
+    /// FOREIGN KEY (cols) REFERENCES ...
+    ForeignKey {
+        cols: Vec<Spanned<Token>>,
+        references: References,
+    },
+}
+
+/// REFERENCES table [(cols)] [ON DELETE action] [ON UPDATE action]
+#[derive(Debug, PartialEq, Clone)]
+pub struct References {
+    pub table: Spanned<Token>,
+
+    /// The referenced table's primary key if empty
+    pub cols: Vec<Spanned<Token>>,
+    pub on_delete: RefAction,
+    pub on_update: RefAction,
+}
+
+/// How much of what other transactions commit a transaction gets to see
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Isolation {
+    /// Each statement sees what was committed before it started
+    #[default]
+    ReadCommitted,
+
+    /// Every statement sees what was committed before the transaction started. Also called
+    /// snapshot isolation.
+    RepeatableRead,
+
+    /// Like `RepeatableRead`, but the transaction can't commit if a table it read was
+    /// changed by a transaction that committed since it started
+    Serializable,
+}
+
+/// What happens to referencing rows when the row they reference is deleted or its key
+/// changes
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum RefAction {
+    /// Fail unless no row references the old key by the end of the statement
+    #[default]
+    NoAction,
+
+    /// Fail as soon as a row references the old key
+    Restrict,
+    Cascade,
+    SetNull,
+    SetDefault,
+}
+
+impl Display for RefAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefAction::NoAction => write!(f, "NO ACTION"),
+            RefAction::Restrict => write!(f, "RESTRICT"),
+            RefAction::Cascade => write!(f, "CASCADE"),
+            RefAction::SetNull => write!(f, "SET NULL"),
+            RefAction::SetDefault => write!(f, "SET DEFAULT"),
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Result};
+
+/// The most digits a `DECIMAL` can be declared with
+pub const MAX_PRECISION: u32 = 1000;
+
+/// The fewest digits after the point a quotient is given with
+const MIN_DIV_SCALE: u32 = 16;
+
+/// An exact decimal number of any size: an integer, kept as its decimal digits, with the
+/// last `scale` of them after the point. `1.5` and `1.50` are equal but print differently.
+#[derive(Clone, Debug)]
+pub struct Decimal {
+    negative: bool,
+
+    /// Most significant first, without leading zeros, so zero has none
+    digits: Vec<u8>,
+    scale: u32,
+}
+
+impl Decimal {
+    fn new(negative: bool, mut digits: Vec<u8>, scale: u32) -> Decimal {
+        let zeros = digits.iter().take_while(|d| **d == 0).count();
+        digits.drain(..zeros);
+        Decimal {
+            negative: negative && !digits.is_empty(),
+            digits,
+            scale,
+        }
+    }
+
+    /// The number `f` prints as, which is the shortest one that reads back as `f`; `None` for
+    /// infinities and `NaN`
+    pub fn from_f64(f: f64) -> Option<Decimal> {
+        if !f.is_finite() {
+            return None;
+        }
+        f.to_string().parse().ok()
+    }
+
+    /// The nearest float
+    pub fn to_f64(&self) -> f64 {
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+
+    /// The number if it is whole and fits in 64 bits
+    pub fn to_i64(&self) -> Option<i64> {
+        let (int, fraction) = self.split();
+        if fraction.iter().any(|d| *d != 0) || int.len() > 19 {
+            return None;
+        }
+        let n = int.iter().fold(0i128, |n, d| n * 10 + *d as i128);
+        i64::try_from(if self.negative { -n } else { n }).ok()
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// How many digits come before the point, not counting leading zeros
+    pub fn int_digits(&self) -> u32 {
+        self.split().0.len() as u32
+    }
+
+    /// Rounded, half away from zero, or padded to `scale` digits after the point
+    pub fn round(&self, scale: u32) -> Decimal {
+        if scale >= self.scale {
+            return Decimal::new(self.negative, self.digits_at(scale), scale);
+        }
+        let dropped = (self.scale - scale) as usize;
+        let kept = self.digits.len().saturating_sub(dropped);
+        let mut digits = self.digits[..kept].to_vec();
+        if dropped <= self.digits.len() && self.digits[kept] >= 5 {
+            digits = add_digits(&digits, &[1]);
+        }
+        Decimal::new(self.negative, digits, scale)
+    }
+
+    pub fn abs(&self) -> Decimal {
+        Decimal::new(false, self.digits.clone(), self.scale)
+    }
+
+    pub fn add(&self, other: &Decimal) -> Decimal {
+        let scale = self.scale.max(other.scale);
+        let (a, b) = (self.digits_at(scale), other.digits_at(scale));
+        if self.negative == other.negative {
+            return Decimal::new(self.negative, add_digits(&a, &b), scale);
+        }
+        match cmp_digits(&a, &b) {
+            Ordering::Less => Decimal::new(other.negative, sub_digits(&b, &a), scale),
+            _ => Decimal::new(self.negative, sub_digits(&a, &b), scale),
+        }
+    }
+
+    pub fn sub(&self, other: &Decimal) -> Decimal {
+        self.add(&-other.clone())
+    }
+
+    pub fn mul(&self, other: &Decimal) -> Decimal {
+        Decimal::new(
+            self.negative != other.negative,
+            mul_digits(&self.digits, &other.digits),
+            self.scale + other.scale,
+        )
+    }
+
+    /// The quotient, rounded to at least 16 digits after the point, and to no fewer than
+    /// either side has
+    pub fn div(&self, other: &Decimal) -> Result<Decimal> {
+        if other.digits.is_empty() {
+            bail!("Division by zero");
+        }
+        let scale = self.scale.max(other.scale).max(MIN_DIV_SCALE);
+        // One digit more than needed, to round on
+        let mut num = self.digits.clone();
+        num.resize(
+            num.len() + (scale + 1 + other.scale - self.scale) as usize,
+            0,
+        );
+        let (quotient, _) = div_digits(&num, &other.digits);
+        Ok(Decimal::new(self.negative != other.negative, quotient, scale + 1).round(scale))
+    }
+
+    /// What is left after taking out `other` a whole number of times, with the sign of `self`
+    pub fn rem(&self, other: &Decimal) -> Result<Decimal> {
+        if other.digits.is_empty() {
+            bail!("Division by zero");
+        }
+        let scale = self.scale.max(other.scale);
+        let (_, rem) = div_digits(&self.digits_at(scale), &other.digits_at(scale));
+        Ok(Decimal::new(self.negative, rem, scale))
+    }
+
+    /// The digits of the number with `scale` digits after the point, which must be at least
+    /// `self.scale`
+    fn digits_at(&self, scale: u32) -> Vec<u8> {
+        let mut digits = self.digits.clone();
+        if !digits.is_empty() {
+            digits.resize(digits.len() + (scale - self.scale) as usize, 0);
+        }
+        digits
+    }
+
+    /// The digits before and after the point, without the fraction's leading zeros
+    fn split(&self) -> (&[u8], &[u8]) {
+        self.digits
+            .split_at(self.digits.len().saturating_sub(self.scale as usize))
+    }
+
+    /// The same number without trailing zeros after the point
+    fn normalized(&self) -> Decimal {
+        let zeros = self
+            .digits
+            .iter()
+            .rev()
+            .take_while(|d| **d == 0)
+            .count()
+            .min(self.scale as usize);
+        Decimal::new(
+            self.negative,
+            self.digits[..self.digits.len() - zeros].to_vec(),
+            self.scale - zeros as u32,
+        )
+    }
+}
+
+impl From<i64> for Decimal {
+    fn from(n: i64) -> Self {
+        let digits = n
+            .unsigned_abs()
+            .to_string()
+            .bytes()
+            .map(|b| b - b'0')
+            .collect();
+        Decimal::new(n < 0, digits, 0)
+    }
+}
+
+/// Reads `[sign]digits[.digits][e[sign]digits]`, with digits on at least one side of the
+/// point
+impl FromStr for Decimal {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid decimal {:?}", s);
+        let (mantissa, exponent) = match s.find(['e', 'E']) {
+            Some(i) => (&s[..i], s[i + 1..].parse::<i64>().map_err(|_| invalid())?),
+            None => (s, 0),
+        };
+        let (negative, mantissa) = match mantissa.as_bytes().first() {
+            Some(b'-') => (true, &mantissa[1..]),
+            Some(b'+') => (false, &mantissa[1..]),
+            _ => (false, mantissa),
+        };
+        let (int, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if int.len() + fraction.len() == 0
+            || !int
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        let mut digits: Vec<u8> = int
+            .bytes()
+            .chain(fraction.bytes())
+            .map(|b| b - b'0')
+            .collect();
+        let scale = fraction.len() as i64 - exponent;
+        if scale.unsigned_abs() > MAX_PRECISION as u64 * 2 {
+            bail!("Decimal {} out of range", s);
+        }
+        if scale < 0 {
+            digits.resize(digits.len() + scale.unsigned_abs() as usize, 0);
+        }
+        Ok(Decimal::new(negative, digits, scale.max(0) as u32))
+    }
+}
+
+impl Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scale = self.scale as usize;
+        let mut digits: String = self.digits.iter().map(|d| (b'0' + d) as char).collect();
+        if digits.len() <= scale {
+            digits.insert_str(0, &"0".repeat(scale + 1 - digits.len()));
+        }
+        if scale > 0 {
+            digits.insert(digits.len() - scale, '.');
+        }
+        if self.negative {
+            digits.insert(0, '-');
+        }
+        write!(f, "{}", digits)
+    }
+}
+
+impl std::ops::Neg for Decimal {
+    type Output = Decimal;
+
+    fn neg(self) -> Decimal {
+        Decimal::new(!self.negative, self.digits, self.scale)
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.negative != other.negative {
+            return other.negative.cmp(&self.negative);
+        }
+        let scale = self.scale.max(other.scale);
+        let ord = cmp_digits(&self.digits_at(scale), &other.digits_at(scale));
+        if self.negative {
+            ord.reverse()
+        } else {
+            ord
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+impl Hash for Decimal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let normalized = self.normalized();
+        normalized.negative.hash(state);
+        normalized.digits.hash(state);
+        normalized.scale.hash(state);
+    }
+}
+
+/// Compares two digit strings without leading zeros
+fn cmp_digits(a: &[u8], b: &[u8]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+fn add_digits(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut sum = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0;
+    let (mut a, mut b) = (a.iter().rev(), b.iter().rev());
+    loop {
+        let (x, y) = (a.next(), b.next());
+        if x.is_none() && y.is_none() {
+            break;
+        }
+        let d = x.unwrap_or(&0) + y.unwrap_or(&0) + carry;
+        sum.push(d % 10);
+        carry = d / 10;
+    }
+    if carry > 0 {
+        sum.push(carry);
+    }
+    sum.reverse();
+    sum
+}
+
+/// `a - b`, where `a` is at least `b`
+fn sub_digits(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut diff = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    let mut b = b.iter().rev();
+    for x in a.iter().rev() {
+        let y = b.next().unwrap_or(&0) + borrow;
+        borrow = (*x < y) as u8;
+        diff.push(x + borrow * 10 - y);
+    }
+    diff.reverse();
+    let zeros = diff.iter().take_while(|d| **d == 0).count();
+    diff.split_off(zeros)
+}
+
+fn mul_digits(a: &[u8], b: &[u8]) -> Vec<u8> {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+    let mut product = vec![0u32; a.len() + b.len()];
+    for (i, x) in a.iter().rev().enumerate() {
+        for (j, y) in b.iter().rev().enumerate() {
+            product[i + j] += *x as u32 * *y as u32;
+        }
+        // Carry as we go so the sums can't overflow
+        for k in i..product.len() - 1 {
+            product[k + 1] += product[k] / 10;
+            product[k] %= 10;
+        }
+    }
+    product.iter().rev().map(|d| *d as u8).collect()
+}
+
+/// Long division: the quotient and remainder, without leading zeros
+fn div_digits(a: &[u8], b: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut quotient = Vec::with_capacity(a.len());
+    let mut rem = Vec::with_capacity(b.len() + 1);
+    for d in a {
+        if !rem.is_empty() || *d != 0 {
+            rem.push(*d);
+        }
+        let mut q = 0;
+        while cmp_digits(&rem, b).is_ge() {
+            rem = sub_digits(&rem, b);
+            q += 1;
+        }
+        if !quotient.is_empty() || q != 0 {
+            quotient.push(q);
+        }
+    }
+    (quotient, rem)
+}
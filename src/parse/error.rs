@@ -1,14 +1,81 @@
-use anyhow::{bail, Result};
+use std::{error::Error, fmt::Display};
 
-use super::Token;
+use anyhow::Result;
+
+use super::{token::Spanned, Span, Token};
 
 pub const ERROR_EOF: &str = "Unexpected end of file";
 
-// TODO: This requires values for literal types like `Number`
-pub fn throw_unexpected<T>(got: &Token, expected: Vec<Token>) -> Result<T> {
-    let mut msg = format!("expected one of: {:?}", expected[0]);
-    for ty in &expected[1..] {
-        msg.push_str(&format!(", {:?}", ty)[..]);
+/// An error that points at the part of the query that caused it
+#[derive(Debug)]
+pub struct SpannedError {
+    msg: String,
+    span: Span,
+}
+
+impl SpannedError {
+    pub fn new(msg: impl Into<String>, span: Span) -> Self {
+        SpannedError {
+            msg: msg.into(),
+            span,
+        }
+    }
+
+    pub fn msg(&self) -> &str {
+        self.msg.as_ref()
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl Display for SpannedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Error for SpannedError {}
+
+/// Shorthand for an `anyhow::Error` located at `span`
+pub fn error_at(span: Span, msg: impl Into<String>) -> anyhow::Error {
+    SpannedError::new(msg, span).into()
+}
+
+pub fn throw_unexpected<T>(got: &Spanned<Token>, expected: Vec<Token>) -> Result<T> {
+    let expected: Vec<_> = expected.iter().map(describe_kind).collect();
+    let span = got.span;
+    let got = match &got.node {
+        Token::Identifier(_)
+        | Token::Number(_)
+        | Token::Integer(_)
+        | Token::Decimal(_)
+        | Token::String(_)
+        | Token::Param(_) => {
+            format!("{} `{}`", describe_kind(got), got.node)
+        }
+        Token::Eof => got.node.to_string(),
+        other => format!("`{}`", other),
+    };
+    Err(error_at(
+        span,
+        format!(
+            "Unexpected {}; expected one of: {}",
+            got,
+            expected.join(", ")
+        ),
+    ))
+}
+
+/// Names literal token kinds without the placeholder value they were constructed with
+fn describe_kind(tok: &Token) -> String {
+    match tok {
+        Token::Identifier(_) => "identifier".into(),
+        Token::Number(_) | Token::Integer(_) | Token::Decimal(_) => "number".into(),
+        Token::String(_) => "string".into(),
+        Token::Bool(_) => "boolean".into(),
+        Token::Param(_) => "parameter".into(),
+        other => other.to_string(),
     }
-    bail!("Unexpected token: `{:?}`; {}", got, msg);
 }
@@ -5,14 +5,23 @@ use parser::Parser;
 use scanner::Scanner;
 
 pub use self::{
-    ast::{ColDecl, Command, Constraint, Expr, Key, LiteralValue, TableDef, Tokens, Ty},
-    token::Token,
+    ast::{
+        ColDecl, Command, Constraint, CopyFormat, CopyOptions, CopyTarget, Expr, FromClause,
+        Isolation, LiteralValue, OnError, RefAction, References, Select, SelectItem,
+        TableConstraint, TableConstraintKind, TableDef, TableRef, Tokens, Ty,
+    },
+    decimal::Decimal,
+    error::{error_at, SpannedError},
+    temporal::Interval,
+    token::{Span, Spanned, Token},
 };
 
 mod ast;
+mod decimal;
 mod error;
 mod parser;
 mod scanner;
+mod temporal;
 mod token;
 
 pub fn parse(stream: Bytes) -> Result<Command> {
@@ -21,32 +30,78 @@ pub fn parse(stream: Bytes) -> Result<Command> {
     parser.parse()
 }
 
+/// Parses an expression on its own, such as a `CHECK` condition read back from disk
+pub fn parse_expr(stream: Bytes) -> Result<Expr> {
+    let tokens = Scanner::scan(stream)?;
+    let mut parser = Parser::new(tokens);
+    parser.parse_expr()
+}
+
+/// The statements of a script, which are separated by `;`. Quoted strings are kept whole,
+/// and `--` comments up to the end of the line are dropped.
+pub fn split_script(script: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut statement = String::new();
+    let mut quote = None;
+    let mut chars = script.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '-') if chars.peek() == Some(&'-') => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+                continue;
+            }
+            (None, ';') => {
+                statements.push(std::mem::take(&mut statement));
+                continue;
+            }
+            (None, _) => {}
+        }
+        statement.push(c);
+    }
+    statements.push(statement);
+    statements
+        .into_iter()
+        .map(|statement| statement.trim().to_string())
+        .filter(|statement| !statement.is_empty())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
+    use ordered_float::OrderedFloat;
 
     use crate::parse::{
-        ast::{Constraint, Expr, TableDef},
+        ast::{
+            Constraint, Expr, FromClause, Isolation, Join, OrderBy, Select, SelectItem, TableDef,
+            TableRef,
+        },
         ColDecl,
     };
 
     use super::{
-        ast::{Command, Key, Ty},
+        ast::{Command, CopyFormat, CopyOptions, CopyTarget, LiteralValue, OnError, Tokens, Ty},
         error::ERROR_EOF,
+        parse, parse_expr,
         parser::Parser,
         scanner::Scanner,
-        token::Token,
+        split_script,
+        token::{Spanned, Token},
+        Decimal, Span, SpannedError,
     };
 
     #[test]
     fn scanner() {
-        let stream = "INSERT 17.6 * (\"one\", \"two\", true) table".into();
+        let stream = "INSERT 17.6 * (\"one\", \"two\", true) people".into();
         let tokens = Scanner::scan(stream).unwrap();
         assert_eq!(
             tokens,
             vec![
                 Token::Insert,
-                Token::Number(17.6),
+                Token::Decimal("17.6".parse().unwrap()),
                 Token::Star,
                 Token::LeftParen,
                 Token::String("one".to_string()),
@@ -55,8 +110,8 @@ mod tests {
                 Token::Comma,
                 Token::Bool(true),
                 Token::RightParen,
-                Token::Identifier(String::from("table")),
-                Token::EOF,
+                Token::Identifier(String::from("people")),
+                Token::Eof,
             ]
         );
 
@@ -89,15 +144,177 @@ mod tests {
                 Token::Null,
                 Token::Unique,
                 Token::RightParen,
-                Token::EOF,
+                Token::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn scanner_identifiers() {
+        let stream = "select user_id, col2, _tmp FROM Insert_log".into();
+        let tokens = Scanner::scan(stream).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Select,
+                Token::Identifier("user_id".into()),
+                Token::Comma,
+                Token::Identifier("col2".into()),
+                Token::Comma,
+                Token::Identifier("_tmp".into()),
+                Token::From,
+                Token::Identifier("Insert_log".into()),
+                Token::Eof,
+            ]
+        );
+
+        let stream = "TRUE False nOt NuLl".into();
+        let tokens = Scanner::scan(stream).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Bool(true),
+                Token::Bool(false),
+                Token::Not,
+                Token::Null,
+                Token::Eof,
             ]
         );
     }
 
+    #[test]
+    fn scanner_numbers() {
+        let dec = |s: &str| Token::Decimal(s.parse().unwrap());
+        let stream = "(-3, +4, 1e6, 2.5E-3, .5, 7., -.25, 10)".into();
+        let tokens = Scanner::scan(stream).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LeftParen,
+                Token::Integer(-3),
+                Token::Comma,
+                Token::Integer(4),
+                Token::Comma,
+                dec("1e6"),
+                Token::Comma,
+                dec("0.0025"),
+                Token::Comma,
+                dec("0.5"),
+                Token::Comma,
+                dec("7"),
+                Token::Comma,
+                dec("-0.25"),
+                Token::Comma,
+                Token::Integer(10),
+                Token::RightParen,
+                Token::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn decimals() {
+        let dec = |s: &str| s.parse::<Decimal>().unwrap();
+        assert_eq!(
+            Scanner::scan("0.1 12345678901234567890.5 1e30 99999999999999999999".into()).unwrap(),
+            vec![
+                Token::Decimal(dec("0.1")),
+                Token::Decimal(dec("12345678901234567890.5")),
+                Token::Decimal(dec("1e30")),
+                Token::Decimal(dec("99999999999999999999")),
+                Token::Eof,
+            ]
+        );
+        assert_eq!(dec("1.50"), dec("1.5"));
+        assert_eq!(dec("-0.00").to_string(), "0.00");
+        assert_eq!(dec("2.5e-3").to_string(), "0.0025");
+        assert_eq!(dec("0.1").add(&dec("0.2")).to_string(), "0.3");
+        assert_eq!(dec("1.05").sub(&dec("3")).to_string(), "-1.95");
+        assert_eq!(dec("-1.5").mul(&dec("1.5")).to_string(), "-2.25");
+        assert_eq!(
+            dec("2").div(&dec("3")).unwrap().to_string(),
+            "0.6666666666666667"
+        );
+        assert_eq!(dec("-7.5").rem(&dec("2")).unwrap().to_string(), "-1.5");
+        assert_eq!(dec("2.345").round(2).to_string(), "2.35");
+        assert_eq!(dec("-0.5").round(0).to_string(), "-1");
+        assert_eq!(dec("0.004").round(2).to_string(), "0.00");
+        assert_eq!(dec("99.95").round(1).to_string(), "100.0");
+        assert_err(dec("1").div(&dec("0.0")), "Division by zero");
+        assert_err(
+            parse("CREATE TABLE t (a decimal(5, 6))".into()),
+            "Expected a number from 0 to 5",
+        );
+    }
+
+    #[test]
+    fn temporal() {
+        let lit = |sql: &str| match parse_expr(sql.to_string().into()) {
+            Ok(Expr::Literal(val)) => val,
+            other => panic!("expected a literal, got {:?}", other),
+        };
+        assert_eq!(lit("date '1600-02-29'").to_string(), "DATE '1600-02-29'");
+        assert_eq!(
+            lit("TIMESTAMPTZ '2024-03-10 01:30-05:30'").to_string(),
+            "TIMESTAMPTZ '2024-03-10 07:00:00+00'"
+        );
+        assert_eq!(
+            lit("INTERVAL '1.5 months 25 hours -90 minutes'").to_string(),
+            "INTERVAL '1 mon 15 days 23:30:00'"
+        );
+        assert_eq!(lit("INTERVAL '1 year'"), lit("INTERVAL '360 days'"));
+        assert_eq!(
+            lit("DATE '2024-01-01'"),
+            lit("TIMESTAMP '2024-01-01 00:00'")
+        );
+        let interval = lit("INTERVAL '1 month'");
+        let add = |date: &str| {
+            lit(date)
+                .binary(&Token::Plus, &interval)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(add("DATE '2023-01-31'"), "TIMESTAMP '2023-02-28 00:00:00'");
+        assert_eq!(add("DATE '2024-01-31'"), "TIMESTAMP '2024-02-29 00:00:00'");
+        let extract = |field: &str, date: &str| lit(date).extract(field).unwrap().to_string();
+        assert_eq!(extract("week", "DATE '2021-01-03'"), "53");
+        assert_eq!(extract("isoyear", "DATE '2021-01-03'"), "2020");
+        assert_eq!(extract("doy", "DATE '2024-12-31'"), "366");
+        assert_eq!(extract("century", "DATE '2000-12-31'"), "20");
+        assert_eq!(
+            lit("TIMESTAMP '2024-05-16 10:00'")
+                .date_trunc("week")
+                .unwrap()
+                .to_string(),
+            "TIMESTAMP '2024-05-13 00:00:00'"
+        );
+        assert_err(
+            lit("DATE '9999-12-31'").binary(&Token::Plus, &LiteralValue::Int(1)),
+            "Date out of range",
+        );
+        assert_err(
+            parse_expr("INTERVAL '3 fortnights'".into()),
+            "Invalid interval unit \"fortnights\"",
+        );
+        assert_err(
+            parse("CREATE TABLE t (a time with time zone)".into()),
+            "TIME WITH TIME ZONE is not supported",
+        );
+    }
+
     #[test]
     fn scanner_err() {
         assert_err(Scanner::scan("#".into()), "Unrecognized token '#'");
         assert_err(Scanner::scan("\"unterminated string".into()), ERROR_EOF);
+        assert_err(Scanner::scan("a ! b".into()), "Unrecognized token '!'");
+        assert_err(
+            Scanner::scan("1e".into()),
+            "Missing exponent in numeric literal \"1e\"",
+        );
+        assert_err(
+            Scanner::scan("12abc".into()),
+            "Invalid numeric literal \"12a\"",
+        );
     }
 
     #[test]
@@ -107,15 +324,22 @@ mod tests {
             Token::Star,
             Token::From,
             Token::Identifier(String::from("people")),
-            Token::EOF,
+            Token::Eof,
         ];
-        let expr = Parser::new(tokens).parse().unwrap();
+        let expr = Parser::new(spanned(tokens)).parse().unwrap();
         assert_eq!(
             expr,
-            Command::Select {
-                key: Key::Glob,
-                table: Token::Identifier(String::from("people")),
-            }
+            Command::Select(Box::new(Select {
+                items: vec![SelectItem::Wildcard(None)],
+                from: Some(FromClause {
+                    table: TableRef {
+                        name: Token::Identifier(String::from("people")).into(),
+                        alias: None,
+                    },
+                    joins: Vec::new(),
+                }),
+                ..Default::default()
+            }))
         );
 
         let tokens = vec![
@@ -146,40 +370,47 @@ mod tests {
             Token::RightParen,
             Token::RightParen,
         ];
-        let expr = Parser::new(tokens).parse().unwrap();
+        let expr = Parser::new(spanned(tokens)).parse().unwrap();
         assert_eq!(
             expr,
             Command::CreateTable {
-                name: Token::Identifier(String::from("people")),
-                def: TableDef::Cols(vec![
-                    ColDecl::new(
-                        Token::Identifier(String::from("ID")),
-                        Ty::Number,
-                        vec![Constraint::PrimaryKey]
-                    ),
-                    ColDecl::new(
-                        Token::Identifier(String::from("FirstName")),
-                        Ty::String,
-                        Vec::new()
-                    ),
-                    ColDecl::new(
-                        Token::Identifier(String::from("LastName")),
-                        Ty::String,
-                        Vec::new()
-                    ),
-                    ColDecl::new(
-                        Token::Identifier(String::from("Age")),
-                        Ty::Number,
-                        vec![
-                            Constraint::NotNull,
-                            Constraint::Check(Expr::Binary {
-                                left: Token::Identifier("Age".into()),
-                                op: Token::GreaterEqual,
-                                right: Token::Number(18.0)
-                            })
-                        ]
-                    ),
-                ])
+                name: Token::Identifier(String::from("people")).into(),
+                def: TableDef::Cols {
+                    cols: vec![
+                        ColDecl::new(
+                            Token::Identifier(String::from("ID")).into(),
+                            Ty::Number,
+                            vec![Constraint::PrimaryKey]
+                        ),
+                        ColDecl::new(
+                            Token::Identifier(String::from("FirstName")).into(),
+                            Ty::String,
+                            Vec::new()
+                        ),
+                        ColDecl::new(
+                            Token::Identifier(String::from("LastName")).into(),
+                            Ty::String,
+                            Vec::new()
+                        ),
+                        ColDecl::new(
+                            Token::Identifier(String::from("Age")).into(),
+                            Ty::Number,
+                            vec![
+                                Constraint::NotNull,
+                                Constraint::Check(Expr::Binary {
+                                    left: Box::new(Expr::Ident(
+                                        Token::Identifier("Age".into()).into()
+                                    )),
+                                    op: Token::GreaterEqual,
+                                    right: Box::new(Expr::Literal(LiteralValue::Number(
+                                        OrderedFloat(18.0)
+                                    )))
+                                })
+                            ]
+                        ),
+                    ],
+                    constraints: Vec::new(),
+                }
             }
         );
 
@@ -194,19 +425,33 @@ mod tests {
             Token::Identifier(String::from("LastName")),
             Token::From,
             Token::Identifier(String::from("people")),
+            Token::Eof,
         ];
-        let expr = Parser::new(tokens).parse().unwrap();
+        let expr = Parser::new(spanned(tokens)).parse().unwrap();
         assert_eq!(
             expr,
             Command::CreateTable {
-                name: Token::Identifier(String::from("names")),
-                def: TableDef::As(Box::new(Command::Select {
-                    key: Key::List(vec![
-                        Token::Identifier(String::from("FirstName")),
-                        Token::Identifier(String::from("LastName"))
-                    ]),
-                    table: Token::Identifier(String::from("people"))
-                }))
+                name: Token::Identifier(String::from("names")).into(),
+                def: TableDef::As(Box::new(Command::Select(Box::new(Select {
+                    items: vec![
+                        SelectItem::Expr {
+                            expr: Expr::Ident(Token::Identifier(String::from("FirstName")).into()),
+                            alias: None,
+                        },
+                        SelectItem::Expr {
+                            expr: Expr::Ident(Token::Identifier(String::from("LastName")).into()),
+                            alias: None,
+                        },
+                    ],
+                    from: Some(FromClause {
+                        table: TableRef {
+                            name: Token::Identifier(String::from("people")).into(),
+                            alias: None,
+                        },
+                        joins: Vec::new(),
+                    }),
+                    ..Default::default()
+                }))))
             }
         );
     }
@@ -214,11 +459,11 @@ mod tests {
     #[test]
     fn parser_err() {
         assert_err(
-            Parser::new(vec![Token::From]).parse(),
-            "Unexpected token: `From`; expected one of: Insert, Select",
+            Parser::new(spanned(vec![Token::From])).parse(),
+            "Unexpected `FROM`; expected one of: INSERT, SELECT, UPDATE, DELETE, CREATE, DROP, EXPLAIN, PREPARE, EXECUTE, DEALLOCATE, BEGIN, COMMIT, ROLLBACK, SAVEPOINT, RELEASE, CHECKPOINT, BACKUP, DUMP, COPY",
         );
         assert_err(
-            Parser::new(vec![
+            Parser::new(spanned(vec![
                 Token::Insert,
                 Token::Into,
                 Token::Identifier("table".to_string()),
@@ -229,12 +474,388 @@ mod tests {
                 Token::LeftParen,
                 Token::Star,
                 Token::RightParen,
-            ])
+            ]))
             .parse(),
-            "Unexpected token: `Star`; expected one of: Number(0.0), String(\"\")",
+            "Unexpected `*`; expected one of: number, string, boolean, NULL",
         )
     }
 
+    #[test]
+    fn nesting() {
+        let nested = |open: &str, close: &str, depth: usize| {
+            let sql = format!("SELECT {}1{}", open.repeat(depth), close.repeat(depth));
+            parse(sql.into())
+        };
+        assert!(nested("(", ")", 50).is_ok());
+        assert_err(nested("(", ")", 200), "Expression nested too deeply");
+        assert_err(nested("- ", "", 10_000), "Expression nested too deeply");
+        assert_err(nested("NOT ", "", 10_000), "Expression nested too deeply");
+    }
+
+    #[test]
+    fn scanner_operators() {
+        let tokens = Scanner::scan("a-3 <> -b != p.c % 2".into()).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Identifier("a".into()),
+                Token::Minus,
+                Token::Integer(3),
+                Token::NotEqual,
+                Token::Minus,
+                Token::Identifier("b".into()),
+                Token::NotEqual,
+                Token::Identifier("p".into()),
+                Token::Dot,
+                Token::Identifier("c".into()),
+                Token::Percent,
+                Token::Integer(2),
+                Token::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn parser_select() {
+        let cmd = parse(
+            "SELECT DISTINCT p.name AS n, count(*) FROM people p JOIN pets ON p.id = pets.owner \
+             WHERE NOT age + 1 * 2 > 3 OR age IS NULL GROUP BY p.name ORDER BY n DESC, 2 LIMIT 5"
+                .into(),
+        )
+        .unwrap();
+        let ident = |name: &str| Expr::Ident(Token::Identifier(name.into()).into());
+        let qualified = |table: &str, col: &str| Expr::Qualified {
+            table: Token::Identifier(table.into()).into(),
+            col: Token::Identifier(col.into()).into(),
+        };
+        let number = |n: f64| Expr::Literal(LiteralValue::Number(OrderedFloat(n)));
+        let binary = |left, op, right| Expr::Binary {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        };
+        assert_eq!(
+            cmd,
+            Command::Select(Box::new(Select {
+                distinct: true,
+                items: vec![
+                    SelectItem::Expr {
+                        expr: qualified("p", "name"),
+                        alias: Some(Token::Identifier("n".into()).into()),
+                    },
+                    SelectItem::Expr {
+                        expr: Expr::Function {
+                            name: Token::Identifier("count".into()).into(),
+                            args: Vec::new(),
+                            star: true,
+                        },
+                        alias: None,
+                    },
+                ],
+                from: Some(FromClause {
+                    table: TableRef {
+                        name: Token::Identifier("people".into()).into(),
+                        alias: Some(Token::Identifier("p".into()).into()),
+                    },
+                    joins: vec![Join {
+                        table: TableRef {
+                            name: Token::Identifier("pets".into()).into(),
+                            alias: None,
+                        },
+                        on: Some(binary(
+                            qualified("p", "id"),
+                            Token::Equal,
+                            qualified("pets", "owner")
+                        )),
+                    }],
+                }),
+                filter: Some(binary(
+                    Expr::Unary {
+                        op: Token::Not,
+                        expr: Box::new(binary(
+                            binary(
+                                ident("age"),
+                                Token::Plus,
+                                binary(number(1.0), Token::Star, number(2.0))
+                            ),
+                            Token::GreaterThan,
+                            number(3.0)
+                        )),
+                    },
+                    Token::Or,
+                    Expr::IsNull {
+                        expr: Box::new(ident("age")),
+                        negated: false,
+                    }
+                )),
+                group_by: vec![qualified("p", "name")],
+                order_by: vec![
+                    OrderBy {
+                        expr: ident("n"),
+                        desc: true,
+                    },
+                    OrderBy {
+                        expr: number(2.0),
+                        desc: false,
+                    },
+                ],
+                limit: Some(number(5.0)),
+                ..Default::default()
+            }))
+        );
+        let Command::Select(select) = cmd else {
+            panic!("expected SELECT")
+        };
+        assert_eq!(
+            select.filter.unwrap().to_string(),
+            "NOT ((age + (1 * 2)) > 3) OR (age IS NULL)"
+        );
+
+        let Command::Select(select) =
+            parse("SELECT a FROM t WHERE a = 1 LIMIT 1 FOR UPDATE".into()).unwrap()
+        else {
+            panic!("expected SELECT")
+        };
+        assert!(select.for_update);
+        assert_eq!(select.tables(), ["t"]);
+
+        assert_err(
+            parse("SELECT a FROM t WHERE a = 1 b".into()),
+            "Unexpected identifier `b`; expected one of: end of input",
+        );
+    }
+
+    #[test]
+    fn params() {
+        let tokens = Scanner::scan("(?, $3, ?)".into()).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LeftParen,
+                Token::Param(1),
+                Token::Comma,
+                Token::Param(3),
+                Token::Comma,
+                Token::Param(2),
+                Token::RightParen,
+                Token::Eof,
+            ]
+        );
+        assert_err(
+            Scanner::scan("$0".into()),
+            "Parameters are written `$1`, `$2`, ...",
+        );
+
+        let cmd = parse("PREPARE add AS INSERT INTO people VALUES ($1, 16)".into()).unwrap();
+        let Command::Prepare { cmd, .. } = cmd else {
+            panic!("expected PREPARE")
+        };
+        assert_eq!(
+            *cmd,
+            Command::Insert {
+                table: Token::Identifier("people".into()).into(),
+                cols: Tokens::Omitted,
+                rows: vec![vec![
+                    Expr::Param(1),
+                    Expr::Literal(LiteralValue::Number(OrderedFloat(16.0)))
+                ]],
+            }
+        );
+        assert_eq!(
+            cmd.bind(&[LiteralValue::String("Elliot".into())]).unwrap(),
+            Command::Insert {
+                table: Token::Identifier("people".into()).into(),
+                cols: Tokens::Omitted,
+                rows: vec![vec![
+                    Expr::Literal(LiteralValue::String("Elliot".into())),
+                    Expr::Literal(LiteralValue::Number(OrderedFloat(16.0)))
+                ]],
+            }
+        );
+        assert_err(cmd.bind(&[]), "Expected 1 parameters, got 0");
+
+        assert_err(
+            parse("PREPARE a AS EXECUTE b".into()),
+            "Cannot prepare EXECUTE",
+        );
+        assert_err(parse("PREPARE a AS BEGIN".into()), "Cannot prepare BEGIN");
+    }
+
+    #[test]
+    fn transactions() {
+        let begin = |isolation| Command::Begin { isolation };
+        assert_eq!(
+            parse("BEGIN".into()).unwrap(),
+            begin(Isolation::ReadCommitted)
+        );
+        assert_eq!(
+            parse("begin transaction".into()).unwrap(),
+            begin(Isolation::ReadCommitted)
+        );
+        for (sql, isolation) in [
+            (
+                "BEGIN ISOLATION LEVEL READ COMMITTED",
+                Isolation::ReadCommitted,
+            ),
+            (
+                "BEGIN TRANSACTION ISOLATION LEVEL REPEATABLE READ",
+                Isolation::RepeatableRead,
+            ),
+            ("BEGIN ISOLATION LEVEL SNAPSHOT", Isolation::RepeatableRead),
+            (
+                "BEGIN ISOLATION LEVEL SERIALIZABLE",
+                Isolation::Serializable,
+            ),
+        ] {
+            assert_eq!(parse(sql.into()).unwrap(), begin(isolation));
+        }
+        assert!(parse("BEGIN ISOLATION LEVEL READ".into()).is_err());
+        assert!(parse("BEGIN ISOLATION SERIALIZABLE".into()).is_err());
+        assert_eq!(parse("COMMIT".into()).unwrap(), Command::Commit);
+        assert_eq!(parse("ROLLBACK".into()).unwrap(), Command::Rollback);
+        assert!(parse("COMMIT TRANSACTION".into()).is_err());
+
+        let name: Spanned<Token> = Token::Identifier("a".into()).into();
+        assert_eq!(
+            parse("SAVEPOINT a".into()).unwrap(),
+            Command::Savepoint { name: name.clone() }
+        );
+        for sql in ["ROLLBACK TO a", "ROLLBACK TO SAVEPOINT a"] {
+            assert_eq!(
+                parse(sql.into()).unwrap(),
+                Command::RollbackTo { name: name.clone() }
+            );
+        }
+        for sql in ["RELEASE a", "RELEASE SAVEPOINT a"] {
+            assert_eq!(
+                parse(sql.into()).unwrap(),
+                Command::Release { name: name.clone() }
+            );
+        }
+        assert!(parse("ROLLBACK TO".into()).is_err());
+
+        assert_eq!(parse("CHECKPOINT".into()).unwrap(), Command::Checkpoint);
+        for sql in ["BACKUP TO 'a/b.db'", "BACKUP TO \"a/b.db\""] {
+            assert_eq!(
+                parse(sql.into()).unwrap(),
+                Command::Backup {
+                    path: "a/b.db".into()
+                }
+            );
+        }
+        assert!(parse("BACKUP TO a".into()).is_err());
+        assert_eq!(parse("DUMP".into()).unwrap(), Command::Dump);
+
+        assert_eq!(
+            parse(
+                "COPY t (a) FROM 'a.csv' WITH (delimiter ';', HEADER, NULL 'x', quote '''', \
+                 ON_ERROR report)"
+                    .into()
+            )
+            .unwrap(),
+            Command::CopyFrom {
+                table: Token::Identifier("t".into()).into(),
+                cols: Tokens::List(vec![Token::Identifier("a".into()).into()]),
+                source: CopyTarget::File("a.csv".into()),
+                options: CopyOptions {
+                    delimiter: ';',
+                    header: true,
+                    null: "x".into(),
+                    quote: '\'',
+                    on_error: OnError::Report,
+                    format: CopyFormat::Csv,
+                },
+            }
+        );
+        assert!(matches!(
+            parse("COPY (SELECT a FROM t) TO 'a.csv' (HEADER false)".into()).unwrap(),
+            Command::CopyTo { options, .. } if options == CopyOptions::default()
+        ));
+        assert_eq!(
+            parse("COPY t TO 'a.csv'".into()).unwrap(),
+            parse("COPY (SELECT * FROM t) TO 'a.csv'".into()).unwrap()
+        );
+        assert!(matches!(
+            parse("COPY t FROM stdin (FORMAT NDJSON)".into()).unwrap(),
+            Command::CopyFrom { source: CopyTarget::Client, options, .. }
+                if options.format == CopyFormat::Ndjson
+        ));
+        assert!(matches!(
+            parse("COPY t TO STDOUT WITH (FORMAT json)".into()).unwrap(),
+            Command::CopyTo {
+                target: CopyTarget::Client,
+                ..
+            }
+        ));
+        for sql in [
+            "COPY t FROM 'a.csv' (DELIMITER ',,')",
+            "COPY t FROM 'a.csv' (QUOTE ',')",
+            "COPY t FROM 'a.csv' (FORMAT xml)",
+            "COPY t FROM 'a.csv' (HEADER, FORMAT json)",
+            "COPY t FROM STDOUT",
+            "COPY t FROM a.csv",
+        ] {
+            assert!(parse(sql.into()).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn scripts() {
+        assert_eq!(
+            split_script(
+                "-- A comment; not a statement\nINSERT INTO t VALUES ('a;''b', \"--\");\n\n;\
+                 DUMP"
+            ),
+            vec!["INSERT INTO t VALUES ('a;''b', \"--\")", "DUMP"]
+        );
+        let tokens = Scanner::scan("'a;''b' \"\"\"\"".into()).unwrap();
+        assert_eq!(tokens[0].node, Token::String("a;'b".into()));
+        assert_eq!(tokens[1].node, Token::String("\"".into()));
+    }
+
+    #[test]
+    fn spans() {
+        let tokens = Scanner::scan("SELECT name,\n  age FROM people".into()).unwrap();
+        let spans: Vec<_> = tokens.iter().map(|tok| tok.span).collect();
+        assert_eq!(
+            spans,
+            vec![
+                span(0, 6, 1, 1),
+                span(7, 11, 1, 8),
+                span(11, 12, 1, 12),
+                span(15, 18, 2, 3),
+                span(19, 23, 2, 7),
+                span(24, 30, 2, 12),
+                span(30, 30, 2, 18),
+            ]
+        );
+
+        let err = parse("INSERT INTO people (name)\nVALUES (*)".into()).unwrap_err();
+        let err = err.downcast_ref::<SpannedError>().unwrap();
+        assert_eq!(
+            err.msg(),
+            "Unexpected `*`; expected one of: number, string, boolean, NULL"
+        );
+        assert_eq!(err.span(), span(34, 35, 2, 9));
+
+        let err = Scanner::scan("SELECT #".into()).unwrap_err();
+        let err = err.downcast_ref::<SpannedError>().unwrap();
+        assert_eq!(err.span(), span(7, 8, 1, 8));
+    }
+
+    fn span(start: usize, end: usize, line: usize, col: usize) -> Span {
+        Span {
+            start,
+            end,
+            line,
+            col,
+        }
+    }
+
+    fn spanned(tokens: Vec<Token>) -> Vec<Spanned<Token>> {
+        tokens.into_iter().map(Spanned::from).collect()
+    }
+
     fn assert_err<T>(res: Result<T>, expected: &str) {
         assert!(res.is_err());
         match res {
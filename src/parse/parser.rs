@@ -1,36 +1,354 @@
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, Result};
 
 use crate::{parse::error::ERROR_EOF, Ty};
 
 use super::{
-    ast::{ColDecl, Command, Constraint, Expr, Key, LiteralValue, Tokens},
-    error::throw_unexpected,
-    token::Token,
+    ast::{
+        ColDecl, Command, Constraint, CopyFormat, CopyOptions, CopyTarget, Expr, FromClause,
+        Isolation, Join, LiteralValue, OnError, OrderBy, RefAction, References, Select, SelectItem,
+        TableConstraint, TableConstraintKind, TableRef, Tokens,
+    },
+    decimal::MAX_PRECISION,
+    error::{error_at, throw_unexpected},
+    token::{Spanned, Token},
     TableDef,
 };
 
+/// The longest a `VARCHAR` or `CHAR` can be declared
+const MAX_LENGTH: u32 = 10_485_760;
+
+/// How deeply expressions can nest, which keeps parsing them from overflowing the stack
+const MAX_DEPTH: usize = 64;
+
 pub struct Parser {
-    tokens: Vec<Token>,
+    tokens: Vec<Spanned<Token>>,
     current: usize,
+
+    /// Expressions currently being parsed inside one another
+    depth: usize,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Parser {
-        Parser { tokens, current: 0 }
+    pub fn new(tokens: Vec<Spanned<Token>>) -> Parser {
+        Parser {
+            tokens,
+            current: 0,
+            depth: 0,
+        }
     }
 
     pub fn parse(&mut self) -> Result<Command> {
-        self.command()
+        let cmd = self.command()?;
+        match self.peek() {
+            Ok(next) if next != &Token::Eof => throw_unexpected(next, vec![Token::Eof]),
+            _ => Ok(cmd),
+        }
+    }
+
+    /// A lone expression, filling the whole input
+    pub fn parse_expr(&mut self) -> Result<Expr> {
+        let expr = self.expr()?;
+        match self.peek() {
+            Ok(next) if next != &Token::Eof => throw_unexpected(next, vec![Token::Eof]),
+            _ => Ok(expr),
+        }
     }
 
     fn command(&mut self) -> Result<Command> {
         let cur = self.advance()?;
-        match cur {
+        match &cur.node {
             Token::Insert => self.insert(),
             Token::Select => self.select(),
-            Token::Create => self.create_table(),
-            _ => throw_unexpected(cur, vec![Token::Insert, Token::Select]),
+            Token::Update => self.update(),
+            Token::Delete => self.delete(),
+            Token::Create => self.create(),
+            Token::Drop => {
+                self.consume(&Token::Index)?;
+                let name = self.consume_ident()?.clone();
+                Ok(Command::DropIndex { name })
+            }
+            Token::Explain => self.explain(),
+            Token::Prepare => self.prepare(),
+            Token::Execute => self.execute(),
+            Token::Deallocate => {
+                let name = self.consume_ident()?.clone();
+                Ok(Command::Deallocate { name })
+            }
+            Token::Begin => {
+                let _ = self.consume(&Token::Transaction);
+                let isolation = match self.consume(&Token::Isolation) {
+                    Ok(_) => {
+                        self.consume(&Token::Level)?;
+                        self.isolation()?
+                    }
+                    Err(_) => Isolation::default(),
+                };
+                Ok(Command::Begin { isolation })
+            }
+            Token::Commit => Ok(Command::Commit),
+            Token::Rollback => {
+                if self.consume(&Token::To).is_err() {
+                    return Ok(Command::Rollback);
+                }
+                let _ = self.consume(&Token::Savepoint);
+                let name = self.consume_ident()?.clone();
+                Ok(Command::RollbackTo { name })
+            }
+            Token::Savepoint => {
+                let name = self.consume_ident()?.clone();
+                Ok(Command::Savepoint { name })
+            }
+            Token::Release => {
+                let _ = self.consume(&Token::Savepoint);
+                let name = self.consume_ident()?.clone();
+                Ok(Command::Release { name })
+            }
+            Token::Checkpoint => Ok(Command::Checkpoint),
+            Token::Dump => Ok(Command::Dump),
+            Token::Backup => {
+                self.consume(&Token::To)?;
+                let path = self.string()?;
+                Ok(Command::Backup { path })
+            }
+            Token::Copy => self.copy(),
+            _ => throw_unexpected(
+                cur,
+                vec![
+                    Token::Insert,
+                    Token::Select,
+                    Token::Update,
+                    Token::Delete,
+                    Token::Create,
+                    Token::Drop,
+                    Token::Explain,
+                    Token::Prepare,
+                    Token::Execute,
+                    Token::Deallocate,
+                    Token::Begin,
+                    Token::Commit,
+                    Token::Rollback,
+                    Token::Savepoint,
+                    Token::Release,
+                    Token::Checkpoint,
+                    Token::Backup,
+                    Token::Dump,
+                    Token::Copy,
+                ],
+            ),
+        }
+    }
+
+    fn copy(&mut self) -> Result<Command> {
+        if self.consume(&Token::LeftParen).is_ok() {
+            self.consume(&Token::Select)?;
+            let Command::Select(query) = self.select()? else {
+                return Err(anyhow!("Internal error"));
+            };
+            self.consume(&Token::RightParen)?;
+            self.consume(&Token::To)?;
+            let target = self.copy_target("STDOUT")?;
+            let options = self.copy_options()?;
+            return Ok(Command::CopyTo {
+                query,
+                target,
+                options,
+            });
+        }
+        let table = self.consume_ident()?.clone();
+        let cols = self.tokens()?;
+        let next = self.advance()?.clone();
+        match next.node {
+            Token::From => {
+                let source = self.copy_target("STDIN")?;
+                let options = self.copy_options()?;
+                Ok(Command::CopyFrom {
+                    table,
+                    cols,
+                    source,
+                    options,
+                })
+            }
+            Token::To => {
+                let items = match cols {
+                    Tokens::List(cols) => cols
+                        .into_iter()
+                        .map(|col| SelectItem::Expr {
+                            expr: Expr::Ident(col),
+                            alias: None,
+                        })
+                        .collect(),
+                    Tokens::Omitted => vec![SelectItem::Wildcard(None)],
+                };
+                let query = Select {
+                    items,
+                    from: Some(FromClause {
+                        table: TableRef {
+                            name: table,
+                            alias: None,
+                        },
+                        joins: Vec::new(),
+                    }),
+                    ..Default::default()
+                };
+                let target = self.copy_target("STDOUT")?;
+                let options = self.copy_options()?;
+                Ok(Command::CopyTo {
+                    query: Box::new(query),
+                    target,
+                    options,
+                })
+            }
+            _ => throw_unexpected(&next, vec![Token::From, Token::To]),
+        }
+    }
+
+    /// A file path, or `stdio` for the connection
+    fn copy_target(&mut self, stdio: &str) -> Result<CopyTarget> {
+        if matches!(&self.peek()?.node, Token::Identifier(name) if name.eq_ignore_ascii_case(stdio))
+        {
+            self.advance()?;
+            return Ok(CopyTarget::Client);
+        }
+        Ok(CopyTarget::File(self.string()?))
+    }
+
+    /// `[WITH] (option [value], ...)`, if there are options
+    fn copy_options(&mut self) -> Result<CopyOptions> {
+        let mut options = CopyOptions::default();
+        // Options that only apply to CSV
+        let mut csv = None;
+        if matches!(&self.peek()?.node, Token::Identifier(with) if with.eq_ignore_ascii_case("with"))
+        {
+            self.advance()?;
+        } else if self.peek()?.node != Token::LeftParen {
+            return Ok(options);
+        }
+        self.consume(&Token::LeftParen)?;
+        loop {
+            let next = self.advance()?.clone();
+            let name = match &next.node {
+                Token::Identifier(name) => name.to_ascii_uppercase(),
+                Token::Null => "NULL".to_string(),
+                _ => return throw_unexpected(&next, vec![Token::Identifier(String::new())]),
+            };
+            if matches!(&name[..], "DELIMITER" | "QUOTE" | "NULL" | "HEADER") {
+                csv.get_or_insert(next.span);
+            }
+            match &name[..] {
+                "DELIMITER" => options.delimiter = self.char_option()?,
+                "QUOTE" => options.quote = self.char_option()?,
+                "NULL" => options.null = self.string()?,
+                "HEADER" => {
+                    if let Token::Bool(header) = self.peek()?.node {
+                        self.advance()?;
+                        options.header = header;
+                    } else {
+                        options.header = true;
+                    }
+                }
+                "ON_ERROR" => {
+                    let value = self.consume_ident()?;
+                    options.on_error = match &value.ident().map(|s| s.to_ascii_uppercase()) {
+                        Some(s) if s == "ABORT" => OnError::Abort,
+                        Some(s) if s == "REPORT" => OnError::Report,
+                        _ => return Err(error_at(value.span, "Expected ABORT or REPORT")),
+                    };
+                }
+                "FORMAT" => {
+                    let value = self.consume_ident()?;
+                    options.format = match &value.ident().map(|s| s.to_ascii_uppercase()) {
+                        Some(s) if s == "CSV" => CopyFormat::Csv,
+                        Some(s) if s == "JSON" => CopyFormat::Json,
+                        Some(s) if s == "NDJSON" => CopyFormat::Ndjson,
+                        _ => return Err(error_at(value.span, "Expected CSV, JSON or NDJSON")),
+                    };
+                }
+                _ => {
+                    return Err(error_at(
+                        next.span,
+                        format!("Unknown COPY option {}", next.node),
+                    ))
+                }
+            }
+            if self.consume(&Token::Comma).is_err() {
+                break;
+            }
+        }
+        self.consume(&Token::RightParen)?;
+        if let (Some(span), CopyFormat::Json | CopyFormat::Ndjson) = (csv, options.format) {
+            return Err(error_at(span, "Only CSV has this option"));
+        }
+        if options.delimiter == options.quote {
+            return Err(error_at(
+                self.previous()?.span,
+                "The delimiter and quote must differ",
+            ));
         }
+        Ok(options)
+    }
+
+    /// A string of one character, other than a line break
+    fn char_option(&mut self) -> Result<char> {
+        let text = self.string()?;
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c != '\n' && c != '\r' => Ok(c),
+            _ => Err(error_at(
+                self.previous()?.span,
+                "Expected a single character",
+            )),
+        }
+    }
+
+    /// A string literal
+    fn string(&mut self) -> Result<String> {
+        let tok = self.advance()?;
+        match &tok.node {
+            Token::String(s) => Ok(s.clone()),
+            _ => throw_unexpected(tok, vec![Token::String(String::new())]),
+        }
+    }
+
+    fn explain(&mut self) -> Result<Command> {
+        let analyze = self.consume(&Token::Analyze).is_ok();
+        let next = self.peek()?;
+        if next != &Token::Select {
+            return Err(error_at(next.span, "Only queries can be explained"));
+        }
+        Ok(Command::Explain {
+            analyze,
+            cmd: Box::new(self.command()?),
+        })
+    }
+
+    fn prepare(&mut self) -> Result<Command> {
+        let name = self.consume_ident()?.clone();
+        self.consume(&Token::As)?;
+        let next = self.peek()?.clone();
+        match next.node {
+            Token::Prepare
+            | Token::Execute
+            | Token::Deallocate
+            | Token::Begin
+            | Token::Commit
+            | Token::Rollback
+            | Token::Checkpoint
+            | Token::Backup => Err(error_at(next.span, format!("Cannot prepare {}", next.node))),
+            _ => Ok(Command::Prepare {
+                name,
+                cmd: Box::new(self.command()?),
+            }),
+        }
+    }
+
+    fn execute(&mut self) -> Result<Command> {
+        let name = self.consume_ident()?.clone();
+        let params = if let Ok(Token::LeftParen) = self.peek().map(|tok| &tok.node) {
+            self.values()?
+        } else {
+            Vec::new()
+        };
+        Ok(Command::Execute { name, params })
     }
 
     fn insert(&mut self) -> Result<Command> {
@@ -43,23 +361,198 @@ impl Parser {
     }
 
     fn select(&mut self) -> Result<Command> {
-        let key = self.key()?;
+        let distinct = self.consume(&Token::Distinct).is_ok();
+        let mut items = vec![self.select_item()?];
+        while self.consume(&Token::Comma).is_ok() {
+            items.push(self.select_item()?);
+        }
+        let from = if self.consume(&Token::From).is_ok() {
+            Some(self.from()?)
+        } else {
+            None
+        };
+        let filter = self.clause(&Token::Where)?;
+        let mut group_by = Vec::new();
+        if self.consume(&Token::Group).is_ok() {
+            self.consume(&Token::By)?;
+            group_by = self.expr_list()?;
+        }
+        let having = self.clause(&Token::Having)?;
+        let mut order_by = Vec::new();
+        if self.consume(&Token::Order).is_ok() {
+            self.consume(&Token::By)?;
+            loop {
+                let expr = self.expr()?;
+                let desc = if self.consume(&Token::Desc).is_ok() {
+                    true
+                } else {
+                    let _ = self.consume(&Token::Asc);
+                    false
+                };
+                order_by.push(OrderBy { expr, desc });
+                if self.consume(&Token::Comma).is_err() {
+                    break;
+                }
+            }
+        }
+        let limit = self.clause(&Token::Limit)?;
+        let offset = self.clause(&Token::Offset)?;
+        let for_update = self.consume(&Token::For).is_ok();
+        if for_update {
+            self.consume(&Token::Update)?;
+        }
+        Ok(Command::Select(Box::new(Select {
+            distinct,
+            items,
+            from,
+            filter,
+            group_by,
+            having,
+            order_by,
+            limit,
+            offset,
+            for_update,
+        })))
+    }
+
+    /// The expression after `keyword`, if the next token is `keyword`
+    fn clause(&mut self, keyword: &Token) -> Result<Option<Expr>> {
+        if self.consume(keyword).is_ok() {
+            Ok(Some(self.expr()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn select_item(&mut self) -> Result<SelectItem> {
+        if self.consume(&Token::Star).is_ok() {
+            return Ok(SelectItem::Wildcard(None));
+        }
+        if matches!(self.peek()?.node, Token::Identifier(_))
+            && self.peek_at(1).map(|tok| &tok.node) == Some(&Token::Dot)
+            && self.peek_at(2).map(|tok| &tok.node) == Some(&Token::Star)
+        {
+            let table = self.advance()?.clone();
+            self.advance()?;
+            self.advance()?;
+            return Ok(SelectItem::Wildcard(Some(table)));
+        }
+        let expr = self.expr()?;
+        let alias = self.alias()?;
+        Ok(SelectItem::Expr { expr, alias })
+    }
+
+    /// `[AS] name`
+    fn alias(&mut self) -> Result<Option<Spanned<Token>>> {
+        if self.consume(&Token::As).is_ok() {
+            return Ok(Some(self.consume_ident()?.clone()));
+        }
+        if let Token::Identifier(_) = self.peek()?.node {
+            return Ok(Some(self.advance()?.clone()));
+        }
+        Ok(None)
+    }
+
+    fn from(&mut self) -> Result<FromClause> {
+        let table = self.table_ref()?;
+        let mut joins = Vec::new();
+        loop {
+            match self.peek()?.node {
+                Token::Comma => {
+                    self.advance()?;
+                    joins.push(Join {
+                        table: self.table_ref()?,
+                        on: None,
+                    });
+                }
+                Token::Cross => {
+                    self.advance()?;
+                    self.consume(&Token::Join)?;
+                    joins.push(Join {
+                        table: self.table_ref()?,
+                        on: None,
+                    });
+                }
+                Token::Inner | Token::Join => {
+                    if self.advance()?.node == Token::Inner {
+                        self.consume(&Token::Join)?;
+                    }
+                    let table = self.table_ref()?;
+                    self.consume(&Token::On)?;
+                    joins.push(Join {
+                        table,
+                        on: Some(self.expr()?),
+                    });
+                }
+                _ => return Ok(FromClause { table, joins }),
+            }
+        }
+    }
+
+    fn table_ref(&mut self) -> Result<TableRef> {
+        let name = self.consume_ident()?.clone();
+        let alias = self.alias()?;
+        Ok(TableRef { name, alias })
+    }
+
+    fn update(&mut self) -> Result<Command> {
+        let table = self.consume_ident()?.clone();
+        self.consume(&Token::Set)?;
+        let mut set = Vec::new();
+        loop {
+            let col = self.consume_ident()?.clone();
+            self.consume(&Token::Equal)?;
+            set.push((col, self.expr()?));
+            if self.consume(&Token::Comma).is_err() {
+                break;
+            }
+        }
+        let filter = self.clause(&Token::Where)?;
+        Ok(Command::Update { table, set, filter })
+    }
+
+    fn delete(&mut self) -> Result<Command> {
         self.consume(&Token::From)?;
         let table = self.consume_ident()?.clone();
-        Ok(Command::Select { key, table })
+        let filter = self.clause(&Token::Where)?;
+        Ok(Command::Delete { table, filter })
+    }
+
+    fn create(&mut self) -> Result<Command> {
+        let next = self.peek()?;
+        match &next.node {
+            Token::Table => self.create_table(),
+            Token::Unique | Token::Index => self.create_index(),
+            _ => throw_unexpected(next, vec![Token::Table, Token::Unique, Token::Index]),
+        }
+    }
+
+    fn create_index(&mut self) -> Result<Command> {
+        let unique = self.consume(&Token::Unique).is_ok();
+        self.consume(&Token::Index)?;
+        let name = self.consume_ident()?.clone();
+        self.consume(&Token::On)?;
+        let table = self.consume_ident()?.clone();
+        self.consume(&Token::LeftParen)?;
+        let cols = self.token_list()?;
+        self.consume(&Token::RightParen)?;
+        Ok(Command::CreateIndex {
+            name,
+            table,
+            cols,
+            unique,
+        })
     }
 
     fn create_table(&mut self) -> Result<Command> {
         self.consume(&Token::Table)?;
         let name = self.consume_ident()?.clone();
-        match self.peek()? {
-            Token::LeftParen => {
-                let col_decls = self.col_decls()?;
-                Ok(Command::CreateTable {
-                    name,
-                    def: TableDef::Cols(col_decls),
-                })
-            }
+        let next = self.peek()?;
+        match &next.node {
+            Token::LeftParen => Ok(Command::CreateTable {
+                name,
+                def: self.table_elements()?,
+            }),
             Token::As => {
                 self.consume(&Token::As)?;
                 self.consume(&Token::Select)?;
@@ -69,30 +562,160 @@ impl Parser {
                     def: TableDef::As(Box::new(cmd)),
                 })
             }
-            other => throw_unexpected(other, vec![Token::LeftParen, Token::As]),
+            _ => throw_unexpected(next, vec![Token::LeftParen, Token::As]),
         }
     }
 
-    fn col_decls(&mut self) -> Result<Vec<ColDecl>> {
+    /// Column declarations and table constraints, in any order
+    fn table_elements(&mut self) -> Result<TableDef> {
         self.consume(&Token::LeftParen)?;
-        let first_ident = self.consume_ident()?.clone();
-        let first_ty = self.ty()?;
-        let mut decls = vec![ColDecl::new(first_ident, first_ty, self.constraints()?)];
-        while self.consume(&Token::Comma).is_ok() {
-            decls.push(ColDecl::new(
-                self.consume_ident()?.clone(),
-                self.ty()?,
-                self.constraints()?,
-            ));
+        let (mut cols, mut constraints) = (Vec::new(), Vec::new());
+        loop {
+            if matches!(
+                self.peek()?.node,
+                Token::Constraint | Token::Primary | Token::Unique | Token::Check | Token::Foreign
+            ) {
+                constraints.push(self.table_constraint()?);
+            } else {
+                cols.push(ColDecl::new(
+                    self.consume_ident()?.clone(),
+                    self.ty()?,
+                    self.constraints()?,
+                ));
+            }
+            if self.consume(&Token::Comma).is_err() {
+                break;
+            }
         }
         self.consume(&Token::RightParen)?;
-        Ok(decls)
+        Ok(TableDef::Cols { cols, constraints })
+    }
+
+    /// `[CONSTRAINT name] PRIMARY KEY (cols) | UNIQUE (cols) | CHECK (expr) | FOREIGN KEY ...`
+    fn table_constraint(&mut self) -> Result<TableConstraint> {
+        let name = match self.consume(&Token::Constraint) {
+            Ok(_) => Some(self.consume_ident()?.clone()),
+            Err(_) => None,
+        };
+        let next = self.advance()?;
+        let kind = match &next.node {
+            Token::Primary => {
+                self.consume(&Token::Key)?;
+                TableConstraintKind::PrimaryKey(self.paren_list()?)
+            }
+            Token::Unique => TableConstraintKind::Unique(self.paren_list()?),
+            Token::Check => {
+                self.consume(&Token::LeftParen)?;
+                let expr = self.expr()?;
+                self.consume(&Token::RightParen)?;
+                TableConstraintKind::Check(expr)
+            }
+            Token::Foreign => {
+                self.consume(&Token::Key)?;
+                let cols = self.paren_list()?;
+                self.consume(&Token::References)?;
+                TableConstraintKind::ForeignKey {
+                    cols,
+                    references: self.references()?,
+                }
+            }
+            _ => {
+                return throw_unexpected(
+                    next,
+                    vec![Token::Primary, Token::Unique, Token::Check, Token::Foreign],
+                )
+            }
+        };
+        Ok(TableConstraint { name, kind })
+    }
+
+    /// A parenthesized list of identifiers
+    fn paren_list(&mut self) -> Result<Vec<Spanned<Token>>> {
+        self.consume(&Token::LeftParen)?;
+        let list = self.token_list()?;
+        self.consume(&Token::RightParen)?;
+        Ok(list)
+    }
+
+    /// What follows `REFERENCES`
+    fn references(&mut self) -> Result<References> {
+        let table = self.consume_ident()?.clone();
+        let mut cols = Vec::new();
+        if self.consume(&Token::LeftParen).is_ok() {
+            cols = self.token_list()?;
+            self.consume(&Token::RightParen)?;
+        }
+        let (mut on_delete, mut on_update) = (RefAction::default(), RefAction::default());
+        while self.consume(&Token::On).is_ok() {
+            let next = self.advance()?;
+            let action = match &next.node {
+                Token::Delete => &mut on_delete,
+                Token::Update => &mut on_update,
+                _ => return throw_unexpected(next, vec![Token::Delete, Token::Update]),
+            };
+            *action = self.ref_action()?;
+        }
+        Ok(References {
+            table,
+            cols,
+            on_delete,
+            on_update,
+        })
+    }
+
+    fn isolation(&mut self) -> Result<Isolation> {
+        let next = self.advance()?;
+        match &next.node {
+            Token::Read => {
+                self.consume(&Token::Committed)?;
+                Ok(Isolation::ReadCommitted)
+            }
+            Token::Repeatable => {
+                self.consume(&Token::Read)?;
+                Ok(Isolation::RepeatableRead)
+            }
+            Token::Snapshot => Ok(Isolation::RepeatableRead),
+            Token::Serializable => Ok(Isolation::Serializable),
+            _ => throw_unexpected(
+                next,
+                vec![
+                    Token::Read,
+                    Token::Repeatable,
+                    Token::Snapshot,
+                    Token::Serializable,
+                ],
+            ),
+        }
+    }
+
+    fn ref_action(&mut self) -> Result<RefAction> {
+        let next = self.advance()?;
+        match &next.node {
+            Token::Cascade => Ok(RefAction::Cascade),
+            Token::Restrict => Ok(RefAction::Restrict),
+            Token::No => {
+                self.consume(&Token::Action)?;
+                Ok(RefAction::NoAction)
+            }
+            Token::Set => {
+                let next = self.advance()?;
+                match &next.node {
+                    Token::Null => Ok(RefAction::SetNull),
+                    Token::Default => Ok(RefAction::SetDefault),
+                    _ => throw_unexpected(next, vec![Token::Null, Token::Default]),
+                }
+            }
+            _ => throw_unexpected(
+                next,
+                vec![Token::Cascade, Token::Restrict, Token::No, Token::Set],
+            ),
+        }
     }
 
     fn constraints(&mut self) -> Result<Vec<Constraint>> {
         let mut constraints = Vec::new();
         loop {
-            match self.peek()? {
+            match &self.peek()?.node {
                 Token::Not => {
                     self.advance()?;
                     self.consume(&Token::Null)?;
@@ -107,10 +730,9 @@ impl Parser {
                     self.consume(&Token::Key)?;
                     constraints.push(Constraint::PrimaryKey);
                 }
-                Token::Foreign => {
+                Token::References => {
                     self.advance()?;
-                    self.consume(&Token::Key)?;
-                    constraints.push(Constraint::ForeignKey);
+                    constraints.push(Constraint::References(self.references()?));
                 }
                 Token::Check => {
                     self.advance()?;
@@ -121,8 +743,12 @@ impl Parser {
                 }
                 Token::Default => {
                     self.advance()?;
-                    let lit = self.literal()?;
-                    constraints.push(Constraint::Default(lit));
+                    // A value, or a function like `now()`
+                    let expr = match self.peek()?.node {
+                        Token::Identifier(_) => self.primary()?,
+                        _ => Expr::Literal(self.literal()?),
+                    };
+                    constraints.push(Constraint::Default(expr));
                 }
                 Token::Create => {
                     self.advance()?;
@@ -135,62 +761,322 @@ impl Parser {
     }
 
     fn expr(&mut self) -> Result<Expr> {
-        let left = self.bin_side()?;
-        let next = self.advance()?;
-        let op = match next {
+        self.nested(Self::or)
+    }
+
+    /// Parses with `f` one level deeper, failing past `MAX_DEPTH`
+    fn nested(&mut self, f: fn(&mut Self) -> Result<Expr>) -> Result<Expr> {
+        if self.depth == MAX_DEPTH {
+            return Err(error_at(self.peek()?.span, "Expression nested too deeply"));
+        }
+        self.depth += 1;
+        let res = f(self);
+        self.depth -= 1;
+        res
+    }
+
+    fn expr_list(&mut self) -> Result<Vec<Expr>> {
+        let mut exprs = vec![self.expr()?];
+        while self.consume(&Token::Comma).is_ok() {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        self.binary(&[Token::Or], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        self.binary(&[Token::And], Self::not)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.consume(&Token::Not).is_ok() {
+            Ok(Expr::Unary {
+                op: Token::Not,
+                expr: Box::new(self.nested(Self::not)?),
+            })
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr> {
+        let left = self.additive()?;
+        match self.peek()?.node {
             Token::LessThan
             | Token::LessEqual
             | Token::GreaterThan
             | Token::GreaterEqual
-            | Token::Equal => Ok(next.clone()),
-            _ => throw_unexpected(
-                next,
-                vec![
-                    Token::LessThan,
-                    Token::LessEqual,
-                    Token::GreaterThan,
-                    Token::GreaterEqual,
-                    Token::Equal,
-                ],
-            ),
-        }?;
-        let right = self.bin_side()?;
-        Ok(Expr::Binary { left, op, right })
+            | Token::Equal
+            | Token::NotEqual => {
+                let op = self.advance()?.node.clone();
+                Ok(Expr::Binary {
+                    left: Box::new(left),
+                    op,
+                    right: Box::new(self.additive()?),
+                })
+            }
+            Token::Is => {
+                self.advance()?;
+                let negated = self.consume(&Token::Not).is_ok();
+                self.consume(&Token::Null)?;
+                Ok(Expr::IsNull {
+                    expr: Box::new(left),
+                    negated,
+                })
+            }
+            _ => Ok(left),
+        }
     }
 
-    fn bin_side(&mut self) -> Result<Token> {
-        let next = self.advance()?;
-        match next {
-            Token::String(_) | Token::Number(_) | Token::Identifier(_) => Ok(next.clone()),
+    fn additive(&mut self) -> Result<Expr> {
+        self.binary(&[Token::Plus, Token::Minus], Self::multiplicative)
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr> {
+        self.binary(&[Token::Star, Token::Slash, Token::Percent], Self::unary)
+    }
+
+    /// Left-associative chain of `operand`s joined by any of `ops`
+    fn binary(&mut self, ops: &[Token], operand: fn(&mut Self) -> Result<Expr>) -> Result<Expr> {
+        let mut expr = operand(self)?;
+        while ops.contains(&self.peek()?.node) {
+            let op = self.advance()?.node.clone();
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                op,
+                right: Box::new(operand(self)?),
+            };
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.peek()?.node {
+            Token::Minus | Token::Plus => {
+                let op = self.advance()?.node.clone();
+                Ok(Expr::Unary {
+                    op,
+                    expr: Box::new(self.nested(Self::unary)?),
+                })
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        if let Some(lit) = self.typed_literal()? {
+            return Ok(Expr::Literal(lit));
+        }
+        let next = self.advance()?.clone();
+        match &next.node {
+            Token::Number(n) => Ok(Expr::Literal(LiteralValue::Number(
+                ordered_float::OrderedFloat(*n),
+            ))),
+            Token::Integer(n) => Ok(Expr::Literal(LiteralValue::Int(*n))),
+            Token::Decimal(d) => Ok(Expr::Literal(LiteralValue::Decimal(d.clone()))),
+            Token::String(s) => Ok(Expr::Literal(LiteralValue::String(s.clone()))),
+            Token::Bool(b) => Ok(Expr::Literal(LiteralValue::Bool(*b))),
+            Token::Null => Ok(Expr::Literal(LiteralValue::Null)),
+            Token::Param(n) => Ok(Expr::Param(*n)),
+            Token::LeftParen => {
+                let expr = self.expr()?;
+                self.consume(&Token::RightParen)?;
+                Ok(expr)
+            }
+            Token::Identifier(_) => {
+                if self.consume(&Token::Dot).is_ok() {
+                    let col = self.consume_ident()?.clone();
+                    Ok(Expr::Qualified { table: next, col })
+                } else if self.consume(&Token::LeftParen).is_ok() {
+                    let star = self.consume(&Token::Star).is_ok();
+                    let args = if star || self.peek()?.node == Token::RightParen {
+                        Vec::new()
+                    } else if next
+                        .ident()
+                        .is_some_and(|name| name.eq_ignore_ascii_case("extract"))
+                        && self.peek_at(1).is_some_and(|tok| tok.node == Token::From)
+                    {
+                        // EXTRACT(field FROM expr)
+                        let field = self.consume_ident()?.ident().cloned().unwrap_or_default();
+                        self.advance()?;
+                        vec![Expr::Literal(LiteralValue::String(field)), self.expr()?]
+                    } else {
+                        self.expr_list()?
+                    };
+                    self.consume(&Token::RightParen)?;
+                    Ok(Expr::Function {
+                        name: next,
+                        args,
+                        star,
+                    })
+                } else if next
+                    .ident()
+                    .is_some_and(|name| name.eq_ignore_ascii_case("current_timestamp"))
+                {
+                    Ok(Expr::Function {
+                        name: Spanned::new(Token::Identifier("now".to_string()), next.span),
+                        args: Vec::new(),
+                        star: false,
+                    })
+                } else {
+                    Ok(Expr::Ident(next))
+                }
+            }
             _ => throw_unexpected(
-                next,
+                &next,
                 vec![
                     Token::String(String::new()),
                     Token::Number(0.0),
                     Token::Identifier(String::new()),
+                    Token::Param(0),
+                    Token::LeftParen,
                 ],
             ),
         }
     }
 
     fn ty(&mut self) -> Result<Ty> {
-        let name = self.consume_ident()?;
-        Ok(
-            match &name.ident().ok_or_else(|| anyhow!("expected ident"))?[..] {
-                "string" => Ty::String,
-                "number" => Ty::Number,
-                other => bail!("unknown type {}", other),
+        let name = self.consume_ident()?.clone();
+        let ty_name = name
+            .ident()
+            .ok_or_else(|| anyhow!("expected ident"))?
+            .to_ascii_lowercase();
+        Ok(match &ty_name[..] {
+            "string" | "text" => Ty::String,
+            "varchar" => self.length()?.map_or(Ty::String, Ty::Varchar),
+            "char" => Ty::Char(self.length()?.unwrap_or(1)),
+            "character" if self.consume_word("varying") => {
+                self.length()?.map_or(Ty::String, Ty::Varchar)
+            }
+            "character" => Ty::Char(self.length()?.unwrap_or(1)),
+            "number" => Ty::Number,
+            "bool" | "boolean" => Ty::Bool,
+            "smallint" | "int2" => Ty::SmallInt,
+            "integer" | "int" | "int4" => Ty::Integer,
+            "bigint" | "int8" => Ty::BigInt,
+            "real" | "float4" => Ty::Real,
+            "float" | "float8" => Ty::Double,
+            "double" => {
+                self.consume_word("precision");
+                Ty::Double
+            }
+            "decimal" | "numeric" => Ty::Decimal(self.precision()?),
+            "date" => Ty::Date,
+            "time" => match self.time_zone()? {
+                Some(true) => {
+                    return Err(error_at(name.span, "TIME WITH TIME ZONE is not supported"))
+                }
+                _ => Ty::Time,
             },
-        )
+            "timestamp" => match self.time_zone()? {
+                Some(true) => Ty::TimestampTz,
+                _ => Ty::Timestamp,
+            },
+            "timestamptz" => Ty::TimestampTz,
+            "interval" => Ty::Interval,
+            other => return Err(error_at(name.span, format!("unknown type {}", other))),
+        })
     }
 
-    fn key(&mut self) -> Result<Key> {
-        if self.peek()? == &Token::Star {
-            self.advance()?;
-            Ok(Key::Glob)
+    /// `[WITH | WITHOUT TIME ZONE]` after `TIME` or `TIMESTAMP`: whether there is a zone, if
+    /// it is given
+    fn time_zone(&mut self) -> Result<Option<bool>> {
+        let with = if self.consume_word("with") {
+            true
+        } else if self.consume_word("without") {
+            false
         } else {
-            let keys = self.token_list()?;
-            Ok(Key::List(keys))
+            return Ok(None);
+        };
+        for word in ["time", "zone"] {
+            if !self.consume_word(word) {
+                let next = self.peek()?;
+                return Err(error_at(
+                    next.span,
+                    format!("Expected {}", word.to_uppercase()),
+                ));
+            }
+        }
+        Ok(Some(with))
+    }
+
+    /// A string after the name of a date or time type, like `DATE '2024-01-01'`, read as a
+    /// value of that type
+    fn typed_literal(&mut self) -> Result<Option<LiteralValue>> {
+        let ty = match self.peek().map(|tok| &tok.node) {
+            Ok(Token::Identifier(name)) => match &name.to_ascii_lowercase()[..] {
+                "date" => Ty::Date,
+                "time" => Ty::Time,
+                "timestamp" => Ty::Timestamp,
+                "timestamptz" => Ty::TimestampTz,
+                "interval" => Ty::Interval,
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        let Some(Spanned {
+            node: Token::String(s),
+            span,
+        }) = self.peek_at(1).cloned()
+        else {
+            return Ok(None);
+        };
+        self.current += 2;
+        LiteralValue::String(s)
+            .coerce(&ty)
+            .map(Some)
+            .map_err(|e| error_at(span, e.to_string()))
+    }
+
+    /// Skips the identifier `word`, in any case, if it comes next
+    fn consume_word(&mut self, word: &str) -> bool {
+        let next = matches!(
+            self.peek().map(|tok| &tok.node),
+            Ok(Token::Identifier(ident)) if ident.eq_ignore_ascii_case(word)
+        );
+        if next {
+            self.current += 1;
+        }
+        next
+    }
+
+    /// `[(length)]` after a string type
+    fn length(&mut self) -> Result<Option<u32>> {
+        if self.consume(&Token::LeftParen).is_err() {
+            return Ok(None);
+        }
+        let len = self.bounded_int(1, MAX_LENGTH)?;
+        self.consume(&Token::RightParen)?;
+        Ok(Some(len))
+    }
+
+    /// `[(precision [, scale])]` after `DECIMAL`
+    fn precision(&mut self) -> Result<Option<(u32, u32)>> {
+        if self.consume(&Token::LeftParen).is_err() {
+            return Ok(None);
+        }
+        let precision = self.bounded_int(1, MAX_PRECISION)?;
+        let scale = if self.consume(&Token::Comma).is_ok() {
+            self.bounded_int(0, precision)?
+        } else {
+            0
+        };
+        self.consume(&Token::RightParen)?;
+        Ok(Some((precision, scale)))
+    }
+
+    fn bounded_int(&mut self, min: u32, max: u32) -> Result<u32> {
+        let tok = self.advance()?;
+        match tok.node {
+            Token::Integer(n) if n >= min as i64 && n <= max as i64 => Ok(n as u32),
+            Token::Integer(_) => Err(error_at(
+                tok.span,
+                format!("Expected a number from {} to {}", min, max),
+            )),
+            _ => throw_unexpected(tok, vec![Token::Integer(0)]),
         }
     }
 
@@ -204,7 +1090,7 @@ impl Parser {
         }
     }
 
-    fn token_list(&mut self) -> Result<Vec<Token>> {
+    fn token_list(&mut self) -> Result<Vec<Spanned<Token>>> {
         let first = self.consume_ident()?.clone();
         let mut tokens = vec![first];
         while self.consume(&Token::Comma).is_ok() {
@@ -213,7 +1099,7 @@ impl Parser {
         Ok(tokens)
     }
 
-    fn rows(&mut self) -> Result<Vec<Vec<LiteralValue>>> {
+    fn rows(&mut self) -> Result<Vec<Vec<Expr>>> {
         let first = self.values()?;
         let mut rows = vec![first];
         while self.consume(&Token::Comma).is_ok() {
@@ -222,33 +1108,63 @@ impl Parser {
         Ok(rows)
     }
 
-    fn values(&mut self) -> Result<Vec<LiteralValue>> {
+    fn values(&mut self) -> Result<Vec<Expr>> {
         self.consume(&Token::LeftParen)?;
-        let first = self.literal()?;
+        let first = self.value()?;
         let mut values = vec![first];
         while self.consume(&Token::Comma).is_ok() {
-            values.push(self.literal()?);
+            values.push(self.value()?);
         }
         self.consume(&Token::RightParen)?;
         Ok(values)
     }
 
+    /// A literal, `NULL`, or a placeholder for a value
+    fn value(&mut self) -> Result<Expr> {
+        match self.peek()?.node {
+            Token::Param(n) => {
+                self.advance()?;
+                Ok(Expr::Param(n))
+            }
+            _ => Ok(Expr::Literal(self.literal()?)),
+        }
+    }
+
     fn literal(&mut self) -> Result<LiteralValue> {
+        if let Some(lit) = self.typed_literal()? {
+            return Ok(lit);
+        }
         let tok = self.advance()?;
-        match tok {
+        match &tok.node {
             Token::Number(n) => Ok(LiteralValue::Number(ordered_float::OrderedFloat(*n))),
+            Token::Integer(n) => Ok(LiteralValue::Int(*n)),
+            Token::Decimal(d) => Ok(LiteralValue::Decimal(d.clone())),
             Token::String(s) => Ok(LiteralValue::String(s.clone())),
-            _ => throw_unexpected(tok, vec![Token::Number(0.0), Token::String(String::new())]),
+            Token::Bool(b) => Ok(LiteralValue::Bool(*b)),
+            Token::Null => Ok(LiteralValue::Null),
+            _ => throw_unexpected(
+                tok,
+                vec![
+                    Token::Number(0.0),
+                    Token::String(String::new()),
+                    Token::Bool(false),
+                    Token::Null,
+                ],
+            ),
         }
     }
 
-    fn peek(&self) -> Result<&Token> {
+    fn peek(&self) -> Result<&Spanned<Token>> {
         self.tokens
             .get(self.current)
             .ok_or_else(|| anyhow!(ERROR_EOF))
     }
 
-    fn previous(&self) -> Result<&Token> {
+    fn peek_at(&self, ahead: usize) -> Option<&Spanned<Token>> {
+        self.tokens.get(self.current + ahead)
+    }
+
+    fn previous(&self) -> Result<&Spanned<Token>> {
         if self.current == 0 {
             Err(anyhow!("Internal error"))
         } else {
@@ -258,12 +1174,12 @@ impl Parser {
         }
     }
 
-    fn advance(&mut self) -> Result<&Token> {
+    fn advance(&mut self) -> Result<&Spanned<Token>> {
         self.current += 1;
         self.previous()
     }
 
-    fn consume(&mut self, ty: &Token) -> Result<&Token> {
+    fn consume(&mut self, ty: &Token) -> Result<&Spanned<Token>> {
         let next = self.peek()?;
         if next == ty {
             self.advance()
@@ -272,9 +1188,9 @@ impl Parser {
         }
     }
 
-    fn consume_ident(&mut self) -> Result<&Token> {
+    fn consume_ident(&mut self) -> Result<&Spanned<Token>> {
         let next = self.peek()?;
-        if let Token::Identifier(_) = next {
+        if let Token::Identifier(_) = next.node {
             self.advance()
         } else {
             throw_unexpected(next, vec![Token::Identifier(String::new())])
@@ -1,43 +1,71 @@
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, Result};
 use bytes::Bytes;
 
 use super::{
-    error::ERROR_EOF,
-    token::{Keyword, Token},
+    error::{error_at, ERROR_EOF},
+    token::{Keyword, Span, Spanned, Token},
 };
 
 pub struct Scanner {
     source: Bytes,
-    tokens: Vec<Token>,
+    tokens: Vec<Spanned<Token>>,
     start: usize,
     current: usize,
+    line: usize,
+    line_start: usize,
+    start_line: usize,
+    start_col: usize,
+    positional_params: usize,
 }
 
 impl Scanner {
-    pub fn scan(source: Bytes) -> Result<Vec<Token>> {
+    pub fn scan(source: Bytes) -> Result<Vec<Spanned<Token>>> {
         let mut scanner = Scanner {
             source,
             tokens: Vec::new(),
             start: 0,
             current: 0,
+            line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_col: 1,
+            positional_params: 0,
         };
 
         while !scanner.is_at_end() {
-            scanner.start = scanner.current;
+            scanner.begin_token();
             scanner.scan_token()?;
         }
-        scanner.tokens.push(Token::EOF);
+        scanner.begin_token();
+        scanner.add_token(Token::Eof);
 
         Ok(scanner.tokens)
     }
 
+    fn begin_token(&mut self) {
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_col = self.current - self.line_start + 1;
+    }
+
     fn scan_token(&mut self) -> Result<()> {
-        match self.advance()? {
+        let c = *self.advance()?;
+        match c {
             b'*' => self.add_token(Token::Star),
-            b'"' => self.string()?,
+            quote @ (b'"' | b'\'') => self.string(quote)?,
             b'(' => self.add_token(Token::LeftParen),
             b')' => self.add_token(Token::RightParen),
             b',' => self.add_token(Token::Comma),
+            b'/' => self.add_token(Token::Slash),
+            b'%' => self.add_token(Token::Percent),
+            b'!' => {
+                if let Ok(b'=') = self.peek() {
+                    self.advance()?;
+                    self.add_token(Token::NotEqual);
+                } else {
+                    return Err(self.error("Unrecognized token '!'"));
+                }
+            }
             b'>' => {
                 if let Ok(b'=') = self.peek() {
                     self.advance()?;
@@ -50,19 +78,32 @@ impl Scanner {
                 if let Ok(b'=') = self.peek() {
                     self.advance()?;
                     self.add_token(Token::LessEqual);
+                } else if let Ok(b'>') = self.peek() {
+                    self.advance()?;
+                    self.add_token(Token::NotEqual);
                 } else {
                     self.add_token(Token::LessThan);
                 }
             }
             b'=' => self.add_token(Token::Equal),
-            b' ' => {}
-            c => {
+            b'?' => {
+                self.positional_params += 1;
+                self.add_token(Token::Param(self.positional_params));
+            }
+            b'$' => self.param()?,
+            b' ' | b'\t' | b'\r' | b'\n' => {}
+            b'-' | b'+' if self.at_number() && !self.after_operand() => self.number()?,
+            b'-' => self.add_token(Token::Minus),
+            b'+' => self.add_token(Token::Plus),
+            b'.' if matches!(self.peek(), Ok(c) if c.is_ascii_digit()) => self.number()?,
+            b'.' => self.add_token(Token::Dot),
+            _ => {
                 if c.is_ascii_digit() {
                     self.number()?;
-                } else if c.is_ascii_alphabetic() {
+                } else if c.is_ascii_alphabetic() || c == b'_' {
                     self.identifier()?;
                 } else {
-                    bail!("Unrecognized token {:?}", *c as char);
+                    return Err(self.error(format!("Unrecognized token {:?}", c as char)));
                 }
             }
         }
@@ -70,7 +111,22 @@ impl Scanner {
     }
 
     fn add_token(&mut self, tok: Token) {
-        self.tokens.push(tok);
+        let span = self.span();
+        self.tokens.push(Spanned::new(tok, span));
+    }
+
+    /// Span of the token currently being scanned
+    fn span(&self) -> Span {
+        Span {
+            start: self.start,
+            end: self.current,
+            line: self.start_line,
+            col: self.start_col,
+        }
+    }
+
+    fn error(&self, msg: impl Into<String>) -> anyhow::Error {
+        error_at(self.span(), msg)
     }
 
     fn is_at_end(&self) -> bool {
@@ -78,6 +134,10 @@ impl Scanner {
     }
 
     fn advance(&mut self) -> Result<&u8> {
+        if let Some(b'\n') = self.source.get(self.current) {
+            self.line += 1;
+            self.line_start = self.current + 1;
+        }
         self.current += 1;
         self.source
             .get(self.current - 1)
@@ -96,42 +156,122 @@ impl Scanner {
             .ok_or_else(|| anyhow!(ERROR_EOF))
     }
 
-    fn string(&mut self) -> Result<()> {
-        while self.peek()? != &b'"' {
+    /// A string between `quote`s, which can be double or single quotes. The quote is
+    /// written twice to have it in the string.
+    fn string(&mut self, quote: u8) -> Result<()> {
+        let mut text = Vec::new();
+        loop {
+            let c = self.peek().ok().copied();
+            let Some(c) = c else {
+                return Err(self.error(ERROR_EOF));
+            };
             self.advance()?;
+            if c == quote {
+                if self.peek().ok() != Some(&quote) {
+                    break;
+                }
+                self.advance()?;
+            }
+            text.push(c);
         }
+        self.add_token(Token::String(String::from_utf8(text)?));
+        Ok(())
+    }
 
-        if self.is_at_end() {
-            bail!(ERROR_EOF);
+    /// Whether the sign just consumed begins a numeric literal like `-3` or `+.5`
+    fn at_number(&self) -> bool {
+        match self.peek() {
+            Ok(b'.') => matches!(self.peek_next(), Ok(c) if c.is_ascii_digit()),
+            Ok(c) => c.is_ascii_digit(),
+            Err(_) => false,
         }
+    }
+
+    /// Whether the previous token ends an operand, making a following sign a binary operator:
+    /// `a-3` is `a - 3`, not `a` then `-3`
+    fn after_operand(&self) -> bool {
+        matches!(
+            self.tokens.last().map(|tok| &tok.node),
+            Some(
+                Token::Identifier(_)
+                    | Token::Number(_)
+                    | Token::Integer(_)
+                    | Token::Decimal(_)
+                    | Token::String(_)
+                    | Token::Param(_)
+                    | Token::Bool(_)
+                    | Token::Null
+                    | Token::RightParen
+            )
+        )
+    }
 
-        self.advance()?;
-        self.add_token(Token::String(String::from_utf8(
-            self.source[self.start + 1..self.current - 1].to_vec(),
-        )?));
+    fn digits(&mut self) -> Result<()> {
+        while matches!(self.peek(), Ok(c) if c.is_ascii_digit()) {
+            self.advance()?;
+        }
         Ok(())
     }
 
+    /// Scans `[+-]digits[.digits][e[+-]digits]`, where either side of the `.` may be empty.
+    /// Without the fraction and exponent it is an integer, unless it is too large for one.
     fn number(&mut self) -> Result<()> {
-        while !self.is_at_end() && self.peek()?.is_ascii_digit() {
+        self.digits()?;
+        let mut integer = true;
+
+        if let Ok(b'.') = self.peek() {
+            integer = false;
             self.advance()?;
+            self.digits()?;
         }
 
-        if self.peek()? == &b'.' && self.peek_next()?.is_ascii_digit() {
+        if let Ok(b'e' | b'E') = self.peek() {
+            integer = false;
             self.advance()?;
-            while self.peek()?.is_ascii_digit() {
+            if let Ok(b'+' | b'-') = self.peek() {
                 self.advance()?;
             }
+            if !matches!(self.peek(), Ok(c) if c.is_ascii_digit()) {
+                return Err(self.error(format!(
+                    "Missing exponent in numeric literal {:?}",
+                    String::from_utf8_lossy(&self.source[self.start..self.current])
+                )));
+            }
+            self.digits()?;
         }
 
-        self.add_token(Token::Number(
-            std::str::from_utf8(&self.source[self.start..self.current])?.parse()?,
-        ));
+        if matches!(self.peek(), Ok(c) if c.is_ascii_alphanumeric() || *c == b'_' || *c == b'.') {
+            self.advance()?;
+            return Err(self.error(format!(
+                "Invalid numeric literal {:?}",
+                String::from_utf8_lossy(&self.source[self.start..self.current])
+            )));
+        }
+
+        // Like in SQL, a fraction or exponent makes an exact decimal, as does an integer too
+        // big for 64 bits. It only becomes a float when it meets a `REAL` or `DOUBLE`.
+        let text = std::str::from_utf8(&self.source[self.start..self.current])?;
+        match text.parse() {
+            Ok(n) if integer => self.add_token(Token::Integer(n)),
+            _ => self.add_token(Token::Decimal(text.parse()?)),
+        }
+        Ok(())
+    }
+
+    fn param(&mut self) -> Result<()> {
+        self.digits()?;
+        let n: usize = std::str::from_utf8(&self.source[self.start + 1..self.current])?
+            .parse()
+            .unwrap_or(0);
+        if n == 0 {
+            return Err(self.error("Parameters are written `$1`, `$2`, ..."));
+        }
+        self.add_token(Token::Param(n));
         Ok(())
     }
 
     fn identifier(&mut self) -> Result<()> {
-        while !self.is_at_end() && self.peek()?.is_ascii_alphabetic() {
+        while matches!(self.peek(), Ok(c) if c.is_ascii_alphanumeric() || *c == b'_') {
             self.advance()?;
         }
 
@@ -0,0 +1,698 @@
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    hash::{Hash, Hasher},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use super::{
+    ast::{LiteralValue, Ty},
+    decimal::Decimal,
+    token::Token,
+};
+
+const MICROS_PER_SEC: i64 = 1_000_000;
+const MICROS_PER_MIN: i64 = 60 * MICROS_PER_SEC;
+const MICROS_PER_HOUR: i64 = 60 * MICROS_PER_MIN;
+const MICROS_PER_DAY: i64 = 24 * MICROS_PER_HOUR;
+
+/// 0001-01-01 and 9999-12-31, the first and last dates that can be stored, in days since
+/// 1970-01-01
+const MIN_DAY: i64 = -719_162;
+const MAX_DAY: i64 = 2_932_896;
+
+/// Days since 1970-01-01 of a day in the Gregorian calendar, extended back before it began
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    // Years start in March here, so that the leap day comes last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The year, month and day `days` after 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u32;
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
+
+fn check_date(days: i64) -> Result<i32> {
+    if !(MIN_DAY..=MAX_DAY).contains(&days) {
+        bail!("Date out of range");
+    }
+    Ok(days as i32)
+}
+
+fn check_timestamp(micros: i64) -> Result<i64> {
+    if !(MIN_DAY * MICROS_PER_DAY..(MAX_DAY + 1) * MICROS_PER_DAY).contains(&micros) {
+        bail!("Timestamp out of range");
+    }
+    Ok(micros)
+}
+
+/// Reads `YYYY-MM-DD`, as days since 1970-01-01
+fn parse_date(s: &str) -> Result<i32> {
+    let invalid = || anyhow!("Invalid date {:?}", s);
+    let parts: Vec<u32> = s
+        .split('-')
+        .map(|part| match part.bytes().all(|b| b.is_ascii_digit()) {
+            true => part.parse().ok(),
+            false => None,
+        })
+        .collect::<Option<_>>()
+        .ok_or_else(invalid)?;
+    let [year, month, day] = parts[..] else {
+        return Err(invalid());
+    };
+    if year == 0 || !(1..=12).contains(&month) || day == 0 {
+        return Err(invalid());
+    }
+    if day > days_in_month(year.into(), month) {
+        return Err(invalid());
+    }
+    check_date(days_from_civil(year.into(), month, day))
+}
+
+/// Reads `H:MM[:SS[.ffffff]]` as microseconds, with any number of hours
+fn parse_clock(s: &str) -> Option<i64> {
+    let (hms, fraction) = s.split_once('.').unwrap_or((s, ""));
+    let fields: Vec<i64> = hms
+        .split(':')
+        .map(|field| match field.bytes().all(|b| b.is_ascii_digit()) {
+            true => field.parse().ok(),
+            false => None,
+        })
+        .collect::<Option<_>>()?;
+    let (hours, minutes, seconds) = match fields[..] {
+        [hours, minutes] if fraction.is_empty() => (hours, minutes, 0),
+        [hours, minutes, seconds] => (hours, minutes, seconds),
+        _ => return None,
+    };
+    if minutes > 59 || seconds > 59 || fraction.len() > 6 {
+        return None;
+    }
+    let fraction = match fraction {
+        "" => 0,
+        digits if digits.bytes().all(|b| b.is_ascii_digit()) => {
+            format!("{:0<6}", digits).parse::<i64>().ok()?
+        }
+        _ => return None,
+    };
+    hours
+        .checked_mul(MICROS_PER_HOUR)?
+        .checked_add(minutes * MICROS_PER_MIN + seconds * MICROS_PER_SEC + fraction)
+}
+
+/// Reads `HH:MM[:SS[.ffffff]]`, as microseconds since midnight
+fn parse_time(s: &str) -> Result<i64> {
+    parse_clock(s)
+        .filter(|micros| *micros < MICROS_PER_DAY)
+        .ok_or_else(|| anyhow!("Invalid time {:?}", s))
+}
+
+/// Reads `YYYY-MM-DD[ HH:MM[:SS[.ffffff]]][zone]`, as microseconds since 1970-01-01 UTC.
+/// A `T` can also come between the date and time. The zone is `Z` or an offset like `+02`,
+/// `+02:00` or `-0530`, which `tz` takes the time back to UTC by; without it, the zone is
+/// ignored.
+fn parse_timestamp(s: &str, tz: bool) -> Result<i64> {
+    let invalid = || anyhow!("Invalid timestamp {:?}", s);
+    let (date, time) = match s.find([' ', 'T']) {
+        Some(i) => (&s[..i], s[i + 1..].trim()),
+        None => (s, ""),
+    };
+    let days = parse_date(date).map_err(|_| invalid())?;
+    let (time, zone) = match time.find(['+', '-', 'Z']) {
+        Some(i) => (time[..i].trim_end(), &time[i..]),
+        None => (time, ""),
+    };
+    let time = match time {
+        "" => 0,
+        time => parse_time(time).map_err(|_| invalid())?,
+    };
+    let offset = match zone {
+        "" | "Z" => 0,
+        zone => {
+            let sign = if zone.starts_with('-') { -1 } else { 1 };
+            let digits = zone[1..].replace(':', "");
+            let (hours, minutes) = match digits.len() {
+                2 => (digits.parse::<i64>().ok(), Some(0)),
+                4 => (digits[..2].parse().ok(), digits[2..].parse().ok()),
+                _ => (None, None),
+            };
+            match (hours, minutes) {
+                (Some(hours), Some(minutes)) if hours < 24 && minutes < 60 => {
+                    sign * (hours * MICROS_PER_HOUR + minutes * MICROS_PER_MIN)
+                }
+                _ => return Err(invalid()),
+            }
+        }
+    };
+    let offset = if tz { offset } else { 0 };
+    check_timestamp(days as i64 * MICROS_PER_DAY + time - offset)
+}
+
+pub fn format_date(days: i32) -> String {
+    let (year, month, day) = civil_from_days(days.into());
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// `HH:MM:SS`, with a fraction of a second if there is one. Hours don't wrap around.
+pub fn format_time(micros: i64) -> String {
+    let secs = micros / MICROS_PER_SEC;
+    let mut time = format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+    let fraction = micros % MICROS_PER_SEC;
+    if fraction != 0 {
+        time += format!(".{:06}", fraction).trim_end_matches('0');
+    }
+    time
+}
+
+/// The date and time, followed by the UTC offset `+00` with `tz`
+pub fn format_timestamp(micros: i64, tz: bool) -> String {
+    format!(
+        "{} {}{}",
+        format_date(micros.div_euclid(MICROS_PER_DAY) as i32),
+        format_time(micros.rem_euclid(MICROS_PER_DAY)),
+        if tz { "+00" } else { "" }
+    )
+}
+
+/// A span of time. Months, days and microseconds are kept apart, since months and days
+/// don't all last as long; for comparing, a month is 30 days and a day 24 hours.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Interval {
+    pub months: i32,
+    pub days: i32,
+    pub micros: i64,
+}
+
+impl Interval {
+    fn new(months: Option<i32>, days: Option<i32>, micros: Option<i64>) -> Result<Interval> {
+        match (months, days, micros) {
+            (Some(months), Some(days), Some(micros)) => Ok(Interval {
+                months,
+                days,
+                micros,
+            }),
+            _ => bail!("Interval out of range"),
+        }
+    }
+
+    fn total(&self) -> i128 {
+        (self.months as i128 * 30 + self.days as i128) * MICROS_PER_DAY as i128
+            + self.micros as i128
+    }
+
+    pub fn add(&self, other: &Interval) -> Result<Interval> {
+        Interval::new(
+            self.months.checked_add(other.months),
+            self.days.checked_add(other.days),
+            self.micros.checked_add(other.micros),
+        )
+    }
+
+    pub fn neg(&self) -> Result<Interval> {
+        Interval::new(
+            self.months.checked_neg(),
+            self.days.checked_neg(),
+            self.micros.checked_neg(),
+        )
+    }
+
+    /// Scaled by `factor`. Parts of a month carry over into days, at 30 to the month, and
+    /// parts of a day into microseconds.
+    pub fn mul(&self, factor: f64) -> Result<Interval> {
+        let months = self.months as f64 * factor;
+        let days = self.days as f64 * factor + months.fract() * 30.0;
+        let micros = (self.micros as f64 * factor + days.fract() * MICROS_PER_DAY as f64).round();
+        let whole = |n: f64, max: f64| (n.abs() < max).then_some(n.trunc());
+        Interval::new(
+            whole(months, i32::MAX as f64).map(|n| n as i32),
+            whole(days, i32::MAX as f64).map(|n| n as i32),
+            whole(micros, i64::MAX as f64).map(|n| n as i64),
+        )
+    }
+}
+
+/// Reads amounts of units, like `1 year 2 months -3 days`, and a time like `04:05:06.5`,
+/// which can have a sign. `ago` at the end turns it around.
+impl FromStr for Interval {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid interval {:?}", s);
+        let mut interval = Interval::default();
+        let mut words = s.split_whitespace().peekable();
+        if words.peek().is_none() {
+            return Err(invalid());
+        }
+        while let Some(word) = words.next() {
+            if word.eq_ignore_ascii_case("ago") && words.peek().is_none() {
+                return interval.neg();
+            }
+            if word.contains(':') {
+                let (negative, time) = match word.strip_prefix('-') {
+                    Some(time) => (true, time),
+                    None => (false, word.strip_prefix('+').unwrap_or(word)),
+                };
+                let micros = parse_clock(time).ok_or_else(invalid)?;
+                let micros = if negative { -micros } else { micros };
+                interval = interval.add(&Interval {
+                    micros,
+                    ..Default::default()
+                })?;
+                continue;
+            }
+            let amount: f64 = word.parse().map_err(|_| invalid())?;
+            let unit = words.next().ok_or_else(invalid)?.to_ascii_lowercase();
+            let (months, days, micros) = match &unit[..] {
+                "microsecond" | "microseconds" | "us" => (0, 0, 1),
+                "millisecond" | "milliseconds" | "ms" => (0, 0, 1000),
+                "second" | "seconds" | "sec" | "secs" | "s" => (0, 0, MICROS_PER_SEC),
+                "minute" | "minutes" | "min" | "mins" | "m" => (0, 0, MICROS_PER_MIN),
+                "hour" | "hours" | "hr" | "hrs" | "h" => (0, 0, MICROS_PER_HOUR),
+                "day" | "days" | "d" => (0, 1, 0),
+                "week" | "weeks" | "w" => (0, 7, 0),
+                "month" | "months" | "mon" | "mons" => (1, 0, 0),
+                "year" | "years" | "yr" | "yrs" | "y" => (12, 0, 0),
+                "decade" | "decades" => (120, 0, 0),
+                "century" | "centuries" => (1200, 0, 0),
+                "millennium" | "millennia" => (12000, 0, 0),
+                _ => bail!("Invalid interval unit {:?}", unit),
+            };
+            let part = Interval {
+                months,
+                days,
+                micros,
+            };
+            interval = interval.add(&part.mul(amount)?)?;
+        }
+        Ok(interval)
+    }
+}
+
+/// Years, months and days, then the time if there is any, like
+/// `1 year 2 mons 3 days 04:05:06`
+impl Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        let mut unit = |n: i32, name: &str| {
+            if n != 0 {
+                parts.push(format!(
+                    "{} {}{}",
+                    n,
+                    name,
+                    if n.abs() == 1 { "" } else { "s" }
+                ));
+            }
+        };
+        unit(self.months / 12, "year");
+        unit(self.months % 12, "mon");
+        unit(self.days, "day");
+        if self.micros != 0 || parts.is_empty() {
+            let sign = if self.micros < 0 { "-" } else { "" };
+            parts.push(format!(
+                "{}{}",
+                sign,
+                format_time(self.micros.unsigned_abs() as i64)
+            ));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl Ord for Interval {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.total().cmp(&other.total())
+    }
+}
+
+impl PartialOrd for Interval {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Interval {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Interval {}
+
+impl Hash for Interval {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.total().hash(state);
+    }
+}
+
+/// `micros` moved by `interval`: first by its months, keeping to the last day of a month
+/// that is shorter, then by its days and microseconds
+fn add_interval(micros: i64, interval: &Interval) -> Result<i64> {
+    let (days, time) = (
+        micros.div_euclid(MICROS_PER_DAY),
+        micros.rem_euclid(MICROS_PER_DAY),
+    );
+    let (year, month, day) = civil_from_days(days);
+    let months = year * 12 + month as i64 - 1 + interval.months as i64;
+    let (year, month) = (months.div_euclid(12), months.rem_euclid(12) as u32 + 1);
+    let day = day.min(days_in_month(year, month));
+    let days = days_from_civil(year, month, day) + interval.days as i64;
+    check_timestamp(
+        days.checked_mul(MICROS_PER_DAY)
+            .and_then(|micros| micros.checked_add(time))
+            .and_then(|micros| micros.checked_add(interval.micros))
+            .ok_or_else(|| anyhow!("Timestamp out of range"))?,
+    )
+}
+
+/// The name of a field of a date or time, in lowercase and without a plural `s`
+fn field_name(field: &str) -> String {
+    let field = field.to_ascii_lowercase();
+    match field.strip_suffix('s') {
+        Some(singular) if singular != "do" && singular != "isodo" => singular.to_string(),
+        _ => field,
+    }
+}
+
+/// A whole number of microseconds, in seconds
+fn seconds(micros: i128) -> Result<LiteralValue> {
+    Ok(LiteralValue::Decimal(format!("{}e-6", micros).parse()?))
+}
+
+impl LiteralValue {
+    /// The current time
+    pub fn now() -> LiteralValue {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        LiteralValue::TimestampTz(since_epoch.as_micros() as i64)
+    }
+
+    pub(super) fn is_temporal(&self) -> bool {
+        matches!(
+            self,
+            LiteralValue::Date(_)
+                | LiteralValue::Time(_)
+                | LiteralValue::Timestamp(_)
+                | LiteralValue::TimestampTz(_)
+                | LiteralValue::Interval(_)
+        )
+    }
+
+    /// Microseconds since 1970-01-01 of a date or timestamp; a date is its midnight
+    pub(super) fn instant(&self) -> Option<i64> {
+        match self {
+            LiteralValue::Date(days) => Some(*days as i64 * MICROS_PER_DAY),
+            LiteralValue::Timestamp(micros) | LiteralValue::TimestampTz(micros) => Some(*micros),
+            _ => None,
+        }
+    }
+
+    /// The value as a date, time or interval of type `ty`. Strings are read in the type's
+    /// format; dates and timestamps convert between each other, and timestamps give their
+    /// time.
+    pub(super) fn into_temporal(self, ty: &Ty) -> Result<LiteralValue> {
+        Ok(match (self, ty) {
+            (LiteralValue::String(s), Ty::Date) => {
+                let micros = parse_timestamp(s.trim(), false)
+                    .map_err(|_| anyhow!("Invalid date {:?}", s))?;
+                LiteralValue::Date(micros.div_euclid(MICROS_PER_DAY) as i32)
+            }
+            (LiteralValue::String(s), Ty::Time) => LiteralValue::Time(parse_time(s.trim())?),
+            (LiteralValue::String(s), Ty::Timestamp) => {
+                LiteralValue::Timestamp(parse_timestamp(s.trim(), false)?)
+            }
+            (LiteralValue::String(s), Ty::TimestampTz) => {
+                LiteralValue::TimestampTz(parse_timestamp(s.trim(), true)?)
+            }
+            (LiteralValue::String(s), Ty::Interval) => LiteralValue::Interval(s.parse()?),
+            (val @ LiteralValue::Time(_), Ty::Time)
+            | (val @ LiteralValue::Interval(_), Ty::Interval)
+            | (val @ LiteralValue::Date(_), Ty::Date) => val,
+            (val, Ty::Date | Ty::Time | Ty::Timestamp | Ty::TimestampTz) => {
+                let micros = val.instant().ok_or_else(|| anyhow!("Expected {}", ty))?;
+                match ty {
+                    Ty::Date => LiteralValue::Date(micros.div_euclid(MICROS_PER_DAY) as i32),
+                    Ty::Time => LiteralValue::Time(micros.rem_euclid(MICROS_PER_DAY)),
+                    Ty::Timestamp => LiteralValue::Timestamp(micros),
+                    _ => LiteralValue::TimestampTz(micros),
+                }
+            }
+            _ => bail!("Expected {}", ty),
+        })
+    }
+
+    /// `+` and `-` between dates, times and intervals, and scaling intervals with `*` and
+    /// `/`; `None` if neither side is a date, time or interval
+    pub(super) fn temporal_arithmetic(
+        &self,
+        op: &Token,
+        other: &LiteralValue,
+    ) -> Option<Result<LiteralValue>> {
+        if !self.is_temporal() && !other.is_temporal() {
+            return None;
+        }
+        let shifted = |val: &LiteralValue, interval: &Interval| -> Result<LiteralValue> {
+            let micros = add_interval(val.instant().unwrap_or_default(), interval)?;
+            Ok(match val {
+                LiteralValue::TimestampTz(_) => LiteralValue::TimestampTz(micros),
+                _ => LiteralValue::Timestamp(micros),
+            })
+        };
+        let res = match (self, op, other) {
+            (LiteralValue::Date(d), Token::Plus, LiteralValue::Int(n))
+            | (LiteralValue::Int(n), Token::Plus, LiteralValue::Date(d)) => {
+                check_date(*d as i64 + n.clamp(&-MAX_DAY, &MAX_DAY)).map(LiteralValue::Date)
+            }
+            (LiteralValue::Date(d), Token::Minus, LiteralValue::Int(n)) => {
+                check_date(*d as i64 - n.clamp(&-MAX_DAY, &MAX_DAY)).map(LiteralValue::Date)
+            }
+            (LiteralValue::Date(a), Token::Minus, LiteralValue::Date(b)) => {
+                Ok(LiteralValue::Int(*a as i64 - *b as i64))
+            }
+            (LiteralValue::Date(d), Token::Plus, LiteralValue::Time(t))
+            | (LiteralValue::Time(t), Token::Plus, LiteralValue::Date(d)) => {
+                Ok(LiteralValue::Timestamp(*d as i64 * MICROS_PER_DAY + t))
+            }
+            (val, Token::Plus, LiteralValue::Interval(i))
+            | (LiteralValue::Interval(i), Token::Plus, val)
+                if val.instant().is_some() =>
+            {
+                shifted(val, i)
+            }
+            (val, Token::Minus, LiteralValue::Interval(i)) if val.instant().is_some() => {
+                i.neg().and_then(|i| shifted(val, &i))
+            }
+            (a, Token::Minus, b) if a.instant().is_some() && b.instant().is_some() => {
+                let diff = a.instant().unwrap_or_default() - b.instant().unwrap_or_default();
+                Ok(LiteralValue::Interval(Interval {
+                    months: 0,
+                    days: (diff / MICROS_PER_DAY) as i32,
+                    micros: diff % MICROS_PER_DAY,
+                }))
+            }
+            (LiteralValue::Time(t), Token::Plus, LiteralValue::Interval(i))
+            | (LiteralValue::Interval(i), Token::Plus, LiteralValue::Time(t)) => Ok(
+                LiteralValue::Time((t + i.micros % MICROS_PER_DAY).rem_euclid(MICROS_PER_DAY)),
+            ),
+            (LiteralValue::Time(t), Token::Minus, LiteralValue::Interval(i)) => Ok(
+                LiteralValue::Time((t - i.micros % MICROS_PER_DAY).rem_euclid(MICROS_PER_DAY)),
+            ),
+            (LiteralValue::Time(a), Token::Minus, LiteralValue::Time(b)) => {
+                Ok(LiteralValue::Interval(Interval {
+                    micros: a - b,
+                    ..Default::default()
+                }))
+            }
+            (LiteralValue::Interval(a), Token::Plus, LiteralValue::Interval(b)) => {
+                a.add(b).map(LiteralValue::Interval)
+            }
+            (LiteralValue::Interval(a), Token::Minus, LiteralValue::Interval(b)) => {
+                b.neg().and_then(|b| a.add(&b)).map(LiteralValue::Interval)
+            }
+            (LiteralValue::Interval(i), Token::Star, n)
+            | (n, Token::Star, LiteralValue::Interval(i))
+                if n.number().is_ok() =>
+            {
+                n.number()
+                    .and_then(|n| i.mul(n.0))
+                    .map(LiteralValue::Interval)
+            }
+            (LiteralValue::Interval(i), Token::Slash, n) if n.number().is_ok() => {
+                match n.number().map(|n| n.0) {
+                    Ok(0.0) => Err(anyhow!("Division by zero")),
+                    n => n.and_then(|n| i.mul(1.0 / n)).map(LiteralValue::Interval),
+                }
+            }
+            _ => Err(anyhow!("Cannot apply {} to {} and {}", op, self, other)),
+        };
+        Some(res)
+    }
+
+    /// A field of a date, time, timestamp or interval, as a decimal, since `second` and
+    /// `epoch` can have a fraction
+    pub fn extract(&self, field: &str) -> Result<LiteralValue> {
+        let name = field_name(field);
+        let unsupported = || anyhow!("Cannot extract {} from {}", field, self);
+        let int = |n: i64| Ok(LiteralValue::Decimal(Decimal::from(n)));
+        if let LiteralValue::Interval(i) = self {
+            let (months, micros) = (i.months as i64, i.micros);
+            return match &name[..] {
+                "millennium" => int(months / 12000),
+                "century" => int(months / 1200),
+                "decade" => int(months / 120),
+                "year" => int(months / 12),
+                "quarter" => int(months % 12 / 3 + 1),
+                "month" => int(months % 12),
+                "day" => int(i.days as i64),
+                "hour" => int(micros / MICROS_PER_HOUR),
+                "minute" => int(micros / MICROS_PER_MIN % 60),
+                "second" => seconds((micros % MICROS_PER_MIN) as i128),
+                // Years of 365.25 days, as in the calendar on average
+                "epoch" => seconds(
+                    (months / 12) as i128 * 36525 * MICROS_PER_DAY as i128 / 100
+                        + ((months % 12) * 30 + i.days as i64) as i128 * MICROS_PER_DAY as i128
+                        + micros as i128,
+                ),
+                _ => Err(unsupported()),
+            };
+        }
+        if let LiteralValue::Time(micros) = self {
+            return match &name[..] {
+                "hour" => int(micros / MICROS_PER_HOUR),
+                "minute" => int(micros / MICROS_PER_MIN % 60),
+                "second" => seconds((micros % MICROS_PER_MIN) as i128),
+                "epoch" => seconds(*micros as i128),
+                _ => Err(unsupported()),
+            };
+        }
+        let micros = self.instant().ok_or_else(unsupported)?;
+        let (days, time) = (
+            micros.div_euclid(MICROS_PER_DAY),
+            micros.rem_euclid(MICROS_PER_DAY),
+        );
+        let (year, month, day) = civil_from_days(days);
+        // 1970-01-01 was a Thursday
+        let dow = (days + 4).rem_euclid(7);
+        let isodow = if dow == 0 { 7 } else { dow };
+        // The ISO week and year are those of the week's Thursday
+        let thursday = days - isodow + 4;
+        let isoyear = civil_from_days(thursday).0;
+        match &name[..] {
+            "millennium" => int((year + 999) / 1000),
+            "century" => int((year + 99) / 100),
+            "decade" => int(year / 10),
+            "year" => int(year),
+            "isoyear" => int(isoyear),
+            "quarter" => int((month as i64 - 1) / 3 + 1),
+            "month" => int(month.into()),
+            "week" => int((thursday - days_from_civil(isoyear, 1, 1)) / 7 + 1),
+            "day" => int(day.into()),
+            "dow" => int(dow),
+            "isodow" => int(isodow),
+            "doy" => int(days - days_from_civil(year, 1, 1) + 1),
+            "hour" => int(time / MICROS_PER_HOUR),
+            "minute" => int(time / MICROS_PER_MIN % 60),
+            "second" => seconds((time % MICROS_PER_MIN) as i128),
+            "epoch" => seconds(micros as i128),
+            _ => Err(unsupported()),
+        }
+    }
+
+    /// A timestamp or interval cut down to the start of its `field`, like its day or month.
+    /// Dates give timestamps.
+    pub fn date_trunc(&self, field: &str) -> Result<LiteralValue> {
+        let name = field_name(field);
+        let unsupported = || anyhow!("Cannot truncate {} to {}", self, field);
+        let floor = |n: i64, unit: i64| n - n % unit;
+        if let LiteralValue::Interval(i) = self {
+            let years = |n: i32| Interval {
+                months: floor(i.months.into(), n as i64 * 12) as i32,
+                ..Default::default()
+            };
+            return Ok(LiteralValue::Interval(match &name[..] {
+                "millennium" => years(1000),
+                "century" => years(100),
+                "decade" => years(10),
+                "year" => years(1),
+                "quarter" => Interval {
+                    months: floor(i.months.into(), 3) as i32,
+                    ..Default::default()
+                },
+                "month" => Interval {
+                    months: i.months,
+                    ..Default::default()
+                },
+                "day" => Interval { micros: 0, ..*i },
+                "hour" => Interval {
+                    micros: floor(i.micros, MICROS_PER_HOUR),
+                    ..*i
+                },
+                "minute" => Interval {
+                    micros: floor(i.micros, MICROS_PER_MIN),
+                    ..*i
+                },
+                "second" => Interval {
+                    micros: floor(i.micros, MICROS_PER_SEC),
+                    ..*i
+                },
+                "millisecond" => Interval {
+                    micros: floor(i.micros, 1000),
+                    ..*i
+                },
+                "microsecond" => *i,
+                _ => return Err(unsupported()),
+            }));
+        }
+        let micros = self.instant().ok_or_else(unsupported)?;
+        let days = micros.div_euclid(MICROS_PER_DAY);
+        let (year, month, _) = civil_from_days(days);
+        let start = |year: i64, month: u32| days_from_civil(year, month, 1) * MICROS_PER_DAY;
+        let below = |unit: i64| micros - micros.rem_euclid(unit);
+        let micros = match &name[..] {
+            "millennium" => start((year - 1) / 1000 * 1000 + 1, 1),
+            "century" => start((year - 1) / 100 * 100 + 1, 1),
+            "decade" => start(year - year % 10, 1),
+            "year" => start(year, 1),
+            "quarter" => start(year, (month - 1) / 3 * 3 + 1),
+            "month" => start(year, month),
+            "week" => (days - (days + 3).rem_euclid(7)) * MICROS_PER_DAY,
+            "day" => below(MICROS_PER_DAY),
+            "hour" => below(MICROS_PER_HOUR),
+            "minute" => below(MICROS_PER_MIN),
+            "second" => below(MICROS_PER_SEC),
+            "millisecond" => below(1000),
+            "microsecond" => micros,
+            _ => return Err(unsupported()),
+        };
+        Ok(match self {
+            LiteralValue::TimestampTz(_) => LiteralValue::TimestampTz(micros),
+            _ => LiteralValue::Timestamp(check_timestamp(micros)?),
+        })
+    }
+}
@@ -1,14 +1,24 @@
+use std::{fmt::Display, ops::Deref};
+
 use macros::Keywords;
 
+use super::Decimal;
+
 #[derive(Debug, Clone, PartialEq, Keywords)]
 pub enum Token {
     Star,
     LeftParen,
     RightParen,
     Comma,
+    Dot,
+    Plus,
+    Minus,
+    Slash,
+    Percent,
     GreaterThan,
     LessThan,
     Equal,
+    NotEqual,
     GreaterEqual,
     LessEqual,
 
@@ -33,6 +43,97 @@ pub enum Token {
     Table,
     #[keyword]
     As,
+    #[keyword]
+    Update,
+    #[keyword]
+    Set,
+    #[keyword]
+    Delete,
+    #[keyword]
+    Drop,
+
+    #[keyword]
+    Distinct,
+    #[keyword]
+    Where,
+    #[keyword]
+    Group,
+    #[keyword]
+    By,
+    #[keyword]
+    Having,
+    #[keyword]
+    Order,
+    #[keyword]
+    Asc,
+    #[keyword]
+    Desc,
+    #[keyword]
+    Limit,
+    #[keyword]
+    Offset,
+    #[keyword]
+    For,
+    #[keyword]
+    Join,
+    #[keyword]
+    Inner,
+    #[keyword]
+    Cross,
+    #[keyword]
+    On,
+    #[keyword]
+    Is,
+
+    #[keyword]
+    Explain,
+    #[keyword]
+    Analyze,
+
+    #[keyword]
+    Prepare,
+    #[keyword]
+    Execute,
+    #[keyword]
+    Deallocate,
+
+    #[keyword]
+    Begin,
+    #[keyword]
+    Transaction,
+    #[keyword]
+    Commit,
+    #[keyword]
+    Rollback,
+    #[keyword]
+    Savepoint,
+    #[keyword]
+    Release,
+    #[keyword]
+    To,
+    #[keyword]
+    Isolation,
+    #[keyword]
+    Level,
+    #[keyword]
+    Read,
+    #[keyword]
+    Committed,
+    #[keyword]
+    Repeatable,
+    #[keyword]
+    Snapshot,
+    #[keyword]
+    Serializable,
+
+    #[keyword]
+    Checkpoint,
+    #[keyword]
+    Backup,
+    #[keyword]
+    Dump,
+    #[keyword]
+    Copy,
 
     #[keyword]
     Not,
@@ -45,21 +146,42 @@ pub enum Token {
     #[keyword]
     Foreign,
     #[keyword]
+    References,
+    #[keyword]
+    Cascade,
+    #[keyword]
+    Restrict,
+    #[keyword]
+    No,
+    #[keyword]
+    Action,
+    #[keyword]
     Key,
     #[keyword]
     Check,
     #[keyword]
+    Constraint,
+    #[keyword]
     Default,
     #[keyword]
     Index,
 
     Identifier(String),
     Number(f64),
+
+    /// A number written without a fraction or exponent that fits in 64 bits
+    Integer(i64),
+
+    /// A number with more digits than a float keeps
+    Decimal(Decimal),
+
+    /// `$n`, or the nth `?` (1-based)
+    Param(usize),
     String(String),
     #[keyword(true = true, false = false)]
     Bool(bool),
 
-    EOF,
+    Eof,
 }
 
 impl Token {
@@ -71,3 +193,84 @@ impl Token {
         }
     }
 }
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Star => write!(f, "*"),
+            Token::LeftParen => write!(f, "("),
+            Token::RightParen => write!(f, ")"),
+            Token::Comma => write!(f, ","),
+            Token::Dot => write!(f, "."),
+            Token::Plus => write!(f, "+"),
+            Token::Minus => write!(f, "-"),
+            Token::Slash => write!(f, "/"),
+            Token::Percent => write!(f, "%"),
+            Token::GreaterThan => write!(f, ">"),
+            Token::LessThan => write!(f, "<"),
+            Token::Equal => write!(f, "="),
+            Token::NotEqual => write!(f, "<>"),
+            Token::GreaterEqual => write!(f, ">="),
+            Token::LessEqual => write!(f, "<="),
+            Token::Identifier(ident) => write!(f, "{}", ident),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Integer(n) => write!(f, "{}", n),
+            Token::Decimal(d) => write!(f, "{}", d),
+            Token::Param(n) => write!(f, "${}", n),
+            Token::String(s) => write!(f, "{:?}", s),
+            Token::Bool(b) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Token::Eof => write!(f, "end of input"),
+            keyword => write!(f, "{}", format!("{:?}", keyword).to_uppercase()),
+        }
+    }
+}
+
+/// Location of a token in the source: the byte range `start..end`, and the line and column
+/// (both 1-based, column counted in bytes) that `start` falls on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+/// A `T` tagged with the span it was parsed from. Spans are ignored by `==`, so hand-built
+/// tokens and ASTs compare equal to parsed ones.
+#[derive(Debug, Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Spanned { node, span }
+    }
+}
+
+impl<T> From<T> for Spanned<T> {
+    fn from(node: T) -> Self {
+        Spanned::new(node, Span::default())
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.node
+    }
+}
+
+impl<T: PartialEq> PartialEq for Spanned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.node == other.node
+    }
+}
+
+impl<T: PartialEq> PartialEq<T> for Spanned<T> {
+    fn eq(&self, other: &T) -> bool {
+        self.node == *other
+    }
+}
@@ -0,0 +1,494 @@
+use std::fmt::Display;
+
+use anyhow::{anyhow, bail, Result};
+use ordered_float::OrderedFloat;
+
+use crate::parse::{Decimal, LiteralValue, Token, Ty};
+
+/// Identifies a column. While planning this is an id unique within the query; once an
+/// expression is resolved against an operator's input it is a position in the input tuple.
+pub type ColumnId = usize;
+
+/// An expression whose names have been resolved to columns
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scalar {
+    Const(LiteralValue),
+    Column(ColumnId),
+    Unary {
+        op: Token,
+        expr: Box<Scalar>,
+    },
+    Binary {
+        left: Box<Scalar>,
+        op: Token,
+        right: Box<Scalar>,
+    },
+    IsNull {
+        expr: Box<Scalar>,
+        negated: bool,
+    },
+
+    /// A scalar function, by lowercase name
+    Call {
+        func: String,
+        args: Vec<Scalar>,
+    },
+}
+
+impl Scalar {
+    pub fn eval(&self, tuple: &[LiteralValue]) -> Result<LiteralValue> {
+        match self {
+            Scalar::Const(val) => Ok(val.clone()),
+            Scalar::Column(i) => Ok(tuple[*i].clone()),
+            Scalar::Unary { op, expr } => expr.eval(tuple)?.unary(op),
+            Scalar::Binary { left, op, right } => left.eval(tuple)?.binary(op, &right.eval(tuple)?),
+            Scalar::IsNull { expr, negated } => Ok(LiteralValue::Bool(
+                (expr.eval(tuple)? == LiteralValue::Null) != *negated,
+            )),
+            Scalar::Call { func, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(tuple))
+                    .collect::<Result<Vec<_>>>()?;
+                call(func, &args)
+            }
+        }
+    }
+
+    /// Whether a predicate holds for `tuple`; `NULL` counts as false
+    pub fn test(&self, tuple: &[LiteralValue]) -> Result<bool> {
+        Ok(self.eval(tuple)?.truth()?.unwrap_or(false))
+    }
+
+    /// Every column the expression refers to
+    pub fn columns(&self) -> Vec<ColumnId> {
+        let mut cols = Vec::new();
+        self.visit(&mut |scalar| {
+            if let Scalar::Column(id) = scalar {
+                cols.push(*id);
+            }
+        });
+        cols
+    }
+
+    fn visit(&self, f: &mut impl FnMut(&Scalar)) {
+        f(self);
+        match self {
+            Scalar::Const(_) | Scalar::Column(_) => {}
+            Scalar::Unary { expr, .. } | Scalar::IsNull { expr, .. } => expr.visit(f),
+            Scalar::Binary { left, right, .. } => {
+                left.visit(f);
+                right.visit(f);
+            }
+            Scalar::Call { args, .. } => args.iter().for_each(|arg| arg.visit(f)),
+        }
+    }
+
+    /// Rebuilds the expression top-down, replacing any subexpression `f` returns a
+    /// replacement for
+    pub fn transform(
+        &self,
+        f: &mut impl FnMut(&Scalar) -> Result<Option<Scalar>>,
+    ) -> Result<Scalar> {
+        if let Some(replacement) = f(self)? {
+            return Ok(replacement);
+        }
+        Ok(match self {
+            Scalar::Const(_) | Scalar::Column(_) => self.clone(),
+            Scalar::Unary { op, expr } => Scalar::Unary {
+                op: op.clone(),
+                expr: Box::new(expr.transform(f)?),
+            },
+            Scalar::Binary { left, op, right } => Scalar::Binary {
+                left: Box::new(left.transform(f)?),
+                op: op.clone(),
+                right: Box::new(right.transform(f)?),
+            },
+            Scalar::IsNull { expr, negated } => Scalar::IsNull {
+                expr: Box::new(expr.transform(f)?),
+                negated: *negated,
+            },
+            Scalar::Call { func, args } => Scalar::Call {
+                func: func.clone(),
+                args: args
+                    .iter()
+                    .map(|arg| arg.transform(f))
+                    .collect::<Result<_>>()?,
+            },
+        })
+    }
+
+    /// Turns column ids into positions in a tuple laid out as `layout`
+    pub fn resolve(&self, layout: &[ColumnId]) -> Result<Scalar> {
+        self.transform(&mut |scalar| match scalar {
+            Scalar::Column(id) => match layout.iter().position(|col| col == id) {
+                Some(i) => Ok(Some(Scalar::Column(i))),
+                None => bail!("Internal error: column {} is not available", id),
+            },
+            _ => Ok(None),
+        })
+    }
+
+    /// Evaluates every subexpression that doesn't depend on a column. Subexpressions that
+    /// fail to evaluate are left alone so the error surfaces if they are ever run.
+    pub fn fold(&self) -> Scalar {
+        let folded = match self {
+            Scalar::Const(_) | Scalar::Column(_) => return self.clone(),
+            Scalar::Unary { op, expr } => Scalar::Unary {
+                op: op.clone(),
+                expr: Box::new(expr.fold()),
+            },
+            Scalar::Binary { left, op, right } => {
+                let (left, right) = (left.fold(), right.fold());
+                // `x AND FALSE` and `x OR TRUE` don't depend on `x`, even if it's NULL
+                match (op, &left, &right) {
+                    (Token::And, Scalar::Const(LiteralValue::Bool(true)), other)
+                    | (Token::And, other, Scalar::Const(LiteralValue::Bool(true)))
+                    | (Token::Or, Scalar::Const(LiteralValue::Bool(false)), other)
+                    | (Token::Or, other, Scalar::Const(LiteralValue::Bool(false))) => {
+                        return other.clone()
+                    }
+                    (Token::And, Scalar::Const(LiteralValue::Bool(false)), _)
+                    | (Token::And, _, Scalar::Const(LiteralValue::Bool(false))) => {
+                        return Scalar::Const(LiteralValue::Bool(false))
+                    }
+                    (Token::Or, Scalar::Const(LiteralValue::Bool(true)), _)
+                    | (Token::Or, _, Scalar::Const(LiteralValue::Bool(true))) => {
+                        return Scalar::Const(LiteralValue::Bool(true))
+                    }
+                    _ => {}
+                }
+                Scalar::Binary {
+                    left: Box::new(left),
+                    op: op.clone(),
+                    right: Box::new(right),
+                }
+            }
+            Scalar::IsNull { expr, negated } => Scalar::IsNull {
+                expr: Box::new(expr.fold()),
+                negated: *negated,
+            },
+            Scalar::Call { func, args } => Scalar::Call {
+                func: func.clone(),
+                args: args.iter().map(Scalar::fold).collect(),
+            },
+        };
+        if folded.columns().is_empty() {
+            if let Ok(val) = folded.eval(&[]) {
+                return Scalar::Const(val);
+            }
+        }
+        folded
+    }
+
+    /// Splits a predicate into the parts that are `AND`ed together
+    pub fn conjuncts(self) -> Vec<Scalar> {
+        match self {
+            Scalar::Binary {
+                left,
+                op: Token::And,
+                right,
+            } => {
+                let mut conjuncts = left.conjuncts();
+                conjuncts.extend(right.conjuncts());
+                conjuncts
+            }
+            other => vec![other],
+        }
+    }
+
+    /// `AND`s predicates back together; `None` if there are none
+    pub fn conjunction(preds: Vec<Scalar>) -> Option<Scalar> {
+        preds.into_iter().reduce(|left, right| Scalar::Binary {
+            left: Box::new(left),
+            op: Token::And,
+            right: Box::new(right),
+        })
+    }
+
+    /// The type of value the expression produces, given the types of the columns
+    pub fn ty(&self, col_ty: &impl Fn(ColumnId) -> Ty) -> Ty {
+        match self {
+            Scalar::Const(val) => match val {
+                LiteralValue::Number(_) => Ty::Number,
+                LiteralValue::Int(_) => Ty::BigInt,
+                LiteralValue::Decimal(_) => Ty::Decimal(None),
+                LiteralValue::Bool(_) => Ty::Bool,
+                LiteralValue::String(_) | LiteralValue::Null => Ty::String,
+                LiteralValue::Date(_) => Ty::Date,
+                LiteralValue::Time(_) => Ty::Time,
+                LiteralValue::Timestamp(_) => Ty::Timestamp,
+                LiteralValue::TimestampTz(_) => Ty::TimestampTz,
+                LiteralValue::Interval(_) => Ty::Interval,
+            },
+            Scalar::Column(id) => col_ty(*id),
+            Scalar::Unary { op: Token::Not, .. } | Scalar::IsNull { .. } => Ty::Bool,
+            Scalar::Unary { expr, .. } => expr.ty(col_ty),
+            Scalar::Binary { left, op, right } => match op {
+                Token::Plus | Token::Minus | Token::Star | Token::Slash | Token::Percent => {
+                    left.ty(col_ty).arithmetic(op, &right.ty(col_ty))
+                }
+                _ => Ty::Bool,
+            },
+            Scalar::Call { func, args } => match &func[..] {
+                "lower" | "upper" => Ty::String,
+                "coalesce" | "abs" | "round" => {
+                    args.first().map_or(Ty::String, |arg| arg.ty(col_ty))
+                }
+                "length" => Ty::Integer,
+                "now" => Ty::TimestampTz,
+                "date_trunc" => match args.get(1).map(|arg| arg.ty(col_ty)) {
+                    Some(ty @ (Ty::TimestampTz | Ty::Interval)) => ty,
+                    _ => Ty::Timestamp,
+                },
+                "extract" | "date_part" => Ty::Decimal(None),
+                _ => Ty::Number,
+            },
+        }
+    }
+
+    /// Displays a resolved expression, naming columns after the input's `columns`
+    pub fn display<'a>(&'a self, columns: &'a [String]) -> impl Display + 'a {
+        Named {
+            scalar: self,
+            columns,
+        }
+    }
+}
+
+struct Named<'a> {
+    scalar: &'a Scalar,
+    columns: &'a [String],
+}
+
+impl<'a> Named<'a> {
+    fn named(&self, scalar: &'a Scalar) -> Named<'a> {
+        Named {
+            scalar,
+            columns: self.columns,
+        }
+    }
+
+    /// Compound operands are parenthesized so nesting survives printing
+    fn operand(&self, scalar: &'a Scalar) -> String {
+        match scalar {
+            Scalar::Binary { .. } | Scalar::IsNull { .. } => format!("({})", self.named(scalar)),
+            _ => self.named(scalar).to_string(),
+        }
+    }
+}
+
+impl Display for Named<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operand = |scalar| self.operand(scalar);
+        match self.scalar {
+            Scalar::Const(val) => write!(f, "{}", val),
+            Scalar::Column(i) => match self.columns.get(*i) {
+                Some(name) => write!(f, "{}", name),
+                None => write!(f, "#{}", i),
+            },
+            Scalar::Unary {
+                op: Token::Not,
+                expr,
+            } => write!(f, "NOT {}", operand(expr)),
+            Scalar::Unary { op, expr } => write!(f, "{}{}", op, operand(expr)),
+            Scalar::Binary { left, op, right } => {
+                write!(f, "{} {} {}", operand(left), op, operand(right))
+            }
+            Scalar::IsNull { expr, negated } => write!(
+                f,
+                "{} IS {}NULL",
+                operand(expr),
+                if *negated { "NOT " } else { "" }
+            ),
+            Scalar::Call { func, args } => {
+                let args: Vec<_> = args.iter().map(|arg| self.named(arg).to_string()).collect();
+                write!(f, "{}({})", func, args.join(", "))
+            }
+        }
+    }
+}
+
+/// The number of arguments a scalar function takes, as an inclusive range; `None` if there
+/// is no such function
+pub fn arity(func: &str) -> Option<(usize, usize)> {
+    match func {
+        "abs" | "round" | "length" | "lower" | "upper" => Some((1, 1)),
+        "coalesce" => Some((1, usize::MAX)),
+        "now" => Some((0, 0)),
+        "date_trunc" | "extract" | "date_part" => Some((2, 2)),
+        _ => None,
+    }
+}
+
+/// Calls a scalar function. Apart from `coalesce`, a `NULL` argument gives `NULL`.
+pub fn call(func: &str, args: &[LiteralValue]) -> Result<LiteralValue> {
+    match arity(func) {
+        None => bail!("Unknown function {}", func),
+        Some((min, max)) if args.len() < min || args.len() > max => {
+            bail!("Wrong number of arguments to {}", func)
+        }
+        _ => {}
+    }
+    if func == "coalesce" {
+        return Ok(args
+            .iter()
+            .find(|arg| **arg != LiteralValue::Null)
+            .cloned()
+            .unwrap_or(LiteralValue::Null));
+    }
+    if args.contains(&LiteralValue::Null) {
+        return Ok(LiteralValue::Null);
+    }
+    let string = |val: &LiteralValue| match val {
+        LiteralValue::String(s) => Ok(s.clone()),
+        other => bail!("{} expects a string, got {}", func, other),
+    };
+    Ok(match func {
+        "abs" => match &args[0] {
+            LiteralValue::Int(n) => LiteralValue::Int(
+                n.checked_abs()
+                    .ok_or_else(|| anyhow!("Integer out of range"))?,
+            ),
+            LiteralValue::Decimal(d) => LiteralValue::Decimal(d.abs()),
+            other => LiteralValue::Number(OrderedFloat(other.number()?.abs())),
+        },
+        "round" => match &args[0] {
+            LiteralValue::Int(n) => LiteralValue::Int(*n),
+            LiteralValue::Decimal(d) => LiteralValue::Decimal(d.round(0)),
+            other => LiteralValue::Number(OrderedFloat(other.number()?.round())),
+        },
+        "length" => LiteralValue::Int(string(&args[0])?.chars().count() as i64),
+        "lower" => LiteralValue::String(string(&args[0])?.to_lowercase()),
+        "upper" => LiteralValue::String(string(&args[0])?.to_uppercase()),
+        "now" => LiteralValue::now(),
+        "date_trunc" => args[1].date_trunc(&string(&args[0])?)?,
+        "extract" | "date_part" => args[1].extract(&string(&args[0])?)?,
+        _ => unreachable!(),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggFunc {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl AggFunc {
+    pub fn from_name(name: &str) -> Option<AggFunc> {
+        match name {
+            "count" => Some(AggFunc::Count),
+            "sum" => Some(AggFunc::Sum),
+            "avg" => Some(AggFunc::Avg),
+            "min" => Some(AggFunc::Min),
+            "max" => Some(AggFunc::Max),
+            _ => None,
+        }
+    }
+}
+
+impl Display for AggFunc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+/// An aggregate function applied to an expression; `count(*)` has no `arg`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggCall {
+    pub func: AggFunc,
+    pub arg: Option<Scalar>,
+}
+
+impl AggCall {
+    pub fn ty(&self, col_ty: &impl Fn(ColumnId) -> Ty) -> Ty {
+        let arg = self.arg.as_ref().map(|arg| arg.ty(col_ty));
+        match (self.func, arg) {
+            (AggFunc::Count, _) => Ty::BigInt,
+            (AggFunc::Min | AggFunc::Max, Some(arg)) => arg,
+            (AggFunc::Sum, Some(Ty::SmallInt | Ty::Integer | Ty::BigInt)) => Ty::BigInt,
+            (AggFunc::Sum, Some(Ty::Real)) => Ty::Real,
+            (AggFunc::Sum | AggFunc::Avg, Some(Ty::Decimal(_))) => Ty::Decimal(None),
+            (AggFunc::Avg, Some(Ty::SmallInt | Ty::Integer | Ty::BigInt | Ty::Double)) => {
+                Ty::Double
+            }
+            _ => Ty::Number,
+        }
+    }
+}
+
+/// Running state of one aggregate over one group. Sums stay integers, failing if they
+/// overflow, until a value that isn't one is added; sums and averages of decimals are
+/// exact.
+pub struct Accumulator {
+    func: AggFunc,
+    count: usize,
+    sum: LiteralValue,
+    value: Option<LiteralValue>,
+}
+
+impl Accumulator {
+    pub fn new(func: AggFunc) -> Self {
+        Accumulator {
+            func,
+            count: 0,
+            sum: LiteralValue::Int(0),
+            value: None,
+        }
+    }
+
+    /// Adds a row's argument; `None` is a `count(*)` row, which has no argument. `NULL`s are
+    /// skipped, as in SQL.
+    pub fn update(&mut self, val: Option<LiteralValue>) -> Result<()> {
+        let val = match val {
+            None => {
+                self.count += 1;
+                return Ok(());
+            }
+            Some(LiteralValue::Null) => return Ok(()),
+            Some(val) => val,
+        };
+        self.count += 1;
+        match self.func {
+            AggFunc::Count => {}
+            AggFunc::Sum | AggFunc::Avg => {
+                val.number()?;
+                self.sum = self.sum.binary(&Token::Plus, &val)?;
+            }
+            AggFunc::Min | AggFunc::Max => {
+                let replace = match &self.value {
+                    None => true,
+                    Some(cur) => {
+                        let ord = cur.binary(&Token::LessThan, &val)?;
+                        (ord == LiteralValue::Bool(true)) == (self.func == AggFunc::Max)
+                    }
+                };
+                if replace {
+                    self.value = Some(val);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(&self) -> LiteralValue {
+        match self.func {
+            AggFunc::Count => LiteralValue::Int(self.count as i64),
+            _ if self.count == 0 => LiteralValue::Null,
+            AggFunc::Sum => self.sum.clone(),
+            AggFunc::Avg => match &self.sum {
+                LiteralValue::Decimal(sum) => sum
+                    .div(&Decimal::from(self.count as i64))
+                    .map_or(LiteralValue::Null, LiteralValue::Decimal),
+                sum => match sum.number() {
+                    Ok(sum) => LiteralValue::Number(sum / self.count as f64),
+                    Err(_) => LiteralValue::Null,
+                },
+            },
+            AggFunc::Min | AggFunc::Max => self.value.clone().unwrap_or(LiteralValue::Null),
+        }
+    }
+}
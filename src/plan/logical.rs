@@ -0,0 +1,636 @@
+use std::{collections::HashMap, ops::Bound};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    db::Tables,
+    parse::{
+        error_at, Expr, FromClause, LiteralValue, Select, SelectItem, Span, Spanned, TableRef,
+        Token, Ty,
+    },
+};
+
+use super::expr::{arity, AggCall, AggFunc, ColumnId, Scalar};
+
+/// A column produced by a plan node
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub id: ColumnId,
+
+    /// Name (or alias) of the table the column can be qualified with
+    pub table: Option<String>,
+    pub name: String,
+    pub ty: Ty,
+
+    /// Table and column the values are read from unchanged, if they are
+    pub source: Option<(String, String)>,
+}
+
+/// The rows of a table whose value in the first column of an index lies between two bounds.
+/// It narrows a scan down; the scan's filter is still applied to every row found.
+#[derive(Debug, Clone)]
+pub struct IndexRange {
+    pub name: String,
+    pub lower: Bound<LiteralValue>,
+    pub upper: Bound<LiteralValue>,
+}
+
+/// What a query computes, as a tree of relational operators over columns identified by
+/// `ColumnId`s. Optimizer passes rewrite it before it is compiled into physical operators.
+#[derive(Debug, Clone)]
+pub enum LogicalPlan {
+    /// A single row with no columns, for queries without `FROM`
+    Unit,
+    Scan {
+        table: String,
+        alias: String,
+
+        /// Every column of the table
+        columns: Vec<Field>,
+
+        /// Applied to each row before projecting
+        filter: Option<Scalar>,
+
+        /// Indices into `columns` of the columns produced
+        projection: Vec<usize>,
+
+        /// Index to read the rows through instead of visiting them all
+        index: Option<IndexRange>,
+    },
+    Filter {
+        input: Box<LogicalPlan>,
+        predicate: Scalar,
+    },
+    Project {
+        input: Box<LogicalPlan>,
+        exprs: Vec<(Scalar, Field)>,
+    },
+
+    /// Every pairing of a left and a right row that satisfies `on`
+    Join {
+        left: Box<LogicalPlan>,
+        right: Box<LogicalPlan>,
+        on: Option<Scalar>,
+    },
+
+    /// One row per distinct `group_by`, holding the group's values followed by `aggs`
+    Aggregate {
+        input: Box<LogicalPlan>,
+        group_by: Vec<(Scalar, Field)>,
+        aggs: Vec<(AggCall, Field)>,
+    },
+
+    /// Sorts by each key in turn, descending where the flag is set
+    Sort {
+        input: Box<LogicalPlan>,
+        keys: Vec<(Scalar, bool)>,
+    },
+    Limit {
+        input: Box<LogicalPlan>,
+        limit: Option<usize>,
+        offset: usize,
+    },
+    Distinct {
+        input: Box<LogicalPlan>,
+    },
+}
+
+impl LogicalPlan {
+    /// The columns of each row the node produces, in order
+    pub fn schema(&self) -> Vec<Field> {
+        match self {
+            LogicalPlan::Unit => Vec::new(),
+            LogicalPlan::Scan {
+                columns,
+                projection,
+                ..
+            } => projection.iter().map(|i| columns[*i].clone()).collect(),
+            LogicalPlan::Filter { input, .. }
+            | LogicalPlan::Sort { input, .. }
+            | LogicalPlan::Limit { input, .. }
+            | LogicalPlan::Distinct { input } => input.schema(),
+            LogicalPlan::Project { exprs, .. } => {
+                exprs.iter().map(|(_, field)| field.clone()).collect()
+            }
+            LogicalPlan::Join { left, right, .. } => {
+                let mut schema = left.schema();
+                schema.extend(right.schema());
+                schema
+            }
+            LogicalPlan::Aggregate { group_by, aggs, .. } => group_by
+                .iter()
+                .map(|(_, field)| field.clone())
+                .chain(aggs.iter().map(|(_, field)| field.clone()))
+                .collect(),
+        }
+    }
+
+    /// Ids of the columns in `schema`
+    pub fn layout(&self) -> Vec<ColumnId> {
+        self.schema().iter().map(|field| field.id).collect()
+    }
+}
+
+/// Builds the logical plan for a query over `tables`, resolving every name it uses
+pub fn build(select: &Select, tables: &Tables) -> Result<LogicalPlan> {
+    Binder {
+        tables,
+        fields: HashMap::new(),
+    }
+    .select(select)
+}
+
+struct Binder<'a> {
+    tables: &'a Tables<'a>,
+
+    /// Every field handed out so far, by id
+    fields: HashMap<ColumnId, Field>,
+}
+
+/// Aggregates found while binding the parts of a query evaluated after grouping
+struct Aggs {
+    group_by: Vec<(Scalar, Field)>,
+    calls: Vec<(AggCall, Field)>,
+}
+
+impl Binder<'_> {
+    fn field(
+        &mut self,
+        table: Option<String>,
+        name: String,
+        ty: Ty,
+        source: Option<(String, String)>,
+    ) -> Field {
+        let field = Field {
+            id: self.fields.len(),
+            table,
+            name,
+            ty,
+            source,
+        };
+        self.fields.insert(field.id, field.clone());
+        field
+    }
+
+    fn ty(&self, scalar: &Scalar) -> Ty {
+        scalar.ty(&|id| self.fields[&id].ty.clone())
+    }
+
+    /// `scalar`, or if it is a string constant used with a `CHAR` column, the string padded
+    /// with spaces like the column's values are. A string compared with a date or time is
+    /// read as one.
+    fn fitted(&self, scalar: Scalar, op: &Token, other: &Scalar) -> Result<Scalar> {
+        let comparison = matches!(
+            op,
+            Token::Equal
+                | Token::NotEqual
+                | Token::LessThan
+                | Token::LessEqual
+                | Token::GreaterThan
+                | Token::GreaterEqual
+        );
+        Ok(match (scalar, self.ty(other)) {
+            (Scalar::Const(LiteralValue::String(s)), Ty::Char(len)) => {
+                Scalar::Const(LiteralValue::String(format!("{:<1$}", s, len as usize)))
+            }
+            (Scalar::Const(val @ LiteralValue::String(_)), ty)
+                if comparison && ty.is_temporal() =>
+            {
+                Scalar::Const(val.coerce(&ty)?)
+            }
+            (scalar, _) => scalar,
+        })
+    }
+
+    fn select(&mut self, select: &Select) -> Result<LogicalPlan> {
+        let mut plan = match &select.from {
+            None => LogicalPlan::Unit,
+            Some(from) => self.from(from)?,
+        };
+        let scope = plan.schema();
+
+        if let Some(filter) = &select.filter {
+            plan = LogicalPlan::Filter {
+                input: Box::new(plan),
+                predicate: self.expr(filter, &scope, None)?,
+            };
+        }
+
+        let grouped = !select.group_by.is_empty()
+            || select.having.is_some()
+            || select.items.iter().any(|item| match item {
+                SelectItem::Expr { expr, .. } => has_aggregate(expr),
+                SelectItem::Wildcard(_) => false,
+            })
+            || select
+                .order_by
+                .iter()
+                .any(|order| has_aggregate(&order.expr));
+        let mut aggs = if grouped {
+            let group_by = select
+                .group_by
+                .iter()
+                .map(|expr| {
+                    let scalar = self.expr(expr, &scope, None)?;
+                    let field = match &scalar {
+                        Scalar::Column(id) => {
+                            let Field {
+                                table,
+                                name,
+                                ty,
+                                source,
+                                ..
+                            } = self.fields[id].clone();
+                            self.field(table, name, ty, source)
+                        }
+                        other => {
+                            let ty = self.ty(other);
+                            self.field(None, expr.to_string(), ty, None)
+                        }
+                    };
+                    Ok((scalar, field))
+                })
+                .collect::<Result<_>>()?;
+            Some(Aggs {
+                group_by,
+                calls: Vec::new(),
+            })
+        } else {
+            None
+        };
+
+        // Output columns, bound against the input rows. After grouping they are rewritten
+        // to refer to the aggregate's output.
+        let mut items = Vec::new();
+        for item in &select.items {
+            match item {
+                SelectItem::Wildcard(table) => {
+                    let qualifier = table
+                        .as_ref()
+                        .map(|table| {
+                            table
+                                .ident()
+                                .cloned()
+                                .ok_or_else(|| anyhow!("Internal error"))
+                        })
+                        .transpose()?;
+                    let fields: Vec<_> = scope
+                        .iter()
+                        .filter(|field| qualifier.is_none() || field.table == qualifier)
+                        .cloned()
+                        .collect();
+                    if let (Some(table), true) = (table, fields.is_empty()) {
+                        return Err(error_at(
+                            table.span,
+                            format!("Table \"{}\" is not in FROM", table.node),
+                        ));
+                    }
+                    for field in fields {
+                        let scalar = self.after_grouping(Scalar::Column(field.id), &aggs)?;
+                        items.push((scalar, None, None));
+                    }
+                }
+                SelectItem::Expr { expr, alias } => {
+                    let scalar = self.expr(expr, &scope, aggs.as_mut())?;
+                    let scalar = self.after_grouping(scalar, &aggs)?;
+                    let alias = alias
+                        .as_ref()
+                        .map(|alias| {
+                            alias
+                                .ident()
+                                .cloned()
+                                .ok_or_else(|| anyhow!("Internal error"))
+                        })
+                        .transpose()?;
+                    items.push((scalar, alias, Some(expr.to_string())));
+                }
+            }
+        }
+
+        let having = select
+            .having
+            .as_ref()
+            .map(|having| {
+                let scalar = self.expr(having, &scope, aggs.as_mut())?;
+                self.after_grouping(scalar, &aggs)
+            })
+            .transpose()?;
+
+        let mut keys = Vec::new();
+        for order in &select.order_by {
+            // `ORDER BY n` sorts by the nth output column, and output columns can be
+            // referred to by alias
+            let item = match &order.expr {
+                Expr::Literal(LiteralValue::Int(n)) => {
+                    if *n < 1 || *n as usize > items.len() {
+                        bail!("ORDER BY position {} is not in select list", n);
+                    }
+                    Some(items[*n as usize - 1].0.clone())
+                }
+                Expr::Ident(ident) => items
+                    .iter()
+                    .find(|(_, alias, _)| alias.is_some() && alias.as_ref() == ident.ident())
+                    .map(|(scalar, _, _)| scalar.clone()),
+                _ => None,
+            };
+            let key = match item {
+                Some(scalar) => scalar,
+                None => {
+                    let scalar = self.expr(&order.expr, &scope, aggs.as_mut())?;
+                    self.after_grouping(scalar, &aggs)?
+                }
+            };
+            keys.push((key, order.desc));
+        }
+
+        if let Some(Aggs { group_by, calls }) = aggs {
+            plan = LogicalPlan::Aggregate {
+                input: Box::new(plan),
+                group_by,
+                aggs: calls,
+            };
+        }
+        if let Some(predicate) = having {
+            plan = LogicalPlan::Filter {
+                input: Box::new(plan),
+                predicate,
+            };
+        }
+        if !keys.is_empty() {
+            plan = LogicalPlan::Sort {
+                input: Box::new(plan),
+                keys,
+            };
+        }
+
+        let exprs = items
+            .into_iter()
+            .map(|(scalar, alias, text)| {
+                let field = match (&scalar, alias) {
+                    // Plain columns pass through under their own name and id
+                    (Scalar::Column(id), None) => self.fields[id].clone(),
+                    (scalar, alias) => {
+                        let ty = self.ty(scalar);
+                        let source = match scalar {
+                            Scalar::Column(id) => self.fields[id].source.clone(),
+                            _ => None,
+                        };
+                        let name = alias.or(text).unwrap_or_default();
+                        self.field(None, name, ty, source)
+                    }
+                };
+                (scalar, field)
+            })
+            .collect();
+        plan = LogicalPlan::Project {
+            input: Box::new(plan),
+            exprs,
+        };
+
+        if select.distinct {
+            plan = LogicalPlan::Distinct {
+                input: Box::new(plan),
+            };
+        }
+        if select.limit.is_some() || select.offset.is_some() {
+            plan = LogicalPlan::Limit {
+                input: Box::new(plan),
+                limit: select
+                    .limit
+                    .as_ref()
+                    .map(|limit| self.count(limit, "LIMIT"))
+                    .transpose()?,
+                offset: select
+                    .offset
+                    .as_ref()
+                    .map(|offset| self.count(offset, "OFFSET"))
+                    .transpose()?
+                    .unwrap_or(0),
+            };
+        }
+        Ok(plan)
+    }
+
+    fn from(&mut self, from: &FromClause) -> Result<LogicalPlan> {
+        let mut plan = self.scan(&from.table)?;
+        for join in &from.joins {
+            let right = self.scan(&join.table)?;
+            let mut scope = plan.schema();
+            scope.extend(right.schema());
+            let on = join
+                .on
+                .as_ref()
+                .map(|on| self.expr(on, &scope, None))
+                .transpose()?;
+            plan = LogicalPlan::Join {
+                left: Box::new(plan),
+                right: Box::new(right),
+                on,
+            };
+        }
+        Ok(plan)
+    }
+
+    fn scan(&mut self, table_ref: &TableRef) -> Result<LogicalPlan> {
+        let name = table_ref
+            .name
+            .ident()
+            .ok_or_else(|| anyhow!("Internal error"))?;
+        let table = self.tables.get(name).ok_or_else(|| {
+            error_at(table_ref.name.span, format!("Table \"{}\" not found", name))
+        })?;
+        let alias = match &table_ref.alias {
+            Some(alias) => alias.ident().ok_or_else(|| anyhow!("Internal error"))?,
+            None => name,
+        };
+        let columns: Vec<_> = table
+            .col_headers()
+            .iter()
+            .map(|header| {
+                self.field(
+                    Some(alias.clone()),
+                    header.name().to_string(),
+                    header.ty().clone(),
+                    Some((name.clone(), header.name().to_string())),
+                )
+            })
+            .collect();
+        Ok(LogicalPlan::Scan {
+            table: name.clone(),
+            alias: alias.clone(),
+            projection: (0..columns.len()).collect(),
+            columns,
+            filter: None,
+            index: None,
+        })
+    }
+
+    /// Resolves the names in `expr` against `scope`. Aggregate calls are only allowed where
+    /// `aggs` is given, and become references to the aggregate's output.
+    fn expr(
+        &mut self,
+        expr: &Expr,
+        scope: &[Field],
+        mut aggs: Option<&mut Aggs>,
+    ) -> Result<Scalar> {
+        Ok(match expr {
+            Expr::Literal(val) => Scalar::Const(val.clone()),
+            Expr::Ident(ident) => self.column(scope, None, ident)?,
+            Expr::Qualified { table, col } => self.column(scope, Some(table), col)?,
+            Expr::Param(n) => bail!("No value supplied for parameter ${}", n),
+            Expr::Unary { op, expr } => Scalar::Unary {
+                op: op.clone(),
+                expr: Box::new(self.expr(expr, scope, aggs)?),
+            },
+            Expr::Binary { left, op, right } => {
+                let left = self.expr(left, scope, aggs.as_deref_mut())?;
+                let right = self.expr(right, scope, aggs)?;
+                Scalar::Binary {
+                    left: Box::new(self.fitted(left.clone(), op, &right)?),
+                    op: op.clone(),
+                    right: Box::new(self.fitted(right, op, &left)?),
+                }
+            }
+            Expr::IsNull { expr, negated } => Scalar::IsNull {
+                expr: Box::new(self.expr(expr, scope, aggs)?),
+                negated: *negated,
+            },
+            Expr::Function { name, args, star } => {
+                let func = name.node.to_string().to_lowercase();
+                if let Some(agg_func) = AggFunc::from_name(&func) {
+                    let aggs = aggs.ok_or_else(|| {
+                        error_at(
+                            name.span,
+                            format!("Aggregate function {} is not allowed here", func),
+                        )
+                    })?;
+                    let arg = match (agg_func, &args[..], star) {
+                        (AggFunc::Count, [], true) => None,
+                        (_, [arg], false) => Some(self.expr(arg, scope, None)?),
+                        _ => {
+                            return Err(error_at(
+                                name.span,
+                                format!("Wrong number of arguments to {}", func),
+                            ))
+                        }
+                    };
+                    let call = AggCall {
+                        func: agg_func,
+                        arg,
+                    };
+                    let field = match aggs.calls.iter().find(|(other, _)| *other == call) {
+                        Some((_, field)) => field.clone(),
+                        None => {
+                            let ty = call.ty(&|id| self.fields[&id].ty.clone());
+                            let field = self.field(None, expr.to_string(), ty, None);
+                            aggs.calls.push((call, field.clone()));
+                            field
+                        }
+                    };
+                    Scalar::Column(field.id)
+                } else {
+                    match arity(&func) {
+                        None => {
+                            return Err(error_at(
+                                name.span,
+                                format!("Unknown function {}", name.node),
+                            ))
+                        }
+                        Some((min, max)) if *star || args.len() < min || args.len() > max => {
+                            return Err(error_at(
+                                name.span,
+                                format!("Wrong number of arguments to {}", func),
+                            ))
+                        }
+                        _ => {}
+                    }
+                    Scalar::Call {
+                        func,
+                        args: args
+                            .iter()
+                            .map(|arg| self.expr(arg, scope, aggs.as_deref_mut()))
+                            .collect::<Result<_>>()?,
+                    }
+                }
+            }
+        })
+    }
+
+    fn column(
+        &self,
+        scope: &[Field],
+        table: Option<&Spanned<Token>>,
+        col: &Spanned<Token>,
+    ) -> Result<Scalar> {
+        let name = col.ident().ok_or_else(|| anyhow!("Internal error"))?;
+        let qualifier = table.and_then(|table| table.ident());
+        let mut matches = scope.iter().filter(|field| {
+            &field.name == name && (qualifier.is_none() || field.table.as_ref() == qualifier)
+        });
+        let display = match table {
+            Some(table) => format!("{}.{}", table.node, name),
+            None => name.to_string(),
+        };
+        let span = match table {
+            Some(table) => Span {
+                end: col.span.end,
+                ..table.span
+            },
+            None => col.span,
+        };
+        match (matches.next(), matches.next()) {
+            (Some(field), None) => Ok(Scalar::Column(field.id)),
+            (None, _) => Err(error_at(span, format!("Unknown column {}", display))),
+            (Some(_), Some(_)) => Err(error_at(
+                span,
+                format!("Column reference \"{}\" is ambiguous", display),
+            )),
+        }
+    }
+
+    /// Rewrites an expression over the input rows to one over the groups: grouped
+    /// expressions become the group's column, and any other column is an error
+    fn after_grouping(&self, scalar: Scalar, aggs: &Option<Aggs>) -> Result<Scalar> {
+        let Some(aggs) = aggs else {
+            return Ok(scalar);
+        };
+        scalar.transform(&mut |scalar| {
+            if let Some((_, field)) = aggs.group_by.iter().find(|(group, _)| group == scalar) {
+                return Ok(Some(Scalar::Column(field.id)));
+            }
+            match scalar {
+                Scalar::Column(id) if aggs.calls.iter().any(|(_, field)| field.id == *id) => {
+                    Ok(Some(scalar.clone()))
+                }
+                Scalar::Column(id) => bail!(
+                    "Column \"{}\" must appear in GROUP BY or be used in an aggregate function",
+                    self.fields[id].name
+                ),
+                _ => Ok(None),
+            }
+        })
+    }
+
+    /// Evaluates the row count given to `LIMIT` or `OFFSET`
+    fn count(&mut self, expr: &Expr, clause: &str) -> Result<usize> {
+        match self.expr(expr, &[], None)?.fold() {
+            Scalar::Const(LiteralValue::Int(n)) if n >= 0 => Ok(n as usize),
+            Scalar::Const(LiteralValue::Number(n)) if n.fract() == 0.0 && *n >= 0.0 => {
+                Ok(n.into_inner() as usize)
+            }
+            _ => bail!("{} must be a non-negative integer", clause),
+        }
+    }
+}
+
+fn has_aggregate(expr: &Expr) -> bool {
+    match expr {
+        Expr::Function { name, args, .. } => {
+            AggFunc::from_name(&name.node.to_string().to_lowercase()).is_some()
+                || args.iter().any(has_aggregate)
+        }
+        Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => has_aggregate(expr),
+        Expr::Binary { left, right, .. } => has_aggregate(left) || has_aggregate(right),
+        Expr::Literal(_) | Expr::Ident(_) | Expr::Qualified { .. } | Expr::Param(_) => false,
+    }
+}
@@ -0,0 +1,424 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use crate::{
+    connection::Frame,
+    db::{Tables, Txn},
+    parse::Select,
+};
+
+pub use self::{
+    expr::call,
+    logical::{Field, LogicalPlan},
+    physical::{
+        Distinct, Filter, HashAggregate, IndexScan, Instrumented, Limit, NestedLoopJoin, Operator,
+        Project, SeqScan, Sort, Values,
+    },
+};
+
+mod expr;
+mod logical;
+mod optimize;
+mod physical;
+
+/// Builds the optimized logical plan of a query over `tables`
+pub fn plan(select: &Select, tables: &Tables) -> Result<LogicalPlan> {
+    Ok(optimize::optimize(logical::build(select, tables)?, tables))
+}
+
+/// Plans a query and compiles it into operators ready to be pulled from. With `analyze`,
+/// every operator is wrapped to record its row count and timing.
+pub fn select<'a>(
+    select: &Select,
+    tables: &'a Tables,
+    txn: &mut Txn,
+    analyze: bool,
+) -> Result<Box<dyn Operator + 'a>> {
+    compile(plan(select, tables)?, tables, txn, analyze)
+}
+
+/// Turns a logical plan into the operator tree that runs it as part of `txn`. The scans
+/// read the rows its snapshot sees from `tables` as they are pulled, so the tables must
+/// stay locked while the operators run.
+pub fn compile<'a>(
+    plan: LogicalPlan,
+    tables: &'a Tables,
+    txn: &mut Txn,
+    analyze: bool,
+) -> Result<Box<dyn Operator + 'a>> {
+    let names = |fields: Vec<Field>| fields.into_iter().map(|field| field.name).collect();
+    let op: Box<dyn Operator + 'a> = match plan {
+        LogicalPlan::Unit => Box::new(Values::new(Vec::new(), vec![Vec::new()])),
+        LogicalPlan::Scan {
+            table,
+            alias,
+            columns,
+            filter,
+            projection,
+            index,
+        } => {
+            let layout: Vec<_> = columns.iter().map(|field| field.id).collect();
+            let filter = filter.map(|filter| filter.resolve(&layout)).transpose()?;
+            txn.read(&table);
+            let snapshot = txn.snapshot();
+            let data = tables
+                .get(&table)
+                .ok_or_else(|| anyhow!("Table \"{}\" not found", table))?;
+            match index {
+                Some(range) => {
+                    let rows = tables.index_rows(
+                        &table,
+                        &range.name,
+                        range.lower.as_ref(),
+                        range.upper.as_ref(),
+                        &snapshot,
+                    )?;
+                    Box::new(
+                        IndexScan::new(table, data, range.name, rows)
+                            .alias(alias)
+                            .filter(filter)
+                            .project(projection),
+                    )
+                }
+                None => Box::new(
+                    SeqScan::new(table.clone(), data, tables.rows(&table, &snapshot)?)
+                        .alias(alias)
+                        .filter(filter)
+                        .project(projection),
+                ),
+            }
+        }
+        LogicalPlan::Filter { input, predicate } => {
+            let predicate = predicate.resolve(&input.layout())?;
+            Box::new(Filter::new(
+                compile(*input, tables, txn, analyze)?,
+                predicate,
+            ))
+        }
+        LogicalPlan::Project { input, exprs } => {
+            let layout = input.layout();
+            let (exprs, fields): (Vec<_>, Vec<_>) = exprs.into_iter().unzip();
+            let exprs = exprs
+                .iter()
+                .map(|expr| expr.resolve(&layout))
+                .collect::<Result<_>>()?;
+            Box::new(Project::new(
+                compile(*input, tables, txn, analyze)?,
+                names(fields),
+                exprs,
+            ))
+        }
+        LogicalPlan::Join { left, right, on } => {
+            let mut layout = left.layout();
+            layout.extend(right.layout());
+            let on = on.map(|on| on.resolve(&layout)).transpose()?;
+            Box::new(NestedLoopJoin::new(
+                compile(*left, tables, txn, analyze)?,
+                compile(*right, tables, txn, analyze)?,
+                on,
+            ))
+        }
+        LogicalPlan::Aggregate {
+            input,
+            group_by,
+            aggs,
+        } => {
+            let layout = input.layout();
+            let (group_by, mut fields): (Vec<_>, Vec<_>) = group_by.into_iter().unzip();
+            let group_by = group_by
+                .iter()
+                .map(|expr| expr.resolve(&layout))
+                .collect::<Result<_>>()?;
+            let (aggs, agg_fields): (Vec<_>, Vec<_>) = aggs.into_iter().unzip();
+            fields.extend(agg_fields);
+            let aggs = aggs
+                .into_iter()
+                .map(|mut agg| {
+                    agg.arg = agg.arg.map(|arg| arg.resolve(&layout)).transpose()?;
+                    Ok(agg)
+                })
+                .collect::<Result<_>>()?;
+            Box::new(HashAggregate::new(
+                compile(*input, tables, txn, analyze)?,
+                names(fields),
+                group_by,
+                aggs,
+            ))
+        }
+        LogicalPlan::Sort { input, keys } => {
+            let layout = input.layout();
+            let keys = keys
+                .into_iter()
+                .map(|(key, desc)| Ok((key.resolve(&layout)?, desc)))
+                .collect::<Result<_>>()?;
+            Box::new(Sort::new(compile(*input, tables, txn, analyze)?, keys))
+        }
+        LogicalPlan::Limit {
+            input,
+            limit,
+            offset,
+        } => Box::new(Limit::new(
+            compile(*input, tables, txn, analyze)?,
+            limit,
+            offset,
+        )),
+        LogicalPlan::Distinct { input } => {
+            Box::new(Distinct::new(compile(*input, tables, txn, analyze)?))
+        }
+    };
+    Ok(if analyze {
+        Box::new(Instrumented::new(op))
+    } else {
+        op
+    })
+}
+
+/// Pulls every tuple out of `op`, returning them under a header of column names
+pub fn run(op: &mut dyn Operator) -> Result<Frame> {
+    let mut rows = vec![op
+        .columns()
+        .iter()
+        .map(|col| Bytes::from(col.clone()))
+        .collect()];
+    while let Some(tuple) = op.next()? {
+        rows.push(tuple.iter().map(Bytes::from).collect());
+    }
+    Ok(Frame::Table(rows))
+}
+
+/// Shows the operator tree, one operator per row, with children indented under their parent.
+/// Operators that were measured get their row count and time alongside.
+pub fn explain(op: &dyn Operator) -> Frame {
+    let mut rows = Vec::new();
+    explain_node(op, 0, &mut rows);
+    let header = if op.stats().is_some() {
+        vec!["QUERY PLAN".into(), "rows".into(), "time".into()]
+    } else {
+        vec!["QUERY PLAN".into()]
+    };
+    rows.insert(0, header);
+    Frame::Table(rows)
+}
+
+fn explain_node(op: &dyn Operator, depth: usize, rows: &mut Vec<Vec<Bytes>>) {
+    let line = if depth == 0 {
+        op.describe()
+    } else {
+        format!("{}-> {}", "   ".repeat(depth - 1), op.describe())
+    };
+    let mut row = vec![Bytes::from(line)];
+    if let Some((count, elapsed)) = op.stats() {
+        row.push(Bytes::from(count.to_string()));
+        row.push(Bytes::from(format!(
+            "{:.3}ms",
+            elapsed.as_secs_f64() * 1000.0
+        )));
+    }
+    rows.push(row);
+    for child in op.children() {
+        explain_node(child, depth + 1, rows);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ordered_float::OrderedFloat;
+
+    use crate::{
+        db::{Column, ColumnHeader, Database, Table},
+        parse::{self, Command, Isolation, LiteralValue, Ty},
+    };
+
+    use super::*;
+
+    #[test]
+    fn predicate_pushdown() {
+        let tables = init_tables();
+        assert_eq!(
+            explain_lines(
+                &tables,
+                "SELECT p.name FROM people p JOIN pets ON pets.owner = p.name \
+                 WHERE p.age > 20 AND pets.name <> \"Rex\" AND p.age < pets.legs * 10",
+            ),
+            vec![
+                "Project (name)",
+                "-> NestedLoopJoin ((owner = name) AND (age < (legs * 10)))",
+                "   -> SeqScan on people p (filter: age > 20)",
+                "   -> SeqScan on pets (filter: name <> \"Rex\")",
+            ]
+        );
+
+        // Conditions on grouped columns filter rows before grouping
+        assert_eq!(
+            explain_lines(
+                &tables,
+                "SELECT age, count(*) FROM people GROUP BY age HAVING age > 1 AND count(*) > 1",
+            ),
+            vec![
+                "Project (age, count(*))",
+                "-> Filter (count(*) > 1)",
+                "   -> HashAggregate by age (count(*))",
+                "      -> SeqScan on people (filter: age > 1)",
+            ]
+        );
+    }
+
+    #[test]
+    fn constant_folding() {
+        let tables = init_tables();
+        assert_eq!(
+            explain_lines(
+                &tables,
+                "SELECT name FROM people WHERE age > 2 * 5 + 1 AND (1 = 1 OR name = \"x\")",
+            ),
+            vec!["Project (name)", "-> SeqScan on people (filter: age > 11)"]
+        );
+        assert_eq!(
+            explain_lines(&tables, "SELECT name FROM people WHERE 1 < 2"),
+            vec!["Project (name)", "-> SeqScan on people"]
+        );
+    }
+
+    #[test]
+    fn projection_pruning() {
+        let tables = init_tables();
+        let Command::Select(select) =
+            parse::parse("SELECT p.name FROM people p, pets WHERE legs > 2".into()).unwrap()
+        else {
+            panic!("expected SELECT")
+        };
+        let db = Database::from(tables);
+        let plan = db
+            .read(&select.tables(), |tables| plan(&select, tables))
+            .unwrap();
+        let mut scanned = Vec::new();
+        collect_scans(&plan, &mut scanned);
+        assert_eq!(
+            scanned,
+            vec![
+                ("people".to_string(), vec!["name".to_string()]),
+                ("pets".into(), vec![])
+            ]
+        );
+    }
+
+    #[test]
+    fn index_selection() {
+        let mut tables = init_tables();
+        let people = tables.get_mut("people").unwrap();
+        people
+            .create_index("people_name".into(), vec!["name".into()], true)
+            .unwrap();
+        people
+            .create_index("people_age".into(), vec!["age".into()], false)
+            .unwrap();
+        assert_eq!(
+            explain_lines(
+                &tables,
+                "SELECT name FROM people WHERE age > 10 AND 20 >= age"
+            ),
+            vec![
+                "Project (name)",
+                "-> IndexScan on people using people_age (filter: (age > 10) AND (20 >= age))",
+            ]
+        );
+
+        // Equality beats a range
+        assert_eq!(
+            explain_lines(
+                &tables,
+                "SELECT age FROM people WHERE age < 30 AND name = \"Elliot\""
+            ),
+            vec![
+                "Project (age)",
+                "-> IndexScan on people using people_name \
+                 (filter: (age < 30) AND (name = \"Elliot\"))",
+            ]
+        );
+        assert_eq!(
+            explain_lines(&tables, "SELECT name FROM people WHERE age + 1 = 17"),
+            vec![
+                "Project (name)",
+                "-> SeqScan on people (filter: (age + 1) = 17)"
+            ]
+        );
+    }
+
+    fn collect_scans(plan: &LogicalPlan, scanned: &mut Vec<(String, Vec<String>)>) {
+        match plan {
+            LogicalPlan::Scan { table, .. } => scanned.push((
+                table.clone(),
+                plan.schema().into_iter().map(|field| field.name).collect(),
+            )),
+            LogicalPlan::Unit => {}
+            LogicalPlan::Join { left, right, .. } => {
+                collect_scans(left, scanned);
+                collect_scans(right, scanned);
+            }
+            LogicalPlan::Filter { input, .. }
+            | LogicalPlan::Project { input, .. }
+            | LogicalPlan::Aggregate { input, .. }
+            | LogicalPlan::Sort { input, .. }
+            | LogicalPlan::Limit { input, .. }
+            | LogicalPlan::Distinct { input } => collect_scans(input, scanned),
+        }
+    }
+
+    fn explain_lines(tables: &HashMap<String, Table>, sql: &str) -> Vec<String> {
+        let Command::Select(select) = parse::parse(Bytes::copy_from_slice(sql.as_bytes())).unwrap()
+        else {
+            panic!("expected SELECT")
+        };
+        let tables = tables
+            .iter()
+            .map(|(name, table)| (name.clone(), table.try_clone().unwrap()))
+            .collect::<HashMap<_, _>>();
+        let db = Database::from(tables);
+        let mut txn = db.begin(Isolation::default());
+        let frame = db
+            .read(&select.tables(), |tables| {
+                Ok(explain(
+                    super::select(&select, tables, &mut txn, false)?.as_ref(),
+                ))
+            })
+            .unwrap();
+        let Frame::Table(rows) = frame else {
+            panic!("expected table")
+        };
+        rows[1..]
+            .iter()
+            .map(|row| String::from_utf8(row[0].to_vec()).unwrap())
+            .collect()
+    }
+
+    fn init_tables() -> HashMap<String, Table> {
+        let table = |cols: &[(&str, Ty)]| {
+            Table::try_from(
+                cols.iter()
+                    .map(|(name, ty)| {
+                        ColumnHeader::new(name.to_string())
+                            .ty(ty.clone())
+                            .build()
+                            .unwrap()
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap()
+        };
+        let mut people = table(&[("name", Ty::String), ("age", Ty::Number)]);
+        people
+            .append(vec![
+                Column::new(LiteralValue::String("Elliot".into()), "name".into()),
+                Column::new(LiteralValue::Number(OrderedFloat(16.0)), "age".into()),
+            ])
+            .unwrap();
+        let pets = table(&[
+            ("name", Ty::String),
+            ("owner", Ty::String),
+            ("legs", Ty::Number),
+        ]);
+        HashMap::from([("people".into(), people), ("pets".into(), pets)])
+    }
+}
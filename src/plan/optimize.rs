@@ -0,0 +1,438 @@
+use std::{collections::HashSet, ops::Bound};
+
+use crate::{
+    db::Tables,
+    parse::{LiteralValue, Token},
+};
+
+use super::{
+    expr::{ColumnId, Scalar},
+    logical::{IndexRange, LogicalPlan},
+};
+
+/// Rewrites a plan over `tables` into an equivalent one that is cheaper to run
+pub fn optimize(plan: LogicalPlan, tables: &Tables) -> LogicalPlan {
+    let plan = fold_constants(plan);
+    let plan = push_down_predicates(plan, Vec::new());
+    let plan = choose_indexes(plan, tables);
+    let required = plan.layout().into_iter().collect();
+    prune_projections(plan, &required)
+}
+
+/// Evaluates constant subexpressions once at planning time, dropping filters that always
+/// pass
+pub fn fold_constants(plan: LogicalPlan) -> LogicalPlan {
+    match map_children(plan, fold_constants) {
+        LogicalPlan::Scan {
+            table,
+            alias,
+            columns,
+            filter,
+            projection,
+            index,
+        } => LogicalPlan::Scan {
+            table,
+            alias,
+            columns,
+            filter: filter
+                .map(|filter| filter.fold())
+                .filter(|filter| !is_true(filter)),
+            projection,
+            index,
+        },
+        LogicalPlan::Filter { input, predicate } => {
+            let predicate = predicate.fold();
+            if is_true(&predicate) {
+                *input
+            } else {
+                LogicalPlan::Filter { input, predicate }
+            }
+        }
+        LogicalPlan::Project { input, exprs } => LogicalPlan::Project {
+            input,
+            exprs: exprs
+                .into_iter()
+                .map(|(scalar, field)| (scalar.fold(), field))
+                .collect(),
+        },
+        LogicalPlan::Join { left, right, on } => LogicalPlan::Join {
+            left,
+            right,
+            on: on.map(|on| on.fold()).filter(|on| !is_true(on)),
+        },
+        LogicalPlan::Aggregate {
+            input,
+            group_by,
+            aggs,
+        } => LogicalPlan::Aggregate {
+            input,
+            group_by: group_by
+                .into_iter()
+                .map(|(scalar, field)| (scalar.fold(), field))
+                .collect(),
+            aggs,
+        },
+        LogicalPlan::Sort { input, keys } => LogicalPlan::Sort {
+            input,
+            keys: keys
+                .into_iter()
+                .map(|(scalar, desc)| (scalar.fold(), desc))
+                .collect(),
+        },
+        other => other,
+    }
+}
+
+fn is_true(scalar: &Scalar) -> bool {
+    *scalar == Scalar::Const(LiteralValue::Bool(true))
+}
+
+/// Moves each part of a filter as close to the rows it tests as possible: into table scans,
+/// to one side of a join, or below grouping. `preds` are the conjuncts pushed down from
+/// above `plan`.
+pub fn push_down_predicates(plan: LogicalPlan, mut preds: Vec<Scalar>) -> LogicalPlan {
+    match plan {
+        LogicalPlan::Filter { input, predicate } => {
+            preds.extend(predicate.conjuncts());
+            push_down_predicates(*input, preds)
+        }
+        LogicalPlan::Scan {
+            table,
+            alias,
+            columns,
+            filter,
+            projection,
+            index,
+        } => {
+            let mut filters: Vec<_> = filter.into_iter().flat_map(Scalar::conjuncts).collect();
+            filters.extend(preds);
+            LogicalPlan::Scan {
+                table,
+                alias,
+                columns,
+                filter: Scalar::conjunction(filters),
+                projection,
+                index,
+            }
+        }
+        LogicalPlan::Join { left, right, on } => {
+            let preds: Vec<_> = on
+                .into_iter()
+                .flat_map(Scalar::conjuncts)
+                .chain(preds)
+                .collect();
+            let left_cols: HashSet<_> = left.layout().into_iter().collect();
+            let right_cols: HashSet<_> = right.layout().into_iter().collect();
+            let (mut left_preds, mut right_preds, mut on) = (Vec::new(), Vec::new(), Vec::new());
+            for pred in preds {
+                let cols = pred.columns();
+                if cols.iter().all(|col| left_cols.contains(col)) {
+                    left_preds.push(pred);
+                } else if cols.iter().all(|col| right_cols.contains(col)) {
+                    right_preds.push(pred);
+                } else {
+                    on.push(pred);
+                }
+            }
+            LogicalPlan::Join {
+                left: Box::new(push_down_predicates(*left, left_preds)),
+                right: Box::new(push_down_predicates(*right, right_preds)),
+                on: Scalar::conjunction(on),
+            }
+        }
+        LogicalPlan::Aggregate {
+            input,
+            group_by,
+            aggs,
+        } => {
+            // A predicate on grouped columns alone holds for a whole group or none of it, so
+            // it can filter the input rows instead. Without `GROUP BY` there is a single
+            // group even if there are no rows, so nothing can move.
+            let (below, above): (Vec<_>, Vec<_>) = preds.into_iter().partition(|pred| {
+                !group_by.is_empty()
+                    && pred
+                        .columns()
+                        .iter()
+                        .all(|col| group_by.iter().any(|(_, field)| field.id == *col))
+            });
+            let below = below
+                .into_iter()
+                .filter_map(|pred| {
+                    pred.transform(&mut |scalar| match scalar {
+                        Scalar::Column(id) => Ok(group_by
+                            .iter()
+                            .find(|(_, field)| field.id == *id)
+                            .map(|(group, _)| group.clone())),
+                        _ => Ok(None),
+                    })
+                    .ok()
+                })
+                .collect();
+            with_filter(
+                LogicalPlan::Aggregate {
+                    input: Box::new(push_down_predicates(*input, below)),
+                    group_by,
+                    aggs,
+                },
+                above,
+            )
+        }
+        LogicalPlan::Sort { input, keys } => LogicalPlan::Sort {
+            input: Box::new(push_down_predicates(*input, preds)),
+            keys,
+        },
+        other => with_filter(
+            map_children(other, |child| push_down_predicates(child, Vec::new())),
+            preds,
+        ),
+    }
+}
+
+fn with_filter(plan: LogicalPlan, preds: Vec<Scalar>) -> LogicalPlan {
+    match Scalar::conjunction(preds) {
+        Some(predicate) => LogicalPlan::Filter {
+            input: Box::new(plan),
+            predicate,
+        },
+        None => plan,
+    }
+}
+
+/// Lets scans whose filter compares the first column of an index with a constant read only
+/// the matching range of the index. Equality is preferred over a range when several indexes
+/// apply.
+pub fn choose_indexes(plan: LogicalPlan, tables: &Tables) -> LogicalPlan {
+    match map_children(plan, |child| choose_indexes(child, tables)) {
+        LogicalPlan::Scan {
+            table,
+            alias,
+            columns,
+            filter,
+            projection,
+            index: None,
+        } => {
+            let conjuncts = filter.clone().map(Scalar::conjuncts).unwrap_or_default();
+            let index = tables
+                .get(&table)
+                .into_iter()
+                .flat_map(|data| data.indexes())
+                .filter_map(|index| {
+                    let first = columns
+                        .iter()
+                        .find(|field| field.name == index.columns()[0])?;
+                    let (lower, upper) = conjuncts
+                        .iter()
+                        .filter_map(|pred| bounds(pred, first.id))
+                        .reduce(|(lower, upper), (l, u)| {
+                            (tighter(lower, l, true), tighter(upper, u, false))
+                        })?;
+                    Some(IndexRange {
+                        name: index.name().to_string(),
+                        lower,
+                        upper,
+                    })
+                })
+                .max_by_key(|range| {
+                    matches!((&range.lower, &range.upper),
+                        (Bound::Included(l), Bound::Included(u)) if l == u)
+                });
+            LogicalPlan::Scan {
+                table,
+                alias,
+                columns,
+                filter,
+                projection,
+                index,
+            }
+        }
+        other => other,
+    }
+}
+
+/// The range of `col` a predicate of the form `col op constant` (or the reverse) allows
+fn bounds(pred: &Scalar, col: ColumnId) -> Option<(Bound<LiteralValue>, Bound<LiteralValue>)> {
+    let Scalar::Binary { left, op, right } = pred else {
+        return None;
+    };
+    let (op, val) = match (left.as_ref(), right.as_ref()) {
+        (Scalar::Column(id), Scalar::Const(val)) if *id == col => (op.clone(), val),
+        (Scalar::Const(val), Scalar::Column(id)) if *id == col => (
+            match op {
+                Token::LessThan => Token::GreaterThan,
+                Token::LessEqual => Token::GreaterEqual,
+                Token::GreaterThan => Token::LessThan,
+                Token::GreaterEqual => Token::LessEqual,
+                other => other.clone(),
+            },
+            val,
+        ),
+        _ => return None,
+    };
+    if *val == LiteralValue::Null {
+        return None;
+    }
+    let val = val.clone();
+    match op {
+        Token::Equal => Some((Bound::Included(val.clone()), Bound::Included(val))),
+        Token::LessThan => Some((Bound::Unbounded, Bound::Excluded(val))),
+        Token::LessEqual => Some((Bound::Unbounded, Bound::Included(val))),
+        Token::GreaterThan => Some((Bound::Excluded(val), Bound::Unbounded)),
+        Token::GreaterEqual => Some((Bound::Included(val), Bound::Unbounded)),
+        _ => None,
+    }
+}
+
+/// The more restrictive of two lower (or upper) bounds. Bounds on values of different
+/// types can't be compared, so the first one is kept.
+fn tighter(a: Bound<LiteralValue>, b: Bound<LiteralValue>, lower: bool) -> Bound<LiteralValue> {
+    let val = |bound: &Bound<LiteralValue>| match bound {
+        Bound::Included(val) | Bound::Excluded(val) => Some(val.clone()),
+        Bound::Unbounded => None,
+    };
+    match (val(&a), val(&b)) {
+        (None, _) => b,
+        (_, None) => a,
+        (Some(x), Some(y)) if !x.same_kind(&y) => a,
+        (Some(x), Some(y)) if x == y => match a {
+            Bound::Excluded(_) => a,
+            _ => b,
+        },
+        (Some(x), Some(y)) => {
+            if (x < y) == lower {
+                b
+            } else {
+                a
+            }
+        }
+    }
+}
+
+/// Narrows table scans to the columns something above them uses. `required` holds the
+/// columns needed from `plan`'s output.
+pub fn prune_projections(plan: LogicalPlan, required: &HashSet<ColumnId>) -> LogicalPlan {
+    let with = |extra: Vec<ColumnId>| -> HashSet<ColumnId> {
+        required.iter().copied().chain(extra).collect()
+    };
+    match plan {
+        LogicalPlan::Scan {
+            table,
+            alias,
+            columns,
+            filter,
+            projection,
+            index,
+        } => LogicalPlan::Scan {
+            table,
+            alias,
+            projection: projection
+                .into_iter()
+                .filter(|i| required.contains(&columns[*i].id))
+                .collect(),
+            columns,
+            filter,
+            index,
+        },
+        LogicalPlan::Filter { input, predicate } => LogicalPlan::Filter {
+            input: Box::new(prune_projections(*input, &with(predicate.columns()))),
+            predicate,
+        },
+        LogicalPlan::Project { input, exprs } => {
+            let needed = exprs
+                .iter()
+                .flat_map(|(scalar, _)| scalar.columns())
+                .collect();
+            LogicalPlan::Project {
+                input: Box::new(prune_projections(*input, &needed)),
+                exprs,
+            }
+        }
+        LogicalPlan::Join { left, right, on } => {
+            let needed = with(on.iter().flat_map(Scalar::columns).collect());
+            LogicalPlan::Join {
+                left: Box::new(prune_projections(*left, &needed)),
+                right: Box::new(prune_projections(*right, &needed)),
+                on,
+            }
+        }
+        LogicalPlan::Aggregate {
+            input,
+            group_by,
+            aggs,
+        } => {
+            let needed = group_by
+                .iter()
+                .flat_map(|(scalar, _)| scalar.columns())
+                .chain(
+                    aggs.iter()
+                        .flat_map(|(call, _)| call.arg.iter().flat_map(Scalar::columns)),
+                )
+                .collect();
+            LogicalPlan::Aggregate {
+                input: Box::new(prune_projections(*input, &needed)),
+                group_by,
+                aggs,
+            }
+        }
+        LogicalPlan::Sort { input, keys } => {
+            let needed = with(keys.iter().flat_map(|(key, _)| key.columns()).collect());
+            LogicalPlan::Sort {
+                input: Box::new(prune_projections(*input, &needed)),
+                keys,
+            }
+        }
+        LogicalPlan::Distinct { input } => {
+            // Every column takes part in telling rows apart
+            let needed = input.layout().into_iter().collect();
+            LogicalPlan::Distinct {
+                input: Box::new(prune_projections(*input, &needed)),
+            }
+        }
+        other => map_children(other, |child| prune_projections(child, required)),
+    }
+}
+
+/// Applies `f` to each of the node's inputs
+fn map_children(plan: LogicalPlan, mut f: impl FnMut(LogicalPlan) -> LogicalPlan) -> LogicalPlan {
+    let mut apply = |input: Box<LogicalPlan>| Box::new(f(*input));
+    match plan {
+        LogicalPlan::Unit | LogicalPlan::Scan { .. } => plan,
+        LogicalPlan::Filter { input, predicate } => LogicalPlan::Filter {
+            input: apply(input),
+            predicate,
+        },
+        LogicalPlan::Project { input, exprs } => LogicalPlan::Project {
+            input: apply(input),
+            exprs,
+        },
+        LogicalPlan::Join { left, right, on } => LogicalPlan::Join {
+            left: apply(left),
+            right: apply(right),
+            on,
+        },
+        LogicalPlan::Aggregate {
+            input,
+            group_by,
+            aggs,
+        } => LogicalPlan::Aggregate {
+            input: apply(input),
+            group_by,
+            aggs,
+        },
+        LogicalPlan::Sort { input, keys } => LogicalPlan::Sort {
+            input: apply(input),
+            keys,
+        },
+        LogicalPlan::Limit {
+            input,
+            limit,
+            offset,
+        } => LogicalPlan::Limit {
+            input: apply(input),
+            limit,
+            offset,
+        },
+        LogicalPlan::Distinct { input } => LogicalPlan::Distinct {
+            input: apply(input),
+        },
+    }
+}
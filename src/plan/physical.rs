@@ -0,0 +1,727 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+    vec,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    db::{Row, Table},
+    parse::LiteralValue,
+};
+
+use super::expr::{Accumulator, AggCall, Scalar};
+
+/// One row flowing between operators, holding a value for each of the operator's `columns`
+pub type Tuple = Vec<LiteralValue>;
+
+/// A node of a physical plan. Operators are pulled from the root: each call to `next` asks
+/// the children for as many tuples as it needs to produce one of its own.
+pub trait Operator {
+    fn next(&mut self) -> Result<Option<Tuple>>;
+
+    /// Names of the values in each produced tuple, in order
+    fn columns(&self) -> &[String];
+
+    /// One-line summary shown by `EXPLAIN`
+    fn describe(&self) -> String;
+
+    fn children(&self) -> Vec<&dyn Operator>;
+
+    /// Rows produced and time spent so far, if the operator is being measured
+    fn stats(&self) -> Option<(usize, Duration)> {
+        None
+    }
+}
+
+/// Produces a fixed list of tuples
+pub struct Values {
+    columns: Vec<String>,
+    rows: vec::IntoIter<Tuple>,
+}
+
+impl Values {
+    pub fn new(columns: Vec<String>, rows: Vec<Tuple>) -> Self {
+        Values {
+            columns,
+            rows: rows.into_iter(),
+        }
+    }
+}
+
+impl Operator for Values {
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        Ok(self.rows.next())
+    }
+
+    fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    fn describe(&self) -> String {
+        "Result".into()
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        Vec::new()
+    }
+}
+
+/// Reads every row of a table in primary key order, keeping those that pass `filter` and
+/// producing the `projection` of their columns
+pub struct SeqScan<'a> {
+    table_name: String,
+    alias: String,
+
+    /// Every column of the table, which `filter` is evaluated against
+    names: Vec<String>,
+    filter: Option<Scalar>,
+    projection: Vec<usize>,
+    columns: Vec<String>,
+    rows: Box<dyn Iterator<Item = Result<Row>> + 'a>,
+}
+
+impl<'a> SeqScan<'a> {
+    /// `rows` are those of `table` the query sees
+    pub fn new(
+        table_name: String,
+        table: &Table,
+        rows: impl Iterator<Item = Result<Row>> + 'a,
+    ) -> Self {
+        let names: Vec<_> = table
+            .col_headers()
+            .iter()
+            .map(|header| header.name().to_string())
+            .collect();
+        SeqScan {
+            alias: table_name.clone(),
+            table_name,
+            projection: (0..names.len()).collect(),
+            columns: names.clone(),
+            names,
+            filter: None,
+            rows: Box::new(rows),
+        }
+    }
+
+    pub fn alias(mut self, alias: String) -> Self {
+        self.alias = alias;
+        self
+    }
+
+    /// `filter` must be resolved against every column of the table
+    pub fn filter(mut self, filter: Option<Scalar>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn project(mut self, projection: Vec<usize>) -> Self {
+        self.columns = projection.iter().map(|i| self.names[*i].clone()).collect();
+        self.projection = projection;
+        self
+    }
+}
+
+impl Operator for SeqScan<'_> {
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        for row in self.rows.by_ref() {
+            if let Some(tuple) = scan_row(&row?, &self.names, &self.filter, &self.projection)? {
+                return Ok(Some(tuple));
+            }
+        }
+        Ok(None)
+    }
+
+    fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    fn describe(&self) -> String {
+        let mut desc = format!("SeqScan on {}", self.table_name);
+        if self.alias != self.table_name {
+            desc = format!("{} {}", desc, self.alias);
+        }
+        if let Some(filter) = &self.filter {
+            desc = format!("{} (filter: {})", desc, filter.display(&self.names));
+        }
+        desc
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        Vec::new()
+    }
+}
+
+/// The projection of `row`, if it passes `filter`
+fn scan_row(
+    row: &Row,
+    names: &[String],
+    filter: &Option<Scalar>,
+    projection: &[usize],
+) -> Result<Option<Tuple>> {
+    let tuple = row
+        .cols(names)
+        .ok_or_else(|| anyhow!("Unknown column names"))?;
+    if let Some(filter) = filter {
+        if !filter.test(&tuple)? {
+            return Ok(None);
+        }
+    }
+    Ok(Some(projection.iter().map(|i| tuple[*i].clone()).collect()))
+}
+
+/// Like `SeqScan`, but only visits the rows an index range points to, in index order
+pub struct IndexScan<'a> {
+    table_name: String,
+    alias: String,
+    index: String,
+    names: Vec<String>,
+    filter: Option<Scalar>,
+    projection: Vec<usize>,
+    columns: Vec<String>,
+    rows: Box<dyn Iterator<Item = Result<Row>> + 'a>,
+}
+
+impl<'a> IndexScan<'a> {
+    /// `rows` are those the query sees in the range of `index`, in index order
+    pub fn new(
+        table_name: String,
+        table: &Table,
+        index: String,
+        rows: impl Iterator<Item = Result<Row>> + 'a,
+    ) -> Self {
+        let names: Vec<_> = table
+            .col_headers()
+            .iter()
+            .map(|header| header.name().to_string())
+            .collect();
+        IndexScan {
+            alias: table_name.clone(),
+            table_name,
+            index,
+            projection: (0..names.len()).collect(),
+            columns: names.clone(),
+            names,
+            filter: None,
+            rows: Box::new(rows),
+        }
+    }
+
+    pub fn alias(mut self, alias: String) -> Self {
+        self.alias = alias;
+        self
+    }
+
+    /// `filter` must be resolved against every column of the table
+    pub fn filter(mut self, filter: Option<Scalar>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn project(mut self, projection: Vec<usize>) -> Self {
+        self.columns = projection.iter().map(|i| self.names[*i].clone()).collect();
+        self.projection = projection;
+        self
+    }
+}
+
+impl Operator for IndexScan<'_> {
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        for row in self.rows.by_ref() {
+            if let Some(tuple) = scan_row(&row?, &self.names, &self.filter, &self.projection)? {
+                return Ok(Some(tuple));
+            }
+        }
+        Ok(None)
+    }
+
+    fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    fn describe(&self) -> String {
+        let mut desc = format!("IndexScan on {}", self.table_name);
+        if self.alias != self.table_name {
+            desc = format!("{} {}", desc, self.alias);
+        }
+        desc = format!("{} using {}", desc, self.index);
+        if let Some(filter) = &self.filter {
+            desc = format!("{} (filter: {})", desc, filter.display(&self.names));
+        }
+        desc
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        Vec::new()
+    }
+}
+
+/// Passes on the child's tuples that satisfy a predicate
+pub struct Filter<'a> {
+    input: Box<dyn Operator + 'a>,
+    predicate: Scalar,
+}
+
+impl<'a> Filter<'a> {
+    pub fn new(input: Box<dyn Operator + 'a>, predicate: Scalar) -> Self {
+        Filter { input, predicate }
+    }
+}
+
+impl Operator for Filter<'_> {
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        while let Some(tuple) = self.input.next()? {
+            if self.predicate.test(&tuple)? {
+                return Ok(Some(tuple));
+            }
+        }
+        Ok(None)
+    }
+
+    fn columns(&self) -> &[String] {
+        self.input.columns()
+    }
+
+    fn describe(&self) -> String {
+        format!("Filter ({})", self.predicate.display(self.input.columns()))
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+}
+
+/// Computes an expression over the child's tuple for each output column
+pub struct Project<'a> {
+    input: Box<dyn Operator + 'a>,
+    columns: Vec<String>,
+    exprs: Vec<Scalar>,
+}
+
+impl<'a> Project<'a> {
+    /// `exprs` are resolved against the input's columns, and named by `columns`
+    pub fn new(input: Box<dyn Operator + 'a>, columns: Vec<String>, exprs: Vec<Scalar>) -> Self {
+        Project {
+            input,
+            columns,
+            exprs,
+        }
+    }
+}
+
+impl Operator for Project<'_> {
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        self.input
+            .next()?
+            .map(|tuple| self.exprs.iter().map(|expr| expr.eval(&tuple)).collect())
+            .transpose()
+    }
+
+    fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    fn describe(&self) -> String {
+        format!("Project ({})", self.columns.join(", "))
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+}
+
+/// Pairs every left tuple with every right tuple satisfying `on`. The right side is read
+/// into memory once, up front.
+pub struct NestedLoopJoin<'a> {
+    left: Box<dyn Operator + 'a>,
+    right: Box<dyn Operator + 'a>,
+    on: Option<Scalar>,
+    columns: Vec<String>,
+    right_rows: Option<Vec<Tuple>>,
+    current: Option<(Tuple, usize)>,
+}
+
+impl<'a> NestedLoopJoin<'a> {
+    /// `on` is resolved against the left columns followed by the right
+    pub fn new(
+        left: Box<dyn Operator + 'a>,
+        right: Box<dyn Operator + 'a>,
+        on: Option<Scalar>,
+    ) -> Self {
+        let columns = left
+            .columns()
+            .iter()
+            .chain(right.columns())
+            .cloned()
+            .collect();
+        NestedLoopJoin {
+            left,
+            right,
+            on,
+            columns,
+            right_rows: None,
+            current: None,
+        }
+    }
+}
+
+impl Operator for NestedLoopJoin<'_> {
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        if self.right_rows.is_none() {
+            let mut rows = Vec::new();
+            while let Some(tuple) = self.right.next()? {
+                rows.push(tuple);
+            }
+            self.right_rows = Some(rows);
+        }
+        let right_rows = self.right_rows.as_ref().unwrap();
+        loop {
+            let (left, i) = match &mut self.current {
+                Some((left, i)) if *i < right_rows.len() => (left, i),
+                _ => match self.left.next()? {
+                    Some(left) => {
+                        self.current = Some((left, 0));
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+            };
+            let mut tuple = left.clone();
+            tuple.extend(right_rows[*i].iter().cloned());
+            *i += 1;
+            match &self.on {
+                Some(on) if !on.test(&tuple)? => {}
+                _ => return Ok(Some(tuple)),
+            }
+        }
+    }
+
+    fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    fn describe(&self) -> String {
+        match &self.on {
+            Some(on) => format!("NestedLoopJoin ({})", on.display(&self.columns)),
+            None => "NestedLoopJoin".into(),
+        }
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.left.as_ref(), self.right.as_ref()]
+    }
+}
+
+/// Groups the child's tuples by `group_by` in a hash table, producing each group's values
+/// followed by its aggregates once the input is exhausted. Groups come out in the order
+/// they were first seen.
+pub struct HashAggregate<'a> {
+    input: Box<dyn Operator + 'a>,
+    group_by: Vec<Scalar>,
+    aggs: Vec<AggCall>,
+    columns: Vec<String>,
+    output: Option<vec::IntoIter<Tuple>>,
+}
+
+impl<'a> HashAggregate<'a> {
+    /// `group_by` and the arguments in `aggs` are resolved against the input's columns.
+    /// `columns` names the groups, then the aggregates.
+    pub fn new(
+        input: Box<dyn Operator + 'a>,
+        columns: Vec<String>,
+        group_by: Vec<Scalar>,
+        aggs: Vec<AggCall>,
+    ) -> Self {
+        HashAggregate {
+            input,
+            group_by,
+            aggs,
+            columns,
+            output: None,
+        }
+    }
+
+    fn aggregate(&mut self) -> Result<Vec<Tuple>> {
+        let mut groups: Vec<(Tuple, Vec<Accumulator>)> = Vec::new();
+        let mut index = HashMap::new();
+        let new_accs =
+            |aggs: &[AggCall]| aggs.iter().map(|agg| Accumulator::new(agg.func)).collect();
+        // Without GROUP BY, an empty input still has one (empty) group
+        if self.group_by.is_empty() {
+            groups.push((Vec::new(), new_accs(&self.aggs)));
+            index.insert(Vec::new(), 0);
+        }
+        while let Some(tuple) = self.input.next()? {
+            let key = self
+                .group_by
+                .iter()
+                .map(|expr| expr.eval(&tuple))
+                .collect::<Result<Tuple>>()?;
+            let i = *index.entry(key.clone()).or_insert_with(|| {
+                groups.push((key, new_accs(&self.aggs)));
+                groups.len() - 1
+            });
+            for (agg, acc) in self.aggs.iter().zip(&mut groups[i].1) {
+                acc.update(agg.arg.as_ref().map(|arg| arg.eval(&tuple)).transpose()?)?;
+            }
+        }
+        Ok(groups
+            .into_iter()
+            .map(|(mut key, accs)| {
+                key.extend(accs.iter().map(Accumulator::finish));
+                key
+            })
+            .collect())
+    }
+}
+
+impl Operator for HashAggregate<'_> {
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        if self.output.is_none() {
+            self.output = Some(self.aggregate()?.into_iter());
+        }
+        Ok(self.output.as_mut().unwrap().next())
+    }
+
+    fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    fn describe(&self) -> String {
+        let groups: Vec<_> = self
+            .group_by
+            .iter()
+            .map(|expr| expr.display(self.input.columns()).to_string())
+            .collect();
+        let aggs = &self.columns[self.group_by.len()..];
+        if groups.is_empty() {
+            format!("Aggregate ({})", aggs.join(", "))
+        } else {
+            format!(
+                "HashAggregate by {} ({})",
+                groups.join(", "),
+                aggs.join(", ")
+            )
+        }
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+}
+
+/// Reads the whole input and produces it ordered by `keys`, each descending where flagged.
+/// `NULL`s sort last.
+pub struct Sort<'a> {
+    input: Box<dyn Operator + 'a>,
+    keys: Vec<(Scalar, bool)>,
+    output: Option<vec::IntoIter<Tuple>>,
+}
+
+impl<'a> Sort<'a> {
+    /// `keys` are resolved against the input's columns
+    pub fn new(input: Box<dyn Operator + 'a>, keys: Vec<(Scalar, bool)>) -> Self {
+        Sort {
+            input,
+            keys,
+            output: None,
+        }
+    }
+
+    fn sort(&mut self) -> Result<Vec<Tuple>> {
+        let mut rows = Vec::new();
+        while let Some(tuple) = self.input.next()? {
+            let key = self
+                .keys
+                .iter()
+                .map(|(expr, _)| expr.eval(&tuple))
+                .collect::<Result<Vec<_>>>()?;
+            rows.push((key, tuple));
+        }
+        // A stable sort keeps ties in input order
+        rows.sort_by(|(a, _), (b, _)| {
+            a.iter()
+                .zip(b)
+                .zip(&self.keys)
+                .map(|((a, b), (_, desc))| match (a, b) {
+                    (LiteralValue::Null, LiteralValue::Null) => std::cmp::Ordering::Equal,
+                    (LiteralValue::Null, _) => std::cmp::Ordering::Greater,
+                    (_, LiteralValue::Null) => std::cmp::Ordering::Less,
+                    _ if *desc => b.cmp(a),
+                    _ => a.cmp(b),
+                })
+                .find(|ord| ord.is_ne())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        Ok(rows.into_iter().map(|(_, tuple)| tuple).collect())
+    }
+}
+
+impl Operator for Sort<'_> {
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        if self.output.is_none() {
+            self.output = Some(self.sort()?.into_iter());
+        }
+        Ok(self.output.as_mut().unwrap().next())
+    }
+
+    fn columns(&self) -> &[String] {
+        self.input.columns()
+    }
+
+    fn describe(&self) -> String {
+        let keys: Vec<_> = self
+            .keys
+            .iter()
+            .map(|(expr, desc)| {
+                let key = expr.display(self.input.columns()).to_string();
+                if *desc {
+                    format!("{} DESC", key)
+                } else {
+                    key
+                }
+            })
+            .collect();
+        format!("Sort ({})", keys.join(", "))
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+}
+
+/// Skips the first `offset` tuples, then passes on at most `limit`
+pub struct Limit<'a> {
+    input: Box<dyn Operator + 'a>,
+    limit: Option<usize>,
+    offset: usize,
+    produced: usize,
+}
+
+impl<'a> Limit<'a> {
+    pub fn new(input: Box<dyn Operator + 'a>, limit: Option<usize>, offset: usize) -> Self {
+        Limit {
+            input,
+            limit,
+            offset,
+            produced: 0,
+        }
+    }
+}
+
+impl Operator for Limit<'_> {
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        while self.offset > 0 {
+            self.offset -= 1;
+            if self.input.next()?.is_none() {
+                return Ok(None);
+            }
+        }
+        if self.limit.is_some_and(|limit| self.produced >= limit) {
+            return Ok(None);
+        }
+        self.produced += 1;
+        self.input.next()
+    }
+
+    fn columns(&self) -> &[String] {
+        self.input.columns()
+    }
+
+    fn describe(&self) -> String {
+        match (self.limit, self.offset) {
+            (Some(limit), 0) => format!("Limit {}", limit),
+            (Some(limit), offset) => format!("Limit {} offset {}", limit, offset),
+            (None, offset) => format!("Offset {}", offset),
+        }
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+}
+
+/// Passes on the first of each set of equal tuples
+pub struct Distinct<'a> {
+    input: Box<dyn Operator + 'a>,
+    seen: HashSet<Tuple>,
+}
+
+impl<'a> Distinct<'a> {
+    pub fn new(input: Box<dyn Operator + 'a>) -> Self {
+        Distinct {
+            input,
+            seen: HashSet::new(),
+        }
+    }
+}
+
+impl Operator for Distinct<'_> {
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        while let Some(tuple) = self.input.next()? {
+            if self.seen.insert(tuple.clone()) {
+                return Ok(Some(tuple));
+            }
+        }
+        Ok(None)
+    }
+
+    fn columns(&self) -> &[String] {
+        self.input.columns()
+    }
+
+    fn describe(&self) -> String {
+        "Distinct".into()
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+}
+
+/// Wraps an operator to count the tuples it produces and the time spent producing them,
+/// including time spent in its children
+pub struct Instrumented<'a> {
+    inner: Box<dyn Operator + 'a>,
+    rows: usize,
+    elapsed: Duration,
+}
+
+impl<'a> Instrumented<'a> {
+    pub fn new(inner: Box<dyn Operator + 'a>) -> Self {
+        Instrumented {
+            inner,
+            rows: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+}
+
+impl Operator for Instrumented<'_> {
+    fn next(&mut self) -> Result<Option<Tuple>> {
+        let start = Instant::now();
+        let tuple = self.inner.next();
+        self.elapsed += start.elapsed();
+        if let Ok(Some(_)) = tuple {
+            self.rows += 1;
+        }
+        tuple
+    }
+
+    fn columns(&self) -> &[String] {
+        self.inner.columns()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        self.inner.children()
+    }
+
+    fn stats(&self) -> Option<(usize, Duration)> {
+        Some((self.rows, self.elapsed))
+    }
+}